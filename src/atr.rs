@@ -0,0 +1,412 @@
+use std::fmt::Write;
+use crate::error::MifareError;
+
+// One group of interface bytes: TAi, TBi, TCi and TDi
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InterfaceBytes {
+    pub ta: Option<u8>,
+    pub tb: Option<u8>,
+    pub tc: Option<u8>,
+    pub td: Option<u8>,
+}
+
+// ATR decoded according to ISO/IEC 7816-3
+#[derive(Debug, Clone, PartialEq)]
+pub struct Atr {
+    pub bytes: Vec<u8>,
+    pub ts: u8,
+    pub t0: u8,
+    // Interface byte groups, index 0 holding TA1/TB1/TC1/TD1
+    pub interface: Vec<InterfaceBytes>,
+    pub historical_bytes: Vec<u8>,
+    pub tck: Option<u8>,
+}
+
+// PC/SC part 3 description of a contactless storage card, built by the reader
+// in the historical bytes: 80 4F 0C <RID> <SS> <NN NN> 00 00 00 00
+#[derive(Debug, Clone, PartialEq)]
+pub struct PcscStorageCard {
+    pub rid: [u8; 5],
+    pub standard: u8,
+    pub card_name: u16,
+}
+
+// Registered application provider identifier of the PC/SC workgroup
+pub const PCSC_RID: [u8; 5] = [0xA0, 0x00, 0x00, 0x03, 0x06];
+
+// Clock rate conversion factor Fi and maximum frequency (MHz) indexed by FI
+const FI_TABLE: [Option<(u16, f32)>; 16] = [
+    Some((372, 4.0)), Some((372, 5.0)), Some((558, 6.0)), Some((744, 8.0)),
+    Some((1116, 12.0)), Some((1488, 16.0)), Some((1860, 20.0)), None,
+    None, Some((512, 5.0)), Some((768, 7.5)), Some((1024, 10.0)),
+    Some((1536, 15.0)), Some((2048, 20.0)), None, None,
+];
+
+// Baud rate adjustment factor Di indexed by DI
+const DI_TABLE: [Option<u8>; 16] = [
+    None, Some(1), Some(2), Some(4), Some(8), Some(16), Some(32), Some(64),
+    Some(12), Some(20), None, None, None, None, None, None,
+];
+
+impl Atr {
+    pub fn parse(bytes: &[u8]) -> Result<Atr, MifareError> {
+        if bytes.len() < 2 {
+            return Err(MifareError::new("ATR too short"));
+        }
+
+        let ts = bytes[0];
+        if ts != 0x3B && ts != 0x3F {
+            return Err(MifareError::new(&format!("Invalid TS byte {:02X}", ts)));
+        }
+
+        let t0 = bytes[1];
+        let historical_count = (t0 & 0x0F) as usize;
+        let mut interface = Vec::new();
+        let mut y = t0 >> 4;
+        let mut pos = 2;
+
+        loop {
+            let mut group = InterfaceBytes::default();
+            for (bit, slot) in [(0x01, &mut group.ta), (0x02, &mut group.tb), (0x04, &mut group.tc), (0x08, &mut group.td)] {
+                if y & bit != 0 {
+                    let byte = *bytes.get(pos).ok_or_else(|| MifareError::new("ATR truncated in interface bytes"))?;
+                    *slot = Some(byte);
+                    pos += 1;
+                }
+            }
+
+            let td = group.td;
+            if y & 0x0F != 0 {
+                interface.push(group);
+            }
+            match td {
+                Some(td) => y = td >> 4,
+                None => break,
+            }
+        }
+
+        if bytes.len() < pos + historical_count {
+            return Err(MifareError::new("ATR truncated in historical bytes"));
+        }
+        let historical_bytes = bytes[pos..pos + historical_count].to_vec();
+        pos += historical_count;
+
+        let mut atr = Atr {
+            bytes: bytes.to_vec(),
+            ts,
+            t0,
+            interface,
+            historical_bytes,
+            tck: None,
+        };
+
+        // TCK is absent when only T=0 is indicated
+        if atr.tck_expected() {
+            atr.tck = Some(*bytes.get(pos).ok_or_else(|| MifareError::new("ATR is missing TCK"))?);
+            pos += 1;
+        }
+
+        if pos != bytes.len() {
+            return Err(MifareError::new(&format!("ATR has {} extra bytes", bytes.len() - pos)));
+        }
+
+        Ok(atr)
+    }
+
+    // Protocols announced in the TDi bytes (T=0 when none is announced)
+    pub fn protocols(&self) -> Vec<u8> {
+        let mut protocols: Vec<u8> = Vec::new();
+        for td in self.interface.iter().filter_map(|g| g.td) {
+            let protocol = td & 0x0F;
+            if !protocols.contains(&protocol) {
+                protocols.push(protocol);
+            }
+        }
+        if protocols.is_empty() {
+            protocols.push(0);
+        }
+        protocols
+    }
+
+    fn tck_expected(&self) -> bool {
+        self.protocols().iter().any(|&t| t != 0)
+    }
+
+    // TCK makes the XOR of T0 up to and including TCK equal to zero
+    pub fn tck_valid(&self) -> Option<bool> {
+        self.tck?;
+        Some(self.bytes[1..].iter().fold(0, |acc, b| acc ^ b) == 0)
+    }
+
+    pub fn is_direct_convention(&self) -> bool {
+        self.ts == 0x3B
+    }
+
+    // Decode the PC/SC part 3 storage card information, if present
+    pub fn pcsc_storage_card(&self) -> Option<PcscStorageCard> {
+        let h = &self.historical_bytes;
+        if h.len() < 11 || h[0] != 0x80 || h[1] != 0x4F || h[2] < 0x0C {
+            return None;
+        }
+
+        let mut rid = [0u8; 5];
+        rid.copy_from_slice(&h[3..8]);
+        Some(PcscStorageCard {
+            rid,
+            standard: h[8],
+            card_name: u16::from_be_bytes([h[9], h[10]]),
+        })
+    }
+
+    // Human readable explanation, in the spirit of pcsc_scan's ATR analysis
+    pub fn explain(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "ATR: {}", spaced_hex(&self.bytes));
+        let _ = writeln!(out, "+ TS = {:02X} --> {}", self.ts,
+                         if self.is_direct_convention() { "Direct Convention" } else { "Inverse Convention" });
+        let _ = writeln!(out, "+ T0 = {:02X}, Y(1): {:04b}, K: {} (historical bytes)",
+                         self.t0, self.t0 >> 4, self.t0 & 0x0F);
+
+        for (i, group) in self.interface.iter().enumerate() {
+            let n = i + 1;
+            // Protocol announced by the previous TD, to interpret TA/TB/TC of i > 2
+            let protocol = if i == 0 { None } else { self.interface[i - 1].td.map(|td| td & 0x0F) };
+
+            if let Some(ta) = group.ta {
+                let _ = writeln!(out, "  TA({}) = {:02X} --> {}", n, ta, explain_ta(n, ta, protocol));
+            }
+            if let Some(tb) = group.tb {
+                let _ = writeln!(out, "  TB({}) = {:02X} --> {}", n, tb, explain_tb(n, tb, protocol));
+            }
+            if let Some(tc) = group.tc {
+                let _ = writeln!(out, "  TC({}) = {:02X} --> {}", n, tc, explain_tc(n, tc, protocol));
+            }
+            if let Some(td) = group.td {
+                let _ = writeln!(out, "  TD({}) = {:02X} --> Y(i+1) = {:04b}, Protocol T = {}",
+                                 n, td, td >> 4, td & 0x0F);
+                let _ = writeln!(out, "-----");
+            }
+        }
+
+        let _ = writeln!(out, "+ Historical bytes: {}", spaced_hex(&self.historical_bytes));
+        out.push_str(&explain_historical_bytes(&self.historical_bytes));
+
+        if let Some(card) = self.pcsc_storage_card() {
+            let _ = writeln!(out, "+ PC/SC contactless storage card");
+            let rid_name = if card.rid == PCSC_RID { " (PC/SC Workgroup)" } else { "" };
+            let _ = writeln!(out, "  RID: {}{}", spaced_hex(&card.rid), rid_name);
+            let _ = writeln!(out, "  Standard: {:02X} --> {}", card.standard, standard_name(card.standard));
+            let _ = writeln!(out, "  Card name: {:04X} --> {}", card.card_name,
+                             card_name(card.card_name).unwrap_or("Unknown"));
+        }
+
+        match (self.tck, self.tck_valid()) {
+            (Some(tck), Some(true)) => { let _ = writeln!(out, "+ TCK = {:02X} (correct checksum)", tck); },
+            (Some(tck), _) => { let _ = writeln!(out, "+ TCK = {:02X} (WRONG checksum)", tck); },
+            (None, _) => { let _ = writeln!(out, "+ No TCK (only T=0 indicated)"); },
+        }
+
+        out
+    }
+}
+
+fn spaced_hex(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn explain_ta(n: usize, ta: u8, protocol: Option<u8>) -> String {
+    match (n, protocol) {
+        (1, _) => {
+            let fi = FI_TABLE[(ta >> 4) as usize];
+            let di = DI_TABLE[(ta & 0x0F) as usize];
+            match (fi, di) {
+                (Some((fi, fmax)), Some(di)) => format!("Fi={}, Di={}, {} cycles/ETU ({} bits/s at 4 MHz, fMax {} MHz)",
+                                                        fi, di, fi / di as u16,
+                                                        4_000_000 / (fi as u32 / di as u32), fmax),
+                _ => "Fi/Di RFU".to_string(),
+            }
+        },
+        (2, _) => format!("Specific mode: protocol T={}{}", ta & 0x0F,
+                          if ta & 0x10 != 0 { ", parameters implicitly defined" } else { "" }),
+        (_, Some(15)) => {
+            let clock_stop = match ta >> 6 {
+                0 => "not supported",
+                1 => "state L",
+                2 => "state H",
+                _ => "no preference",
+            };
+            let mut classes = Vec::new();
+            for (bit, class) in [(0x01, "A 5V"), (0x02, "B 3V"), (0x04, "C 1.8V")] {
+                if ta & bit != 0 {
+                    classes.push(class);
+                }
+            }
+            format!("Clock stop: {} - Class accepted by the card: {}", clock_stop, classes.join(", "))
+        },
+        (_, Some(1)) => format!("IFSC: {}", ta),
+        _ => "RFU".to_string(),
+    }
+}
+
+fn explain_tb(n: usize, tb: u8, protocol: Option<u8>) -> String {
+    match (n, protocol) {
+        (1, _) | (2, _) => format!("Programming voltage parameters (deprecated): {:02X}", tb),
+        (_, Some(1)) => format!("Character Waiting Time Integer: {}, Block Waiting Time Integer: {}",
+                                tb & 0x0F, tb >> 4),
+        (_, Some(15)) => format!("Standard or proprietary use of SPU: {:02X}", tb),
+        _ => "RFU".to_string(),
+    }
+}
+
+fn explain_tc(n: usize, tc: u8, protocol: Option<u8>) -> String {
+    match (n, protocol) {
+        (1, _) => format!("Extra guard time: {}{}", tc, if tc == 255 { " (minimum)" } else { "" }),
+        (2, _) => format!("Work waiting time: 960 x {} x (Fi/F)", tc),
+        (_, Some(1)) => format!("Error detection code: {}", if tc & 0x01 != 0 { "CRC" } else { "LRC" }),
+        _ => "RFU".to_string(),
+    }
+}
+
+// Compact-TLV tag names of ISO/IEC 7816-4 historical bytes
+fn compact_tlv_tag_name(tag: u8) -> &'static str {
+    match tag {
+        0x1 => "country code, national date",
+        0x2 => "issuer identification number",
+        0x3 => "card service data",
+        0x4 => "initial access data",
+        0x5 => "card issuer's data",
+        0x6 => "pre-issuing data",
+        0x7 => "card capabilities",
+        0x8 => "status indicator",
+        0xF => "application identifier",
+        _ => "RFU",
+    }
+}
+
+fn explain_historical_bytes(h: &[u8]) -> String {
+    let mut out = String::new();
+    let category = match h.first() {
+        Some(&category) => category,
+        None => return out,
+    };
+
+    match category {
+        0x00 => {
+            let _ = writeln!(out, "  Category indicator byte: 00 (status indicator in the last 3 bytes)");
+        },
+        0x10 => {
+            let _ = writeln!(out, "  Category indicator byte: 10 (DIR data reference)");
+        },
+        0x80 => {
+            let _ = writeln!(out, "  Category indicator byte: 80 (compact TLV data object)");
+            let mut pos = 1;
+            while pos < h.len() {
+                let tag = h[pos] >> 4;
+                let len = (h[pos] & 0x0F) as usize;
+                let end = (pos + 1 + len).min(h.len());
+                let _ = writeln!(out, "    Tag: {:X}, len: {:X} ({})", tag, len, compact_tlv_tag_name(tag));
+                let _ = writeln!(out, "      Data: {}", spaced_hex(&h[pos + 1..end]));
+                pos = end;
+            }
+        },
+        _ => {
+            let _ = writeln!(out, "  Category indicator byte: {:02X} (proprietary format)", category);
+        },
+    }
+
+    out
+}
+
+// Standard byte (SS) of the PC/SC part 3 storage card ATR
+pub fn standard_name(standard: u8) -> &'static str {
+    match standard {
+        0x01 => "ISO 14443 A, part 1",
+        0x02 => "ISO 14443 A, part 2",
+        0x03 => "ISO 14443 A, part 3",
+        0x05 => "ISO 14443 B, part 1",
+        0x06 => "ISO 14443 B, part 2",
+        0x07 => "ISO 14443 B, part 3",
+        0x09 => "ISO 15693, part 1",
+        0x0A => "ISO 15693, part 2",
+        0x0B => "ISO 15693, part 3",
+        0x0C => "ISO 15693, part 4",
+        0x0D => "Contact (7816-10) I2C",
+        0x0E => "Contact (7816-10) Extended I2C",
+        0x0F => "Contact (7816-10) 2WBP",
+        0x10 => "Contact (7816-10) 3WBP",
+        0x11 => "FeliCa",
+        0x40 => "Low frequency contactless cards",
+        _ => "RFU",
+    }
+}
+
+// Card name bytes (NN NN) of the PC/SC part 3 storage card ATR
+pub fn card_name(name: u16) -> Option<&'static str> {
+    let name = match name {
+        0x0001 => "MIFARE Classic 1K",
+        0x0002 => "MIFARE Classic 4K",
+        0x0003 => "MIFARE Ultralight",
+        0x0004 => "SLE55R_XXXX",
+        0x0006 => "SR176",
+        0x0007 => "SRI X4K",
+        0x0008 => "AT88RF020",
+        0x0009 => "AT88SC0204CRF",
+        0x000A => "AT88SC0808CRF",
+        0x000B => "AT88SC1616CRF",
+        0x000C => "AT88SC3216CRF",
+        0x000D => "AT88SC6416CRF",
+        0x000E => "SRF55V10P",
+        0x000F => "SRF55V02P",
+        0x0010 => "SRF55V10S",
+        0x0011 => "SRF55V02S",
+        0x0012 => "TAG_IT",
+        0x0013 => "LRI512",
+        0x0014 => "ICODESLI",
+        0x0015 => "TEMPSENS",
+        0x0016 => "I.CODE1",
+        0x0017 => "PicoPass 2K",
+        0x0018 => "PicoPass 2KS",
+        0x0019 => "PicoPass 16K",
+        0x001A => "PicoPass 16Ks",
+        0x001B => "PicoPass 16K (8x2)",
+        0x001C => "PicoPass 16KS (8x2)",
+        0x001D => "PicoPass 32KS (16+16)",
+        0x001E => "PicoPass 32KS (16+8x2)",
+        0x001F => "PicoPass 32KS (8x2+16)",
+        0x0020 => "PicoPass 32KS (8x2+8x2)",
+        0x0021 => "LRI64",
+        0x0022 => "I.CODE UID",
+        0x0023 => "I.CODE EPC",
+        0x0024 => "LRI12",
+        0x0025 => "LRI128",
+        0x0026 => "MIFARE Mini",
+        0x0027 => "my-d move (SLE 66R01P)",
+        0x0028 => "my-d NFC (SLE 66RxxP)",
+        0x0029 => "my-d proximity 2 (SLE 66RxxS)",
+        0x002A => "my-d proximity enhanced (SLE 55RxxE)",
+        0x002B => "my-d light (SRF 55V01P)",
+        0x002C => "PJM Stack Tag (SRF 66V10ST)",
+        0x002D => "PJM Item Tag (SRF 66V10IT)",
+        0x002E => "PJM Light (SRF 66V01ST)",
+        0x002F => "Jewel Tag",
+        0x0030 => "Topaz NFC Tag",
+        0x0031 => "AT88SC0104CRF",
+        0x0032 => "AT88SC0404CRF",
+        0x0033 => "AT88RF01C",
+        0x0034 => "AT88RF04C",
+        0x0035 => "i-Code SL2",
+        0x0036 => "MIFARE Plus SL1 2K",
+        0x0037 => "MIFARE Plus SL1 4K",
+        0x0038 => "MIFARE Plus SL2 2K",
+        0x0039 => "MIFARE Plus SL2 4K",
+        0x003A => "MIFARE Ultralight C",
+        0x003B => "FeliCa",
+        0x003C => "Melexis Sensor Tag (MLX90129)",
+        0x003D => "MIFARE Ultralight EV1",
+        _ => return None,
+    };
+    Some(name)
+}
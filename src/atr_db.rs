@@ -0,0 +1,556 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::UNIX_EPOCH;
+use crate::atr_pattern::AtrPattern;
+
+// The list shipped with the crate, compiled into the binary
+const BUNDLED_LIST: &str = include_str!("../smartcard_list.txt");
+
+// Magic header of the compact cache file
+const CACHE_MAGIC: &[u8; 6] = b"ATRDB4";
+
+// Where an entry comes from, in increasing order of precedence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AtrSource {
+    // smartcard_list.txt compiled into the binary
+    Bundled,
+    // A newer copy of smartcard_list.txt downloaded from upstream
+    Downloaded,
+    // Local additions and corrections (e.g. private badge ATRs)
+    Override,
+}
+
+impl AtrSource {
+    pub fn to_string(&self) -> &str {
+        match self {
+            AtrSource::Bundled => "bundled list",
+            AtrSource::Downloaded => "downloaded list",
+            AtrSource::Override => "local overrides",
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            AtrSource::Bundled => 0,
+            AtrSource::Downloaded => 1,
+            AtrSource::Override => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<AtrSource> {
+        match byte {
+            0 => Some(AtrSource::Bundled),
+            1 => Some(AtrSource::Downloaded),
+            2 => Some(AtrSource::Override),
+            _ => None,
+        }
+    }
+}
+
+// One entry of smartcard_list.txt: an ATR pattern and its description lines
+#[derive(Debug, Clone, PartialEq)]
+pub struct AtrEntry {
+    pub pattern: AtrPattern,
+    pub descriptions: Vec<String>,
+    pub source: AtrSource,
+}
+
+// Two sources describing the same ATR pattern differently
+#[derive(Debug, Clone, PartialEq)]
+pub struct AtrConflict {
+    pub pattern: String,
+    pub kept: AtrSource,
+    pub kept_descriptions: Vec<String>,
+    pub dropped: AtrSource,
+    pub dropped_descriptions: Vec<String>,
+}
+
+// Trie node over ATR bytes; an edge accepts the bytes b with b & mask == value
+#[derive(Debug, Clone, Default)]
+struct Node {
+    edges: Vec<(u8, u8, usize)>,
+    // Entries whose pattern ends at this node
+    entries: Vec<usize>,
+}
+
+// ATR database parsed once and indexed for fast lookups
+#[derive(Debug, Clone)]
+pub struct AtrDatabase {
+    entries: Vec<AtrEntry>,
+    nodes: Vec<Node>,
+    // Entries with repetitions (".*"), which cannot live in the trie
+    unindexed: Vec<usize>,
+}
+
+impl AtrDatabase {
+    // The bundled smartcard_list.txt, parsed on first use
+    pub fn bundled() -> &'static AtrDatabase {
+        static BUNDLED: OnceLock<AtrDatabase> = OnceLock::new();
+        BUNDLED.get_or_init(|| AtrDatabase::parse(BUNDLED_LIST, AtrSource::Bundled))
+    }
+
+    // The bundled list merged with an optional newer downloaded copy and an
+    // optional override file, both in smartcard_list.txt syntax. Missing files
+    // are an error: callers decide which paths are worth passing.
+    pub fn load_merged(downloaded: Option<&Path>, overrides: Option<&Path>) -> io::Result<(AtrDatabase, Vec<AtrConflict>)> {
+        let mut db = AtrDatabase::bundled().clone();
+        let mut conflicts = Vec::new();
+
+        if let Some(path) = downloaded {
+            conflicts.extend(db.merge(AtrDatabase::from_file(path, AtrSource::Downloaded)?));
+        }
+        if let Some(path) = overrides {
+            conflicts.extend(db.merge(AtrDatabase::from_file(path, AtrSource::Override)?));
+        }
+
+        Ok((db, conflicts))
+    }
+
+    // `load_merged` through a cache file, so that a run does not parse the
+    // lists again. The cache is rebuilt when the bundled list or one of the
+    // given files changes; failing to write it only costs speed.
+    pub fn load_cached(cache: &Path, downloaded: Option<&Path>, overrides: Option<&Path>)
+        -> io::Result<(AtrDatabase, Vec<AtrConflict>)> {
+        let stamp = sources_stamp(&[downloaded, overrides])?;
+        if let Ok(cached) = AtrDatabase::load_cache(cache, stamp) {
+            return Ok(cached);
+        }
+
+        let (db, conflicts) = AtrDatabase::load_merged(downloaded, overrides)?;
+        let _ = db.save_cache(cache, &conflicts, stamp);
+        Ok((db, conflicts))
+    }
+
+    // Parse the smartcard_list.txt syntax: an ATR pattern line followed by
+    // tab-indented description lines; '#' starts a comment
+    pub fn parse(text: &str, source: AtrSource) -> AtrDatabase {
+        let mut entries = Vec::new();
+        let mut current: Option<AtrEntry> = None;
+
+        for line in text.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('\t') || line.starts_with(' ') {
+                let description = line.trim();
+                if let (Some(entry), false) = (current.as_mut(), description.is_empty()) {
+                    entry.descriptions.push(description.to_string());
+                }
+                continue;
+            }
+
+            // A blank line or a new pattern closes the previous entry
+            if let Some(entry) = current.take() {
+                entries.push(entry);
+            }
+
+            let line = line.trim();
+            if !line.is_empty() {
+                // Malformed patterns are skipped rather than failing the whole list
+                current = AtrPattern::compile(line)
+                    .ok()
+                    .map(|pattern| AtrEntry { pattern, descriptions: Vec::new(), source });
+            }
+        }
+
+        if let Some(entry) = current.take() {
+            entries.push(entry);
+        }
+
+        AtrDatabase::from_entries(entries)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P, source: AtrSource) -> io::Result<AtrDatabase> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        Ok(AtrDatabase::parse(&text, source))
+    }
+
+    pub fn from_entries(entries: Vec<AtrEntry>) -> AtrDatabase {
+        let mut db = AtrDatabase {
+            entries: Vec::new(),
+            nodes: vec![Node::default()],
+            unindexed: Vec::new(),
+        };
+        for entry in entries {
+            db.insert(entry);
+        }
+        db
+    }
+
+    // Add an entry, keeping duplicates of the same pattern
+    pub fn insert(&mut self, entry: AtrEntry) {
+        let index = self.entries.len();
+
+        match entry.pattern.byte_masks() {
+            Some(masks) => {
+                let mut node = 0;
+                for (value, mask) in masks {
+                    let existing = self.nodes[node].edges.iter()
+                        .find(|&&(v, m, _)| v == value && m == mask)
+                        .map(|&(_, _, child)| child);
+                    node = match existing {
+                        Some(child) => child,
+                        None => {
+                            self.nodes.push(Node::default());
+                            let child = self.nodes.len() - 1;
+                            self.nodes[node].edges.push((value, mask, child));
+                            child
+                        },
+                    };
+                }
+                self.nodes[node].entries.push(index);
+            },
+            None => self.unindexed.push(index),
+        }
+
+        self.entries.push(entry);
+    }
+
+    // Merge the entries of another database. An entry whose pattern already
+    // exists with different descriptions is a conflict: the source with the
+    // higher precedence wins and the conflict is reported, once for every
+    // entry it replaces. Identical entries are dropped; duplicates within one
+    // source are kept as they are.
+    pub fn merge(&mut self, other: AtrDatabase) -> Vec<AtrConflict> {
+        let mut entries: Vec<Option<AtrEntry>> = std::mem::take(&mut self.entries).into_iter().map(Some).collect();
+        let mut by_pattern: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, entry) in entries.iter().flatten().enumerate() {
+            by_pattern.entry(pattern_key(entry.pattern.source())).or_default().push(i);
+        }
+
+        let mut conflicts = Vec::new();
+        for incoming in other.entries {
+            let indices = by_pattern.entry(pattern_key(incoming.pattern.source())).or_default();
+            let existing = |i: &usize| entries[*i].as_ref().unwrap();
+            let top = indices.iter().map(|i| existing(i).source).max();
+
+            if top.is_some_and(|top| top > incoming.source) {
+                if !indices.iter().any(|i| existing(i).descriptions == incoming.descriptions) {
+                    let current = indices.iter().map(existing).find(|e| Some(e.source) == top).unwrap();
+                    conflicts.push(AtrConflict {
+                        pattern: current.pattern.source().to_string(),
+                        kept: current.source,
+                        kept_descriptions: current.descriptions.clone(),
+                        dropped: incoming.source,
+                        dropped_descriptions: incoming.descriptions,
+                    });
+                }
+                continue;
+            }
+
+            // Every lower-precedence entry that disagrees is replaced
+            let mut replaced = Vec::new();
+            indices.retain(|&i| {
+                let current = entries[i].as_ref().unwrap();
+                if current.source == incoming.source || current.descriptions == incoming.descriptions {
+                    return true;
+                }
+                conflicts.push(AtrConflict {
+                    pattern: incoming.pattern.source().to_string(),
+                    kept: incoming.source,
+                    kept_descriptions: incoming.descriptions.clone(),
+                    dropped: current.source,
+                    dropped_descriptions: current.descriptions.clone(),
+                });
+                replaced.push(i);
+                false
+            });
+            for &i in &replaced {
+                entries[i] = None;
+            }
+
+            if indices.iter().any(|&i| entries[i].as_ref().unwrap().descriptions == incoming.descriptions) {
+                continue;
+            }
+            // Take the place of the first entry replaced, if any
+            match replaced.first() {
+                Some(&i) => entries[i] = Some(incoming),
+                None => {
+                    indices.push(entries.len());
+                    entries.push(Some(incoming));
+                },
+            }
+            indices.sort_unstable();
+        }
+
+        *self = AtrDatabase::from_entries(entries.into_iter().flatten().collect());
+        conflicts
+    }
+
+    // Write the entries whose pattern `upstream` lacks in the
+    // smartcard_list.txt syntax used for submissions upstream
+    // (https://smartcard-atr.apdu.fr/). Corrections to upstream entries stay
+    // local: only new ATRs are worth submitting.
+    pub fn export_additions<W: Write>(&self, upstream: &AtrDatabase, out: &mut W) -> io::Result<usize> {
+        let known: HashSet<String> = upstream.entries.iter().map(|e| pattern_key(e.pattern.source())).collect();
+        let mut count = 0;
+        for entry in self.entries.iter().filter(|e| !known.contains(&pattern_key(e.pattern.source()))) {
+            writeln!(out, "{}", entry.pattern.source())?;
+            for description in &entry.descriptions {
+                writeln!(out, "\t{}", description)?;
+            }
+            writeln!(out)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> &[AtrEntry] {
+        &self.entries
+    }
+
+    // All entries matching the ATR, most specific first
+    pub fn lookup(&self, atr: &[u8]) -> Vec<&AtrEntry> {
+        let mut candidates = Vec::new();
+        let mut stack = vec![(0usize, 0usize)];
+
+        // Walk every trie path compatible with the ATR bytes
+        while let Some((node, depth)) = stack.pop() {
+            if depth == atr.len() {
+                candidates.extend_from_slice(&self.nodes[node].entries);
+                continue;
+            }
+            for &(value, mask, child) in &self.nodes[node].edges {
+                if atr[depth] & mask == value {
+                    stack.push((child, depth + 1));
+                }
+            }
+        }
+        candidates.extend_from_slice(&self.unindexed);
+
+        // The trie widens bracket classes to whole nibbles: confirm each hit
+        let mut matches: Vec<&AtrEntry> = candidates.into_iter()
+            .map(|i| &self.entries[i])
+            .filter(|entry| entry.pattern.matches(atr))
+            .collect();
+        matches.sort_by(|a, b| a.pattern.specificity_cmp(&b.pattern));
+        matches
+    }
+
+    // Write the entries and the conflicts met while merging them to a
+    // compact binary cache file. `stamp` identifies the sources the entries
+    // came from (see `load_cached`).
+    pub fn save_cache<P: AsRef<Path>>(&self, path: P, conflicts: &[AtrConflict], stamp: u64) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(CACHE_MAGIC)?;
+        out.write_all(&stamp.to_le_bytes())?;
+        out.write_all(&(self.entries.len() as u32).to_le_bytes())?;
+
+        for entry in &self.entries {
+            write_string(&mut out, entry.pattern.source())?;
+            write_bytes(&mut out, &entry.pattern.compiled())?;
+            out.write_all(&[entry.source.to_byte()])?;
+            write_strings(&mut out, &entry.descriptions)?;
+        }
+
+        // The trie as built, so loading neither compiles nor indexes
+        out.write_all(&(self.nodes.len() as u32).to_le_bytes())?;
+        for node in &self.nodes {
+            out.write_all(&(node.edges.len() as u16).to_le_bytes())?;
+            for &(value, mask, child) in &node.edges {
+                out.write_all(&[value, mask])?;
+                out.write_all(&(child as u32).to_le_bytes())?;
+            }
+            write_indices(&mut out, &node.entries)?;
+        }
+        write_indices(&mut out, &self.unindexed)?;
+
+        out.write_all(&(conflicts.len() as u32).to_le_bytes())?;
+        for conflict in conflicts {
+            write_string(&mut out, &conflict.pattern)?;
+            out.write_all(&[conflict.kept.to_byte(), conflict.dropped.to_byte()])?;
+            write_strings(&mut out, &conflict.kept_descriptions)?;
+            write_strings(&mut out, &conflict.dropped_descriptions)?;
+        }
+
+        out.flush()
+    }
+
+    // Read a cache file written by `save_cache` with the same stamp
+    pub fn load_cache<P: AsRef<Path>>(path: P, stamp: u64) -> io::Result<(AtrDatabase, Vec<AtrConflict>)> {
+        let mut input = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 6];
+        input.read_exact(&mut magic)?;
+        if &magic != CACHE_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an ATR database cache file"));
+        }
+        let mut cached_stamp = [0u8; 8];
+        input.read_exact(&mut cached_stamp)?;
+        if u64::from_le_bytes(cached_stamp) != stamp {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "ATR database cache is out of date"));
+        }
+
+        let count = read_u32(&mut input)? as usize;
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let source = read_string(&mut input)?;
+            let pattern = AtrPattern::from_compiled(source, &read_bytes(&mut input)?)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Malformed compiled pattern in cache"))?;
+
+            let source = read_source(&mut input)?;
+            let descriptions = read_strings(&mut input)?;
+            entries.push(AtrEntry { pattern, descriptions, source });
+        }
+
+        let node_count = read_u32(&mut input)? as usize;
+        let mut nodes = Vec::with_capacity(node_count);
+        for _ in 0..node_count {
+            let edge_count = read_u16(&mut input)? as usize;
+            let mut edges = Vec::with_capacity(edge_count);
+            for _ in 0..edge_count {
+                let mut edge = [0u8; 6];
+                input.read_exact(&mut edge)?;
+                let child = u32::from_le_bytes([edge[2], edge[3], edge[4], edge[5]]) as usize;
+                if child >= node_count {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Trie edge out of range in cache"));
+                }
+                edges.push((edge[0], edge[1], child));
+            }
+            nodes.push(Node { edges, entries: read_indices(&mut input, entries.len())? });
+        }
+        if nodes.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty trie in cache"));
+        }
+        let unindexed = read_indices(&mut input, entries.len())?;
+
+        let count = read_u32(&mut input)? as usize;
+        let mut conflicts = Vec::with_capacity(count);
+        for _ in 0..count {
+            let pattern = read_string(&mut input)?;
+            let kept = read_source(&mut input)?;
+            let dropped = read_source(&mut input)?;
+            let kept_descriptions = read_strings(&mut input)?;
+            let dropped_descriptions = read_strings(&mut input)?;
+            conflicts.push(AtrConflict { pattern, kept, kept_descriptions, dropped, dropped_descriptions });
+        }
+
+        Ok((AtrDatabase { entries, nodes, unindexed }, conflicts))
+    }
+}
+
+// Patterns are compared case-insensitively and regardless of spacing
+fn pattern_key(source: &str) -> String {
+    source.split_whitespace()
+        .map(|part| part.to_uppercase())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// FNV-1a over the bundled list and the size and modification time of each
+// source file, so that a cache built from other sources is not used
+fn sources_stamp(paths: &[Option<&Path>]) -> io::Result<u64> {
+    fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+        bytes.iter().fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01B3))
+    }
+
+    let mut hash = fnv(0xCBF2_9CE4_8422_2325, BUNDLED_LIST.as_bytes());
+    for path in paths {
+        let Some(path) = path else {
+            hash = fnv(hash, &[0]);
+            continue;
+        };
+        let metadata = path.metadata()?;
+        let modified = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        hash = fnv(hash, &[1]);
+        hash = fnv(hash, path.to_string_lossy().as_bytes());
+        hash = fnv(hash, &metadata.len().to_le_bytes());
+        hash = fnv(hash, &modified.to_le_bytes());
+    }
+    Ok(hash)
+}
+
+fn write_string<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+    let len = u16::try_from(s.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "String too long for cache"))?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(s.as_bytes())
+}
+
+fn write_strings<W: Write>(out: &mut W, strings: &[String]) -> io::Result<()> {
+    out.write_all(&(strings.len() as u16).to_le_bytes())?;
+    for s in strings {
+        write_string(out, s)?;
+    }
+    Ok(())
+}
+
+fn write_bytes<W: Write>(out: &mut W, bytes: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Pattern too long for cache"))?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(bytes)
+}
+
+fn write_indices<W: Write>(out: &mut W, indices: &[usize]) -> io::Result<()> {
+    out.write_all(&(indices.len() as u32).to_le_bytes())?;
+    for &index in indices {
+        out.write_all(&(index as u32).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u16<R: Read>(input: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    input.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_string<R: Read>(input: &mut R) -> io::Result<String> {
+    let len = read_u16(input)? as usize;
+    let mut buf = vec![0u8; len];
+    input.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn read_strings<R: Read>(input: &mut R) -> io::Result<Vec<String>> {
+    let count = read_u16(input)? as usize;
+    (0..count).map(|_| read_string(input)).collect()
+}
+
+fn read_bytes<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u16(input)? as usize;
+    let mut buf = vec![0u8; len];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+// Entry indices, each checked against the number of entries
+fn read_indices<R: Read>(input: &mut R, entries: usize) -> io::Result<Vec<usize>> {
+    let count = read_u32(input)? as usize;
+    (0..count)
+        .map(|_| match read_u32(input)? as usize {
+            index if index < entries => Ok(index),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Entry index out of range in cache")),
+        })
+        .collect()
+}
+
+fn read_source<R: Read>(input: &mut R) -> io::Result<AtrSource> {
+    let mut byte = [0u8; 1];
+    input.read_exact(&mut byte)?;
+    AtrSource::from_byte(byte[0])
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unknown entry source in cache"))
+}
@@ -0,0 +1,230 @@
+use std::cmp::Ordering;
+use crate::error::MifareError;
+
+// Symbols of an ATR written as text: the 16 hex digits and the separating space
+const SPACE: u32 = 1 << 16;
+const ANY: u32 = 0x1FFFF;
+
+// One position of a compiled pattern: the set of accepted symbols and how
+// many times it may repeat
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    mask: u32,
+    min: usize,
+    unbounded: bool,
+}
+
+// ATR pattern in the regular expression form of smartcard_list.txt, e.g.
+// "3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 .. .. 00 00 00 00 .."
+#[derive(Debug, Clone, PartialEq)]
+pub struct AtrPattern {
+    source: String,
+    tokens: Vec<Token>,
+}
+
+fn symbol(c: char) -> Option<u32> {
+    match c {
+        ' ' => Some(SPACE),
+        _ => c.to_digit(16).map(|d| 1 << d),
+    }
+}
+
+impl AtrPattern {
+    // Compile a pattern: hex digits, spaces, "." (any symbol), bracket classes
+    // with ranges ("[1-5]", "[FE]", "[^0]") and the quantifiers "*", "+" and "?"
+    pub fn compile(source: &str) -> Result<AtrPattern, MifareError> {
+        let source = source.trim();
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut chars = source.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            let mask = match c {
+                '.' => ANY,
+                '[' => {
+                    let negated = chars.peek() == Some(&'^');
+                    if negated {
+                        chars.next();
+                    }
+
+                    let mut mask = 0;
+                    let mut previous: Option<char> = None;
+                    loop {
+                        let c = chars.next()
+                            .ok_or_else(|| MifareError::new(&format!("Unterminated class in pattern '{}'", source)))?;
+                        match c {
+                            ']' => break,
+                            '-' if previous.is_some() && chars.peek().is_some_and(|&n| n != ']') => {
+                                let start = previous.and_then(|p| p.to_digit(16));
+                                let end = chars.next().and_then(|n| n.to_digit(16));
+                                match (start, end) {
+                                    (Some(start), Some(end)) if start <= end => {
+                                        for d in start..=end {
+                                            mask |= 1 << d;
+                                        }
+                                    },
+                                    _ => return Err(MifareError::new(&format!("Invalid range in pattern '{}'", source))),
+                                }
+                                previous = None;
+                            },
+                            // Commas and other separators never appear in an ATR
+                            c => {
+                                mask |= symbol(c).unwrap_or(0);
+                                previous = Some(c);
+                            },
+                        }
+                    }
+
+                    if negated {
+                        ANY & !mask
+                    } else {
+                        mask
+                    }
+                },
+                '*' | '+' | '?' => {
+                    let last = tokens.last_mut()
+                        .ok_or_else(|| MifareError::new(&format!("Dangling quantifier in pattern '{}'", source)))?;
+                    match c {
+                        '*' => { last.min = 0; last.unbounded = true; },
+                        '+' => { last.unbounded = true; },
+                        _ => { last.min = 0; },
+                    }
+                    continue;
+                },
+                '\t' => SPACE,
+                c => symbol(c)
+                    .ok_or_else(|| MifareError::new(&format!("Unsupported character '{}' in pattern '{}'", c, source)))?,
+            };
+
+            tokens.push(Token { mask, min: 1, unbounded: false });
+        }
+
+        Ok(AtrPattern { source: source.to_string(), tokens })
+    }
+
+    // Compiled form kept in the ATR database cache: four bytes per token, the
+    // symbol mask (three bytes, little endian) then the minimum count in bit 0
+    // and the unbounded flag in bit 1
+    pub(crate) fn compiled(&self) -> Vec<u8> {
+        self.tokens.iter()
+            .flat_map(|t| {
+                let [m0, m1, m2, _] = t.mask.to_le_bytes();
+                [m0, m1, m2, t.min as u8 | (t.unbounded as u8) << 1]
+            })
+            .collect()
+    }
+
+    // Pattern read back from `compiled`, without parsing `source` again
+    pub(crate) fn from_compiled(source: String, compiled: &[u8]) -> Option<AtrPattern> {
+        if !compiled.len().is_multiple_of(4) {
+            return None;
+        }
+        let tokens = compiled.chunks(4)
+            .map(|t| {
+                let mask = u32::from_le_bytes([t[0], t[1], t[2], 0]);
+                (mask != 0 && mask <= ANY && t[3] <= 3)
+                    .then_some(Token { mask, min: (t[3] & 1) as usize, unbounded: t[3] & 2 != 0 })
+            })
+            .collect::<Option<Vec<Token>>>()?;
+        Some(AtrPattern { source, tokens })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // A pattern without any wildcard matches exactly one ATR
+    pub fn is_exact(&self) -> bool {
+        self.tokens.iter().all(|t| t.min == 1 && !t.unbounded && t.mask.count_ones() == 1)
+    }
+
+    // Number of wildcard nibbles; an open-ended repetition counts as more than
+    // any ATR can hold
+    pub fn wildcard_count(&self) -> usize {
+        self.tokens.iter()
+            .filter(|t| t.mask != SPACE)
+            .map(|t| if t.unbounded { 100 } else if t.mask.count_ones() > 1 || t.min == 0 { 1 } else { 0 })
+            .sum()
+    }
+
+    // Nibbles of the pattern that are fully specified
+    pub fn fixed_count(&self) -> usize {
+        self.tokens.iter()
+            .filter(|t| t.mask != SPACE && t.min == 1 && !t.unbounded && t.mask.count_ones() == 1)
+            .count()
+    }
+
+    // Order patterns from most to least specific: exact patterns first, then
+    // fewer wildcards, then more fixed nibbles
+    pub fn specificity_cmp(&self, other: &AtrPattern) -> Ordering {
+        other.is_exact().cmp(&self.is_exact())
+            .then(self.wildcard_count().cmp(&other.wildcard_count()))
+            .then(other.fixed_count().cmp(&self.fixed_count()))
+    }
+
+    // Fixed-length view of the pattern as (value, mask) per byte, where a nibble
+    // that is not a single hex digit is fully masked out. Classes such as [1-5]
+    // therefore widen to a wildcard, so a byte-mask hit still needs `matches`.
+    // None for patterns with repetitions, which have no fixed length.
+    pub fn byte_masks(&self) -> Option<Vec<(u8, u8)>> {
+        if self.tokens.iter().any(|t| t.min != 1 || t.unbounded) || !(self.tokens.len() + 1).is_multiple_of(3) {
+            return None;
+        }
+
+        let mut bytes = Vec::new();
+        for chunk in self.tokens.chunks(3) {
+            if chunk.len() == 3 && chunk[2].mask != SPACE {
+                return None;
+            }
+
+            let mut value = 0;
+            let mut mask = 0;
+            for (nibble, shift) in [(&chunk[0], 4), (&chunk[1], 0)] {
+                if nibble.mask == SPACE {
+                    return None;
+                }
+                if nibble.mask.count_ones() == 1 {
+                    value |= (nibble.mask.trailing_zeros() as u8) << shift;
+                    mask |= 0x0F << shift;
+                }
+            }
+            bytes.push((value, mask));
+        }
+        Some(bytes)
+    }
+
+    pub fn matches(&self, atr: &[u8]) -> bool {
+        let text: Vec<u32> = atr.iter()
+            .enumerate()
+            .flat_map(|(i, b)| {
+                let space = if i == 0 { None } else { Some(SPACE) };
+                space.into_iter().chain([1 << (b >> 4), 1 << (b & 0x0F)])
+            })
+            .collect();
+        self.match_at(0, &text)
+    }
+
+    // Whole-string match starting at token `ti`, with backtracking for repetitions
+    fn match_at(&self, ti: usize, text: &[u32]) -> bool {
+        let token = match self.tokens.get(ti) {
+            Some(token) => token,
+            None => return text.is_empty(),
+        };
+
+        let max = if token.unbounded { text.len() } else { 1 };
+        let mut consumed = 0;
+        while consumed < max && consumed < text.len() && token.mask & text[consumed] != 0 {
+            consumed += 1;
+        }
+
+        // Greedy first, giving symbols back as needed
+        (token.min..=consumed).rev().any(|n| self.match_at(ti + 1, &text[n..]))
+    }
+}
+
+// Text form of an ATR, as written in smartcard_list.txt
+pub fn atr_to_string(atr: &[u8]) -> String {
+    atr.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
@@ -1,337 +1,41 @@
 use std::time::Duration;
 use std::thread;
 use std::error::Error;
-use std::fmt;
-use pcsc::{Card, Context, Scope, ShareMode, Protocols, Disposition};
-
-// Custom error type for MIFARE operations
-#[derive(Debug)]
-struct MifareError {
-    message: String,
-    status: Option<(u8, u8)>,
-}
-
-impl MifareError {
-    fn new(message: &str) -> Self {
-        MifareError {
-            message: message.to_string(),
-            status: None,
-        }
-    }
-
-    fn with_status(message: &str, status1: u8, status2: u8) -> Self {
-        MifareError {
-            message: message.to_string(),
-            status: Some((status1, status2)),
-        }
-    }
-}
-
-impl fmt::Display for MifareError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.status {
-            Some((s1, s2)) => write!(f, "{}: Status {:02X} {:02X}", self.message, s1, s2),
-            None => write!(f, "{}", self.message),
-        }
-    }
-}
-
-impl Error for MifareError {}
-
-// Enum for key types
-#[derive(Copy, Clone)]
-enum KeyType {
-    KeyA = 0x60,
-    KeyB = 0x61,
-}
-
-// Structure to represent a MIFARE Classic card
-struct MifareClassic<'a> {
-    card: &'a Card,
-}
-
-impl<'a> MifareClassic<'a> {
-    // Create a new MIFARE Classic handler
-    fn new(card: &'a Card) -> Self {
-        MifareClassic { card }
-    }
-
-    // Read UID of the card
-    fn read_uid(&self) -> Result<Vec<u8>, Box<dyn Error>> {
-        let get_uid = [0xFF, 0xCA, 0x00, 0x00, 0x00];
-        let mut recv_buffer = [0; 256];
-        
-        let response = self.card.transmit(&get_uid, &mut recv_buffer)?;
-        if response.len() >= 2 {
-            let status1 = response[response.len() - 2];
-            let status2 = response[response.len() - 1];
-            
-            if status1 == 0x90 && status2 == 0x00 {
-                // Extract UID (excluding status bytes)
-                return Ok(response[0..response.len() - 2].to_vec());
-            } else {
-                return Err(Box::new(MifareError::with_status(
-                    "Failed to read UID", status1, status2
-                )));
-            }
-        }
-        
-        Err(Box::new(MifareError::new("Invalid response length when reading UID")))
-    }
-
-    // Load authentication key
-    fn load_key(&self, key: &[u8]) -> Result<(), Box<dyn Error>> {
-        if key.len() != 6 {
-            return Err(Box::new(MifareError::new("Key must be exactly 6 bytes")));
-        }
-        
-        let mut load_key_cmd = vec![0xFF, 0x82, 0x00, 0x00, 0x06];
-        load_key_cmd.extend_from_slice(key);
-        
-        let mut recv_buffer = [0; 256];
-        let response = self.card.transmit(&load_key_cmd, &mut recv_buffer)?;
-        
-        if response.len() >= 2 {
-            let status1 = response[response.len() - 2];
-            let status2 = response[response.len() - 1];
-            
-            if status1 == 0x90 && status2 == 0x00 {
-                return Ok(());
-            } else {
-                return Err(Box::new(MifareError::with_status(
-                    "Failed to load key", status1, status2
-                )));
-            }
-        }
-        
-        Err(Box::new(MifareError::new("Invalid response length when loading key")))
-    }
-
-    // Authenticate with loaded key
-    fn authenticate(&self, block: u8, key_type: KeyType) -> Result<(), Box<dyn Error>> {
-        let key_value = key_type as u8;
-        let auth_cmd = [0xFF, 0x86, 0x00, 0x00, 0x05, 0x01, 0x00, block, key_value, 0x00];
-        
-        let mut recv_buffer = [0; 256];
-        let response = self.card.transmit(&auth_cmd, &mut recv_buffer)?;
-        
-        if response.len() >= 2 {
-            let status1 = response[response.len() - 2];
-            let status2 = response[response.len() - 1];
-            
-            if status1 == 0x90 && status2 == 0x00 {
-                return Ok(());
-            } else {
-                return Err(Box::new(MifareError::with_status(
-                    &format!("Authentication failed for block {}", block), 
-                    status1, status2
-                )));
-            }
-        }
-        
-        Err(Box::new(MifareError::new("Invalid response length during authentication")))
-    }
-
-    // Read a block
-    fn read_block(&self, block: u8) -> Result<Vec<u8>, Box<dyn Error>> {
-        let read_cmd = [0xFF, 0xB0, 0x00, block, 0x10];
-        
-        let mut recv_buffer = [0; 256];
-        let response = self.card.transmit(&read_cmd, &mut recv_buffer)?;
-        
-        if response.len() >= 2 {
-            let status1 = response[response.len() - 2];
-            let status2 = response[response.len() - 1];
-            
-            if status1 == 0x90 && status2 == 0x00 {
-                // Extract data (excluding status bytes)
-                return Ok(response[0..response.len() - 2].to_vec());
-            } else {
-                return Err(Box::new(MifareError::with_status(
-                    &format!("Failed to read block {}", block), 
-                    status1, status2
-                )));
-            }
-        }
-        
-        Err(Box::new(MifareError::new("Invalid response length when reading block")))
-    }
-
-    // Write to a block
-    fn write_block(&self, block: u8, data: &[u8]) -> Result<(), Box<dyn Error>> {
-        if data.len() != 16 {
-            return Err(Box::new(MifareError::new("Data must be exactly 16 bytes")));
-        }
-        
-        let mut write_cmd = vec![0xFF, 0xD6, 0x00, block, 0x10];
-        write_cmd.extend_from_slice(data);
-        
-        let mut recv_buffer = [0; 256];
-        let response = self.card.transmit(&write_cmd, &mut recv_buffer)?;
-        
-        if response.len() >= 2 {
-            let status1 = response[response.len() - 2];
-            let status2 = response[response.len() - 1];
-            
-            if status1 == 0x90 && status2 == 0x00 {
-                return Ok(());
-            } else {
-                return Err(Box::new(MifareError::with_status(
-                    &format!("Failed to write to block {}", block), 
-                    status1, status2
-                )));
-            }
-        }
-        
-        Err(Box::new(MifareError::new("Invalid response length when writing block")))
-    }
-
-    // Increment a value block
-    fn increment_value(&self, block: u8, value: i32) -> Result<(), Box<dyn Error>> {
-        // Value blocks must be in a specific format
-        let mut cmd = vec![0xFF, 0xD7, 0x00, block, 0x05, 0x01];
-        
-        // Convert value to bytes (little-endian)
-        let value_bytes = value.to_le_bytes();
-        cmd.extend_from_slice(&value_bytes);
-        
-        let mut recv_buffer = [0; 256];
-        let response = self.card.transmit(&cmd, &mut recv_buffer)?;
-        
-        if response.len() >= 2 {
-            let status1 = response[response.len() - 2];
-            let status2 = response[response.len() - 1];
-            
-            if status1 == 0x90 && status2 == 0x00 {
-                return Ok(());
-            } else {
-                return Err(Box::new(MifareError::with_status(
-                    &format!("Failed to increment value block {}", block), 
-                    status1, status2
-                )));
-            }
-        }
-        
-        Err(Box::new(MifareError::new("Invalid response length when incrementing value")))
-    }
-
-    // Decrement a value block
-    fn decrement_value(&self, block: u8, value: i32) -> Result<(), Box<dyn Error>> {
-        let mut cmd = vec![0xFF, 0xD7, 0x00, block, 0x05, 0x02];
-        
-        // Convert value to bytes (little-endian)
-        let value_bytes = value.to_le_bytes();
-        cmd.extend_from_slice(&value_bytes);
-        
-        let mut recv_buffer = [0; 256];
-        let response = self.card.transmit(&cmd, &mut recv_buffer)?;
-        
-        if response.len() >= 2 {
-            let status1 = response[response.len() - 2];
-            let status2 = response[response.len() - 1];
-            
-            if status1 == 0x90 && status2 == 0x00 {
-                return Ok(());
-            } else {
-                return Err(Box::new(MifareError::with_status(
-                    &format!("Failed to decrement value block {}", block), 
-                    status1, status2
-                )));
-            }
-        }
-        
-        Err(Box::new(MifareError::new("Invalid response length when decrementing value")))
-    }
-
-    // Initialize a block as value block
-    fn init_value_block(&self, block: u8, value: i32) -> Result<(), Box<dyn Error>> {
-        // Value block format: value (4 bytes), ~value (4 bytes), value (4 bytes), block address (1 byte), ~block address (1 byte), block address (1 byte), ~block address (1 byte)
-        let mut data = [0u8; 16];
-        
-        // Convert value to bytes (little-endian)
-        let value_bytes = value.to_le_bytes();
-        
-        // Set value (first 4 bytes)
-        data[0..4].copy_from_slice(&value_bytes);
-        
-        // Set inverted value (next 4 bytes)
-        let inverted_value = !value;
-        let inverted_bytes = inverted_value.to_le_bytes();
-        data[4..8].copy_from_slice(&inverted_bytes);
-        
-        // Set value again (next 4 bytes)
-        data[8..12].copy_from_slice(&value_bytes);
-        
-        // Set block address and its complement
-        data[12] = block;
-        data[13] = !block;
-        data[14] = block;
-        data[15] = !block;
-        
-        // Write the value block
-        self.write_block(block, &data)
-    }
-
-    // Read a value from a value block
-    fn read_value(&self, block: u8) -> Result<i32, Box<dyn Error>> {
-        let data = self.read_block(block)?;
-        
-        if data.len() < 16 {
-            return Err(Box::new(MifareError::new("Invalid value block data length")));
-        }
-        
-        // Check if this is a valid value block
-        if data[0..4] != data[8..12] || data[12] != data[14] || data[13] != data[15] {
-            return Err(Box::new(MifareError::new("Invalid value block format")));
-        }
-        
-        // Convert first 4 bytes to i32 (little-endian)
-        let mut value_bytes = [0u8; 4];
-        value_bytes.copy_from_slice(&data[0..4]);
-        let value = i32::from_le_bytes(value_bytes);
-        
-        Ok(value)
-    }
-
-    // MIFARE direct command (for advanced operations)
-    fn direct_command(&self, command: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut recv_buffer = [0; 256];
-        let response = self.card.transmit(command, &mut recv_buffer)?;
-        
-        if response.len() >= 2 {
-            let status1 = response[response.len() - 2];
-            let status2 = response[response.len() - 1];
-            
-            if status1 == 0x90 && status2 == 0x00 {
-                return Ok(response[0..response.len() - 2].to_vec());
-            } else {
-                return Err(Box::new(MifareError::with_status(
-                    "Direct command failed", status1, status2
-                )));
-            }
-        }
-        
-        Err(Box::new(MifareError::new("Invalid response length for direct command")))
-    }
-}
-
-// Helper function to format bytes as hex string
-fn format_hex(bytes: &[u8]) -> String {
-    bytes.iter()
-         .map(|b| format!("{:02X}", b))
-         .collect::<Vec<String>>()
-         .join("")
-}
+use std::path::Path;
+use pcsc::{Context, Scope, ShareMode, Protocols, Disposition};
+use acr122u_test::capability::{Capabilities, Handler};
+use acr122u_test::classic::{KeyType, MifareClassic};
+use acr122u_test::format_hex;
+use acr122u_test::identify::{self, MifareType};
+use acr122u_test::keys::{KeyDictionary, DICTIONARY_PATH};
+use acr122u_test::mad::{crc8, Mad};
+use acr122u_test::nested::NestedAttack;
+use acr122u_test::pn532::{Pn532, MAX_TARGETS};
+use acr122u_test::transport::RawReaderLink;
 
 // Helper function to print block data
 fn print_block_data(block_num: u8, data: &[u8]) {
     println!("Block {:02}: {:?}", block_num, format_hex(data));
     
     println!("       : ASCII: {}", data.iter()
-        .map(|&b| if b >= 32 && b <= 126 { b as char } else { '.' })
+        .map(|&b| if (32..=126).contains(&b) { b as char } else { '.' })
         .collect::<String>());
 }
 
+// Helper function to print the MAD held in sector 0 blocks 1-2
+fn print_mad(mad1: &[u8]) {
+    match Mad::decode_unchecked(mad1, None, 16) {
+        Ok(mad) => {
+            let crc = if crc8(&mad1[1..]) == mad1[0] { "valid" } else { "INVALID" };
+            println!("  Blocks 01-02: MAD, CRC {:02X} ({})", mad1[0], crc);
+            for line in mad.to_string().lines() {
+                println!("    {}", line);
+            }
+        },
+        Err(e) => println!("  Blocks 01-02: Error decoding MAD: {}", e),
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     println!("MIFARE Classic Card Operations");
     println!("-----------------------------");
@@ -376,6 +80,14 @@ fn main() -> Result<(), Box<dyn Error>> {
         [0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // All zeros
     ];
     
+    // Plus the keys of keys.dic, where mfkey stores the keys it recovers
+    let mut dictionary = KeyDictionary::new(&default_keys);
+    match KeyDictionary::load(Path::new(DICTIONARY_PATH)) {
+        Ok(extra) => dictionary.merge(&extra),
+        Err(e) => println!("Ignoring {}: {}", DICTIONARY_PATH, e),
+    }
+    let default_keys = dictionary.keys().to_vec();
+    
     // Main loop
     loop {
         // Try to connect to a card
@@ -386,16 +98,45 @@ fn main() -> Result<(), Box<dyn Error>> {
                 // Give the card a moment to stabilize
                 thread::sleep(Duration::from_millis(100));
                 
-                // Create MIFARE handler
-                let mifare = MifareClassic::new(&card);
-                
+                // Describe and identify the card (UID, ATQA, SAK, ATS, ATR, product)
+                match identify::probe(&card) {
+                    Ok((info, identification)) => {
+                        println!("{}", info);
+                        println!("Card type: {}", identification.product);
+
+                        // The menu below speaks MIFARE Classic; say so for other cards
+                        let caps = Capabilities::for_identification(&identification);
+                        if identification.card_type != MifareType::Unknown && !caps.handlers.contains(&Handler::Classic) {
+                            println!("Warning: this card is not MIFARE Classic compatible, block operations will fail");
+                            println!("Run card_identifier to see what this card supports");
+                        }
+                    },
+                    Err(e) => println!("Could not query card information: {}", e),
+                }
+
+                // Run anti-collision to find every card in the field
+                let mut targets = Pn532::new(&card).list_passive_targets(MAX_TARGETS).unwrap_or_default();
+
+                // Create MIFARE handler; with several cards present, address the first one explicitly
+                let mifare = if targets.len() > 1 {
+                    println!("{} cards in the field:", targets.len());
+                    for target in &targets {
+                        println!("  Target {}: UID {} ATQA {} SAK {:02X}",
+                                 target.tg, format_hex(&target.uid), format_hex(&target.atqa), target.sak);
+                    }
+                    println!("Using target {}", targets[0].tg);
+                    MifareClassic::with_target(&card, targets.remove(0))
+                } else {
+                    MifareClassic::new(&card)
+                };
+
                 // Read and display card UID
                 match mifare.read_uid() {
                     Ok(uid) => {
                         println!("Card UID: {}", format_hex(&uid));
                         
-                        // Menu loop for operations
-                        'menu: loop {
+                        // Menu for operations (exits after performing one operation)
+                        'menu: {
                             println!("\nChoose an operation:");
                             println!("1. Read a block");
                             println!("2. Write to a block");
@@ -404,7 +145,10 @@ fn main() -> Result<(), Box<dyn Error>> {
                             println!("5. Decrement a value block");
                             println!("6. Read a value block");
                             println!("7. Dump all accessible blocks");
-                            println!("8. Exit");
+                            println!("8. Read NDEF message");
+                            println!("9. Detect magic card");
+                            println!("10. Recover keys with the nested attack");
+                            println!("11. Exit");
                             
                             // For simplicity in this example, we'll use a fixed choice
                             // In a real application, you'd read user input
@@ -606,6 +350,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                                                                 sector, key_name, format_hex(key));
                                                         
                                                         // Read all blocks in the sector
+                                                        let mut mad1 = Vec::new();
                                                         for i in 0..4 {
                                                             let block = first_block + i;
                                                             
@@ -616,6 +361,8 @@ fn main() -> Result<(), Box<dyn Error>> {
                                                             }
                                                             
                                                             match mifare.read_block(block) {
+                                                                // Blocks 1-2 of sector 0 hold the MAD, shown decoded below
+                                                                Ok(data) if is_first_sector && i < 3 => mad1.extend(data),
                                                                 Ok(data) => {
                                                                     print!("  ");
                                                                     print_block_data(block, &data);
@@ -626,6 +373,10 @@ fn main() -> Result<(), Box<dyn Error>> {
                                                             }
                                                         }
                                                         
+                                                        if mad1.len() == 32 {
+                                                            print_mad(&mad1);
+                                                        }
+                                                        
                                                         // If we authenticated with this key, no need to try others
                                                         break;
                                                     }
@@ -637,20 +388,92 @@ fn main() -> Result<(), Box<dyn Error>> {
                                     println!("\nDump complete.");
                                 },
                                 8 => {
+                                    // Read the NDEF message of an NFC Forum formatted 1K card
+                                    match mifare.read_ndef(16) {
+                                        Ok(message) if message.is_empty() => println!("Empty NDEF message"),
+                                        Ok(message) => {
+                                            for (i, record) in message.records.iter().enumerate() {
+                                                println!("Record {}: {}", i + 1, record.describe());
+                                            }
+                                        },
+                                        Err(e) => println!("Error reading NDEF: {}", e),
+                                    }
+                                },
+                                9 => {
+                                    // Detect a magic card and show its block 0
+                                    let key = default_keys[0];
+                                    match mifare.detect_magic(&key) {
+                                        Ok(Some(generation)) => {
+                                            println!("Magic card: {}", generation.to_string());
+                                            match mifare.read_magic_block0(&key) {
+                                                Ok(block) => print_block_data(0, &block.encode()),
+                                                Err(e) => println!("Error reading block 0: {}", e),
+                                            }
+                                        },
+                                        Ok(None) => println!("Not a magic card (genuine, or Gen4 with a non-default password)"),
+                                        Err(e) => println!("Error detecting magic card: {}", e),
+                                    }
+                                },
+                                10 => {
+                                    // Find the keys the dictionary knows, then attack the other sectors from one of them
+                                    let mut known = Vec::new();
+                                    let mut missing = Vec::new();
+                                    for sector in 0..16u8 {
+                                        for key_type in [KeyType::KeyA, KeyType::KeyB] {
+                                            let found = default_keys.iter().find(|key| {
+                                                mifare.load_key(&key[..]).is_ok() && mifare.authenticate(sector * 4, key_type).is_ok()
+                                            });
+                                            match found {
+                                                Some(key) => known.push((sector, key_type, *key)),
+                                                None => missing.push((sector, key_type)),
+                                            }
+                                        }
+                                    }
+                                    let Some(&(known_sector, known_key_type, known_key)) = known.first() else {
+                                        println!("No dictionary key opens any sector, the nested attack needs one");
+                                        break 'menu;
+                                    };
+                                    println!("Known key for sector {}: {}", known_sector, format_hex(&known_key));
+
+                                    let link = match RawReaderLink::new(&card) {
+                                        Ok(link) => link,
+                                        Err(e) => {
+                                            println!("Error switching the reader to raw frames: {}", e);
+                                            break 'menu;
+                                        },
+                                    };
+                                    // Crypto1 uses the last 4 bytes of a 7-byte UID
+                                    let Some(start) = uid.len().checked_sub(4) else {
+                                        println!("Card UID too short for authentication");
+                                        break 'menu;
+                                    };
+                                    let auth_uid = u32::from_be_bytes([uid[start], uid[start + 1], uid[start + 2], uid[start + 3]]);
+                                    let attack = NestedAttack::new(&link, auth_uid, known_sector * 4, known_key_type, known_key);
+                                    for (sector, key_type) in missing {
+                                        match attack.recover_key(sector * 4, key_type) {
+                                            Ok(key) => {
+                                                println!("Sector {} key {:?}: {}", sector, key_type, format_hex(&key));
+                                                if let Err(e) = KeyDictionary::add_to_file(Path::new(DICTIONARY_PATH), key) {
+                                                    println!("Could not add the key to {}: {}", DICTIONARY_PATH, e);
+                                                }
+                                            },
+                                            Err(e) => println!("Sector {} key {:?}: {}", sector, key_type, e),
+                                        }
+                                    }
+                                },
+                                11 => {
                                     println!("Exiting menu...");
                                     break 'menu;
                                 },
                                 _ => println!("Invalid choice!"),
                             }
-                            
-                            // Exit the menu after performing the operation
-                            break 'menu;
                         }
                     },
                     Err(e) => println!("Error reading UID: {}", e),
                 }
                 
                 // Disconnect from the card properly
+                drop(mifare);
                 let _ = card.disconnect(Disposition::LeaveCard);
                 
                 // Wait a bit before trying to connect again
@@ -1,132 +1,36 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::io;
+use std::ffi::CString;
 use std::path::Path;
-use std::process::Command;
+use std::thread;
 use std::time::Duration;
-use regex::Regex;
+use pcsc::{Attribute, Context, Disposition, Protocols, Scope, ShareMode};
+use serde_json::{json, Value};
+use acr122u_test::atr::{card_name, standard_name, Atr};
+use acr122u_test::atr_db::{AtrConflict, AtrDatabase, AtrEntry, AtrSource};
+use acr122u_test::capability::Capabilities;
+use acr122u_test::card_info::CardInfo;
+use acr122u_test::format_hex;
+use acr122u_test::identify::{self, Identification, MifareType};
 
-// Main struct to hold card information
-struct CardInfo {
-    atr: String,
-    description: Vec<String>,
-    card_type: MifareType,
-}
-
-// Enum for different Mifare card types
-#[derive(Debug, Clone, PartialEq)]
-enum MifareType {
-    MifareClassic1K,
-    MifareClassic4K,
-    MifareMini,
-    MifareUltralight,
-    MifareDesfire,
-    MifarePlus,
-    OtherMifare,
-    Unknown,
-}
-
-impl MifareType {
-    fn to_string(&self) -> &str {
-        match self {
-            MifareType::MifareClassic1K => "Mifare Classic 1K",
-            MifareType::MifareClassic4K => "Mifare Classic 4K",
-            MifareType::MifareMini => "Mifare Mini",
-            MifareType::MifareUltralight => "Mifare Ultralight",
-            MifareType::MifareDesfire => "Mifare DESFire",
-            MifareType::MifarePlus => "Mifare Plus",
-            MifareType::OtherMifare => "Other Mifare Type",
-            MifareType::Unknown => "Unknown Card Type",
-        }
-    }
-}
-
-// Function to parse the smartcard list file and build a database of ATRs
-fn build_atr_database(file_path: &str) -> Result<HashMap<String, CardInfo>, io::Error> {
-    let file = File::open(file_path)?;
-    let reader = BufReader::new(file);
-    let mut database = HashMap::new();
-    
-    let mut current_atr = String::new();
-    let mut current_descriptions = Vec::new();
-    let mut line_count = 0;
-    let mut atr_count = 0;
-    
-    println!("Parsing smartcard database file...");
-    
-    for line in reader.lines() {
-        let line = line?;
-        let trimmed = line.trim();
-        line_count += 1;
-        
-        // Skip empty lines and comments
-        if trimmed.is_empty() || trimmed.starts_with('#') {
-            continue;
-        }
-        
-        // If line has ATR format (starts with 3B or 3F)
-        if trimmed.starts_with("3B") || trimmed.starts_with("3F") {
-            // If we have a previous ATR, save it before starting a new one
-            if !current_atr.is_empty() && !current_descriptions.is_empty() {
-                let card_type = identify_card_type(&current_atr, &current_descriptions);
-                database.insert(current_atr.clone(), CardInfo {
-                    atr: current_atr.clone(),
-                    description: current_descriptions.clone(),
-                    card_type,
-                });
-                atr_count += 1;
-            }
-            
-            // Start new ATR
-            current_atr = trimmed.to_string();
-            current_descriptions = Vec::new();
-        } else if trimmed.starts_with('\t') || (!trimmed.starts_with("3B") && !trimmed.starts_with("3F") && !current_atr.is_empty()) {
-            // This is a description line - note we're being more lenient about format
-            current_descriptions.push(trimmed.trim().to_string());
-        }
-    }
-    
-    // Don't forget to add the last entry
-    if !current_atr.is_empty() && !current_descriptions.is_empty() {
-        let card_type = identify_card_type(&current_atr, &current_descriptions);
-        database.insert(current_atr.clone(), CardInfo {
-            atr: current_atr,
-            description: current_descriptions,
-            card_type,
-        });
-        atr_count += 1;
+// Function to identify the card type from the ATR and the descriptions of a
+// database entry: what the ATR itself announces comes first
+fn identify_card_type(atr: &[u8], descriptions: &[String]) -> MifareType {
+    let from_atr = identify::identify_atr(atr);
+    if from_atr != MifareType::Unknown {
+        return from_atr;
     }
-    
-    println!("Processed {} lines, found {} ATR entries", line_count, atr_count);
-    
-    if database.is_empty() {
-        println!("Warning: No ATR entries found in the file. Check file format.");
-        println!("File should contain lines starting with '3B' or '3F' for ATRs");
-        println!("followed by indented description lines.");
-    }
-    
-    Ok(database)
-}
 
-// Function to identify the card type based on ATR and descriptions
-fn identify_card_type(atr: &str, descriptions: &[String]) -> MifareType {
-    // Check if any description contains Mifare keywords
     let desc_text = descriptions.join(" ").to_lowercase();
-    
-    // Specific ATR patterns for common Mifare cards
-    if atr.contains("3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 01") {
-        return MifareType::MifareClassic1K;
-    } else if atr.contains("3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 02") {
-        return MifareType::MifareClassic4K;
-    } else if atr.contains("3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 03") {
-        return MifareType::MifareUltralight;
-    } else if atr.contains("3B 8F 80 01 80 4F 0C A0 00 00 03 06 00 26") {
-        return MifareType::MifareMini;
-    } else if atr.contains("3B 81 80 01 80 80") || desc_text.contains("desfire") {
+    if desc_text.contains("desfire") {
         return MifareType::MifareDesfire;
     } else if desc_text.contains("mifare plus") {
         return MifareType::MifarePlus;
-    } 
+    }
+    
+    // NTAG entries often mention Ultralight compatibility, so check them first
+    if desc_text.contains("ntag") {
+        return MifareType::Ntag;
+    }
     
     // Check in descriptions
     if desc_text.contains("mifare") {
@@ -138,8 +42,6 @@ fn identify_card_type(atr: &str, descriptions: &[String]) -> MifareType {
             return MifareType::MifareMini;
         } else if desc_text.contains("ultralight") {
             return MifareType::MifareUltralight;
-        } else if desc_text.contains("desfire") {
-            return MifareType::MifareDesfire;
         } else if desc_text.contains("plus") {
             return MifareType::MifarePlus;
         } else {
@@ -150,169 +52,337 @@ fn identify_card_type(atr: &str, descriptions: &[String]) -> MifareType {
     MifareType::Unknown
 }
 
-// Function to read ATR from an ACR122U reader
-fn read_atr_from_acr122u() -> Result<String, String> {
-    // First check if PC/SC daemon is running
-    let pcscd_status = Command::new("systemctl")
-        .args(["is-active", "pcscd"])
-        .output()
-        .map_err(|e| format!("Failed to check pcscd status: {}", e))?;
-    
-    let pcscd_active = String::from_utf8_lossy(&pcscd_status.stdout).trim() == "active";
-    
-    if !pcscd_active {
-        println!("Warning: pcscd service is not running. Attempting to start it...");
-        let _ = Command::new("sudo")
-            .args(["systemctl", "start", "pcscd"])
-            .output();
-        
-        println!("Waiting 3 seconds for pcscd to start...");
-        std::thread::sleep(std::time::Duration::from_secs(3));
+// Explain a PC/SC error, pointing at pcscd when the service is missing
+fn describe_pcsc_error(e: pcsc::Error) -> String {
+    match e {
+        pcsc::Error::NoService | pcsc::Error::ServiceStopped => {
+            "The PC/SC service (pcscd) is not running. Start it with 'sudo systemctl start pcscd'".to_string()
+        },
+        pcsc::Error::NoReadersAvailable => "No smart card readers are connected".to_string(),
+        e => format!("PC/SC error: {}", e),
     }
-    
-    // Try nfc-list first as it's more reliable with ACR122U
-    println!("Trying nfc-list to detect card...");
-    let nfc_output = Command::new("nfc-list")
-        .output();
-    
-    if let Ok(output) = nfc_output {
-        let output_text = String::from_utf8_lossy(&output.stdout);
-        println!("nfc-list output: {}", output_text);
-        
-        // Extract UID and try to determine card type from nfc-list output
-        if output_text.contains("UID") {
-            // This is a fallback since we couldn't get the real ATR
-            // We'll create a synthetic ATR based on what we know
-            if output_text.contains("MIFARE Classic") || output_text.contains("MIFARE 1k") {
-                return Ok("3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 01 00 00 00 00 00".to_string());
-            } else if output_text.contains("MIFARE 4k") {
-                return Ok("3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 02 00 00 00 00 00".to_string());
-            } else if output_text.contains("Ultralight") || output_text.contains("NTAG") {
-                return Ok("3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 03 00 00 00 00 00".to_string());
-            } else if output_text.contains("DESFire") {
-                return Ok("3B 81 80 01 80 80".to_string());
-            } else {
-                println!("Card detected but type not recognized from nfc-list");
-                // Return a generic Mifare card ATR
-                return Ok("3B 8F 80 01 80 4F 0C A0 00 00 03 06 00 00 00 00 00 00 00 00".to_string());
-            }
+}
+
+// Establish a PC/SC context and find the ACR122U reader
+fn find_acr122u() -> Result<(Context, CString), String> {
+    let ctx = Context::establish(Scope::User).map_err(describe_pcsc_error)?;
+
+    let mut readers_buffer = [0; 2048];
+    let readers = ctx.list_readers(&mut readers_buffer).map_err(describe_pcsc_error)?;
+
+    for reader in readers {
+        if reader.to_string_lossy().contains("ACR122") {
+            let reader = reader.to_owned();
+            return Ok((ctx, reader));
         }
-    } else {
-        println!("nfc-list command failed, falling back to pcsc_scan");
     }
-    
-    // Fall back to pcsc_scan
-    println!("Using pcsc_scan to detect card...");
-    let output = Command::new("pcsc_scan")
-        .args(["-r"])  // Run once
-        .output()
-        .map_err(|e| format!("Failed to execute pcsc_scan: {}", e))?;
-    
-    let output_text = String::from_utf8_lossy(&output.stdout);
-    println!("pcsc_scan output: {}", output_text);
-    
-    // Use regex to extract the ATR from pcsc_scan output
-    let re = Regex::new(r"ATR: ([0-9A-F ]+)").unwrap();
-    if let Some(captures) = re.captures(&output_text) {
-        if let Some(atr_match) = captures.get(1) {
-            return Ok(atr_match.as_str().to_string());
+
+    Err("No ACR122U reader found".to_string())
+}
+
+// What a scan of the ACR122U found
+struct Scan {
+    reader: String,
+    atr: Vec<u8>,
+    // Card details and identification from the card's own answers, when the
+    // reader allowed querying them
+    card_info: Option<CardInfo>,
+    identification: Option<Identification>,
+    probe_error: Option<String>,
+}
+
+// Function to read ATR from an ACR122U reader, along with the identification
+// gathered from the card itself (ATQA/SAK/ATS/GET_VERSION)
+fn read_atr_from_acr122u(verbose: bool) -> Result<Scan, String> {
+    let (ctx, reader) = find_acr122u()?;
+    if verbose {
+        println!("Using reader: {}", reader.to_string_lossy());
+        println!("Waiting for a card (up to 10 seconds)...");
+    }
+
+    // Try to connect for a while, like the other tools do
+    let mut attempts = 0;
+    let card = loop {
+        match ctx.connect(&reader, ShareMode::Shared, Protocols::ANY) {
+            Ok(card) => break card,
+            Err(pcsc::Error::NoSmartcard) | Err(pcsc::Error::RemovedCard) if attempts < 50 => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(200));
+            },
+            Err(pcsc::Error::NoSmartcard) | Err(pcsc::Error::RemovedCard) => {
+                return Err("No card detected. Please ensure the card is placed properly on the reader.".to_string());
+            },
+            Err(e) => return Err(describe_pcsc_error(e)),
         }
+    };
+
+    // The ATR the reader built for this card
+    let atr = match card.status2_owned() {
+        Ok(status) => status.atr().to_vec(),
+        Err(_) => card.get_attribute_owned(Attribute::AtrString).map_err(describe_pcsc_error)?,
+    };
+
+    if atr.is_empty() {
+        return Err("The reader did not report an ATR for this card".to_string());
     }
-    
-    // Alternative: try to use pcsc_tools' scriptor or pcsc-lite directly
-    println!("No card detected. Please ensure the card is placed properly on the reader.");
-    
-    Err("Could not find ATR or detect card. Is a card present on the reader?".to_string())
+
+    let (card_info, identification, probe_error) = match identify::probe(&card) {
+        Ok((info, identification)) => (Some(info), Some(identification), None),
+        Err(e) => (None, None, Some(e.to_string())),
+    };
+
+    let _ = card.disconnect(Disposition::LeaveCard);
+
+    Ok(Scan {
+        reader: reader.to_string_lossy().into_owned(),
+        atr,
+        card_info,
+        identification,
+        probe_error,
+    })
 }
 
-// Function to determine the authentication methods available for the identified card type
-fn get_authentication_methods(card_type: &MifareType) -> Vec<String> {
-    match card_type {
-        MifareType::MifareClassic1K | MifareType::MifareClassic4K => {
-            vec![
-                "Type A authentication".to_string(),
-                "3-Pass Authentication (ISO 9798-2)".to_string(),
-                "CRYPTO1 cipher (proprietary)".to_string(),
-                "Note: Classic encryption has been broken and is not secure".to_string(),
-            ]
-        },
-        MifareType::MifareMini => {
-            vec![
-                "Type A authentication".to_string(),
-                "CRYPTO1 cipher (proprietary)".to_string(),
-                "Same security as Mifare Classic but less memory".to_string(),
-            ]
-        },
-        MifareType::MifareUltralight => {
-            vec![
-                "No cryptographic protection in basic version".to_string(),
-                "Ultralight C adds 3DES authentication".to_string(),
-                "Mainly relies on limited access facility".to_string(),
-            ]
-        },
-        MifareType::MifareDesfire => {
-            vec![
-                "DESFire EV1: 3DES and AES (128-bit) encryption".to_string(),
-                "DESFire EV2/EV3: AES (128-bit) encryption".to_string(),
-                "Supports ISO/IEC 7816-4 command set".to_string(),
-                "Multiple applications with diverse keys".to_string(),
-                "Mutual three-pass authentication".to_string(),
-            ]
-        },
-        MifareType::MifarePlus => {
-            vec![
-                "Supports AES 128-bit encryption".to_string(),
-                "Backwards compatibility with CRYPTO1".to_string(),
-                "Multiple security levels (SL0-SL3)".to_string(),
-                "Enhanced key management features".to_string(),
-            ]
-        },
-        MifareType::OtherMifare => {
-            vec![
-                "Authentication method depends on specific Mifare variant".to_string(),
-                "Please consult the specific card documentation".to_string(),
-            ]
-        },
-        MifareType::Unknown => {
-            vec![
-                "Unknown card type, cannot determine authentication methods".to_string(),
-                "Try manual identification or contact card manufacturer".to_string(),
-            ]
-        },
+// Settle on a card type: the card's own answers first, then the best database
+// match, then what the ATR announces. Also returns how confident we are and
+// what the decision is based on.
+fn resolve_card_type(identification: Option<&Identification>, matches: &[&AtrEntry], atr: &[u8]) -> (MifareType, &'static str, &'static str) {
+    if let Some(identification) = identification.filter(|i| i.card_type != MifareType::Unknown) {
+        return (identification.card_type.clone(), "high", "card");
+    }
+
+    if let Some(best) = matches.first() {
+        let card_type = identify_card_type(atr, &best.descriptions);
+        let confidence = match (best.pattern.is_exact(), card_type == MifareType::Unknown) {
+            (true, false) => "high",
+            (false, false) | (true, true) => "medium",
+            (false, true) => "low",
+        };
+        return (card_type, confidence, "database");
+    }
+
+    match identify::identify_atr(atr) {
+        MifareType::Unknown => (MifareType::Unknown, "none", "none"),
+        card_type => (card_type, "low", "atr"),
     }
 }
 
+fn hex_or_null(bytes: Option<&[u8]>) -> Value {
+    match bytes {
+        Some(bytes) => json!(format_hex(bytes)),
+        None => Value::Null,
+    }
+}
+
+// Decoded ATR fields for the JSON report
+fn atr_to_json(atr: &Atr) -> Value {
+    let interface: Vec<Value> = atr.interface.iter()
+        .map(|group| json!({
+            "ta": group.ta,
+            "tb": group.tb,
+            "tc": group.tc,
+            "td": group.td,
+        }))
+        .collect();
+
+    let pcsc = match atr.pcsc_storage_card() {
+        Some(storage) => json!({
+            "rid": format_hex(&storage.rid),
+            "standard": storage.standard,
+            "standard_name": standard_name(storage.standard),
+            "card_name": storage.card_name,
+            "card_name_text": card_name(storage.card_name),
+        }),
+        None => Value::Null,
+    };
+
+    json!({
+        "ts": atr.ts,
+        "t0": atr.t0,
+        "direct_convention": atr.is_direct_convention(),
+        "protocols": atr.protocols(),
+        "interface_bytes": interface,
+        "historical_bytes": format_hex(&atr.historical_bytes),
+        "tck": atr.tck,
+        "tck_valid": atr.tck_valid(),
+        "pcsc_storage_card": pcsc,
+    })
+}
+
+// Identify the card without prompting and describe everything in one JSON
+// document. Failures are reported in the "error" field.
+fn json_report(database: &AtrDatabase, conflicts: &[AtrConflict]) -> Value {
+    let database_json = json!({
+        "records": database.len(),
+        "conflicts": conflicts.iter().map(|c| json!({
+            "pattern": c.pattern,
+            "kept": c.kept.to_string(),
+            "dropped": c.dropped.to_string(),
+        })).collect::<Vec<Value>>(),
+    });
+
+    let scan = match read_atr_from_acr122u(false) {
+        Ok(scan) => scan,
+        Err(e) => {
+            return json!({
+                "error": e,
+                "database": database_json,
+            });
+        }
+    };
+
+    let (atr_fields, atr_error) = match Atr::parse(&scan.atr) {
+        Ok(parsed) => (atr_to_json(&parsed), None),
+        Err(e) => (Value::Null, Some(e.to_string())),
+    };
+
+    let matches = database.lookup(&scan.atr);
+    let matches_json: Vec<Value> = matches.iter()
+        .map(|entry| json!({
+            "pattern": entry.pattern.source(),
+            "exact": entry.pattern.is_exact(),
+            "wildcards": entry.pattern.wildcard_count(),
+            "card_type": identify_card_type(&scan.atr, &entry.descriptions).to_string(),
+            "descriptions": entry.descriptions,
+            "source": entry.source.to_string(),
+        }))
+        .collect();
+
+    let card = match &scan.card_info {
+        Some(info) => json!({
+            "uid": format_hex(&info.uid),
+            "atqa": hex_or_null(info.atqa.as_ref().map(|a| &a[..])),
+            "sak": info.sak,
+            "ats": hex_or_null(info.ats.as_deref()),
+            "historical_bytes": format_hex(&info.historical_bytes),
+        }),
+        None => Value::Null,
+    };
+
+    let identification = match &scan.identification {
+        Some(identification) => json!({
+            "product": identification.product,
+            "generation": identification.generation,
+            "memory_size": identification.memory_size,
+            "security_level": identification.security_level,
+            "originality": identification.originality.as_ref().map(|o| o.to_string()),
+        }),
+        None => Value::Null,
+    };
+
+    let (card_type, confidence, basis) = resolve_card_type(scan.identification.as_ref(), &matches, &scan.atr);
+    let caps = card_capabilities(&card_type, scan.identification.as_ref());
+
+    json!({
+        "error": Value::Null,
+        "reader": scan.reader,
+        "atr": format_hex(&scan.atr),
+        "atr_fields": atr_fields,
+        "atr_error": atr_error,
+        "card": card,
+        "probe_error": scan.probe_error,
+        "identification": identification,
+        "database": database_json,
+        "matches": matches_json,
+        "card_type": card_type.to_string(),
+        "authentication_methods": caps.crypto.iter().map(|c| c.to_string()).collect::<Vec<&str>>(),
+        "capabilities": capabilities_to_json(&caps),
+        "confidence": confidence,
+        "confidence_basis": basis,
+    })
+}
+
 // Main function that brings everything together
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // A newer smartcard_list.txt downloaded from upstream and local overrides,
+    // merged over the bundled database when present in the working directory.
+    // The download gets its own name so that the bundled copy at the root of
+    // the checkout is not merged over itself.
+    let downloaded_path = Path::new("smartcard_list.downloaded.txt");
+    let overrides_path = Path::new("smartcard_list.local.txt");
+    // The merged database, kept between runs so that a tap needs no parsing
+    let cache_path = Path::new("smartcard_list.cache");
+    let downloaded = Some(downloaded_path).filter(|p| p.exists());
+    let overrides = Some(overrides_path).filter(|p| p.exists());
+
+    // Print our local additions in the upstream submission format, then stop
+    if std::env::args().any(|arg| arg == "--export-additions") {
+        let (upstream, _) = AtrDatabase::load_merged(downloaded, None)?;
+        let local = match overrides {
+            Some(path) => AtrDatabase::from_file(path, AtrSource::Override)?,
+            None => AtrDatabase::from_entries(Vec::new()),
+        };
+        let count = local.export_additions(&upstream, &mut io::stdout())?;
+        eprintln!("Exported {} local additions from {}", count, overrides_path.display());
+        return Ok(());
+    }
+
+    // Non-interactive mode for scripts: one JSON document on stdout
+    if std::env::args().any(|arg| arg == "--json") {
+        let (database, conflicts) = match AtrDatabase::load_cached(cache_path, downloaded, overrides) {
+            Ok(merged) => merged,
+            Err(_) => AtrDatabase::load_merged(None, None)?,
+        };
+        let report = json_report(&database, &conflicts);
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        if !report["error"].is_null() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     println!("Mifare Card Identifier for ACR122U");
     println!("==================================\n");
     
-    // Check if required tools are available
+    // Check that the PC/SC service and the reader are available
     check_prerequisites();
     
-    // Build the database from the smartcard list file
-    let database_path = "smartcard_list.txt";
-    println!("Building ATR database from {}...", database_path);
-    
-    let database = match build_atr_database(database_path) {
-        Ok(db) => {
-            println!("Successfully loaded {} ATR records", db.len());
-            db
-        },
+    println!("Loading ATR database...");
+    let (database, conflicts) = match AtrDatabase::load_cached(cache_path, downloaded, overrides) {
+        Ok(merged) => merged,
         Err(e) => {
-            println!("Warning: Could not load ATR database: {}", e);
-            println!("Continuing with limited identification capabilities...");
-            HashMap::new()
+            println!("Could not read the ATR list files: {}", e);
+            println!("Using the bundled ATR database instead");
+            AtrDatabase::load_merged(None, None)?
         }
     };
-    
+    println!("Successfully loaded {} ATR records", database.len());
+
+    for conflict in &conflicts {
+        println!("Conflict for {}: using the {} over the {}",
+                 conflict.pattern, conflict.kept.to_string(), conflict.dropped.to_string());
+        for description in &conflict.dropped_descriptions {
+            println!("  - {}", description);
+        }
+        for description in &conflict.kept_descriptions {
+            println!("  + {}", description);
+        }
+    }
+
     println!("\nLooking for ACR122U reader and card...");
     
     // Try to read the ATR from the card
-    let atr = match read_atr_from_acr122u() {
-        Ok(atr) => {
-            println!("Successfully read card ATR: {}", atr);
-            atr
+    let (atr_bytes, identification) = match read_atr_from_acr122u(true) {
+        Ok(scan) => {
+            match (&scan.card_info, &scan.probe_error) {
+                (Some(info), _) => {
+                    println!("Card information:");
+                    for line in info.to_string().lines() {
+                        println!("  {}", line);
+                    }
+                },
+                (None, Some(e)) => println!("Could not query card details: {}", e),
+                (None, None) => {},
+            }
+
+            // Decode the ATR structure (interface bytes, historical bytes, TCK)
+            match Atr::parse(&scan.atr) {
+                Ok(parsed) => {
+                    println!("\nATR analysis:");
+                    print!("{}", parsed.explain());
+                },
+                Err(e) => println!("Could not parse ATR: {}", e),
+            }
+            (scan.atr, scan.identification)
         },
         Err(e) => {
             println!("Error reading card: {}", e);
@@ -326,14 +396,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             if input.trim().to_lowercase() == "y" {
                 let card_type = manual_card_selection()?;
                 
-                // Display authentication methods for manually selected card
-                println!("\nAuthentication Methods for {}:", card_type.to_string());
-                for method in get_authentication_methods(&card_type) {
-                    println!("  - {}", method);
-                }
-                
-                // Provide usage recommendations
-                print_usage_recommendations(&card_type);
+                print_capabilities(&Capabilities::for_card_type(&card_type));
                 
                 return Ok(());
             } else {
@@ -342,107 +405,70 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
     
-    // Normalize ATR by removing spaces for lookup
-    let normalized_atr = atr.replace(" ", "");
+    // Look up the ATR in our database, most specific entries first
+    let matches = database.lookup(&atr_bytes);
     
-    // Look up the ATR in our database
-    let mut found = false;
-    let mut identified_card_type = MifareType::Unknown;
-    
-    for (db_atr, card_info) in &database {
-        let normalized_db_atr = db_atr.replace(" ", "");
-        
-        // Also try with wildcard pattern matching for flexible ATR matching
-        if normalized_atr == normalized_db_atr || atr_pattern_match(&normalized_atr, &normalized_db_atr) {
-            found = true;
-            identified_card_type = card_info.card_type.clone();
-            
-            println!("\nCard Identified:");
-            println!("  Type: {}", card_info.card_type.to_string());
-            println!("  ATR: {}", card_info.atr);
-            println!("  Descriptions:");
-            for desc in &card_info.description {
-                println!("    - {}", desc);
+    if !matches.is_empty() {
+        println!("\nCard Identified ({} matching database entries):", matches.len());
+        for (rank, entry) in matches.iter().enumerate() {
+            let card_type = identify_card_type(&atr_bytes, &entry.descriptions);
+            let kind = if entry.pattern.is_exact() {
+                "exact".to_string()
+            } else {
+                format!("{} wildcards", entry.pattern.wildcard_count())
+            };
+            println!("  {}. Type: {} ({})", rank + 1, card_type.to_string(), kind);
+            println!("     ATR: {}", entry.pattern.source());
+            println!("     Descriptions:");
+            for desc in &entry.descriptions {
+                println!("       - {}", desc);
             }
-            break;
         }
+    } else {
+        println!("\nCard not found in database. Identifying from the ATR structure...");
+        println!("  Identified as: {}", identify::identify_atr(&atr_bytes).to_string());
     }
     
-    // If the card is not found in the database, try to identify by ATR pattern
-    if !found {
-        println!("\nCard not found in database. Attempting pattern-based identification...");
-        identified_card_type = identify_by_atr_pattern(&atr);
-        println!("  Identified as: {}", identified_card_type.to_string());
+    // The card's own answers (SAK, GET_VERSION, ...) are more precise than the ATR
+    if let Some(identification) = &identification {
+        println!("\nCard reports itself as: {}", identification.product);
+        if let Some(generation) = &identification.generation {
+            println!("  Generation: {}", generation);
+        }
+        if let Some(size) = identification.memory_size {
+            println!("  Memory: {} bytes", size);
+        }
+        if let Some(level) = &identification.security_level {
+            println!("  Security level: {}", level);
+        }
+        if let Some(originality) = &identification.originality {
+            println!("  Originality: {}", originality.to_string());
+        }
     }
     
-    // Display authentication methods
-    println!("\nAuthentication Methods for {}:", identified_card_type.to_string());
-    for method in get_authentication_methods(&identified_card_type) {
-        println!("  - {}", method);
-    }
+    let (identified_card_type, confidence, basis) = resolve_card_type(identification.as_ref(), &matches, &atr_bytes);
+    println!("\nCard type: {} (confidence: {}, based on: {})", identified_card_type.to_string(), confidence, basis);
     
-    // Provide usage recommendations
-    print_usage_recommendations(&identified_card_type);
+    print_capabilities(&card_capabilities(&identified_card_type, identification.as_ref()));
     
     println!("\nMifare Card Identification Complete");
     
     Ok(())
 }
 
-// Function to check if required tools are installed
+// Function to check that the PC/SC service and the reader are available
 fn check_prerequisites() {
-    println!("Checking for required tools...");
-    
-    // Check for pcsc_scan
-    let pcsc_scan_check = Command::new("which")
-        .arg("pcsc_scan")
-        .output();
-    
-    match pcsc_scan_check {
-        Ok(output) => {
-            if output.status.success() {
-                println!("✓ pcsc_scan found");
-            } else {
-                println!("✗ pcsc_scan not found. Install with 'sudo apt-get install pcsc-tools'");
-            }
-        },
-        Err(_) => println!("✗ pcsc_scan not found. Install with 'sudo apt-get install pcsc-tools'"),
-    }
+    println!("Checking PC/SC service and reader...");
     
-    // Check for nfc-list
-    let nfc_list_check = Command::new("which")
-        .arg("nfc-list")
-        .output();
-    
-    match nfc_list_check {
-        Ok(output) => {
-            if output.status.success() {
-                println!("✓ nfc-list found");
-            } else {
-                println!("✗ nfc-list not found. Install with 'sudo apt-get install libnfc-bin'");
-            }
+    match find_acr122u() {
+        Ok((_, reader)) => {
+            println!("✓ PC/SC service is running");
+            println!("✓ ACR122U reader found: {}", reader.to_string_lossy());
         },
-        Err(_) => println!("✗ nfc-list not found. Install with 'sudo apt-get install libnfc-bin'"),
+        Err(e) => println!("✗ {}", e),
     }
     
-    // Check if pcscd service is running
-    let pcscd_check = Command::new("systemctl")
-        .args(["is-active", "pcscd"])
-        .output();
-    
-    match pcscd_check {
-        Ok(output) => {
-            let status = String::from_utf8_lossy(&output.stdout).trim().to_string();
-            if status == "active" {
-                println!("✓ pcscd service is running");
-            } else {
-                println!("✗ pcscd service is not running. Start with 'sudo systemctl start pcscd'");
-            }
-        },
-        Err(_) => println!("✗ Could not check pcscd service status"),
-    }
-    
-    println!("");
+    println!();
 }
 
 // Function for manual card type selection
@@ -478,94 +504,65 @@ fn manual_card_selection() -> Result<MifareType, Box<dyn std::error::Error>> {
     Ok(card_type)
 }
 
-// Separate function for usage recommendations
-fn print_usage_recommendations(identified_card_type: &MifareType) {
-    println!("\nRecommended Usage:");
-    match identified_card_type {
-        MifareType::MifareClassic1K | MifareType::MifareClassic4K => {
-            println!("  - For this card, use libnfc with mfoc or mfcuk tools for authentication");
-            println!("  - Basic command: 'nfc-list' to detect the card");
-            println!("  - Authentication command: 'mfoc -O dump.mfd' to dump the card contents");
-            println!("  - Install required tools: 'sudo apt-get install libnfc-bin mfoc'");
-        },
-        MifareType::MifareUltralight => {
-            println!("  - For Ultralight cards, use nfc-mfultralight tool");
-            println!("  - Command: 'nfc-mfultralight r dump.mfd' to read the card");
-            println!("  - Install required tools: 'sudo apt-get install libnfc-bin'");
-        },
-        MifareType::MifareDesfire => {
-            println!("  - Use mifare-desfire-tool or official NXP libraries");
-            println!("  - Requires proper key management and authentication procedures");
-            println!("  - Consider installing: 'sudo apt-get install libfreefare-bin'");
-        },
-        MifareType::MifarePlus => {
-            println!("  - Depending on security level, use appropriate AES libraries");
-            println!("  - Consider using official SDK for secure implementation");
-            println!("  - In security level 1, can be accessed like Mifare Classic");
-        },
-        _ => {
-            println!("  - For this card type, consult specific documentation");
-            println!("  - Start with 'pcsc_scan' and 'pcsc_tools' for basic interaction");
-            println!("  - Try 'sudo apt-get install pcsc-tools libnfc-bin libfreefare-bin'");
-        }
+// Describe what the card offers and what this crate can do with it
+fn print_capabilities(caps: &Capabilities) {
+    println!("\nCapabilities of {}:", caps.card_type.to_string());
+    if let Some(size) = caps.memory_size {
+        println!("  Memory: {} bytes", size);
     }
-}
 
-// Function to match ATR patterns with wildcards
-fn atr_pattern_match(actual_atr: &str, pattern_atr: &str) -> bool {
-    let pattern_bytes: Vec<&str> = pattern_atr
-        .split("")
-        .filter(|s| !s.is_empty())
-        .collect();
-    
-    let actual_bytes: Vec<&str> = actual_atr
-        .split("")
-        .filter(|s| !s.is_empty())
-        .collect();
-    
-    if actual_bytes.len() != pattern_bytes.len() {
-        return false;
+    println!("  Cryptography:");
+    if caps.crypto.is_empty() {
+        println!("    - unknown");
     }
-    
-    for (i, &pattern_char) in pattern_bytes.iter().enumerate() {
-        if pattern_char != "." && pattern_char != actual_bytes[i] {
-            return false;
+    for suite in &caps.crypto {
+        match (suite.key_bits(), suite.is_broken()) {
+            (Some(bits), true) => println!("    - {} ({}-bit key, broken)", suite.to_string(), bits),
+            (Some(bits), false) => println!("    - {} ({}-bit key)", suite.to_string(), bits),
+            (None, _) => println!("    - {}", suite.to_string()),
         }
     }
-    
-    true
-}
 
-// Function to identify card type based on ATR pattern when not found in database
-fn identify_by_atr_pattern(atr: &str) -> MifareType {
-    // Common patterns for Mifare cards
-    if atr.contains("3B 8F 80 01 80 4F") || atr.contains("3B8F80018F4F") {
-        // This is a PCSC standard for contactless cards
-        if atr.contains("00 01") || atr.contains("0001") {
-            return MifareType::MifareClassic1K;
-        } else if atr.contains("00 02") || atr.contains("0002") {
-            return MifareType::MifareClassic4K;
-        } else if atr.contains("00 03") || atr.contains("0003") {
-            return MifareType::MifareUltralight;
-        } else if atr.contains("00 26") || atr.contains("0026") {
-            return MifareType::MifareMini;
+    let available = caps.available_operations();
+    if available.is_empty() {
+        println!("  This crate cannot operate on this card yet");
+    } else {
+        for handler in &caps.handlers {
+            println!("  Handled by {}:", handler.to_string());
+            for operation in available.iter().filter(|op| handler.operations().contains(op)) {
+                println!("    - {}", operation.to_string());
+            }
         }
     }
-    
-    // DESFire pattern
-    if atr.contains("3B 81 80") || atr.contains("3B8180") {
-        return MifareType::MifareDesfire;
-    }
-    
-    // Check for Mifare Plus indicators
-    if atr.contains("3B 8F") && (atr.contains("PLUS") || atr.contains("plus")) {
-        return MifareType::MifarePlus;
+
+    let unavailable = caps.unavailable_operations();
+    if !unavailable.is_empty() {
+        println!("  Supported by the card but not by this crate:");
+        for operation in unavailable {
+            println!("    - {}", operation.to_string());
+        }
     }
-    
-    // General Mifare pattern
-    if atr.contains("3B") && atr.contains("80") {
-        return MifareType::OtherMifare;
+}
+
+// Capabilities for the resolved card type, refined by the card's own answers
+// when they agree on the type
+fn card_capabilities(card_type: &MifareType, identification: Option<&Identification>) -> Capabilities {
+    match identification.filter(|i| i.card_type == *card_type) {
+        Some(identification) => Capabilities::for_identification(identification),
+        None => Capabilities::for_card_type(card_type),
     }
-    
-    MifareType::Unknown
+}
+
+fn capabilities_to_json(caps: &Capabilities) -> Value {
+    json!({
+        "crypto": caps.crypto.iter().map(|c| json!({
+            "suite": c.to_string(),
+            "key_bits": c.key_bits(),
+            "broken": c.is_broken(),
+        })).collect::<Vec<Value>>(),
+        "memory_size": caps.memory_size,
+        "operations": caps.operations.iter().map(|op| op.to_string()).collect::<Vec<&str>>(),
+        "available_operations": caps.available_operations().iter().map(|op| op.to_string()).collect::<Vec<&str>>(),
+        "handlers": caps.handlers.iter().map(|h| h.to_string()).collect::<Vec<&str>>(),
+    })
 }
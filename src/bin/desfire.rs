@@ -0,0 +1,365 @@
+use std::error::Error;
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+use pcsc::{Context, Disposition, Protocols, Scope, ShareMode};
+use std::path::Path;
+use acr122u_test::desfire::{AccessRights, CipherType, CommunicationMode, Desfire, KeySettings, PICC_AID};
+use acr122u_test::desfire_auth::AuthMode;
+use acr122u_test::desfire_crypto::DesfireKey;
+use acr122u_test::desfire_profile::{parse_access, parse_hex, Profile};
+use acr122u_test::format_hex;
+
+// Ask a question and return the trimmed answer
+fn prompt(question: &str) -> Result<String, Box<dyn Error>> {
+    print!("{}", question);
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_string())
+}
+
+// Application ID as 6 hex digits
+fn prompt_aid(question: &str) -> Result<u32, Box<dyn Error>> {
+    let answer = prompt(question)?;
+    if answer.len() != 6 {
+        return Err("Application IDs are 6 hex digits".into());
+    }
+    Ok(u32::from_str_radix(&answer, 16)?)
+}
+
+// Any number of hex bytes, spaces allowed
+fn prompt_hex(question: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let answer: String = prompt(question)?.chars().filter(|c| !c.is_whitespace()).collect();
+    Ok(parse_hex(&answer)?)
+}
+
+// Authentication command and key to use, asked in turn
+fn prompt_authentication(desfire: &Desfire) -> Result<(), Box<dyn Error>> {
+    println!("1. Legacy (DES/2K3DES)  2. ISO (DES/2K3DES/3K3DES)  3. AES  4. EV2 first  5. EV2 non-first");
+    let mode = match prompt("Authentication: ")?.as_str() {
+        "1" => AuthMode::Legacy,
+        "2" => AuthMode::Iso,
+        "3" => AuthMode::Aes,
+        "4" => AuthMode::Ev2First,
+        "5" => AuthMode::Ev2NonFirst,
+        _ => return Err("Unknown authentication".into()),
+    };
+    let key_no = prompt("Key number: ")?.parse::<u8>()?;
+    let bytes = prompt_hex("Key (hex, empty for all zeros): ")?;
+    let cipher = match (mode, bytes.len()) {
+        (AuthMode::Legacy, _) | (AuthMode::Iso, 0 | 8 | 16) => CipherType::TripleDes,
+        (AuthMode::Iso, _) => CipherType::TripleDes3K,
+        _ => CipherType::Aes,
+    };
+    let key = if bytes.is_empty() { DesfireKey::zero(cipher) } else { DesfireKey::new(cipher, &bytes)? };
+
+    desfire.authenticate(mode, key_no, &key)?;
+    println!("Authenticated with key {} ({})", key_no, mode.to_string());
+    Ok(())
+}
+
+fn prompt_cipher() -> Result<CipherType, Box<dyn Error>> {
+    match prompt("Key type (1. DES/2K3DES  2. 3K3DES  3. AES): ")?.as_str() {
+        "1" => Ok(CipherType::TripleDes),
+        "2" => Ok(CipherType::TripleDes3K),
+        "3" => Ok(CipherType::Aes),
+        _ => Err("Unknown key type".into()),
+    }
+}
+
+fn prompt_key(question: &str, cipher: CipherType) -> Result<DesfireKey, Box<dyn Error>> {
+    let bytes = prompt_hex(question)?;
+    Ok(if bytes.is_empty() { DesfireKey::zero(cipher) } else { DesfireKey::new(cipher, &bytes)? })
+}
+
+fn prompt_number(question: &str) -> Result<u32, Box<dyn Error>> {
+    Ok(prompt(question)?.parse::<u32>()?)
+}
+
+fn prompt_communication() -> Result<CommunicationMode, Box<dyn Error>> {
+    match prompt("Communication (1. plain  2. MACed  3. enciphered): ")?.as_str() {
+        "1" => Ok(CommunicationMode::Plain),
+        "2" => Ok(CommunicationMode::Maced),
+        "3" => Ok(CommunicationMode::Full),
+        _ => Err("Unknown communication mode".into()),
+    }
+}
+
+// Read, write, read/write and change key as 4 hex digits
+fn prompt_access() -> Result<AccessRights, Box<dyn Error>> {
+    Ok(parse_access(&prompt("Access keys (read, write, read/write, change; E free, F never, e.g. 12E0): ")?)?)
+}
+
+fn create_application(desfire: &Desfire) -> Result<(), Box<dyn Error>> {
+    let aid = prompt_aid("Application ID (6 hex digits): ")?;
+    let cipher = prompt_cipher()?;
+    let key_count = prompt("Number of keys (1-14): ")?.parse::<u8>()?;
+    let settings = u8::from_str_radix(&prompt("Key settings (hex, e.g. 0F): ")?, 16)?;
+    desfire.create_application(aid, &KeySettings { settings, key_count, cipher })?;
+    println!("Application {:06X} created", aid);
+    Ok(())
+}
+
+fn create_file(desfire: &Desfire) -> Result<(), Box<dyn Error>> {
+    println!("1. Standard data  2. Backup data  3. Value  4. Linear record  5. Cyclic record");
+    let kind = prompt("File type: ")?;
+    let file = prompt("File number: ")?.parse::<u8>()?;
+    let communication = prompt_communication()?;
+    let access = prompt_access()?;
+    match kind.as_str() {
+        "1" => desfire.create_std_data_file(file, communication, &access, prompt_number("Size: ")?)?,
+        "2" => desfire.create_backup_data_file(file, communication, &access, prompt_number("Size: ")?)?,
+        "3" => {
+            let lower = prompt("Lower limit: ")?.parse::<i32>()?;
+            let upper = prompt("Upper limit: ")?.parse::<i32>()?;
+            let value = prompt("Initial value: ")?.parse::<i32>()?;
+            let limited_credit = prompt("Limited credit (y/n): ")? == "y";
+            desfire.create_value_file(file, communication, &access, lower..=upper, value, limited_credit)?
+        },
+        "4" | "5" => {
+            let record_size = prompt_number("Record size: ")?;
+            let max_records = prompt_number("Maximum records: ")?;
+            if kind == "4" {
+                desfire.create_linear_record_file(file, communication, &access, record_size, max_records)?
+            } else {
+                desfire.create_cyclic_record_file(file, communication, &access, record_size, max_records)?
+            }
+        },
+        _ => return Err("Unknown file type".into()),
+    }
+    println!("File {} created", file);
+    Ok(())
+}
+
+// Read or write data or records, then commit if asked
+fn file_contents(desfire: &Desfire) -> Result<(), Box<dyn Error>> {
+    println!("1. Read data  2. Write data  3. Read records  4. Write record");
+    let choice = prompt("Operation: ")?;
+    let file = prompt("File number: ")?.parse::<u8>()?;
+    match choice.as_str() {
+        "1" => {
+            let offset = prompt_number("Offset: ")?;
+            let length = prompt_number("Length (0 for all): ")?;
+            println!("{}", format_hex(&desfire.read_data(file, offset, length)?));
+        },
+        "3" => {
+            let offset = prompt_number("Records back from the newest: ")?;
+            let count = prompt_number("Count (0 for all): ")?;
+            println!("{}", format_hex(&desfire.read_records(file, offset, count)?));
+        },
+        "2" | "4" => {
+            let offset = prompt_number("Offset: ")?;
+            let data = prompt_hex("Data (hex): ")?;
+            if choice == "2" {
+                desfire.write_data(file, offset, &data)?;
+            } else {
+                desfire.write_record(file, offset, &data)?;
+            }
+            println!("Written; backup and record files change on commit");
+        },
+        _ => return Err("Unknown operation".into()),
+    }
+    Ok(())
+}
+
+fn value_operation(desfire: &Desfire) -> Result<(), Box<dyn Error>> {
+    println!("1. Get value  2. Credit  3. Debit");
+    let choice = prompt("Operation: ")?;
+    let file = prompt("File number: ")?.parse::<u8>()?;
+    match choice.as_str() {
+        "1" => println!("Value: {}", desfire.get_value(file)?),
+        "2" => desfire.credit(file, prompt("Amount: ")?.parse::<i32>()?)?,
+        "3" => desfire.debit(file, prompt("Amount: ")?.parse::<i32>()?)?,
+        _ => return Err("Unknown operation".into()),
+    }
+    Ok(())
+}
+
+fn transaction(desfire: &Desfire) -> Result<(), Box<dyn Error>> {
+    match prompt("1. Commit  2. Abort: ")?.as_str() {
+        "1" => desfire.commit_transaction().map(|()| println!("Transaction committed")),
+        "2" => desfire.abort_transaction().map(|()| println!("Transaction aborted")),
+        _ => Err("Unknown operation".into()),
+    }
+}
+
+// Change a key of the selected application, or the card master key
+fn change_key(desfire: &Desfire) -> Result<(), Box<dyn Error>> {
+    let key_no = prompt("Key number: ")?.parse::<u8>()?;
+    let cipher = prompt_cipher()?;
+    let new_key = prompt_key("New key (hex, empty for all zeros): ", cipher)?;
+    let version = u8::from_str_radix(&prompt("Key version (hex): ")?, 16)?;
+    let old_key = if desfire.authenticated_key() == Some(key_no) {
+        None
+    } else {
+        Some(prompt_key("Current key (hex, empty for all zeros): ", cipher)?)
+    };
+    desfire.change_key(key_no, &new_key, old_key.as_ref(), version)?;
+    println!("Key {} changed", key_no);
+    Ok(())
+}
+
+// Create what a profile file describes and is missing from the card
+fn provision(desfire: &Desfire) -> Result<(), Box<dyn Error>> {
+    let profile = Profile::load(Path::new(&prompt("Profile file: ")?))?;
+    let actions = desfire.provision(&profile)?;
+    if actions.is_empty() {
+        println!("The card already matches the profile");
+    }
+    for action in actions {
+        println!("  {}", action);
+    }
+    Ok(())
+}
+
+fn print_key_settings(settings: &KeySettings) {
+    println!("  Keys: {} {} key(s), settings {:02X}", settings.key_count, settings.cipher.to_string(), settings.settings);
+}
+
+// Files of the selected application with their settings
+fn print_files(desfire: &Desfire) -> Result<(), Box<dyn Error>> {
+    let files = desfire.get_file_ids()?;
+    if files.is_empty() {
+        println!("  No files");
+    }
+    for file in files {
+        match desfire.get_file_settings(file) {
+            Ok(settings) => println!("  File {:02X}: {}", file, settings),
+            Err(e) => println!("  File {:02X}: {}", file, e),
+        }
+    }
+    Ok(())
+}
+
+// Every application with its keys and files, then back to the card level
+fn print_applications(desfire: &Desfire) -> Result<(), Box<dyn Error>> {
+    desfire.select_application(PICC_AID)?;
+    print_key_settings(&desfire.get_key_settings()?);
+    let aids = desfire.get_application_ids()?;
+    if aids.is_empty() {
+        println!("No applications");
+    }
+    for aid in aids {
+        println!("Application {:06X}:", aid);
+        desfire.select_application(aid)?;
+        match desfire.get_key_settings() {
+            Ok(settings) => print_key_settings(&settings),
+            Err(e) => println!("  Key settings: {}", e),
+        }
+        print_files(desfire)?;
+    }
+    desfire.select_application(PICC_AID)
+}
+
+fn run_menu(desfire: &Desfire) -> Result<(), Box<dyn Error>> {
+    loop {
+        println!("\nChoose an operation:");
+        println!("1. Show version");
+        println!("2. List applications and files");
+        println!("3. Select application");
+        println!("4. List files of the selected application");
+        println!("5. Show free memory");
+        println!("6. Authenticate");
+        println!("7. Show real UID (needs authentication)");
+        println!("8. Create application");
+        println!("9. Delete application");
+        println!("10. Create file");
+        println!("11. Delete file");
+        println!("12. Read or write data and records");
+        println!("13. Get, credit or debit a value");
+        println!("14. Commit or abort the transaction");
+        println!("15. Change key");
+        println!("16. Change key settings");
+        println!("17. Provision from a profile file");
+        println!("18. Exit");
+
+        let choice = prompt("> ")?.parse::<u8>().unwrap_or(0);
+        let result: Result<(), Box<dyn Error>> = match choice {
+            1 => desfire.get_version().map(|version| println!("{}", version)),
+            2 => print_applications(desfire),
+            3 => {
+                let aid = prompt_aid("Application ID (6 hex digits, 000000 for the card): ")?;
+                desfire.select_application(aid).map(|()| println!("Application {:06X} selected", aid))
+            },
+            4 => print_files(desfire),
+            5 => desfire.free_memory().map(|free| println!("Free memory: {} bytes", free)),
+            6 => prompt_authentication(desfire),
+            7 => desfire.get_card_uid().map(|uid| println!("UID: {}", format_hex(&uid))),
+            8 => create_application(desfire),
+            9 => {
+                let aid = prompt_aid("Application ID (6 hex digits): ")?;
+                desfire.delete_application(aid).map(|()| println!("Application {:06X} deleted", aid))
+            },
+            10 => create_file(desfire),
+            11 => {
+                let file = prompt("File number: ")?.parse::<u8>()?;
+                desfire.delete_file(file).map(|()| println!("File {} deleted", file))
+            },
+            12 => file_contents(desfire),
+            13 => value_operation(desfire),
+            14 => transaction(desfire),
+            15 => change_key(desfire),
+            16 => {
+                let settings = u8::from_str_radix(&prompt("Key settings (hex): ")?, 16)?;
+                desfire.change_key_settings(settings).map(|()| println!("Key settings changed"))
+            },
+            17 => provision(desfire),
+            18 => return Ok(()),
+            _ => {
+                println!("Invalid choice!");
+                Ok(())
+            },
+        };
+
+        if let Err(e) = result {
+            println!("Error: {}", e);
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    println!("MIFARE DESFire Operations");
+    println!("-------------------------");
+
+    // Initialize PC/SC context
+    let ctx = Context::establish(Scope::User)?;
+
+    // Find ACR122U reader
+    let mut readers_buffer = [0; 2048];
+    let acr122u = match ctx.list_readers(&mut readers_buffer)?.find(|r| r.to_string_lossy().contains("ACR122")) {
+        Some(reader) => reader.to_owned(),
+        None => {
+            println!("No ACR122U reader found!");
+            return Ok(());
+        }
+    };
+    println!("Using reader: {}", acr122u.to_string_lossy());
+    println!("Waiting for card... (place card on reader)");
+
+    let card = loop {
+        match ctx.connect(&acr122u, ShareMode::Shared, Protocols::ANY) {
+            Ok(card) => break card,
+            Err(pcsc::Error::NoSmartcard) | Err(pcsc::Error::RemovedCard) => thread::sleep(Duration::from_millis(200)),
+            Err(e) => return Err(Box::new(e)),
+        }
+    };
+
+    // Give the card a moment to stabilize
+    thread::sleep(Duration::from_millis(100));
+
+    let desfire = Desfire::new(&card);
+    match desfire.get_version() {
+        Ok(version) => println!("\n{}", version),
+        Err(e) => {
+            println!("Not a DESFire card: {}", e);
+            return Ok(());
+        },
+    }
+
+    run_menu(&desfire)?;
+
+    drop(desfire);
+    let _ = card.disconnect(Disposition::LeaveCard);
+    Ok(())
+}
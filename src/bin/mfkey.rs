@@ -0,0 +1,54 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use acr122u_test::format_hex;
+use acr122u_test::keys::{KeyDictionary, DICTIONARY_PATH};
+use acr122u_test::mfkey::{recover_key, AuthTrace};
+
+// Offline key recovery from captured MIFARE Classic authentications.
+//
+//   mfkey <uid> <nt> <{nr}> <{ar}> <{at}>                  (mfkey64)
+//   mfkey <uid> <nt0> <{nr0}> <{ar0}> <nt1> <{nr1}> <{ar1}>  (mfkey32v2)
+//   mfkey --file <traces.txt>    one authentication per line, same layout
+//
+// A recovered key is added to keys.dic, which the card tools try on every
+// sector.
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut traces = Vec::new();
+    if args.first().map(String::as_str) == Some("--file") {
+        let path = args.get(1).ok_or("--file needs a path")?;
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if !line.is_empty() {
+                traces.extend(AuthTrace::parse_line(line)?);
+            }
+        }
+    } else if !args.is_empty() {
+        traces = AuthTrace::parse_line(&args.join(" "))?;
+    } else {
+        println!("Usage: mfkey <uid> <nt> <{{nr}}> <{{ar}}> <{{at}}>");
+        println!("       mfkey <uid> <nt0> <{{nr0}}> <{{ar0}}> <nt1> <{{nr1}}> <{{ar1}}>");
+        println!("       mfkey --file <traces.txt>");
+        std::process::exit(2);
+    }
+
+    println!("Recovering key from {} authentication(s)...", traces.len());
+    let key = match recover_key(&traces) {
+        Some(key) => key,
+        None => {
+            println!("No key found (traces must share the same card and key)");
+            std::process::exit(1);
+        }
+    };
+    println!("Found key: {}", format_hex(&key));
+
+    let path = Path::new(DICTIONARY_PATH);
+    if KeyDictionary::add_to_file(path, key)? {
+        println!("Added to {}", path.display());
+    } else {
+        println!("Already in {}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,384 @@
+use std::error::Error;
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+use pcsc::{Context, Disposition, Protocols, Scope, ShareMode};
+use acr122u_test::format_hex;
+use acr122u_test::ndef::{NdefMessage, NdefRecord};
+use acr122u_test::ntag::{AccessConfig, Protection};
+use acr122u_test::originality::check_originality;
+use acr122u_test::ultralight::{MifareUltralight, UltralightModel};
+use acr122u_test::ultralight_c::{AuthConfig, DEFAULT_KEY};
+
+// Print one page with its ASCII rendering
+fn print_page(page: usize, data: &[u8]) {
+    let ascii: String = data.iter()
+        .map(|&b| if (32..=126).contains(&b) { b as char } else { '.' })
+        .collect();
+    println!("Page {:3} (0x{:02X}): {}  {}", page, page, format_hex(data), ascii);
+}
+
+// Ask a question and return the trimmed answer
+fn prompt(question: &str) -> Result<String, Box<dyn Error>> {
+    print!("{}", question);
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_string())
+}
+
+fn prompt_page(question: &str) -> Result<u8, Box<dyn Error>> {
+    let answer = prompt(question)?;
+    let page = match answer.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16)?,
+        None => answer.parse::<u8>()?,
+    };
+    Ok(page)
+}
+
+fn prompt_hex(question: &str, len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let answer: String = prompt(question)?.chars().filter(|c| !c.is_whitespace()).collect();
+    if answer.len() != len * 2 {
+        return Err(format!("Expected {} hex bytes", len).into());
+    }
+
+    let mut bytes = Vec::with_capacity(len);
+    for i in (0..answer.len()).step_by(2) {
+        bytes.push(u8::from_str_radix(&answer[i..i + 2], 16)?);
+    }
+    Ok(bytes)
+}
+
+// Lock bits and OTP page of the card
+fn print_locks(ultralight: &MifareUltralight, model: UltralightModel) {
+    match ultralight.read_lock_bits(model) {
+        Ok(locks) => {
+            println!("Static lock bytes: {}", format_hex(&locks.static_lock));
+            if let Some(dynamic) = locks.dynamic_lock {
+                println!("Dynamic lock bytes: {}", format_hex(&dynamic));
+            }
+            println!("OTP: {}{}", format_hex(&locks.otp), if locks.otp_locked() { " (locked)" } else { "" });
+
+            let locked = locks.locked_pages(model);
+            if locked.is_empty() {
+                println!("No pages are locked");
+            } else {
+                let pages: Vec<String> = locked.iter().map(|p| p.to_string()).collect();
+                println!("Locked pages: {}", pages.join(", "));
+            }
+        },
+        Err(e) => println!("Error reading lock bytes: {}", e),
+    }
+}
+
+fn print_access_config(config: &AccessConfig, model: UltralightModel) {
+    if config.is_protected(model) {
+        let protection = match config.protection {
+            Protection::Write => "write",
+            Protection::ReadWrite => "read and write",
+        };
+        println!("Password protects {} access from page {} (AUTH0 = 0x{:02X})", protection, config.auth0, config.auth0);
+    } else {
+        println!("Password protection disabled (AUTH0 = 0x{:02X})", config.auth0);
+    }
+
+    match config.attempt_limit(model) {
+        Some(limit) => println!("PWD_AUTH disabled after {} failed attempts (AUTHLIM = {})", limit, config.authlim),
+        None => println!("Unlimited PWD_AUTH attempts"),
+    }
+    if config.cfglck {
+        println!("Configuration permanently locked (CFGLCK)");
+    }
+}
+
+// Set PWD/PACK, AUTH0, PROT and AUTHLIM, optionally locking the configuration
+fn configure_password(ultralight: &MifareUltralight, model: UltralightModel) -> Result<(), Box<dyn Error>> {
+    let answer = prompt("1. Enable or change protection  2. Disable protection  3. Lock configuration (CFGLCK)\n> ")?;
+    match answer.as_str() {
+        "1" => {
+            let password = prompt_hex("New password (4 bytes hex): ", 4)?;
+            let pack = prompt_hex("PACK (2 bytes hex): ", 2)?;
+            let auth0 = prompt_page("Protect from page (AUTH0): ")?;
+            let protection = match prompt("Protect reads as well? (y/n) ")?.to_lowercase().as_str() {
+                "y" => Protection::ReadWrite,
+                _ => Protection::Write,
+            };
+            let authlim = prompt("AUTHLIM (0-7, 0 = unlimited attempts): ")?.parse::<u8>()?;
+
+            ultralight.enable_password(model, &[password[0], password[1], password[2], password[3]],
+                                       &[pack[0], pack[1]], auth0, protection, authlim)?;
+            println!("Password protection configured");
+        },
+        "2" => {
+            ultralight.disable_password(model)?;
+            println!("Password protection disabled");
+        },
+        "3" => {
+            println!("Warning: AUTH0, PROT and AUTHLIM can never be changed again");
+            if prompt("Continue? (y/n) ")?.to_lowercase() == "y" {
+                ultralight.lock_config(model)?;
+                println!("Configuration locked");
+            }
+        },
+        _ => println!("Invalid choice!"),
+    }
+    Ok(())
+}
+
+fn print_auth_config(config: &AuthConfig) {
+    if config.is_protected() {
+        let protection = match config.protection {
+            Protection::Write => "write",
+            Protection::ReadWrite => "read and write",
+        };
+        println!("3DES key protects {} access from page {} (AUTH0 = 0x{:02X})", protection, config.auth0, config.auth0);
+    } else {
+        println!("3DES protection disabled (AUTH0 = 0x{:02X})", config.auth0);
+    }
+}
+
+// Empty answer for the factory key
+fn prompt_key(question: &str) -> Result<[u8; 16], Box<dyn Error>> {
+    if prompt(&format!("{} (Enter for the default key) ", question))?.is_empty() {
+        return Ok(DEFAULT_KEY);
+    }
+    let key = prompt_hex("Key (16 bytes hex): ", 16)?;
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&key);
+    Ok(bytes)
+}
+
+// Ultralight C counterpart of configure_password: key, AUTH0 and AUTH1
+fn configure_3des(ultralight: &MifareUltralight, model: UltralightModel) -> Result<(), Box<dyn Error>> {
+    let answer = prompt("1. Enable or change protection  2. Disable protection  3. Change key only\n> ")?;
+    match answer.as_str() {
+        "1" => {
+            let key = prompt_key("New key?")?;
+            let auth0 = prompt_page("Protect from page (AUTH0, 03h-2Fh): ")?;
+            let protection = match prompt("Protect reads as well? (y/n) ")?.to_lowercase().as_str() {
+                "y" => Protection::ReadWrite,
+                _ => Protection::Write,
+            };
+
+            ultralight.enable_3des_protection(model, &key, auth0, protection)?;
+            println!("3DES protection configured");
+        },
+        "2" => {
+            ultralight.disable_3des_protection(model)?;
+            println!("3DES protection disabled");
+        },
+        "3" => {
+            let key = prompt_key("New key?")?;
+            ultralight.change_key(model, &key)?;
+            println!("Key written");
+        },
+        _ => println!("Invalid choice!"),
+    }
+    Ok(())
+}
+
+fn print_ndef(message: &NdefMessage) {
+    if message.is_empty() {
+        println!("NDEF message is empty");
+        return;
+    }
+    for (i, record) in message.records.iter().enumerate() {
+        println!("Record {}: {}", i + 1, record.describe());
+    }
+}
+
+// Minimal vCard 3.0 contact
+fn prompt_vcard() -> Result<NdefRecord, Box<dyn Error>> {
+    let name = prompt("Name: ")?;
+    let phone = prompt("Phone (optional): ")?;
+    let email = prompt("E-mail (optional): ")?;
+
+    let mut vcard = format!("BEGIN:VCARD\r\nVERSION:3.0\r\nFN:{}\r\nN:{};;;;\r\n", name, name);
+    if !phone.is_empty() {
+        vcard.push_str(&format!("TEL:{}\r\n", phone));
+    }
+    if !email.is_empty() {
+        vcard.push_str(&format!("EMAIL:{}\r\n", email));
+    }
+    vcard.push_str("END:VCARD\r\n");
+    Ok(NdefRecord::mime("text/vcard", vcard.as_bytes()))
+}
+
+fn run_menu(ultralight: &MifareUltralight, model: UltralightModel) -> Result<(), Box<dyn Error>> {
+    loop {
+        println!("\nChoose an operation:");
+        println!("1. Read a page");
+        println!("2. Write a page");
+        println!("3. Write a page (COMPATIBILITY WRITE)");
+        println!("4. Dump all pages");
+        println!("5. Show lock bits and OTP");
+        println!("6. Read counters");
+        println!("7. Check originality signature");
+        if model == UltralightModel::UltralightC {
+            println!("8. Authenticate with 3DES key");
+            println!("9. Show 3DES protection");
+            println!("10. Configure 3DES protection");
+        } else {
+            println!("8. Authenticate with password (PWD_AUTH)");
+            println!("9. Show password protection");
+            println!("10. Configure password protection");
+        }
+        println!("11. Read NDEF message");
+        println!("12. Write URL (NDEF)");
+        println!("13. Write vCard (NDEF)");
+        println!("14. Format for NDEF");
+        println!("15. Make NDEF tag read-only");
+        println!("16. Exit");
+
+        let choice = prompt("> ")?.parse::<u8>().unwrap_or(0);
+        let result: Result<(), Box<dyn Error>> = match choice {
+            1 => {
+                let page = prompt_page("Page: ")?;
+                ultralight.read_page(page).map(|data| print_page(page as usize, &data))
+            },
+            2 | 3 => {
+                let page = prompt_page("Page: ")?;
+                let data = prompt_hex("Data (4 bytes hex): ", 4)?;
+                if page < 4 {
+                    println!("Warning: pages 0-3 hold the UID, lock bits and OTP; lock and OTP bits cannot be cleared");
+                    if prompt("Continue? (y/n) ")?.to_lowercase() != "y" {
+                        continue;
+                    }
+                }
+                let written = if choice == 2 {
+                    ultralight.write_page(page, &data)
+                } else {
+                    ultralight.compat_write(page, &data)
+                };
+                written.map(|()| println!("Page {} written", page))
+            },
+            4 => {
+                ultralight.dump(model).map(|pages| {
+                    for (page, data) in pages.iter().enumerate() {
+                        print_page(page, data);
+                    }
+                    if pages.len() < model.page_count() {
+                        println!("Stopped at page {}: the card refused to read further", pages.len());
+                    }
+                })
+            },
+            5 => {
+                print_locks(ultralight, model);
+                Ok(())
+            },
+            6 => {
+                if model.counters().is_empty() {
+                    println!("{} has no counters", model.to_string());
+                }
+                for &counter in model.counters() {
+                    match ultralight.read_counter(counter) {
+                        Ok(value) => println!("Counter {}: {}", counter, value),
+                        Err(e) => {
+                            println!("Counter {}: {}", counter, e);
+                            let _ = ultralight.reactivate();
+                        },
+                    }
+                }
+                Ok(())
+            },
+            7 => ultralight.read_uid().and_then(|uid| ultralight.read_signature().map(|signature| {
+                println!("Signature: {}", format_hex(&signature));
+                println!("Originality: {}", check_originality(&uid, Some(&signature)).to_string());
+            })),
+            8 if model == UltralightModel::UltralightC => {
+                let key = prompt_key("Key?")?;
+                ultralight.authenticate_3des(&key).map(|()| println!("Authenticated"))
+            },
+            9 if model == UltralightModel::UltralightC => ultralight.read_auth_config(model).map(|config| print_auth_config(&config)),
+            10 if model == UltralightModel::UltralightC => configure_3des(ultralight, model),
+            8 => {
+                let password = prompt_hex("Password (4 bytes hex): ", 4)?;
+                ultralight.pwd_auth(&[password[0], password[1], password[2], password[3]])
+                    .map(|pack| println!("Authenticated, PACK: {}", format_hex(&pack)))
+            },
+            9 => ultralight.read_access_config(model).map(|config| print_access_config(&config, model)),
+            10 => configure_password(ultralight, model),
+            11 => ultralight.read_ndef().map(|message| print_ndef(&message)),
+            12 => {
+                let uri = prompt("URL: ")?;
+                ultralight.write_ndef(&NdefMessage::new(vec![NdefRecord::uri(&uri)]))
+                    .map(|()| println!("URL written"))
+            },
+            13 => {
+                let record = prompt_vcard()?;
+                ultralight.write_ndef(&NdefMessage::new(vec![record]))
+                    .map(|()| println!("vCard written"))
+            },
+            14 => ultralight.format_ndef(model).map(|()| println!("Tag formatted with an empty NDEF message")),
+            15 => {
+                println!("Warning: this sets the lock bits and can never be undone");
+                if prompt("Continue? (y/n) ")?.to_lowercase() != "y" {
+                    continue;
+                }
+                ultralight.make_read_only(model).map(|()| println!("Tag is now read-only"))
+            },
+            16 => return Ok(()),
+            _ => {
+                println!("Invalid choice!");
+                Ok(())
+            },
+        };
+
+        if let Err(e) = result {
+            println!("Error: {}", e);
+            let _ = ultralight.reactivate();
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    println!("MIFARE Ultralight / NTAG Operations");
+    println!("-----------------------------------");
+
+    // Initialize PC/SC context
+    let ctx = Context::establish(Scope::User)?;
+
+    // Find ACR122U reader
+    let mut readers_buffer = [0; 2048];
+    let acr122u = match ctx.list_readers(&mut readers_buffer)?.find(|r| r.to_string_lossy().contains("ACR122")) {
+        Some(reader) => reader.to_owned(),
+        None => {
+            println!("No ACR122U reader found!");
+            return Ok(());
+        }
+    };
+    println!("Using reader: {}", acr122u.to_string_lossy());
+    println!("Waiting for card... (place card on reader)");
+
+    let card = loop {
+        match ctx.connect(&acr122u, ShareMode::Shared, Protocols::ANY) {
+            Ok(card) => break card,
+            Err(pcsc::Error::NoSmartcard) | Err(pcsc::Error::RemovedCard) => thread::sleep(Duration::from_millis(200)),
+            Err(e) => return Err(Box::new(e)),
+        }
+    };
+
+    // Give the card a moment to stabilize
+    thread::sleep(Duration::from_millis(100));
+
+    let ultralight = MifareUltralight::new(&card);
+    let model = ultralight.detect_model()?;
+    println!("\nCard: {} ({} pages, {} bytes user memory)", model.to_string(), model.page_count(), model.user_memory());
+
+    if model.has_ev1_commands() {
+        if let Ok(version) = ultralight.get_version() {
+            println!("Version: {}", format_hex(&version));
+        }
+    }
+
+    match ultralight.read_uid() {
+        Ok(uid) => println!("UID: {}", format_hex(&uid)),
+        Err(e) => println!("Error reading UID: {}", e),
+    }
+
+    run_menu(&ultralight, model)?;
+
+    drop(ultralight);
+    let _ = card.disconnect(Disposition::LeaveCard);
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use crate::identify::{Identification, MifareType};
+
+// Cryptographic protection a card family offers for its data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoSuite {
+    // Data readable by anyone, at most protected by lock bits
+    None,
+    // NXP proprietary stream cipher with 48-bit keys
+    Crypto1,
+    // 32-bit password (PWD_AUTH) confirmed by a 16-bit PACK
+    Password32,
+    Des,
+    // Two-key triple DES
+    TripleDes,
+    Aes128,
+}
+
+impl CryptoSuite {
+    pub fn to_string(&self) -> &str {
+        match self {
+            CryptoSuite::None => "none",
+            CryptoSuite::Crypto1 => "CRYPTO1",
+            CryptoSuite::Password32 => "32-bit password",
+            CryptoSuite::Des => "DES",
+            CryptoSuite::TripleDes => "2K3DES",
+            CryptoSuite::Aes128 => "AES-128",
+        }
+    }
+
+    // Effective key size in bits
+    pub fn key_bits(&self) -> Option<u16> {
+        match self {
+            CryptoSuite::None => None,
+            CryptoSuite::Crypto1 => Some(48),
+            CryptoSuite::Password32 => Some(32),
+            CryptoSuite::Des => Some(56),
+            CryptoSuite::TripleDes => Some(112),
+            CryptoSuite::Aes128 => Some(128),
+        }
+    }
+
+    // Whether keys can be recovered or brute forced in practice
+    pub fn is_broken(&self) -> bool {
+        matches!(self, CryptoSuite::Crypto1 | CryptoSuite::Password32 | CryptoSuite::Des)
+    }
+}
+
+// Operations a card supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    ReadUid,
+    // 16-byte blocks behind sector keys
+    ReadBlock,
+    WriteBlock,
+    ValueBlock,
+    // 4-byte pages
+    ReadPage,
+    WritePage,
+    PasswordAuth,
+    ThreeDesAuth,
+    ReadCounter,
+    OriginalitySignature,
+    Ndef,
+    Applications,
+    // DESFire authentication with MACed or enciphered commands after it
+    SecureMessaging,
+}
+
+impl Operation {
+    pub fn to_string(&self) -> &str {
+        match self {
+            Operation::ReadUid => "Read UID",
+            Operation::ReadBlock => "Read blocks",
+            Operation::WriteBlock => "Write blocks",
+            Operation::ValueBlock => "Value block operations",
+            Operation::ReadPage => "Read pages",
+            Operation::WritePage => "Write pages",
+            Operation::PasswordAuth => "Password authentication",
+            Operation::ThreeDesAuth => "3DES authentication",
+            Operation::ReadCounter => "Read counters",
+            Operation::OriginalitySignature => "Originality signature",
+            Operation::Ndef => "NDEF messages",
+            Operation::Applications => "Application and file management",
+            Operation::SecureMessaging => "Authentication and secure messaging",
+        }
+    }
+}
+
+// The parts of this crate that operate on a card family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handler {
+    // classic::MifareClassic, used by the `card` tool
+    Classic,
+    // ultralight::MifareUltralight, used by the `ultralight` tool
+    Ultralight,
+    // desfire::Desfire, used by the `desfire` tool
+    Desfire,
+}
+
+impl Handler {
+    pub fn to_string(&self) -> &str {
+        match self {
+            Handler::Classic => "classic::MifareClassic (card tool)",
+            Handler::Ultralight => "ultralight::MifareUltralight (ultralight tool)",
+            Handler::Desfire => "desfire::Desfire (desfire tool)",
+        }
+    }
+
+    // Operations the handler implements
+    pub fn operations(&self) -> &'static [Operation] {
+        match self {
+            Handler::Classic => &[
+                Operation::ReadUid,
+                Operation::ReadBlock,
+                Operation::WriteBlock,
+                Operation::ValueBlock,
+                Operation::Ndef,
+            ],
+            Handler::Ultralight => &[
+                Operation::ReadUid,
+                Operation::ReadPage,
+                Operation::WritePage,
+                Operation::PasswordAuth,
+                Operation::ThreeDesAuth,
+                Operation::ReadCounter,
+                Operation::OriginalitySignature,
+                Operation::Ndef,
+            ],
+            Handler::Desfire => &[
+                Operation::ReadUid,
+                Operation::Applications,
+                Operation::SecureMessaging,
+            ],
+        }
+    }
+}
+
+// What a card can do and how this crate can work with it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    pub card_type: MifareType,
+    pub crypto: Vec<CryptoSuite>,
+    // Total memory in bytes, when it is fixed for the family or was reported
+    pub memory_size: Option<usize>,
+    pub operations: Vec<Operation>,
+    pub handlers: Vec<Handler>,
+}
+
+impl Capabilities {
+    // Capabilities of a card family, assuming its most common member
+    pub fn for_card_type(card_type: &MifareType) -> Capabilities {
+        use Operation::*;
+
+        let (crypto, memory_size, operations, handlers) = match card_type {
+            MifareType::MifareClassic1K => (vec![CryptoSuite::Crypto1], Some(1024), vec![ReadUid, ReadBlock, WriteBlock, ValueBlock, Ndef], vec![Handler::Classic]),
+            MifareType::MifareClassic4K => (vec![CryptoSuite::Crypto1], Some(4096), vec![ReadUid, ReadBlock, WriteBlock, ValueBlock, Ndef], vec![Handler::Classic]),
+            MifareType::MifareMini => (vec![CryptoSuite::Crypto1], Some(320), vec![ReadUid, ReadBlock, WriteBlock, ValueBlock], vec![Handler::Classic]),
+            MifareType::MifareUltralight => (vec![CryptoSuite::None], Some(64), vec![ReadUid, ReadPage, WritePage, Ndef], vec![Handler::Ultralight]),
+            MifareType::Ntag => (vec![CryptoSuite::Password32], None, vec![ReadUid, ReadPage, WritePage, PasswordAuth, ReadCounter, OriginalitySignature, Ndef], vec![Handler::Ultralight]),
+            // NDEF lives in a Type 4 Tag application, which no handler maps yet
+            MifareType::MifareDesfire => (vec![CryptoSuite::TripleDes, CryptoSuite::Aes128], None, vec![ReadUid, Applications, SecureMessaging, Ndef], vec![Handler::Desfire]),
+            // Plus cards leave the factory in SL1 (Classic compatible) or SL0/SL3
+            MifareType::MifarePlus => (vec![CryptoSuite::Crypto1, CryptoSuite::Aes128], None, vec![ReadUid, ReadBlock, WriteBlock, ValueBlock], vec![]),
+            MifareType::OtherMifare | MifareType::Unknown => (vec![], None, vec![ReadUid], vec![]),
+        };
+
+        Capabilities {
+            card_type: card_type.clone(),
+            crypto,
+            memory_size,
+            operations,
+            handlers,
+        }
+    }
+
+    // Capabilities refined with what the card reported about itself
+    pub fn for_identification(identification: &Identification) -> Capabilities {
+        use Operation::*;
+
+        let mut caps = Capabilities::for_card_type(&identification.card_type);
+        if identification.memory_size.is_some() {
+            caps.memory_size = identification.memory_size;
+        }
+
+        let generation = identification.generation.as_deref();
+        match identification.card_type {
+            MifareType::MifareUltralight => match generation {
+                Some("C") => {
+                    caps.crypto = vec![CryptoSuite::TripleDes];
+                    caps.operations = vec![ReadUid, ReadPage, WritePage, ThreeDesAuth, Ndef];
+                },
+                Some("EV1") => {
+                    caps.crypto = vec![CryptoSuite::Password32];
+                    caps.operations = vec![ReadUid, ReadPage, WritePage, PasswordAuth, ReadCounter, OriginalitySignature, Ndef];
+                },
+                _ => {},
+            },
+            MifareType::MifareDesfire => {
+                caps.crypto = match generation {
+                    Some("EV0") => vec![CryptoSuite::Des, CryptoSuite::TripleDes],
+                    _ if identification.product.contains("Light") => vec![CryptoSuite::Aes128],
+                    _ => vec![CryptoSuite::Des, CryptoSuite::TripleDes, CryptoSuite::Aes128],
+                };
+            },
+            MifareType::MifarePlus => {
+                match identification.security_level.as_deref() {
+                    // SL1 behaves like a Classic card, so the Classic handler applies
+                    Some("SL1") => {
+                        caps.crypto = vec![CryptoSuite::Crypto1];
+                        caps.handlers = vec![Handler::Classic];
+                    },
+                    Some("SL2") => caps.crypto = vec![CryptoSuite::Crypto1, CryptoSuite::Aes128],
+                    Some(_) => caps.crypto = vec![CryptoSuite::Aes128],
+                    None => {},
+                }
+            },
+            _ => {},
+        }
+
+        caps
+    }
+
+    pub fn supports(&self, operation: Operation) -> bool {
+        self.operations.contains(&operation)
+    }
+
+    // Operations this crate can perform on the card right away
+    pub fn available_operations(&self) -> Vec<Operation> {
+        self.operations.iter()
+            .copied()
+            .filter(|op| self.handlers.iter().any(|h| h.operations().contains(op)))
+            .collect()
+    }
+
+    // Supported by the card, but not (yet) by this crate
+    pub fn unavailable_operations(&self) -> Vec<Operation> {
+        let available = self.available_operations();
+        self.operations.iter()
+            .copied()
+            .filter(|op| !available.contains(op))
+            .collect()
+    }
+
+    // The strongest key size the card offers
+    pub fn max_key_bits(&self) -> Option<u16> {
+        self.crypto.iter().filter_map(|c| c.key_bits()).max()
+    }
+}
@@ -0,0 +1,132 @@
+use std::error::Error;
+use std::fmt;
+use pcsc::Card;
+use crate::atr::Atr;
+use crate::error::MifareError;
+use crate::format_hex;
+use crate::pn532::{Pn532, Target};
+
+// Everything the reader can tell about the card in the field
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardInfo {
+    pub uid: Vec<u8>,
+    // SENS_RES, as reported by the PN532
+    pub atqa: Option<[u8; 2]>,
+    // SEL_RES
+    pub sak: Option<u8>,
+    // Answer To Select, including the TL byte (ISO 14443-4 cards only)
+    pub ats: Option<Vec<u8>>,
+    // Historical bytes, from the ATS when present, otherwise from the ATR
+    pub historical_bytes: Vec<u8>,
+    // ATR built by the reader according to PC/SC part 3
+    pub atr: Vec<u8>,
+}
+
+impl CardInfo {
+    // Query the connected card: ATR from PC/SC, UID and ATS through the GET DATA
+    // pseudo-APDU, ATQA/SAK (and ATS) from the PN532 target data. Every call
+    // runs InListPassiveTarget again, which reselects the card and drops any
+    // authentication; callers that already listed the targets should use
+    // `from_target` instead.
+    pub fn query(card: &Card) -> Result<CardInfo, Box<dyn Error>> {
+        let atr = card.status2_owned()?.atr().to_vec();
+        let uid = get_data(card, 0x00)?;
+
+        // Target data is optional: not every reader firmware allows direct transmit
+        let target = Pn532::new(card)
+            .list_passive_targets(1)
+            .ok()
+            .and_then(|targets| targets.into_iter().find(|t| t.uid == uid));
+
+        let ats = match &target {
+            Some(t) => t.ats.clone(),
+            None => get_data(card, 0x01).ok().filter(|ats| !ats.is_empty()),
+        };
+
+        Ok(CardInfo::build(uid, target.as_ref(), ats, atr))
+    }
+
+    // Build the description of a target found by InListPassiveTarget; `atr` is
+    // the ATR the reader reported for the connection, if any
+    pub fn from_target(target: &Target, atr: &[u8]) -> CardInfo {
+        CardInfo::build(target.uid.clone(), Some(target), target.ats.clone(), atr.to_vec())
+    }
+
+    fn build(uid: Vec<u8>, target: Option<&Target>, ats: Option<Vec<u8>>, atr: Vec<u8>) -> CardInfo {
+        let historical_bytes = match &ats {
+            Some(ats) => ats_historical_bytes(ats),
+            None => Atr::parse(&atr).map(|a| a.historical_bytes).unwrap_or_default(),
+        };
+
+        CardInfo {
+            uid,
+            atqa: target.map(|t| t.atqa),
+            sak: target.map(|t| t.sak),
+            ats,
+            historical_bytes,
+            atr,
+        }
+    }
+
+    // Whether the card supports ISO/IEC 14443-4 (SAK bit 6 or an ATS)
+    pub fn supports_iso14443_4(&self) -> bool {
+        self.ats.is_some() || self.sak.is_some_and(|sak| sak & 0x20 != 0)
+    }
+}
+
+impl fmt::Display for CardInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "UID: {}", format_hex(&self.uid))?;
+        if let Some(atqa) = self.atqa {
+            writeln!(f, "ATQA: {}", format_hex(&atqa))?;
+        }
+        if let Some(sak) = self.sak {
+            writeln!(f, "SAK: {:02X}", sak)?;
+        }
+        if let Some(ats) = &self.ats {
+            writeln!(f, "ATS: {}", format_hex(ats))?;
+        }
+        if !self.historical_bytes.is_empty() {
+            writeln!(f, "Historical bytes: {}", format_hex(&self.historical_bytes))?;
+        }
+        write!(f, "ATR: {}", format_hex(&self.atr))
+    }
+}
+
+// GET DATA pseudo-APDU: P1 = 00 for the UID, 01 for the ATS
+fn get_data(card: &Card, p1: u8) -> Result<Vec<u8>, Box<dyn Error>> {
+    let cmd = [0xFF, 0xCA, p1, 0x00, 0x00];
+    let mut recv_buffer = [0; 256];
+
+    let response = card.transmit(&cmd, &mut recv_buffer)?;
+    if response.len() >= 2 {
+        let status1 = response[response.len() - 2];
+        let status2 = response[response.len() - 1];
+
+        if status1 == 0x90 && status2 == 0x00 {
+            return Ok(response[0..response.len() - 2].to_vec());
+        } else {
+            return Err(Box::new(MifareError::with_status("GET DATA failed", status1, status2)));
+        }
+    }
+
+    Err(Box::new(MifareError::new("Invalid response length for GET DATA")))
+}
+
+// Historical bytes of an ATS: TL, T0, then TA/TB/TC as announced by T0
+pub fn ats_historical_bytes(ats: &[u8]) -> Vec<u8> {
+    if ats.len() < 2 {
+        return Vec::new();
+    }
+
+    let t0 = ats[1];
+    let interface_bytes = [0x10, 0x20, 0x40].iter().filter(|&&bit| t0 & bit != 0).count();
+    let start = 2 + interface_bytes;
+    let end = (ats[0] as usize).min(ats.len());
+
+    if start < end {
+        ats[start..end].to_vec()
+    } else {
+        Vec::new()
+    }
+}
@@ -0,0 +1,532 @@
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use pcsc::Card;
+use crate::crc::with_crc;
+use crate::crypto1::{Crypto1, ReaderAuth};
+use crate::error::MifareError;
+use crate::pn532::{Pn532, Target};
+use crate::transport::{RawFrame, RawTransceive};
+
+// Enum for key types
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum KeyType {
+    KeyA = 0x60,
+    KeyB = 0x61,
+}
+
+// Sectors 0-31 have 4 blocks; sectors 32-39 of a 4K card have 16
+pub fn sector_block_count(sector: u8) -> u8 {
+    if sector < 32 { 4 } else { 16 }
+}
+
+pub fn sector_first_block(sector: u8) -> u8 {
+    if sector < 32 {
+        sector * 4
+    } else {
+        128 + (sector - 32) * 16
+    }
+}
+
+// Last block of a sector, holding its keys and access bits
+pub fn sector_trailer(sector: u8) -> u8 {
+    sector_first_block(sector) + (sector_block_count(sector) - 1)
+}
+
+// Any reader nonce will do
+const READER_NONCE: u32 = 0x1A2B3C4D;
+
+const READ: u8 = 0x30;
+const WRITE: u8 = 0xA0;
+const ACK: u8 = 0x0A;
+
+// Three-pass authentication in software over raw frames, from a freshly
+// selected card; returns the session cipher and the card's nonce
+pub fn authenticate_raw<T: RawTransceive + ?Sized>(link: &T, uid: u32, block: u8, key_type: KeyType, key: &[u8; 6]) -> Result<(Crypto1, u32), Box<dyn Error>> {
+    let answer = link.transceive_raw(&RawFrame::plain(&with_crc(&[key_type as u8, block])))?;
+    let nt: [u8; 4] = answer.data[..].try_into()
+        .map_err(|_| MifareError::new(&format!("Expected a 4-byte nonce, got {} bytes", answer.data.len())))?;
+    let nt = u32::from_be_bytes(nt);
+
+    let mut reader = ReaderAuth::new(key, uid, nt);
+    let (nr_ar, parity) = reader.respond(READER_NONCE);
+    let at = link.transceive_raw(&RawFrame::new(nr_ar.to_vec(), parity.to_vec()))?;
+    Ok((reader.verify(&at.data, &at.parity)?, nt))
+}
+
+// Card reached through raw frames, with Crypto1 done in software
+struct RawSession<'a> {
+    link: Box<dyn RawTransceive + 'a>,
+    uid: Vec<u8>,
+    // Cipher of the current authentication; a NAK or a failed exchange ends it
+    cipher: RefCell<Option<Crypto1>>,
+}
+
+impl RawSession<'_> {
+    fn authenticate(&self, block: u8, key_type: KeyType, key: &[u8; 6]) -> Result<(), Box<dyn Error>> {
+        // A new authentication starts from a selected card, not inside the
+        // current session
+        self.cipher.replace(None);
+        if self.uid.len() < 4 {
+            return Err(Box::new(MifareError::new("Target UID too short for authentication")));
+        }
+        self.link.reactivate()?;
+
+        let uid = u32::from_be_bytes(self.uid[self.uid.len() - 4..].try_into()?);
+        let (cipher, _) = authenticate_raw(&*self.link, uid, block, key_type, key)
+            .map_err(|e| MifareError::new(&format!("Authentication failed for block {}: {}", block, e)))?;
+        self.cipher.replace(Some(cipher));
+        Ok(())
+    }
+
+    // Send an encrypted command and return the decrypted answer: the data
+    // without its CRC, or the 4-bit ACK/NAK
+    fn exchange(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut session = self.cipher.borrow_mut();
+        let cipher = session.as_mut().ok_or_else(|| MifareError::new("Not authenticated"))?;
+
+        let (frame, parity) = cipher.encrypt(&with_crc(data));
+        let answer = match self.link.transceive_raw(&RawFrame::new(frame, parity)) {
+            Ok(answer) => answer,
+            Err(e) => {
+                *session = None;
+                return Err(e);
+            },
+        };
+
+        if answer.is_nibble() {
+            let nibble = cipher.nibble(answer.data[0]);
+            if nibble != ACK {
+                *session = None;
+                return Err(Box::new(MifareError::new(&format!("NAK {:X}", nibble))));
+            }
+            return Ok(vec![nibble]);
+        }
+
+        let plain = cipher.decrypt(&answer.data, &answer.parity)?;
+        if plain.len() < 2 || with_crc(&plain[..plain.len() - 2]) != plain {
+            *session = None;
+            return Err(Box::new(MifareError::new("Wrong CRC in card response")));
+        }
+        Ok(plain[..plain.len() - 2].to_vec())
+    }
+
+    fn read_block(&self, block: u8) -> Result<Vec<u8>, Box<dyn Error>> {
+        let data = self.exchange(&[READ, block])
+            .map_err(|e| MifareError::new(&format!("Failed to read block {}: {}", block, e)))?;
+        if data.len() != 16 {
+            return Err(Box::new(MifareError::new("Invalid response length when reading block")));
+        }
+        Ok(data)
+    }
+
+    fn write_block(&self, block: u8, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.exchange(&[WRITE, block])
+            .and_then(|_| self.exchange(data))
+            .map_err(|e| MifareError::new(&format!("Failed to write to block {}: {}", block, e)))?;
+        Ok(())
+    }
+}
+
+// Structure to represent a MIFARE Classic card
+pub struct MifareClassic<'a> {
+    // Reader for the ACR122U's MIFARE APDUs; None on a raw link
+    card: Option<&'a Card>,
+    // PN532 target addressed through InDataExchange, if one was selected
+    target: Option<Target>,
+    // Key loaded for target-mode or raw authentication
+    key: Cell<Option<[u8; 6]>>,
+    raw: Option<RawSession<'a>>,
+}
+
+impl<'a> MifareClassic<'a> {
+    // Create a new MIFARE Classic handler
+    pub fn new(card: &'a Card) -> Self {
+        MifareClassic { card: Some(card), target: None, key: Cell::new(None), raw: None }
+    }
+
+    // Create a handler addressing one of the targets found by InListPassiveTarget,
+    // so that several cards in the field can be used side by side
+    pub fn with_target(card: &'a Card, target: Target) -> Self {
+        MifareClassic { card: Some(card), target: Some(target), key: Cell::new(None), raw: None }
+    }
+
+    // Create a handler doing Crypto1 itself over raw frames, e.g. to a
+    // simulated card, for the card with `uid`. Block operations only: value
+    // blocks and direct commands need the reader.
+    pub fn with_raw_transceiver<T: RawTransceive + 'a>(link: T, uid: &[u8]) -> Self {
+        let raw = RawSession { link: Box::new(link), uid: uid.to_vec(), cipher: RefCell::new(None) };
+        MifareClassic { card: None, target: None, key: Cell::new(None), raw: Some(raw) }
+    }
+
+    // Target addressed by this handler, if any
+    pub fn target(&self) -> Option<&Target> {
+        self.target.as_ref()
+    }
+
+    // Raw frame link, when this handler does Crypto1 itself
+    pub(crate) fn raw_link(&self) -> Option<&(dyn RawTransceive + 'a)> {
+        self.raw.as_ref().map(|raw| &*raw.link)
+    }
+
+    // Wake the card up and select it again, ending any authentication
+    pub fn reactivate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(raw) = &self.raw {
+            raw.cipher.replace(None);
+            return raw.link.reactivate();
+        }
+        self.pn532()?.list_passive_targets(1)?;
+        Ok(())
+    }
+
+    fn card(&self) -> Result<&'a Card, MifareError> {
+        self.card.ok_or_else(|| MifareError::new("Operation needs the reader, not available on a raw link"))
+    }
+
+    // PN532 of the reader, for raw frames outside the ACR122U's MIFARE APDUs
+    pub(crate) fn pn532(&self) -> Result<Pn532<'a>, MifareError> {
+        Ok(Pn532::new(self.card()?))
+    }
+
+    // Send a MIFARE command to the selected target through InDataExchange
+    fn exchange(&self, target: &Target, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.pn532()?.data_exchange(target.tg, data)
+    }
+
+    // Read UID of the card
+    pub fn read_uid(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        if let Some(target) = &self.target {
+            return Ok(target.uid.clone());
+        }
+        if let Some(raw) = &self.raw {
+            return Ok(raw.uid.clone());
+        }
+
+        let get_uid = [0xFF, 0xCA, 0x00, 0x00, 0x00];
+        let mut recv_buffer = [0; 256];
+        
+        let response = self.card()?.transmit(&get_uid, &mut recv_buffer)?;
+        if response.len() >= 2 {
+            let status1 = response[response.len() - 2];
+            let status2 = response[response.len() - 1];
+            
+            if status1 == 0x90 && status2 == 0x00 {
+                // Extract UID (excluding status bytes)
+                return Ok(response[0..response.len() - 2].to_vec());
+            } else {
+                return Err(Box::new(MifareError::with_status(
+                    "Failed to read UID", status1, status2
+                )));
+            }
+        }
+        
+        Err(Box::new(MifareError::new("Invalid response length when reading UID")))
+    }
+
+    // Load authentication key
+    pub fn load_key(&self, key: &[u8]) -> Result<(), Box<dyn Error>> {
+        if key.len() != 6 {
+            return Err(Box::new(MifareError::new("Key must be exactly 6 bytes")));
+        }
+
+        if self.target.is_some() || self.raw.is_some() {
+            // InDataExchange and software authentication carry the key itself
+            let mut loaded = [0u8; 6];
+            loaded.copy_from_slice(key);
+            self.key.set(Some(loaded));
+            return Ok(());
+        }
+        
+        let mut load_key_cmd = vec![0xFF, 0x82, 0x00, 0x00, 0x06];
+        load_key_cmd.extend_from_slice(key);
+        
+        let mut recv_buffer = [0; 256];
+        let response = self.card()?.transmit(&load_key_cmd, &mut recv_buffer)?;
+        
+        if response.len() >= 2 {
+            let status1 = response[response.len() - 2];
+            let status2 = response[response.len() - 1];
+            
+            if status1 == 0x90 && status2 == 0x00 {
+                return Ok(());
+            } else {
+                return Err(Box::new(MifareError::with_status(
+                    "Failed to load key", status1, status2
+                )));
+            }
+        }
+        
+        Err(Box::new(MifareError::new("Invalid response length when loading key")))
+    }
+
+    // Authenticate with loaded key
+    pub fn authenticate(&self, block: u8, key_type: KeyType) -> Result<(), Box<dyn Error>> {
+        let key_value = key_type as u8;
+
+        if let Some(raw) = &self.raw {
+            let key = self.key.get()
+                .ok_or_else(|| MifareError::new("No key loaded for authentication"))?;
+            return raw.authenticate(block, key_type, &key);
+        }
+
+        if let Some(target) = &self.target {
+            let key = self.key.get()
+                .ok_or_else(|| MifareError::new("No key loaded for authentication"))?;
+            if target.uid.len() < 4 {
+                return Err(Box::new(MifareError::new("Target UID too short for authentication")));
+            }
+
+            // Authentication uses the last four bytes of the UID
+            let mut cmd = vec![key_value, block];
+            cmd.extend_from_slice(&key);
+            cmd.extend_from_slice(&target.uid[target.uid.len() - 4..]);
+            return self.exchange(target, &cmd).map(|_| ()).map_err(|e| {
+                Box::new(MifareError::new(
+                    &format!("Authentication failed for block {}: {}", block, e)
+                )) as Box<dyn Error>
+            });
+        }
+
+        let auth_cmd = [0xFF, 0x86, 0x00, 0x00, 0x05, 0x01, 0x00, block, key_value, 0x00];
+        
+        let mut recv_buffer = [0; 256];
+        let response = self.card()?.transmit(&auth_cmd, &mut recv_buffer)?;
+        
+        if response.len() >= 2 {
+            let status1 = response[response.len() - 2];
+            let status2 = response[response.len() - 1];
+            
+            if status1 == 0x90 && status2 == 0x00 {
+                return Ok(());
+            } else {
+                return Err(Box::new(MifareError::with_status(
+                    &format!("Authentication failed for block {}", block), 
+                    status1, status2
+                )));
+            }
+        }
+        
+        Err(Box::new(MifareError::new("Invalid response length during authentication")))
+    }
+
+    // Read a block
+    pub fn read_block(&self, block: u8) -> Result<Vec<u8>, Box<dyn Error>> {
+        if let Some(raw) = &self.raw {
+            return raw.read_block(block);
+        }
+        if let Some(target) = &self.target {
+            let data = self.exchange(target, &[0x30, block])?;
+            if data.len() < 16 {
+                return Err(Box::new(MifareError::new("Invalid response length when reading block")));
+            }
+            return Ok(data[..16].to_vec());
+        }
+
+        let read_cmd = [0xFF, 0xB0, 0x00, block, 0x10];
+        
+        let mut recv_buffer = [0; 256];
+        let response = self.card()?.transmit(&read_cmd, &mut recv_buffer)?;
+        
+        if response.len() >= 2 {
+            let status1 = response[response.len() - 2];
+            let status2 = response[response.len() - 1];
+            
+            if status1 == 0x90 && status2 == 0x00 {
+                // Extract data (excluding status bytes)
+                return Ok(response[0..response.len() - 2].to_vec());
+            } else {
+                return Err(Box::new(MifareError::with_status(
+                    &format!("Failed to read block {}", block), 
+                    status1, status2
+                )));
+            }
+        }
+        
+        Err(Box::new(MifareError::new("Invalid response length when reading block")))
+    }
+
+    // Write to a block
+    pub fn write_block(&self, block: u8, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        if data.len() != 16 {
+            return Err(Box::new(MifareError::new("Data must be exactly 16 bytes")));
+        }
+
+        if let Some(raw) = &self.raw {
+            return raw.write_block(block, data);
+        }
+
+        if let Some(target) = &self.target {
+            let mut cmd = vec![0xA0, block];
+            cmd.extend_from_slice(data);
+            self.exchange(target, &cmd)?;
+            return Ok(());
+        }
+
+        let mut write_cmd = vec![0xFF, 0xD6, 0x00, block, 0x10];
+        write_cmd.extend_from_slice(data);
+        
+        let mut recv_buffer = [0; 256];
+        let response = self.card()?.transmit(&write_cmd, &mut recv_buffer)?;
+        
+        if response.len() >= 2 {
+            let status1 = response[response.len() - 2];
+            let status2 = response[response.len() - 1];
+            
+            if status1 == 0x90 && status2 == 0x00 {
+                return Ok(());
+            } else {
+                return Err(Box::new(MifareError::with_status(
+                    &format!("Failed to write to block {}", block), 
+                    status1, status2
+                )));
+            }
+        }
+        
+        Err(Box::new(MifareError::new("Invalid response length when writing block")))
+    }
+
+    // Increment a value block
+    pub fn increment_value(&self, block: u8, value: i32) -> Result<(), Box<dyn Error>> {
+        if let Some(target) = &self.target {
+            return self.value_operation(target, 0xC1, block, value);
+        }
+
+        // Value blocks must be in a specific format
+        let mut cmd = vec![0xFF, 0xD7, 0x00, block, 0x05, 0x01];
+        
+        // Convert value to bytes (little-endian)
+        let value_bytes = value.to_le_bytes();
+        cmd.extend_from_slice(&value_bytes);
+        
+        let mut recv_buffer = [0; 256];
+        let response = self.card()?.transmit(&cmd, &mut recv_buffer)?;
+        
+        if response.len() >= 2 {
+            let status1 = response[response.len() - 2];
+            let status2 = response[response.len() - 1];
+            
+            if status1 == 0x90 && status2 == 0x00 {
+                return Ok(());
+            } else {
+                return Err(Box::new(MifareError::with_status(
+                    &format!("Failed to increment value block {}", block), 
+                    status1, status2
+                )));
+            }
+        }
+        
+        Err(Box::new(MifareError::new("Invalid response length when incrementing value")))
+    }
+
+    // Decrement a value block
+    pub fn decrement_value(&self, block: u8, value: i32) -> Result<(), Box<dyn Error>> {
+        if let Some(target) = &self.target {
+            return self.value_operation(target, 0xC0, block, value);
+        }
+
+        let mut cmd = vec![0xFF, 0xD7, 0x00, block, 0x05, 0x02];
+        
+        // Convert value to bytes (little-endian)
+        let value_bytes = value.to_le_bytes();
+        cmd.extend_from_slice(&value_bytes);
+        
+        let mut recv_buffer = [0; 256];
+        let response = self.card()?.transmit(&cmd, &mut recv_buffer)?;
+        
+        if response.len() >= 2 {
+            let status1 = response[response.len() - 2];
+            let status2 = response[response.len() - 1];
+            
+            if status1 == 0x90 && status2 == 0x00 {
+                return Ok(());
+            } else {
+                return Err(Box::new(MifareError::with_status(
+                    &format!("Failed to decrement value block {}", block), 
+                    status1, status2
+                )));
+            }
+        }
+        
+        Err(Box::new(MifareError::new("Invalid response length when decrementing value")))
+    }
+
+    // Increment/decrement through InDataExchange, followed by a transfer back
+    // into the same block
+    fn value_operation(&self, target: &Target, op: u8, block: u8, value: i32) -> Result<(), Box<dyn Error>> {
+        let mut cmd = vec![op, block];
+        cmd.extend_from_slice(&value.to_le_bytes());
+        self.exchange(target, &cmd)?;
+        self.exchange(target, &[0xB0, block])?;
+        Ok(())
+    }
+
+    // Initialize a block as value block
+    pub fn init_value_block(&self, block: u8, value: i32) -> Result<(), Box<dyn Error>> {
+        // Value block format: value (4 bytes), ~value (4 bytes), value (4 bytes), block address (1 byte), ~block address (1 byte), block address (1 byte), ~block address (1 byte)
+        let mut data = [0u8; 16];
+        
+        // Convert value to bytes (little-endian)
+        let value_bytes = value.to_le_bytes();
+        
+        // Set value (first 4 bytes)
+        data[0..4].copy_from_slice(&value_bytes);
+        
+        // Set inverted value (next 4 bytes)
+        let inverted_value = !value;
+        let inverted_bytes = inverted_value.to_le_bytes();
+        data[4..8].copy_from_slice(&inverted_bytes);
+        
+        // Set value again (next 4 bytes)
+        data[8..12].copy_from_slice(&value_bytes);
+        
+        // Set block address and its complement
+        data[12] = block;
+        data[13] = !block;
+        data[14] = block;
+        data[15] = !block;
+        
+        // Write the value block
+        self.write_block(block, &data)
+    }
+
+    // Read a value from a value block
+    pub fn read_value(&self, block: u8) -> Result<i32, Box<dyn Error>> {
+        let data = self.read_block(block)?;
+        
+        if data.len() < 16 {
+            return Err(Box::new(MifareError::new("Invalid value block data length")));
+        }
+        
+        // Check if this is a valid value block
+        if data[0..4] != data[8..12] || data[12] != data[14] || data[13] != data[15] {
+            return Err(Box::new(MifareError::new("Invalid value block format")));
+        }
+        
+        // Convert first 4 bytes to i32 (little-endian)
+        let mut value_bytes = [0u8; 4];
+        value_bytes.copy_from_slice(&data[0..4]);
+        let value = i32::from_le_bytes(value_bytes);
+        
+        Ok(value)
+    }
+
+    // MIFARE direct command (for advanced operations)
+    pub fn direct_command(&self, command: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut recv_buffer = [0; 256];
+        let response = self.card()?.transmit(command, &mut recv_buffer)?;
+        
+        if response.len() >= 2 {
+            let status1 = response[response.len() - 2];
+            let status2 = response[response.len() - 1];
+            
+            if status1 == 0x90 && status2 == 0x00 {
+                return Ok(response[0..response.len() - 2].to_vec());
+            } else {
+                return Err(Box::new(MifareError::with_status(
+                    "Direct command failed", status1, status2
+                )));
+            }
+        }
+        
+        Err(Box::new(MifareError::new("Invalid response length for direct command")))
+    }
+}
@@ -0,0 +1,181 @@
+use std::error::Error;
+use crate::classic::{sector_block_count, sector_first_block, sector_trailer, KeyType, MifareClassic};
+use crate::error::MifareError;
+use crate::mad::{Mad, AID_NDEF, MAD_KEY_A};
+use crate::ndef::NdefMessage;
+use crate::type2::{walk_tlvs, Tlv, TLV_TERMINATOR};
+
+// NFC Forum mapping of NDEF on MIFARE Classic: the MAD lists the NDEF sectors,
+// whose data blocks hold the same TLV blocks as a Type 2 Tag
+
+// Key A of the NDEF sectors, public so phones can read them
+pub const NFC_KEY_A: [u8; 6] = [0xD3, 0xF7, 0xD3, 0xF7, 0xD3, 0xF7];
+
+// Access bits and GPB of the MAD sector: key A reads, key B writes; GPB C1
+// (MAD1) or C2 (MAD2), with the DA bit set
+const MAD_ACCESS_BITS: [u8; 3] = [0x78, 0x77, 0x88];
+const MAD1_GPB: u8 = 0xC1;
+const MAD2_GPB: u8 = 0xC2;
+
+// Access bits and GPB of the NDEF sectors: key A reads, key B writes,
+// mapping version 1.0 with read and write access
+const NDEF_ACCESS_BITS: [u8; 3] = [0x7F, 0x07, 0x88];
+const NDEF_GPB: u8 = 0x40;
+
+const BLOCK_SIZE: usize = 16;
+
+fn trailer(key_a: &[u8; 6], access_bits: &[u8; 3], gpb: u8, key_b: &[u8; 6]) -> [u8; 16] {
+    let mut data = [0u8; 16];
+    data[0..6].copy_from_slice(key_a);
+    data[6..9].copy_from_slice(access_bits);
+    data[9] = gpb;
+    data[10..16].copy_from_slice(key_b);
+    data
+}
+
+// Data blocks of the given sectors, in order, without sector trailers
+pub fn data_blocks(sectors: &[u8]) -> Vec<u8> {
+    sectors.iter()
+        .flat_map(|&sector| sector_first_block(sector)..sector_trailer(sector))
+        .collect()
+}
+
+// TLV stream holding `message`, padded to whole blocks, or an error when it
+// does not fit in `capacity` bytes
+pub fn ndef_tlv_stream(message: &NdefMessage, capacity: usize) -> Result<Vec<u8>, MifareError> {
+    let bytes = if message.is_empty() { Vec::new() } else { message.to_bytes()? };
+    let mut stream = Tlv::Ndef(bytes).encode();
+    if stream.len() > capacity {
+        return Err(MifareError::new(&format!("NDEF message needs {} bytes, the card has room for {}", stream.len(), capacity)));
+    }
+    if stream.len() < capacity {
+        stream.push(TLV_TERMINATOR);
+    }
+
+    let blocks = stream.len().div_ceil(BLOCK_SIZE);
+    stream.resize(blocks * BLOCK_SIZE, 0x00);
+    Ok(stream)
+}
+
+// NDEF message from the TLV stream of the NDEF sectors
+pub fn parse_ndef_stream(stream: &[u8]) -> Result<NdefMessage, MifareError> {
+    for (_, tlv) in walk_tlvs(stream)? {
+        match tlv {
+            Tlv::Ndef(bytes) if bytes.is_empty() => return Ok(NdefMessage::empty()),
+            Tlv::Ndef(bytes) => return NdefMessage::parse(&bytes),
+            Tlv::Terminator => break,
+            _ => {},
+        }
+    }
+    Err(MifareError::new("No NDEF TLV in the NDEF sectors"))
+}
+
+// NFC Forum MIFARE Classic formatting and NDEF access
+impl MifareClassic<'_> {
+    fn authenticate_sector(&self, sector: u8, key: &[u8; 6], key_type: KeyType) -> Result<(), Box<dyn Error>> {
+        self.load_key(key)?;
+        self.authenticate(sector_first_block(sector), key_type)
+    }
+
+    // Format as NFC Forum MIFARE Classic: MAD marking every other sector as
+    // NDEF, an empty NDEF message and the public read keys. `key` is the
+    // current key A of every sector (FFFFFFFFFFFF on a blank card); it
+    // becomes key B, which grants write access from then on.
+    pub fn format_ndef(&self, sectors: u8, key: &[u8; 6]) -> Result<(), Box<dyn Error>> {
+        if !matches!(sectors, 5 | 16 | 32 | 40) {
+            return Err(Box::new(MifareError::new("Sector count must be 5 (Mini), 16 (1K), 32 (2K) or 40 (4K)")));
+        }
+
+        let mut mad = Mad::new(sectors);
+        for sector in 1..sectors {
+            if !Mad::is_mad_sector(sector) {
+                mad.aids[sector as usize] = AID_NDEF;
+            }
+        }
+
+        // NDEF sectors first, so a failure leaves no MAD pointing at them
+        let empty = ndef_tlv_stream(&NdefMessage::empty(), BLOCK_SIZE)?;
+        for sector in mad.sectors_of(AID_NDEF) {
+            self.authenticate_sector(sector, key, KeyType::KeyA)?;
+            for (i, block) in (sector_first_block(sector)..sector_trailer(sector)).enumerate() {
+                if sector == 1 && i == 0 {
+                    self.write_block(block, &empty)?;
+                } else {
+                    self.write_block(block, &[0x00; BLOCK_SIZE])?;
+                }
+            }
+            self.write_block(sector_trailer(sector), &trailer(&NFC_KEY_A, &NDEF_ACCESS_BITS, NDEF_GPB, key))?;
+        }
+
+        let gpb = if mad.is_mad2() { MAD2_GPB } else { MAD1_GPB };
+        self.write_mad(&mad, key, KeyType::KeyA)?;
+        if mad.is_mad2() {
+            self.write_block(sector_trailer(16), &trailer(&MAD_KEY_A, &MAD_ACCESS_BITS, gpb, key))?;
+            self.authenticate_sector(0, key, KeyType::KeyA)?;
+        }
+        self.write_block(sector_trailer(0), &trailer(&MAD_KEY_A, &MAD_ACCESS_BITS, gpb, key))
+    }
+
+    // Sectors the MAD assigns to NDEF
+    fn ndef_sectors(&self, sectors: u8) -> Result<Vec<u8>, Box<dyn Error>> {
+        let ndef_sectors = self.read_mad(sectors)?.sectors_of(AID_NDEF);
+        if ndef_sectors.is_empty() {
+            return Err(Box::new(MifareError::new("The MAD lists no NDEF sectors")));
+        }
+        Ok(ndef_sectors)
+    }
+
+    pub fn read_ndef(&self, sectors: u8) -> Result<NdefMessage, Box<dyn Error>> {
+        let mut stream = Vec::new();
+        for sector in self.ndef_sectors(sectors)? {
+            self.authenticate_sector(sector, &NFC_KEY_A, KeyType::KeyA)?;
+            for block in sector_first_block(sector)..sector_trailer(sector) {
+                stream.extend(self.read_block(block)?);
+            }
+
+            // Stop once the TLVs read so far are complete
+            if let Ok(message) = parse_ndef_stream(&stream) {
+                return Ok(message);
+            }
+        }
+        Ok(parse_ndef_stream(&stream)?)
+    }
+
+    // Replace the NDEF message, writing with key B. The first block is
+    // written with a zero length first and completed last, so a card pulled
+    // away part way holds an empty message rather than a corrupt one.
+    pub fn write_ndef(&self, sectors: u8, message: &NdefMessage, key_b: &[u8; 6]) -> Result<(), Box<dyn Error>> {
+        let ndef_sectors = self.ndef_sectors(sectors)?;
+        let blocks = data_blocks(&ndef_sectors);
+        let stream = ndef_tlv_stream(message, blocks.len() * BLOCK_SIZE)?;
+
+        let first = &stream[0..BLOCK_SIZE];
+        let length = if first[1] == 0xFF { 2..4 } else { 1..2 };
+        let mut empty = first.to_vec();
+        for byte in &mut empty[length] {
+            *byte = 0x00;
+        }
+
+        let sector_of = |block: u8| ndef_sectors.iter().copied()
+            .find(|&sector| (sector_first_block(sector)..sector_first_block(sector) + sector_block_count(sector)).contains(&block))
+            .unwrap_or(0);
+
+        self.authenticate_sector(sector_of(blocks[0]), key_b, KeyType::KeyB)?;
+        self.write_block(blocks[0], &empty)?;
+
+        let mut current = sector_of(blocks[0]);
+        for (i, data) in stream.chunks(BLOCK_SIZE).enumerate().skip(1) {
+            let sector = sector_of(blocks[i]);
+            if sector != current {
+                self.authenticate_sector(sector, key_b, KeyType::KeyB)?;
+                current = sector;
+            }
+            self.write_block(blocks[i], data)?;
+        }
+
+        if current != sector_of(blocks[0]) {
+            self.authenticate_sector(sector_of(blocks[0]), key_b, KeyType::KeyB)?;
+        }
+        self.write_block(blocks[0], first)
+    }
+}
@@ -0,0 +1,17 @@
+// ISO/IEC 14443-3 CRC_A, appended low byte first
+pub fn crc_a(data: &[u8]) -> [u8; 2] {
+    let mut crc: u16 = 0x6363;
+    for &byte in data {
+        let mut b = byte ^ (crc as u8);
+        b ^= b << 4;
+        crc = (crc >> 8) ^ ((b as u16) << 8) ^ ((b as u16) << 3) ^ ((b as u16) >> 4);
+    }
+    crc.to_le_bytes()
+}
+
+// Frame with its CRC_A appended
+pub fn with_crc(data: &[u8]) -> Vec<u8> {
+    let mut frame = data.to_vec();
+    frame.extend_from_slice(&crc_a(data));
+    frame
+}
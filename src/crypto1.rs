@@ -0,0 +1,297 @@
+use crate::error::MifareError;
+
+// Crypto1, the stream cipher of MIFARE Classic. The 48-bit LFSR is kept as
+// its odd and even bits, as crapto1 does, so the filter reads its 20 input
+// bits from a single word and clocking swaps the halves.
+
+// Feedback taps of the odd and even halves
+pub(crate) const LF_POLY_ODD: u32 = 0x29CE5C;
+pub(crate) const LF_POLY_EVEN: u32 = 0x870804;
+
+fn bit(x: u32, n: u32) -> u32 {
+    (x >> n) & 1
+}
+
+// Bit `n` of a word sent as four bytes, most significant byte first, each
+// byte least significant bit first
+pub(crate) fn be_bit(x: u32, n: u32) -> u32 {
+    bit(x, n ^ 24)
+}
+
+pub(crate) fn parity32(x: u32) -> u32 {
+    x.count_ones() & 1
+}
+
+// Parity bit sent after a plaintext byte: 1 when the byte has an even number
+// of ones
+pub fn odd_parity(byte: u8) -> u8 {
+    (byte.count_ones() as u8 & 1) ^ 1
+}
+
+// Two-layer non-linear filter over the odd half
+pub(crate) fn filter(x: u32) -> u32 {
+    let mut f = (0xF22C0 >> (x & 0xF)) & 16;
+    f |= (0x6C9C0 >> ((x >> 4) & 0xF)) & 8;
+    f |= (0x3C8B0 >> ((x >> 8) & 0xF)) & 4;
+    f |= (0x1E458 >> ((x >> 12) & 0xF)) & 2;
+    f |= (0x0D938 >> ((x >> 16) & 0xF)) & 1;
+    (0xEC57E80A >> f) & 1
+}
+
+// Card nonce `n` steps further along the 16-bit PRNG of MIFARE Classic
+pub fn prng_successor(x: u32, n: u32) -> u32 {
+    let mut x = x.swap_bytes();
+    for _ in 0..n {
+        x = (x >> 1) | (((x >> 16) ^ (x >> 18) ^ (x >> 19) ^ (x >> 21)) & 1) << 31;
+    }
+    x.swap_bytes()
+}
+
+// Whether a nonce can come from the PRNG: its low half is its high half 16
+// steps on
+pub fn is_prng_nonce(nt: u32) -> bool {
+    prng_successor(nt >> 16, 16) == nt
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crypto1 {
+    odd: u32,
+    even: u32,
+}
+
+impl Crypto1 {
+    // Cipher loaded with a 48-bit key, first key byte most significant
+    pub fn new(key: u64) -> Crypto1 {
+        let mut odd = 0;
+        let mut even = 0;
+        for i in (1..48).rev().step_by(2) {
+            odd = (odd << 1) | ((key >> ((i - 1) ^ 7)) & 1) as u32;
+            even = (even << 1) | ((key >> (i ^ 7)) & 1) as u32;
+        }
+        Crypto1 { odd, even }
+    }
+
+    pub(crate) fn from_halves(odd: u32, even: u32) -> Crypto1 {
+        Crypto1 { odd, even }
+    }
+
+    pub fn from_key(key: &[u8; 6]) -> Crypto1 {
+        let mut bytes = [0u8; 8];
+        bytes[2..8].copy_from_slice(key);
+        Crypto1::new(u64::from_be_bytes(bytes))
+    }
+
+    // LFSR contents in key order; right after `new` this is the key
+    pub fn lfsr(&self) -> u64 {
+        let mut lfsr = 0u64;
+        for i in (0..24).rev() {
+            lfsr = (lfsr << 1) | bit(self.odd, i ^ 3) as u64;
+            lfsr = (lfsr << 1) | bit(self.even, i ^ 3) as u64;
+        }
+        lfsr
+    }
+
+    pub fn key(&self) -> [u8; 6] {
+        let bytes = self.lfsr().to_be_bytes();
+        let mut key = [0u8; 6];
+        key.copy_from_slice(&bytes[2..8]);
+        key
+    }
+
+    // Next keystream bit, without clocking
+    pub fn peek(&self) -> u8 {
+        filter(self.odd) as u8
+    }
+
+    // Clock once, feeding `input` (and the keystream bit when `input` is
+    // ciphertext), and return the keystream bit
+    pub fn bit(&mut self, input: u8, encrypted: bool) -> u8 {
+        let ks = filter(self.odd);
+        let mut feed = ks & encrypted as u32;
+        feed ^= (input != 0) as u32;
+        feed ^= LF_POLY_ODD & self.odd;
+        feed ^= LF_POLY_EVEN & self.even;
+        self.even = (self.even << 1) | parity32(feed);
+        std::mem::swap(&mut self.odd, &mut self.even);
+        ks as u8
+    }
+
+    pub fn byte(&mut self, input: u8, encrypted: bool) -> u8 {
+        (0..8).fold(0, |ks, i| ks | self.bit((input >> i) & 1, encrypted) << i)
+    }
+
+    // Keystream for a word sent most significant byte first
+    pub fn word(&mut self, input: u32, encrypted: bool) -> u32 {
+        (0..32).fold(0, |ks, i| ks | (self.bit(be_bit(input, i) as u8, encrypted) as u32) << (i ^ 24))
+    }
+
+    // Undo one clock, given the bit that was fed in
+    pub fn rollback_bit(&mut self, input: u8, encrypted: bool) -> u8 {
+        self.odd &= 0xFFFFFF;
+        std::mem::swap(&mut self.odd, &mut self.even);
+
+        let mut out = self.even & 1;
+        self.even >>= 1;
+        out ^= LF_POLY_EVEN & self.even;
+        out ^= LF_POLY_ODD & self.odd;
+        out ^= (input != 0) as u32;
+        let ks = filter(self.odd);
+        out ^= ks & encrypted as u32;
+
+        self.even |= parity32(out) << 23;
+        ks as u8
+    }
+
+    pub fn rollback_word(&mut self, input: u32, encrypted: bool) -> u32 {
+        (0..32).rev().fold(0, |ks, i| ks | (self.rollback_bit(be_bit(input, i) as u8, encrypted) as u32) << (i ^ 24))
+    }
+
+    // Keystream applied to `data`, feeding the plaintext when `feed` is set,
+    // and the encrypted parity bit of every byte
+    fn crypt(&mut self, data: &[u8], feed: bool, encrypted: bool) -> (Vec<u8>, Vec<u8>) {
+        let mut output = Vec::with_capacity(data.len());
+        let mut parity = Vec::with_capacity(data.len());
+        for &byte in data {
+            let input = if feed { byte } else { 0 };
+            let out = byte ^ self.byte(input, feed && encrypted);
+            let plain = if encrypted { out } else { byte };
+            output.push(out);
+            parity.push(odd_parity(plain) ^ self.peek());
+        }
+        (output, parity)
+    }
+
+    // Encrypt a frame, returning its bytes and parity bits
+    pub fn encrypt(&mut self, plain: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        self.crypt(plain, false, false)
+    }
+
+    // Decrypt a frame, checking its parity bits
+    pub fn decrypt(&mut self, data: &[u8], parity: &[u8]) -> Result<Vec<u8>, MifareError> {
+        let (plain, expected) = self.crypt(data, false, true);
+        check_parity(data, parity, &expected)?;
+        Ok(plain)
+    }
+
+    // Encrypt or decrypt the 4 bits of an ACK or NAK
+    pub fn nibble(&mut self, data: u8) -> u8 {
+        (0..4).fold(data & 0x0F, |out, i| out ^ self.bit(0, false) << i)
+    }
+}
+
+fn check_parity(data: &[u8], parity: &[u8], expected: &[u8]) -> Result<(), MifareError> {
+    if parity.len() != data.len() {
+        return Err(MifareError::new("One parity bit per byte expected"));
+    }
+    if parity.iter().zip(expected).any(|(&got, &want)| got & 1 != want) {
+        return Err(MifareError::new("Parity error"));
+    }
+    Ok(())
+}
+
+// Plaintext parity bits of a frame
+pub fn plain_parity(data: &[u8]) -> Vec<u8> {
+    data.iter().map(|&byte| odd_parity(byte)).collect()
+}
+
+// Reader side of the three-pass authentication: the card's nonce nt, then
+// {nr}{ar} from the reader, then {at} from the card, where ar and at are nt
+// 64 and 96 PRNG steps on
+pub struct ReaderAuth {
+    cipher: Crypto1,
+    nt: u32,
+}
+
+impl ReaderAuth {
+    // First authentication, with nt received in the clear
+    pub fn new(key: &[u8; 6], uid: u32, nt: u32) -> ReaderAuth {
+        let mut cipher = Crypto1::from_key(key);
+        cipher.word(uid ^ nt, false);
+        ReaderAuth { cipher, nt }
+    }
+
+    // Nested authentication, where the card sends nt encrypted with the
+    // new key
+    pub fn nested(key: &[u8; 6], uid: u32, encrypted_nt: u32) -> ReaderAuth {
+        let mut cipher = Crypto1::from_key(key);
+        let nt = encrypted_nt ^ cipher.word(uid ^ encrypted_nt, true);
+        ReaderAuth { cipher, nt }
+    }
+
+    pub fn nt(&self) -> u32 {
+        self.nt
+    }
+
+    // {nr}{ar} and their parity bits, for the reader nonce `nr`
+    pub fn respond(&mut self, nr: u32) -> ([u8; 8], [u8; 8]) {
+        let (nr_enc, nr_parity) = self.cipher.crypt(&nr.to_be_bytes(), true, false);
+        let (ar_enc, ar_parity) = self.cipher.encrypt(&prng_successor(self.nt, 64).to_be_bytes());
+
+        let mut data = [0u8; 8];
+        let mut parity = [0u8; 8];
+        data[0..4].copy_from_slice(&nr_enc);
+        data[4..8].copy_from_slice(&ar_enc);
+        parity[0..4].copy_from_slice(&nr_parity);
+        parity[4..8].copy_from_slice(&ar_parity);
+        (data, parity)
+    }
+
+    // Check the card's {at} and return the cipher for the session
+    pub fn verify(mut self, at: &[u8], parity: &[u8]) -> Result<Crypto1, MifareError> {
+        let at = self.cipher.decrypt(at, parity)?;
+        if at != prng_successor(self.nt, 96).to_be_bytes() {
+            return Err(MifareError::new("Card did not prove knowledge of the key (at mismatch)"));
+        }
+        Ok(self.cipher)
+    }
+}
+
+// Card side of the three-pass authentication
+pub struct CardAuth {
+    cipher: Crypto1,
+    nt: u32,
+}
+
+impl CardAuth {
+    // First authentication, nt sent in the clear
+    pub fn new(key: &[u8; 6], uid: u32, nt: u32) -> CardAuth {
+        let mut cipher = Crypto1::from_key(key);
+        cipher.word(uid ^ nt, false);
+        CardAuth { cipher, nt }
+    }
+
+    // Nested authentication: returns the state with {nt} and its parity bits
+    pub fn nested(key: &[u8; 6], uid: u32, nt: u32) -> (CardAuth, [u8; 4], [u8; 4]) {
+        let mut cipher = Crypto1::from_key(key);
+        let mut data = [0u8; 4];
+        let mut parity = [0u8; 4];
+        for (i, &byte) in nt.to_be_bytes().iter().enumerate() {
+            let uid_byte = uid.to_be_bytes()[i];
+            data[i] = byte ^ cipher.byte(uid_byte ^ byte, false);
+            parity[i] = odd_parity(byte) ^ cipher.peek();
+        }
+        (CardAuth { cipher, nt }, data, parity)
+    }
+
+    // Check the reader's {nr}{ar} and answer {at}; returns the cipher for
+    // the session, {at} and its parity bits
+    pub fn respond(mut self, data: &[u8], parity: &[u8]) -> Result<(Crypto1, [u8; 4], [u8; 4]), MifareError> {
+        if data.len() != 8 || parity.len() != 8 {
+            return Err(MifareError::new("Authentication answer must be 8 bytes"));
+        }
+
+        let (_, nr_parity) = self.cipher.crypt(&data[0..4], true, true);
+        check_parity(&data[0..4], &parity[0..4], &nr_parity)?;
+        let ar = self.cipher.decrypt(&data[4..8], &parity[4..8])?;
+        if ar != prng_successor(self.nt, 64).to_be_bytes() {
+            return Err(MifareError::new("Reader did not prove knowledge of the key (ar mismatch)"));
+        }
+
+        let (at, at_parity) = self.cipher.encrypt(&prng_successor(self.nt, 96).to_be_bytes());
+        let mut answer = [0u8; 4];
+        let mut answer_parity = [0u8; 4];
+        answer.copy_from_slice(&at);
+        answer_parity.copy_from_slice(&at_parity);
+        Ok((self.cipher, answer, answer_parity))
+    }
+}
@@ -0,0 +1,618 @@
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::fmt;
+use pcsc::Card;
+use crate::desfire_crypto::{Messaging, Session};
+use crate::error::MifareError;
+use crate::format_hex;
+use crate::transport::ApduTransceive;
+
+// MIFARE DESFire native commands, sent wrapped in ISO 7816-4 APDUs:
+// 90 <command> 00 00 [Lc <data>] 00, answered with <data> 91 <status>.
+// Status AF means more data follows, fetched with the AF command.
+
+pub const GET_VERSION: u8 = 0x60;
+pub const GET_APPLICATION_IDS: u8 = 0x6A;
+pub const SELECT_APPLICATION: u8 = 0x5A;
+pub const GET_FILE_IDS: u8 = 0x6F;
+pub const GET_FILE_SETTINGS: u8 = 0xF5;
+pub const GET_KEY_SETTINGS: u8 = 0x45;
+pub const FREE_MEMORY: u8 = 0x6E;
+pub const ADDITIONAL_FRAME: u8 = 0xAF;
+
+// Most data sent in one frame; longer commands go on in additional frames
+pub const MAX_FRAME_DATA: usize = 52;
+
+// Status bytes (SW2 after 91)
+pub const OPERATION_OK: u8 = 0x00;
+pub const NO_CHANGES: u8 = 0x0C;
+pub const OUT_OF_EEPROM: u8 = 0x0E;
+pub const ILLEGAL_COMMAND: u8 = 0x1C;
+pub const INTEGRITY_ERROR: u8 = 0x1E;
+pub const NO_SUCH_KEY: u8 = 0x40;
+pub const LENGTH_ERROR: u8 = 0x7E;
+pub const PERMISSION_DENIED: u8 = 0x9D;
+pub const PARAMETER_ERROR: u8 = 0x9E;
+pub const APPLICATION_NOT_FOUND: u8 = 0xA0;
+pub const AUTHENTICATION_ERROR: u8 = 0xAE;
+pub const BOUNDARY_ERROR: u8 = 0xBE;
+pub const COMMAND_ABORTED: u8 = 0xCA;
+pub const DUPLICATE_ERROR: u8 = 0xDE;
+pub const FILE_NOT_FOUND: u8 = 0xF0;
+
+// Application 000000 is the card (PICC) level
+pub const PICC_AID: u32 = 0x000000;
+
+// Meaning of a status byte
+pub fn status_name(status: u8) -> &'static str {
+    match status {
+        OPERATION_OK => "OPERATION_OK",
+        NO_CHANGES => "NO_CHANGES",
+        OUT_OF_EEPROM => "OUT_OF_EEPROM_ERROR",
+        ILLEGAL_COMMAND => "ILLEGAL_COMMAND_CODE",
+        INTEGRITY_ERROR => "INTEGRITY_ERROR",
+        NO_SUCH_KEY => "NO_SUCH_KEY",
+        LENGTH_ERROR => "LENGTH_ERROR",
+        PERMISSION_DENIED => "PERMISSION_DENIED",
+        PARAMETER_ERROR => "PARAMETER_ERROR",
+        APPLICATION_NOT_FOUND => "APPLICATION_NOT_FOUND",
+        0xA1 => "APPL_INTEGRITY_ERROR",
+        AUTHENTICATION_ERROR => "AUTHENTICATION_ERROR",
+        ADDITIONAL_FRAME => "ADDITIONAL_FRAME",
+        BOUNDARY_ERROR => "BOUNDARY_ERROR",
+        0xC1 => "PICC_INTEGRITY_ERROR",
+        COMMAND_ABORTED => "COMMAND_ABORTED",
+        0xCD => "PICC_DISABLED_ERROR",
+        0xCE => "COUNT_ERROR",
+        DUPLICATE_ERROR => "DUPLICATE_ERROR",
+        0xEE => "EEPROM_ERROR",
+        FILE_NOT_FOUND => "FILE_NOT_FOUND",
+        0xF1 => "FILE_INTEGRITY_ERROR",
+        _ => "unknown status",
+    }
+}
+
+// ISO 7816-4 wrapping of a native command
+pub fn wrap(command: u8, data: &[u8]) -> Vec<u8> {
+    let mut apdu = vec![0x90, command, 0x00, 0x00];
+    if !data.is_empty() {
+        apdu.push(data.len() as u8);
+        apdu.extend_from_slice(data);
+    }
+    apdu.push(0x00);
+    apdu
+}
+
+// Application IDs are 3 bytes, least significant first
+pub fn aid_bytes(aid: u32) -> [u8; 3] {
+    let bytes = aid.to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+pub(crate) fn le24(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0])
+}
+
+pub(crate) fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// Hardware or software half of the GetVersion answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionPart {
+    pub vendor: u8,
+    pub card_type: u8,
+    pub subtype: u8,
+    pub major: u8,
+    pub minor: u8,
+    pub storage_size: u8,
+    pub protocol: u8,
+}
+
+impl VersionPart {
+    fn decode(bytes: &[u8]) -> VersionPart {
+        VersionPart {
+            vendor: bytes[0],
+            card_type: bytes[1],
+            subtype: bytes[2],
+            major: bytes[3],
+            minor: bytes[4],
+            storage_size: bytes[5],
+            protocol: bytes[6],
+        }
+    }
+
+    // Storage in bytes: 2^(n/2), or somewhere above that when n is odd
+    pub fn storage_bytes(&self) -> usize {
+        1 << (self.storage_size >> 1)
+    }
+
+    pub fn storage_is_exact(&self) -> bool {
+        self.storage_size & 1 == 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesfireVersion {
+    pub hardware: VersionPart,
+    pub software: VersionPart,
+    pub uid: [u8; 7],
+    pub batch: [u8; 5],
+    // Calendar week and year (two digits) of production, BCD coded
+    pub production_week: u8,
+    pub production_year: u8,
+}
+
+impl DesfireVersion {
+    // The three frames of GetVersion, concatenated (28 bytes)
+    pub fn decode(bytes: &[u8]) -> Result<DesfireVersion, MifareError> {
+        if bytes.len() < 28 {
+            return Err(MifareError::new(&format!("GetVersion answer too short: {} bytes", bytes.len())));
+        }
+
+        let mut uid = [0u8; 7];
+        uid.copy_from_slice(&bytes[14..21]);
+        let mut batch = [0u8; 5];
+        batch.copy_from_slice(&bytes[21..26]);
+        Ok(DesfireVersion {
+            hardware: VersionPart::decode(&bytes[0..7]),
+            software: VersionPart::decode(&bytes[7..14]),
+            uid,
+            batch,
+            production_week: bytes[26],
+            production_year: bytes[27],
+        })
+    }
+}
+
+impl fmt::Display for DesfireVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let storage = if self.hardware.storage_is_exact() { "" } else { "more than " };
+        writeln!(f, "Hardware: vendor {:02X}, type {:02X}.{:02X}, version {}.{}, {}{} bytes",
+                 self.hardware.vendor, self.hardware.card_type, self.hardware.subtype,
+                 self.hardware.major, self.hardware.minor, storage, self.hardware.storage_bytes())?;
+        writeln!(f, "Software: vendor {:02X}, type {:02X}.{:02X}, version {}.{}",
+                 self.software.vendor, self.software.card_type, self.software.subtype,
+                 self.software.major, self.software.minor)?;
+        writeln!(f, "UID: {}", format_hex(&self.uid))?;
+        write!(f, "Batch {}, produced week {:02X} of 20{:02X}", format_hex(&self.batch), self.production_week, self.production_year)
+    }
+}
+
+// Cipher of an application's keys, from the high bits of the key count byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherType {
+    // DES and 2-key 3DES
+    TripleDes,
+    TripleDes3K,
+    Aes,
+}
+
+impl CipherType {
+    pub fn from_bits(bits: u8) -> Option<CipherType> {
+        match bits >> 6 {
+            0 => Some(CipherType::TripleDes),
+            1 => Some(CipherType::TripleDes3K),
+            2 => Some(CipherType::Aes),
+            _ => None,
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        match self {
+            CipherType::TripleDes => 0x00,
+            CipherType::TripleDes3K => 0x40,
+            CipherType::Aes => 0x80,
+        }
+    }
+
+    pub fn to_string(&self) -> &str {
+        match self {
+            CipherType::TripleDes => "DES/2K3DES",
+            CipherType::TripleDes3K => "3K3DES",
+            CipherType::Aes => "AES",
+        }
+    }
+}
+
+// Answer to GetKeySettings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeySettings {
+    // Bit 0: master key changeable; 1: listing without master key; 2: create
+    // and delete without master key; 3: settings changeable; bits 4-7: key
+    // needed to change the other keys (E: the key itself, F: none)
+    pub settings: u8,
+    pub key_count: u8,
+    pub cipher: CipherType,
+}
+
+impl KeySettings {
+    pub fn decode(bytes: &[u8]) -> Result<KeySettings, MifareError> {
+        if bytes.len() != 2 {
+            return Err(MifareError::new("Key settings must be 2 bytes"));
+        }
+        let cipher = CipherType::from_bits(bytes[1])
+            .ok_or_else(|| MifareError::new(&format!("Unknown key type bits in {:02X}", bytes[1])))?;
+        Ok(KeySettings { settings: bytes[0], key_count: bytes[1] & 0x0F, cipher })
+    }
+
+    pub fn encode(&self) -> [u8; 2] {
+        [self.settings, self.key_count | self.cipher.bits()]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    StandardData,
+    BackupData,
+    Value,
+    LinearRecord,
+    CyclicRecord,
+    TransactionMac,
+}
+
+impl FileType {
+    pub fn from_byte(byte: u8) -> Option<FileType> {
+        match byte {
+            0x00 => Some(FileType::StandardData),
+            0x01 => Some(FileType::BackupData),
+            0x02 => Some(FileType::Value),
+            0x03 => Some(FileType::LinearRecord),
+            0x04 => Some(FileType::CyclicRecord),
+            0x05 => Some(FileType::TransactionMac),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            FileType::StandardData => 0x00,
+            FileType::BackupData => 0x01,
+            FileType::Value => 0x02,
+            FileType::LinearRecord => 0x03,
+            FileType::CyclicRecord => 0x04,
+            FileType::TransactionMac => 0x05,
+        }
+    }
+
+    pub fn to_string(&self) -> &str {
+        match self {
+            FileType::StandardData => "Standard data",
+            FileType::BackupData => "Backup data",
+            FileType::Value => "Value",
+            FileType::LinearRecord => "Linear record",
+            FileType::CyclicRecord => "Cyclic record",
+            FileType::TransactionMac => "Transaction MAC",
+        }
+    }
+}
+
+// How a file's data travels once authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommunicationMode {
+    Plain,
+    Maced,
+    Full,
+}
+
+impl CommunicationMode {
+    pub fn from_byte(byte: u8) -> CommunicationMode {
+        match byte & 0x03 {
+            0x01 => CommunicationMode::Maced,
+            0x03 => CommunicationMode::Full,
+            _ => CommunicationMode::Plain,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            CommunicationMode::Plain => 0x00,
+            CommunicationMode::Maced => 0x01,
+            CommunicationMode::Full => 0x03,
+        }
+    }
+
+    pub fn to_string(&self) -> &str {
+        match self {
+            CommunicationMode::Plain => "plain",
+            CommunicationMode::Maced => "MACed",
+            CommunicationMode::Full => "fully enciphered",
+        }
+    }
+}
+
+// Key number needed for each kind of access; E means free access, F never
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessRights {
+    pub read: u8,
+    pub write: u8,
+    pub read_write: u8,
+    pub change: u8,
+}
+
+pub const FREE_ACCESS: u8 = 0x0E;
+pub const NO_ACCESS: u8 = 0x0F;
+
+impl AccessRights {
+    pub fn decode(bytes: &[u8]) -> AccessRights {
+        AccessRights {
+            read: bytes[1] >> 4,
+            write: bytes[1] & 0x0F,
+            read_write: bytes[0] >> 4,
+            change: bytes[0] & 0x0F,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; 2] {
+        [(self.read_write << 4) | (self.change & 0x0F), (self.read << 4) | (self.write & 0x0F)]
+    }
+}
+
+fn key_name(key: u8) -> String {
+    match key {
+        FREE_ACCESS => "free".to_string(),
+        NO_ACCESS => "never".to_string(),
+        key => format!("key {}", key),
+    }
+}
+
+impl fmt::Display for AccessRights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "read {}, write {}, read/write {}, change {}",
+               key_name(self.read), key_name(self.write), key_name(self.read_write), key_name(self.change))
+    }
+}
+
+// Type-specific part of the file settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileLayout {
+    Data { size: u32 },
+    Value { lower_limit: i32, upper_limit: i32, limited_credit_value: i32, limited_credit_enabled: bool },
+    Record { record_size: u32, max_records: u32, current_records: u32 },
+}
+
+// Answer to GetFileSettings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSettings {
+    pub file_type: FileType,
+    pub communication: CommunicationMode,
+    pub access: AccessRights,
+    pub layout: FileLayout,
+}
+
+impl FileSettings {
+    pub fn decode(bytes: &[u8]) -> Result<FileSettings, MifareError> {
+        if bytes.len() < 7 {
+            return Err(MifareError::new("File settings too short"));
+        }
+        let file_type = FileType::from_byte(bytes[0])
+            .ok_or_else(|| MifareError::new(&format!("Unknown file type {:02X}", bytes[0])))?;
+        let truncated = || MifareError::new(&format!("{} file settings too short", file_type.to_string()));
+
+        let layout = match file_type {
+            FileType::Value => {
+                if bytes.len() < 17 {
+                    return Err(truncated());
+                }
+                FileLayout::Value {
+                    lower_limit: le32(&bytes[4..8]) as i32,
+                    upper_limit: le32(&bytes[8..12]) as i32,
+                    limited_credit_value: le32(&bytes[12..16]) as i32,
+                    limited_credit_enabled: bytes[16] & 0x01 != 0,
+                }
+            },
+            FileType::LinearRecord | FileType::CyclicRecord => {
+                if bytes.len() < 13 {
+                    return Err(truncated());
+                }
+                FileLayout::Record {
+                    record_size: le24(&bytes[4..7]),
+                    max_records: le24(&bytes[7..10]),
+                    current_records: le24(&bytes[10..13]),
+                }
+            },
+            _ => FileLayout::Data { size: le24(&bytes[4..7]) },
+        };
+
+        Ok(FileSettings {
+            file_type,
+            communication: CommunicationMode::from_byte(bytes[1]),
+            access: AccessRights::decode(&bytes[2..4]),
+            layout,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![self.file_type.to_byte(), self.communication.to_byte()];
+        bytes.extend_from_slice(&self.access.encode());
+        match self.layout {
+            FileLayout::Data { size } => bytes.extend_from_slice(&size.to_le_bytes()[..3]),
+            FileLayout::Value { lower_limit, upper_limit, limited_credit_value, limited_credit_enabled } => {
+                bytes.extend_from_slice(&lower_limit.to_le_bytes());
+                bytes.extend_from_slice(&upper_limit.to_le_bytes());
+                bytes.extend_from_slice(&limited_credit_value.to_le_bytes());
+                bytes.push(limited_credit_enabled as u8);
+            },
+            FileLayout::Record { record_size, max_records, current_records } => {
+                bytes.extend_from_slice(&record_size.to_le_bytes()[..3]);
+                bytes.extend_from_slice(&max_records.to_le_bytes()[..3]);
+                bytes.extend_from_slice(&current_records.to_le_bytes()[..3]);
+            },
+        }
+        bytes
+    }
+}
+
+impl fmt::Display for FileSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} file, {}, {}", self.file_type.to_string(), self.communication.to_string(), self.access)?;
+        match self.layout {
+            FileLayout::Data { size } => write!(f, ", {} bytes", size),
+            FileLayout::Value { lower_limit, upper_limit, limited_credit_value, limited_credit_enabled } => {
+                write!(f, ", limits {} to {}", lower_limit, upper_limit)?;
+                if limited_credit_enabled {
+                    write!(f, ", limited credit {}", limited_credit_value)?;
+                }
+                Ok(())
+            },
+            FileLayout::Record { record_size, max_records, current_records } => {
+                write!(f, ", {} of {} records of {} bytes", current_records, max_records, record_size)
+            },
+        }
+    }
+}
+
+// Structure to represent a MIFARE DESFire card
+pub struct Desfire<'a> {
+    link: Box<dyn ApduTransceive + 'a>,
+    // Secure messaging state after an authentication
+    pub(crate) session: RefCell<Option<Session>>,
+    // Application last selected; the card starts at its own level
+    pub(crate) selected: Cell<u32>,
+}
+
+impl<'a> Desfire<'a> {
+    // Create a handler for the card the reader activated
+    pub fn new(card: &'a Card) -> Self {
+        Desfire::with_transceiver(card)
+    }
+
+    // Create a handler on any transport, e.g. a simulated card
+    pub fn with_transceiver<T: ApduTransceive + 'a>(link: T) -> Self {
+        Desfire { link: Box::new(link), session: RefCell::new(None), selected: Cell::new(PICC_AID) }
+    }
+
+    pub(crate) fn failure(command: u8, status: u8) -> MifareError {
+        MifareError::with_status(&format!("Command {:02X} failed: {}", command, status_name(status)), 0x91, status)
+    }
+
+    // Send one frame and split the answer into data and status
+    pub(crate) fn exchange(&self, command: u8, data: &[u8]) -> Result<(Vec<u8>, u8), Box<dyn Error>> {
+        let mut response = self.link.transmit(&wrap(command, data))?;
+        if response.len() < 2 {
+            return Err(Box::new(MifareError::new("DESFire answer without status")));
+        }
+        let status = response.split_off(response.len() - 2);
+        if status[0] != 0x91 {
+            return Err(Box::new(MifareError::with_status("Not a DESFire answer", status[0], status[1])));
+        }
+        Ok((response, status[1]))
+    }
+
+    // Send a command across as many frames as it needs and collect the
+    // answer across additional frames
+    fn transfer(&self, command: u8, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut chunks = data.chunks(MAX_FRAME_DATA);
+        let (mut answer, mut status) = self.exchange(command, chunks.next().unwrap_or_default())?;
+        for chunk in chunks {
+            if status != ADDITIONAL_FRAME {
+                break;
+            }
+            (answer, status) = self.exchange(ADDITIONAL_FRAME, chunk)?;
+        }
+        while status == ADDITIONAL_FRAME {
+            let (more, next) = self.exchange(ADDITIONAL_FRAME, &[])?;
+            answer.extend_from_slice(&more);
+            status = next;
+        }
+
+        if status != OPERATION_OK {
+            return Err(Box::new(Desfire::failure(command, status)));
+        }
+        Ok(answer)
+    }
+
+    // Send a command under the session, if any: `header` goes as it is,
+    // `data` protected as `send` says, and the answer is checked as
+    // `receive` says. Any failure ends the session, as it does on the card.
+    pub fn command_secure(&self, command: u8, header: &[u8], data: &[u8], send: CommunicationMode, receive: CommunicationMode)
+        -> Result<Vec<u8>, Box<dyn Error>> {
+        let body = match self.session.borrow_mut().as_mut() {
+            Some(session) => session.wrap_command(command, header, data, send),
+            None => [header, data].concat(),
+        };
+        self.send_wrapped(command, &body, receive)
+    }
+
+    // Send a command already protected under the session and check its
+    // answer as `receive` says
+    pub(crate) fn send_wrapped(&self, command: u8, body: &[u8], receive: CommunicationMode) -> Result<Vec<u8>, Box<dyn Error>> {
+        let result = self.transfer(command, body).and_then(|answer| match self.session.borrow_mut().as_mut() {
+            Some(session) => Ok(session.unwrap_response(&answer, receive)?),
+            None => Ok(answer),
+        });
+        if result.is_err() {
+            self.clear_session();
+        }
+        result
+    }
+
+    // Send a native command and collect its answer across additional
+    // frames. After an authentication it runs in the mode the card expects
+    // for commands without file settings: MACed under EV2, plain otherwise.
+    pub fn command(&self, command: u8, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mode = self.management_mode();
+        self.command_secure(command, &[], data, mode, mode)
+    }
+
+    pub(crate) fn management_mode(&self) -> CommunicationMode {
+        match self.session.borrow().as_ref().map(Session::messaging) {
+            Some(Messaging::Ev2) => CommunicationMode::Maced,
+            _ => CommunicationMode::Plain,
+        }
+    }
+
+    // GetVersion's three frames, undecoded
+    pub fn get_version_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.command(GET_VERSION, &[])
+    }
+
+    pub fn get_version(&self) -> Result<DesfireVersion, Box<dyn Error>> {
+        Ok(DesfireVersion::decode(&self.get_version_bytes()?)?)
+    }
+
+    // Applications on the card; needs the PICC level selected
+    pub fn get_application_ids(&self) -> Result<Vec<u32>, Box<dyn Error>> {
+        let answer = self.command(GET_APPLICATION_IDS, &[])?;
+        if answer.len() % 3 != 0 {
+            return Err(Box::new(MifareError::new("Application IDs must be 3 bytes each")));
+        }
+        Ok(answer.chunks(3).map(le24).collect())
+    }
+
+    // Select an application, or the card itself with PICC_AID
+    pub fn select_application(&self, aid: u32) -> Result<(), Box<dyn Error>> {
+        // Selecting ends the session, and is never protected
+        self.clear_session();
+        self.command(SELECT_APPLICATION, &aid_bytes(aid))?;
+        self.selected.set(aid);
+        Ok(())
+    }
+
+    pub fn selected_application(&self) -> u32 {
+        self.selected.get()
+    }
+
+    // Files of the selected application
+    pub fn get_file_ids(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.command(GET_FILE_IDS, &[])
+    }
+
+    pub fn get_file_settings(&self, file: u8) -> Result<FileSettings, Box<dyn Error>> {
+        Ok(FileSettings::decode(&self.command(GET_FILE_SETTINGS, &[file])?)?)
+    }
+
+    // Key settings of the selected application (or of the card)
+    pub fn get_key_settings(&self) -> Result<KeySettings, Box<dyn Error>> {
+        Ok(KeySettings::decode(&self.command(GET_KEY_SETTINGS, &[])?)?)
+    }
+
+    // Free memory of the card in bytes (EV1 and later)
+    pub fn free_memory(&self) -> Result<u32, Box<dyn Error>> {
+        let answer = self.command(FREE_MEMORY, &[])?;
+        if answer.len() != 3 {
+            return Err(Box::new(MifareError::new("FreeMemory answer must be 3 bytes")));
+        }
+        Ok(le24(&answer))
+    }
+}
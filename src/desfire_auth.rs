@@ -0,0 +1,446 @@
+use std::error::Error;
+use crate::crc::crc_a;
+use crate::desfire::{CipherType, CommunicationMode, Desfire, ADDITIONAL_FRAME, OPERATION_OK, PICC_AID};
+use crate::desfire_crypto::{cbc_receive, cbc_send, crc32, ev2_session_keys, session_key, CipherOp, DesfireKey, Messaging, Session};
+use crate::error::MifareError;
+
+// DESFire mutual authentication. The reader names a key, the card answers
+// AF ek(RndB), the reader sends AF ek(RndA || RndB') and the card closes
+// with ek(RndA'), where ' is a left rotation by one byte. The commands
+// differ in how the blocks are chained and which secure messaging follows.
+
+pub const AUTHENTICATE_LEGACY: u8 = 0x0A;
+pub const AUTHENTICATE_ISO: u8 = 0x1A;
+pub const AUTHENTICATE_AES: u8 = 0xAA;
+pub const AUTHENTICATE_EV2_FIRST: u8 = 0x71;
+pub const AUTHENTICATE_EV2_NON_FIRST: u8 = 0x77;
+
+// UID of a card with random ID enabled; needs authentication
+pub const GET_CARD_UID: u8 = 0x51;
+
+pub const CHANGE_KEY: u8 = 0xC4;
+pub const CHANGE_KEY_SETTINGS: u8 = 0x54;
+
+// Key settings bits
+pub const SETTING_MASTER_KEY_CHANGEABLE: u8 = 0x01;
+pub const SETTING_FREE_LISTING: u8 = 0x02;
+pub const SETTING_FREE_CREATE_DELETE: u8 = 0x04;
+pub const SETTING_CONFIGURATION_CHANGEABLE: u8 = 0x08;
+// High nibble: the key needed to change the others; E for each key
+// itself, F for none
+pub const CHANGE_KEY_SAME: u8 = 0x0E;
+pub const CHANGE_KEY_FROZEN: u8 = 0x0F;
+
+// PDcap2 and PCDcap2 closing AuthenticateEV2First
+const CAPABILITIES_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMode {
+    // DES and 2K3DES as the original DESFire does it
+    Legacy,
+    // DES, 2K3DES and 3K3DES with EV1 secure messaging
+    Iso,
+    Aes,
+    // AES with EV2 secure messaging, opening a transaction
+    Ev2First,
+    // AES within the transaction of a previous EV2 authentication
+    Ev2NonFirst,
+}
+
+impl AuthMode {
+    pub fn from_command(command: u8) -> Option<AuthMode> {
+        match command {
+            AUTHENTICATE_LEGACY => Some(AuthMode::Legacy),
+            AUTHENTICATE_ISO => Some(AuthMode::Iso),
+            AUTHENTICATE_AES => Some(AuthMode::Aes),
+            AUTHENTICATE_EV2_FIRST => Some(AuthMode::Ev2First),
+            AUTHENTICATE_EV2_NON_FIRST => Some(AuthMode::Ev2NonFirst),
+            _ => None,
+        }
+    }
+
+    pub fn command(&self) -> u8 {
+        match self {
+            AuthMode::Legacy => AUTHENTICATE_LEGACY,
+            AuthMode::Iso => AUTHENTICATE_ISO,
+            AuthMode::Aes => AUTHENTICATE_AES,
+            AuthMode::Ev2First => AUTHENTICATE_EV2_FIRST,
+            AuthMode::Ev2NonFirst => AUTHENTICATE_EV2_NON_FIRST,
+        }
+    }
+
+    pub fn to_string(&self) -> &str {
+        match self {
+            AuthMode::Legacy => "AuthenticateLegacy",
+            AuthMode::Iso => "AuthenticateISO",
+            AuthMode::Aes => "AuthenticateAES",
+            AuthMode::Ev2First => "AuthenticateEV2First",
+            AuthMode::Ev2NonFirst => "AuthenticateEV2NonFirst",
+        }
+    }
+
+    pub fn messaging(&self) -> Messaging {
+        match self {
+            AuthMode::Legacy => Messaging::Legacy,
+            AuthMode::Iso | AuthMode::Aes => Messaging::Ev1,
+            AuthMode::Ev2First | AuthMode::Ev2NonFirst => Messaging::Ev2,
+        }
+    }
+
+    // Whether a key of this type can be used
+    pub fn accepts(&self, cipher: CipherType) -> bool {
+        match self {
+            AuthMode::Legacy => cipher == CipherType::TripleDes,
+            AuthMode::Iso => cipher != CipherType::Aes,
+            _ => cipher == CipherType::Aes,
+        }
+    }
+
+    // Data of the first command frame
+    pub fn request(&self, key_no: u8) -> Vec<u8> {
+        match self {
+            // No PCDcap2 sent
+            AuthMode::Ev2First => vec![key_no, 0x00],
+            _ => vec![key_no],
+        }
+    }
+
+    // Length of RndA and RndB for a key
+    pub fn random_size(&self, key: &DesfireKey) -> usize {
+        match self {
+            AuthMode::Legacy => 8,
+            _ => key.random_size(),
+        }
+    }
+
+    // ISO and AES chain the IV through the whole exchange; legacy and EV2
+    // start every step from zero
+    fn step_iv<'b>(&self, iv: &'b mut [u8]) -> &'b mut [u8] {
+        if !matches!(self, AuthMode::Iso | AuthMode::Aes) {
+            iv.fill(0x00);
+        }
+        iv
+    }
+
+    // Only the legacy reader deciphers what it sends
+    fn send_op(&self) -> CipherOp {
+        if *self == AuthMode::Legacy { CipherOp::Decipher } else { CipherOp::Encipher }
+    }
+
+    fn session(&self, key_no: u8, key: &DesfireKey, rnd_a: &[u8], rnd_b: &[u8], ti: [u8; 4], cmd_ctr: u16) -> Session {
+        match self {
+            AuthMode::Ev2First | AuthMode::Ev2NonFirst => {
+                let (enc_key, mac_key) = ev2_session_keys(key, rnd_a, rnd_b);
+                Session::ev2(key_no, enc_key, mac_key, ti, cmd_ctr)
+            },
+            _ => Session::new(self.messaging(), key_no, session_key(key, rnd_a, rnd_b)),
+        }
+    }
+}
+
+fn rotate(rnd: &[u8]) -> Vec<u8> {
+    let mut rotated = rnd.to_vec();
+    rotated.rotate_left(1);
+    rotated
+}
+
+fn check_mode(mode: AuthMode, key: &DesfireKey, random: &[u8]) -> Result<(), MifareError> {
+    if !mode.accepts(key.cipher()) {
+        return Err(MifareError::new(&format!("{} cannot use a {} key", mode.to_string(), key.cipher().to_string())));
+    }
+    if random.len() != mode.random_size(key) {
+        return Err(MifareError::new(&format!("{} needs {}-byte random numbers", mode.to_string(), mode.random_size(key))));
+    }
+    Ok(())
+}
+
+// Transaction of a previous EV2 authentication, carried over by NonFirst
+fn transaction(mode: AuthMode, previous: Option<&Session>) -> Result<([u8; 4], u16), MifareError> {
+    match (mode, previous) {
+        (AuthMode::Ev2NonFirst, Some(session)) if session.messaging() == Messaging::Ev2 => Ok((session.ti(), session.cmd_ctr())),
+        (AuthMode::Ev2NonFirst, _) => Err(MifareError::new("AuthenticateEV2NonFirst needs an EV2 session")),
+        _ => Ok(([0x00; 4], 0)),
+    }
+}
+
+// Reader side of the authentication, kept apart from the card I/O so each
+// step can be checked against known vectors
+pub struct Handshake {
+    mode: AuthMode,
+    key_no: u8,
+    key: DesfireKey,
+    rnd_a: Vec<u8>,
+    rnd_b: Vec<u8>,
+    iv: Vec<u8>,
+    previous: Option<([u8; 4], u16)>,
+}
+
+impl Handshake {
+    pub fn new(mode: AuthMode, key_no: u8, key: &DesfireKey, rnd_a: &[u8]) -> Result<Handshake, MifareError> {
+        check_mode(mode, key, rnd_a)?;
+        Ok(Handshake {
+            mode, key_no, key: key.clone(), rnd_a: rnd_a.to_vec(), rnd_b: Vec::new(),
+            iv: vec![0x00; key.block_size()], previous: None,
+        })
+    }
+
+    // AuthenticateEV2NonFirst keeps the TI and command counter of the
+    // session it follows
+    pub fn continuing(mut self, previous: &Session) -> Result<Handshake, MifareError> {
+        self.previous = Some(transaction(self.mode, Some(previous))?);
+        Ok(self)
+    }
+
+    // Decipher ek(RndB) from the card's first answer and build ek(RndA || RndB')
+    pub fn respond(&mut self, ek_rnd_b: &[u8]) -> Result<Vec<u8>, MifareError> {
+        if ek_rnd_b.len() != self.rnd_a.len() {
+            return Err(MifareError::new(&format!("Expected a {}-byte challenge, got {} bytes", self.rnd_a.len(), ek_rnd_b.len())));
+        }
+        self.rnd_b = cbc_receive(&self.key, self.mode.step_iv(&mut self.iv), ek_rnd_b, CipherOp::Decipher);
+        let plain = [&self.rnd_a[..], &rotate(&self.rnd_b)].concat();
+        Ok(cbc_send(&self.key, self.mode.step_iv(&mut self.iv), &plain, self.mode.send_op()))
+    }
+
+    // Check that the card's final answer proves it knows the key, and open
+    // the session
+    pub fn verify(&mut self, answer: &[u8]) -> Result<Session, MifareError> {
+        let expected_len = match self.mode {
+            AuthMode::Ev2First => 4 + self.rnd_a.len() + CAPABILITIES_LEN,
+            _ => self.rnd_a.len(),
+        };
+        if answer.len() != expected_len {
+            return Err(MifareError::new(&format!("Expected a {}-byte final answer, got {} bytes", expected_len, answer.len())));
+        }
+        let plain = cbc_receive(&self.key, self.mode.step_iv(&mut self.iv), answer, CipherOp::Decipher);
+
+        let (ti, cmd_ctr, rnd_a) = match self.mode {
+            AuthMode::Ev2First => (plain[0..4].try_into().unwrap(), 0, &plain[4..4 + self.rnd_a.len()]),
+            AuthMode::Ev2NonFirst => {
+                let (ti, cmd_ctr) = self.previous.ok_or_else(|| MifareError::new("AuthenticateEV2NonFirst needs an EV2 session"))?;
+                (ti, cmd_ctr, &plain[..])
+            },
+            _ => ([0x00; 4], 0, &plain[..]),
+        };
+        if rnd_a != rotate(&self.rnd_a) {
+            return Err(MifareError::new("Card did not prove knowledge of the key (RndA' mismatch)"));
+        }
+        Ok(self.mode.session(self.key_no, &self.key, &self.rnd_a, &self.rnd_b, ti, cmd_ctr))
+    }
+}
+
+// Card side of the authentication, as the simulator plays it
+pub struct CardHandshake {
+    mode: AuthMode,
+    key_no: u8,
+    key: DesfireKey,
+    rnd_b: Vec<u8>,
+    iv: Vec<u8>,
+    ti: [u8; 4],
+    cmd_ctr: u16,
+}
+
+impl CardHandshake {
+    // `ti` is the transaction identifier AuthenticateEV2First hands out;
+    // NonFirst takes it from `previous` instead
+    pub fn new(mode: AuthMode, key_no: u8, key: &DesfireKey, rnd_b: &[u8], ti: [u8; 4], previous: Option<&Session>)
+        -> Result<CardHandshake, MifareError> {
+        check_mode(mode, key, rnd_b)?;
+        let (ti, cmd_ctr) = match mode {
+            AuthMode::Ev2First => (ti, 0),
+            _ => transaction(mode, previous)?,
+        };
+        Ok(CardHandshake { mode, key_no, key: key.clone(), rnd_b: rnd_b.to_vec(), iv: vec![0x00; key.block_size()], ti, cmd_ctr })
+    }
+
+    // ek(RndB), the answer to the authentication command
+    pub fn challenge(&mut self) -> Vec<u8> {
+        cbc_send(&self.key, self.mode.step_iv(&mut self.iv), &self.rnd_b, CipherOp::Encipher)
+    }
+
+    // Check ek(RndA || RndB') and return the final answer with the session
+    pub fn answer(&mut self, token: &[u8]) -> Result<(Vec<u8>, Session), MifareError> {
+        if token.len() != 2 * self.rnd_b.len() {
+            return Err(MifareError::new("Invalid authentication token length"));
+        }
+        let receive_op = if self.mode == AuthMode::Legacy { CipherOp::Encipher } else { CipherOp::Decipher };
+        let plain = cbc_receive(&self.key, self.mode.step_iv(&mut self.iv), token, receive_op);
+        let (rnd_a, rnd_b) = plain.split_at(self.rnd_b.len());
+        if rnd_b != rotate(&self.rnd_b) {
+            return Err(MifareError::new("Reader did not prove knowledge of the key (RndB' mismatch)"));
+        }
+
+        let mut answer = Vec::new();
+        if self.mode == AuthMode::Ev2First {
+            answer.extend_from_slice(&self.ti);
+        }
+        answer.extend_from_slice(&rotate(rnd_a));
+        if self.mode == AuthMode::Ev2First {
+            answer.extend_from_slice(&[0x00; CAPABILITIES_LEN]);
+        }
+        let answer = cbc_send(&self.key, self.mode.step_iv(&mut self.iv), &answer, CipherOp::Encipher);
+        Ok((answer, self.mode.session(self.key_no, &self.key, rnd_a, &self.rnd_b, self.ti, self.cmd_ctr)))
+    }
+}
+
+// AES keys, and every key under EV2, are followed by their version; DES
+// keys hold it in their parity bits
+fn carries_version(messaging: Messaging, cipher: CipherType) -> bool {
+    cipher == CipherType::Aes || messaging == Messaging::Ev2
+}
+
+fn xor_key(key: &mut [u8], other: &DesfireKey) -> Result<(), MifareError> {
+    if key.len() != other.bytes().len() {
+        return Err(MifareError::new("Old and new key must have the same length"));
+    }
+    key.iter_mut().zip(other.bytes()).for_each(|(byte, old)| *byte ^= old);
+    Ok(())
+}
+
+// Checksums closing a ChangeKey cryptogram: one over the key data (under
+// EV1 also over the command and key number), and one over the new key
+// alone when another key than the session's is changed
+fn change_key_checksums(messaging: Messaging, key_byte: u8, data: &[u8], new_key: &DesfireKey, other_key: bool) -> Vec<u8> {
+    let mut checksums = match messaging {
+        Messaging::Legacy => crc_a(data).to_vec(),
+        Messaging::Ev1 => crc32(&[&[CHANGE_KEY, key_byte][..], data].concat()).to_vec(),
+        Messaging::Ev2 => Vec::new(),
+    };
+    if other_key {
+        match messaging {
+            Messaging::Legacy => checksums.extend_from_slice(&crc_a(new_key.bytes())),
+            _ => checksums.extend_from_slice(&crc32(new_key.bytes())),
+        }
+    }
+    checksums
+}
+
+// Plaintext of a ChangeKey cryptogram. Another key than the session's goes
+// XORed with its current value `old_key`.
+pub fn change_key_cryptogram(messaging: Messaging, key_byte: u8, new_key: &DesfireKey, old_key: Option<&DesfireKey>, version: u8)
+    -> Result<Vec<u8>, MifareError> {
+    let mut data = new_key.bytes().to_vec();
+    if let Some(old_key) = old_key {
+        xor_key(&mut data, old_key)?;
+    }
+    if carries_version(messaging, new_key.cipher()) {
+        data.push(version);
+    }
+    let checksums = change_key_checksums(messaging, key_byte, &data, new_key, old_key.is_some());
+    data.extend_from_slice(&checksums);
+    Ok(data)
+}
+
+// Card side: the new key from a deciphered ChangeKey cryptogram
+pub fn read_change_key(messaging: Messaging, key_byte: u8, plain: &[u8], cipher: CipherType, old_key: Option<&DesfireKey>)
+    -> Result<DesfireKey, MifareError> {
+    let key_len = DesfireKey::zero(cipher).bytes().len();
+    let data_len = key_len + carries_version(messaging, cipher) as usize;
+    if plain.len() < data_len {
+        return Err(MifareError::new("ChangeKey cryptogram too short"));
+    }
+
+    let mut bytes = plain[..key_len].to_vec();
+    if let Some(old_key) = old_key {
+        xor_key(&mut bytes, old_key)?;
+    }
+    let new_key = DesfireKey::new(cipher, &bytes)?;
+    let checksums = change_key_checksums(messaging, key_byte, &plain[..data_len], &new_key, old_key.is_some());
+    let rest = &plain[data_len..];
+    if !rest.starts_with(&checksums) || rest[checksums.len()..].iter().any(|&byte| byte != 0x00) {
+        return Err(MifareError::new("ChangeKey checksum does not match"));
+    }
+    Ok(new_key)
+}
+
+impl<'a> Desfire<'a> {
+    // Authenticate with key `key_no` of the selected application; later
+    // commands run under the session until another application is selected
+    // or a command fails
+    pub fn authenticate(&self, mode: AuthMode, key_no: u8, key: &DesfireKey) -> Result<(), Box<dyn Error>> {
+        let mut rnd_a = vec![0u8; mode.random_size(key)];
+        getrandom::getrandom(&mut rnd_a)?;
+        self.authenticate_with_random(mode, key_no, key, &rnd_a)
+    }
+
+    // Same with a chosen RndA, for reproducible exchanges
+    pub fn authenticate_with_random(&self, mode: AuthMode, key_no: u8, key: &DesfireKey, rnd_a: &[u8]) -> Result<(), Box<dyn Error>> {
+        let previous = self.session.borrow_mut().take();
+        let mut handshake = Handshake::new(mode, key_no, key, rnd_a)?;
+        if mode == AuthMode::Ev2NonFirst {
+            let previous = previous.ok_or_else(|| MifareError::new("AuthenticateEV2NonFirst needs an EV2 session"))?;
+            handshake = handshake.continuing(&previous)?;
+        }
+
+        let (challenge, status) = self.exchange(mode.command(), &mode.request(key_no))?;
+        if status != ADDITIONAL_FRAME {
+            return Err(Box::new(Desfire::failure(mode.command(), status)));
+        }
+        let token = handshake.respond(&challenge)?;
+        let (answer, status) = self.exchange(ADDITIONAL_FRAME, &token)?;
+        if status != OPERATION_OK {
+            return Err(Box::new(Desfire::failure(mode.command(), status)));
+        }
+        *self.session.borrow_mut() = Some(handshake.verify(&answer)?);
+        Ok(())
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.session.borrow().is_some()
+    }
+
+    // Key number of the current session
+    pub fn authenticated_key(&self) -> Option<u8> {
+        self.session.borrow().as_ref().map(Session::key_no)
+    }
+
+    // Drop the session, as the card does on its own after an error
+    pub fn clear_session(&self) {
+        *self.session.borrow_mut() = None;
+    }
+
+    // Change key `key_no` of the selected application, or the card master
+    // key at the card level. Changing another key than the session's needs
+    // its current value; changing the session's own key ends the session.
+    pub fn change_key(&self, key_no: u8, new_key: &DesfireKey, old_key: Option<&DesfireKey>, version: u8) -> Result<(), Box<dyn Error>> {
+        let session_key_no = self.authenticated_key().ok_or_else(|| MifareError::new("ChangeKey needs authentication"))?;
+        let own_key = session_key_no == key_no;
+        let old_key = match (own_key, old_key) {
+            (true, _) => None,
+            (false, Some(old_key)) => Some(old_key),
+            (false, None) => return Err(Box::new(MifareError::new(&format!("Changing key {} needs its current value", key_no)))),
+        };
+        // The card master key can change type; its number says which
+        let key_byte = if self.selected_application() == PICC_AID { key_no | new_key.cipher().bits() } else { key_no };
+
+        let body = {
+            let mut session = self.session.borrow_mut();
+            let session = session.as_mut().ok_or_else(|| MifareError::new("ChangeKey needs authentication"))?;
+            let plain = change_key_cryptogram(session.messaging(), key_byte, new_key, old_key, version)?;
+            session.wrap_enciphered(CHANGE_KEY, &[key_byte], &plain)
+        };
+        if own_key {
+            self.clear_session();
+        }
+        self.send_wrapped(CHANGE_KEY, &body, CommunicationMode::Maced)?;
+        Ok(())
+    }
+
+    // Change the key settings of the selected application (or the card);
+    // needs its master key
+    pub fn change_key_settings(&self, settings: u8) -> Result<(), Box<dyn Error>> {
+        if self.authenticated_key() != Some(0) {
+            return Err(Box::new(MifareError::new("ChangeKeySettings needs the master key")));
+        }
+        self.command_secure(CHANGE_KEY_SETTINGS, &[], &[settings], CommunicationMode::Full, CommunicationMode::Maced)?;
+        Ok(())
+    }
+
+    // Real UID of the card, sent enciphered; needs authentication
+    pub fn get_card_uid(&self) -> Result<[u8; 7], Box<dyn Error>> {
+        if !self.is_authenticated() {
+            return Err(Box::new(MifareError::new("GetCardUID needs authentication")));
+        }
+        let uid = self.command_secure(GET_CARD_UID, &[], &[], CommunicationMode::Full, CommunicationMode::Full)?;
+        Ok(uid[..].try_into().map_err(|_| MifareError::new("GetCardUID answer must be 7 bytes"))?)
+    }
+}
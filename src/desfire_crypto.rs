@@ -0,0 +1,633 @@
+use aes::Aes128;
+use des::cipher::generic_array::GenericArray;
+use des::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use des::{TdesEde2, TdesEde3};
+use crate::crc::crc_a;
+use crate::desfire::{CipherType, CommunicationMode, OPERATION_OK};
+use crate::error::MifareError;
+
+// Cryptography of MIFARE DESFire: the keys, CBC as the card chains it, CMAC,
+// the CRCs, session keys, and the secure messaging of the three schemes
+// (legacy DES, EV1 ISO/AES, EV2), reader and card side alike.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesfireKey {
+    cipher: CipherType,
+    // DES keys are kept as 2K3DES with both halves equal
+    bytes: Vec<u8>,
+}
+
+impl DesfireKey {
+    pub fn des(key: &[u8; 8]) -> DesfireKey {
+        DesfireKey { cipher: CipherType::TripleDes, bytes: [&key[..], &key[..]].concat() }
+    }
+
+    pub fn tdes(key: &[u8; 16]) -> DesfireKey {
+        DesfireKey { cipher: CipherType::TripleDes, bytes: key.to_vec() }
+    }
+
+    pub fn tdes3k(key: &[u8; 24]) -> DesfireKey {
+        DesfireKey { cipher: CipherType::TripleDes3K, bytes: key.to_vec() }
+    }
+
+    pub fn aes(key: &[u8; 16]) -> DesfireKey {
+        DesfireKey { cipher: CipherType::Aes, bytes: key.to_vec() }
+    }
+
+    // Key of the given type from its bytes; DES/2K3DES takes 8 or 16
+    pub fn new(cipher: CipherType, bytes: &[u8]) -> Result<DesfireKey, MifareError> {
+        match (cipher, bytes.len()) {
+            (CipherType::TripleDes, 8) => Ok(DesfireKey::des(bytes.try_into().unwrap())),
+            (CipherType::TripleDes, 16) | (CipherType::TripleDes3K, 24) | (CipherType::Aes, 16) => {
+                Ok(DesfireKey { cipher, bytes: bytes.to_vec() })
+            },
+            _ => Err(MifareError::new(&format!("A {} key cannot be {} bytes", cipher.to_string(), bytes.len()))),
+        }
+    }
+
+    // All-zero key of a card in its factory state
+    pub fn zero(cipher: CipherType) -> DesfireKey {
+        let len = match cipher {
+            CipherType::TripleDes | CipherType::Aes => 16,
+            CipherType::TripleDes3K => 24,
+        };
+        DesfireKey { cipher, bytes: vec![0x00; len] }
+    }
+
+    pub fn cipher(&self) -> CipherType {
+        self.cipher
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // Single DES: a 2K3DES key whose halves are equal, ignoring the parity
+    // bits, which DESFire uses for the key version
+    pub fn is_des(&self) -> bool {
+        self.cipher == CipherType::TripleDes
+            && self.bytes[0..8].iter().zip(&self.bytes[8..16]).all(|(a, b)| a & 0xFE == b & 0xFE)
+    }
+
+    pub fn block_size(&self) -> usize {
+        match self.cipher {
+            CipherType::Aes => 16,
+            _ => 8,
+        }
+    }
+
+    // Length of RndA and RndB in the ISO and AES authentications
+    pub fn random_size(&self) -> usize {
+        match self.cipher {
+            CipherType::TripleDes => 8,
+            _ => 16,
+        }
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8]) {
+        match self.cipher {
+            CipherType::TripleDes => TdesEde2::new(GenericArray::from_slice(&self.bytes)).encrypt_block(GenericArray::from_mut_slice(block)),
+            CipherType::TripleDes3K => TdesEde3::new(GenericArray::from_slice(&self.bytes)).encrypt_block(GenericArray::from_mut_slice(block)),
+            CipherType::Aes => Aes128::new(GenericArray::from_slice(&self.bytes)).encrypt_block(GenericArray::from_mut_slice(block)),
+        }
+    }
+
+    pub fn decrypt_block(&self, block: &mut [u8]) {
+        match self.cipher {
+            CipherType::TripleDes => TdesEde2::new(GenericArray::from_slice(&self.bytes)).decrypt_block(GenericArray::from_mut_slice(block)),
+            CipherType::TripleDes3K => TdesEde3::new(GenericArray::from_slice(&self.bytes)).decrypt_block(GenericArray::from_mut_slice(block)),
+            CipherType::Aes => Aes128::new(GenericArray::from_slice(&self.bytes)).decrypt_block(GenericArray::from_mut_slice(block)),
+        }
+    }
+}
+
+// Block operation used by the chaining below. Legacy DESFire has the reader
+// decipher what it sends, so the card only ever enciphers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherOp {
+    Encipher,
+    Decipher,
+}
+
+fn run_block(key: &DesfireKey, op: CipherOp, block: &mut [u8]) {
+    match op {
+        CipherOp::Encipher => key.encrypt_block(block),
+        CipherOp::Decipher => key.decrypt_block(block),
+    }
+}
+
+// Chaining for data going out: each block XORed with the IV, then run
+// through the cipher, and the result becomes the IV (CBC encryption when
+// enciphering). `data` is a multiple of the block size.
+pub fn cbc_send(key: &DesfireKey, iv: &mut [u8], data: &[u8], op: CipherOp) -> Vec<u8> {
+    let mut output = data.to_vec();
+    for block in output.chunks_mut(key.block_size()) {
+        block.iter_mut().zip(iv.iter()).for_each(|(byte, chain)| *byte ^= chain);
+        run_block(key, op, block);
+        iv.copy_from_slice(block);
+    }
+    output
+}
+
+// Chaining for data coming in: each block run through the cipher, then
+// XORed with the IV, and the incoming block becomes the IV (CBC decryption
+// when deciphering)
+pub fn cbc_receive(key: &DesfireKey, iv: &mut [u8], data: &[u8], op: CipherOp) -> Vec<u8> {
+    let mut output = data.to_vec();
+    for block in output.chunks_mut(key.block_size()) {
+        let incoming = block.to_vec();
+        run_block(key, op, block);
+        block.iter_mut().zip(iv.iter()).for_each(|(byte, chain)| *byte ^= chain);
+        iv.copy_from_slice(&incoming);
+    }
+    output
+}
+
+// Left shift of a block by one bit, for the CMAC subkeys
+fn shift_left(block: &[u8]) -> Vec<u8> {
+    let mut shifted = vec![0u8; block.len()];
+    for i in 0..block.len() {
+        shifted[i] = block[i] << 1 | block.get(i + 1).map_or(0, |next| next >> 7);
+    }
+    shifted
+}
+
+// CMAC subkeys K1 and K2 (NIST SP 800-38B)
+pub fn cmac_subkeys(key: &DesfireKey) -> (Vec<u8>, Vec<u8>) {
+    let rb = if key.block_size() == 16 { 0x87 } else { 0x1B };
+    let derive = |block: &[u8]| {
+        let mut next = shift_left(block);
+        if block[0] & 0x80 != 0 {
+            *next.last_mut().unwrap() ^= rb;
+        }
+        next
+    };
+
+    let mut l = vec![0u8; key.block_size()];
+    key.encrypt_block(&mut l);
+    let k1 = derive(&l);
+    let k2 = derive(&k1);
+    (k1, k2)
+}
+
+// CMAC of `data` chained from `iv`, which is left holding the full CMAC, as
+// EV1 secure messaging does; start from a zero IV for the standard CMAC
+pub fn cmac(key: &DesfireKey, iv: &mut [u8], data: &[u8]) -> Vec<u8> {
+    let size = key.block_size();
+    let (k1, k2) = cmac_subkeys(key);
+    let mut padded = data.to_vec();
+    let subkey = if !data.is_empty() && data.len().is_multiple_of(size) {
+        k1
+    } else {
+        padded.push(0x80);
+        padded.resize(padded.len().div_ceil(size) * size, 0x00);
+        k2
+    };
+    let last = padded.len() - size;
+    padded[last..].iter_mut().zip(&subkey).for_each(|(byte, k)| *byte ^= k);
+
+    let output = cbc_send(key, iv, &padded, CipherOp::Encipher);
+    output[last..].to_vec()
+}
+
+// CRC32 of EV1 secure messaging: the IEEE 802.3 CRC without the final
+// inversion, sent least significant byte first
+pub fn crc32(data: &[u8]) -> [u8; 4] {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    crc.to_le_bytes()
+}
+
+fn pad_zero(data: &mut Vec<u8>, size: usize) {
+    data.resize(data.len().div_ceil(size) * size, 0x00);
+}
+
+// ISO/IEC 9797-1 padding method 2, always at least one byte
+fn pad_iso(data: &mut Vec<u8>, size: usize) {
+    data.push(0x80);
+    pad_zero(data, size);
+}
+
+fn unpad_iso(mut data: Vec<u8>) -> Result<Vec<u8>, MifareError> {
+    while data.last() == Some(&0x00) {
+        data.pop();
+    }
+    if data.pop() != Some(0x80) {
+        return Err(MifareError::new("Invalid padding in enciphered data"));
+    }
+    Ok(data)
+}
+
+// Length of the data in a deciphered frame: the data, its CRC (also over
+// `prefix` and `suffix`), then less than a block of zero padding. Shorter
+// lengths are tried first, as the CRC of the data and the first CRC bytes
+// is the rest of the CRC followed by zeros, which looks like padding.
+fn find_crc(plain: &[u8], size: usize, crc_len: usize, crc: impl Fn(&[u8]) -> Vec<u8>, prefix: &[u8], suffix: &[u8])
+    -> Result<usize, MifareError> {
+    let last = plain.len().checked_sub(crc_len).ok_or_else(|| MifareError::new("Enciphered data too short for its CRC"))?;
+    for end in last.saturating_sub(size - 1)..=last {
+        if plain[end + crc_len..].iter().any(|&byte| byte != 0x00) {
+            continue;
+        }
+        let covered = [prefix, &plain[..end], suffix].concat();
+        if crc(&covered) == plain[end..end + crc_len] {
+            return Ok(end);
+        }
+    }
+    Err(MifareError::new("CRC of enciphered data does not match"))
+}
+
+// First `len` bytes of rnd_a and rnd_b interleaved four by four from the
+// given offsets, the layout of the legacy and EV1 session keys
+fn interleave(rnd_a: &[u8], rnd_b: &[u8], offsets: &[usize]) -> Vec<u8> {
+    offsets.iter().flat_map(|&offset| [&rnd_a[offset..offset + 4], &rnd_b[offset..offset + 4]].concat()).collect()
+}
+
+// Session key of the legacy and EV1 authentications
+pub fn session_key(key: &DesfireKey, rnd_a: &[u8], rnd_b: &[u8]) -> DesfireKey {
+    match key.cipher {
+        CipherType::TripleDes if key.is_des() => {
+            let half = interleave(rnd_a, rnd_b, &[0]);
+            DesfireKey::des(half[..].try_into().unwrap())
+        },
+        CipherType::TripleDes => DesfireKey { cipher: key.cipher, bytes: interleave(rnd_a, rnd_b, &[0, 4]) },
+        CipherType::TripleDes3K => DesfireKey { cipher: key.cipher, bytes: interleave(rnd_a, rnd_b, &[0, 6, 12]) },
+        CipherType::Aes => DesfireKey { cipher: key.cipher, bytes: interleave(rnd_a, rnd_b, &[0, 12]) },
+    }
+}
+
+// Session vector SV1 (label A5 5A) or SV2 (5A A5) of AuthenticateEV2First
+fn ev2_session_vector(label: [u8; 2], rnd_a: &[u8], rnd_b: &[u8]) -> Vec<u8> {
+    let mut sv = vec![label[0], label[1], 0x00, 0x01, 0x00, 0x80];
+    sv.extend_from_slice(&rnd_a[0..2]);
+    sv.extend(rnd_a[2..8].iter().zip(&rnd_b[0..6]).map(|(a, b)| a ^ b));
+    sv.extend_from_slice(&rnd_b[6..16]);
+    sv.extend_from_slice(&rnd_a[8..16]);
+    sv
+}
+
+// KSesAuthENC and KSesAuthMAC of an EV2 authentication
+pub fn ev2_session_keys(key: &DesfireKey, rnd_a: &[u8], rnd_b: &[u8]) -> (DesfireKey, DesfireKey) {
+    let derive = |label| {
+        let bytes = cmac(key, &mut [0u8; 16], &ev2_session_vector(label, rnd_a, rnd_b));
+        DesfireKey { cipher: CipherType::Aes, bytes }
+    };
+    (derive([0xA5, 0x5A]), derive([0x5A, 0xA5]))
+}
+
+// EV2 MACs keep the odd bytes of the CMAC
+fn truncate_mac(mac: &[u8]) -> Vec<u8> {
+    mac.iter().skip(1).step_by(2).copied().collect()
+}
+
+// Which secure messaging follows an authentication
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Messaging {
+    // AuthenticateLegacy (0A): 4-byte DES MAC, CRC16, IV reset per frame
+    Legacy,
+    // AuthenticateISO (1A) and AuthenticateAES (AA): CMAC and CRC32 with an
+    // IV carried from one command to the next
+    Ev1,
+    // AuthenticateEV2First/NonFirst (71/77): truncated CMAC over a command
+    // counter and transaction identifier
+    Ev2,
+}
+
+// Keys and state shared by reader and card after an authentication
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    messaging: Messaging,
+    key_no: u8,
+    enc_key: DesfireKey,
+    mac_key: DesfireKey,
+    // EV1: IV carried across commands
+    iv: Vec<u8>,
+    // EV2: transaction identifier and command counter
+    ti: [u8; 4],
+    cmd_ctr: u16,
+}
+
+impl Session {
+    // Session of a legacy or EV1 authentication
+    pub fn new(messaging: Messaging, key_no: u8, session_key: DesfireKey) -> Session {
+        let iv = vec![0u8; session_key.block_size()];
+        Session { messaging, key_no, mac_key: session_key.clone(), enc_key: session_key, iv, ti: [0; 4], cmd_ctr: 0 }
+    }
+
+    pub fn ev2(key_no: u8, enc_key: DesfireKey, mac_key: DesfireKey, ti: [u8; 4], cmd_ctr: u16) -> Session {
+        Session { messaging: Messaging::Ev2, key_no, enc_key, mac_key, iv: vec![0u8; 16], ti, cmd_ctr }
+    }
+
+    pub fn messaging(&self) -> Messaging {
+        self.messaging
+    }
+
+    // Key number the session was opened with
+    pub fn key_no(&self) -> u8 {
+        self.key_no
+    }
+
+    pub fn enc_key(&self) -> &DesfireKey {
+        &self.enc_key
+    }
+
+    pub fn mac_key(&self) -> &DesfireKey {
+        &self.mac_key
+    }
+
+    pub fn ti(&self) -> [u8; 4] {
+        self.ti
+    }
+
+    pub fn cmd_ctr(&self) -> u16 {
+        self.cmd_ctr
+    }
+
+    fn size(&self) -> usize {
+        self.enc_key.block_size()
+    }
+
+    // Legacy MAC: first 4 bytes of the last block of a DES CBC encryption
+    fn legacy_mac(&self, data: &[u8]) -> Vec<u8> {
+        let mut padded = data.to_vec();
+        pad_zero(&mut padded, self.size());
+        let output = cbc_send(&self.enc_key, &mut vec![0u8; self.size()], &padded, CipherOp::Encipher);
+        output[output.len() - self.size()..][..4].to_vec()
+    }
+
+    fn ev1_cmac(&mut self, data: &[u8]) -> Vec<u8> {
+        let mac = cmac(&self.mac_key, &mut self.iv, data);
+        mac[..8].to_vec()
+    }
+
+    // EV2 MACt over a label byte (command or status), the counter, TI and data
+    fn ev2_mac(&self, first: u8, cmd_ctr: u16, data: &[u8]) -> Vec<u8> {
+        let input = [&[first][..], &cmd_ctr.to_le_bytes(), &self.ti, data].concat();
+        truncate_mac(&cmac(&self.mac_key, &mut [0u8; 16], &input))
+    }
+
+    // EV2 IV for command (A5 5A) or response (5A A5) encryption
+    fn ev2_iv(&self, label: [u8; 2], cmd_ctr: u16) -> Vec<u8> {
+        let mut iv = [&label[..], &self.ti, &cmd_ctr.to_le_bytes(), &[0u8; 8]].concat();
+        self.enc_key.encrypt_block(&mut iv);
+        iv
+    }
+
+    fn check_mac(expected: &[u8], got: &[u8]) -> Result<(), MifareError> {
+        if expected != got {
+            return Err(MifareError::new("MAC mismatch"));
+        }
+        Ok(())
+    }
+
+    // Split `len` trailing MAC bytes off a frame
+    fn split_mac(body: &[u8], len: usize) -> Result<(&[u8], &[u8]), MifareError> {
+        let at = body.len().checked_sub(len).ok_or_else(|| MifareError::new("Frame too short for its MAC"))?;
+        Ok(body.split_at(at))
+    }
+
+    // Reader side: the bytes following the command byte, for `header` sent
+    // as it is and `data` protected as `mode` says
+    pub fn wrap_command(&mut self, command: u8, header: &[u8], data: &[u8], mode: CommunicationMode) -> Vec<u8> {
+        let size = self.size();
+        let plain = [header, data].concat();
+        let full = [&[command][..], &plain].concat();
+        match (self.messaging, mode) {
+            (Messaging::Legacy, CommunicationMode::Plain) => plain,
+            (Messaging::Legacy, _) if data.is_empty() => plain,
+            (Messaging::Legacy, CommunicationMode::Maced) => [&plain[..], &self.legacy_mac(data)].concat(),
+            (Messaging::Legacy, CommunicationMode::Full) => {
+                let mut enc = [data, &crc_a(data)].concat();
+                pad_zero(&mut enc, size);
+                let enc = cbc_send(&self.enc_key, &mut vec![0u8; size], &enc, CipherOp::Decipher);
+                [header, &enc].concat()
+            },
+
+            (Messaging::Ev1, CommunicationMode::Full) if !data.is_empty() => {
+                let mut enc = [data, &crc32(&full)].concat();
+                pad_zero(&mut enc, size);
+                let enc = cbc_send(&self.enc_key, &mut self.iv, &enc, CipherOp::Encipher);
+                [header, &enc].concat()
+            },
+            (Messaging::Ev1, _) => {
+                let mac = self.ev1_cmac(&full);
+                if mode == CommunicationMode::Maced && !data.is_empty() {
+                    [&plain[..], &mac].concat()
+                } else {
+                    plain
+                }
+            },
+
+            (Messaging::Ev2, CommunicationMode::Plain) => plain,
+            (Messaging::Ev2, CommunicationMode::Full) if !data.is_empty() => {
+                let mut enc = data.to_vec();
+                pad_iso(&mut enc, size);
+                let enc = cbc_send(&self.enc_key, &mut self.ev2_iv([0xA5, 0x5A], self.cmd_ctr), &enc, CipherOp::Encipher);
+                let body = [header, &enc].concat();
+                let mac = self.ev2_mac(command, self.cmd_ctr, &body);
+                [&body[..], &mac].concat()
+            },
+            (Messaging::Ev2, _) => {
+                let mac = self.ev2_mac(command, self.cmd_ctr, &plain);
+                [&plain[..], &mac].concat()
+            },
+        }
+    }
+
+    // Length `data_len` bytes of command data take once protected as `mode`
+    // says, which tells the card how many additional frames to expect
+    pub fn wrapped_len(&self, data_len: usize, mode: CommunicationMode) -> usize {
+        let padded = |len: usize| len.div_ceil(self.size()) * self.size();
+        match (self.messaging, mode) {
+            (Messaging::Legacy | Messaging::Ev1, CommunicationMode::Plain) => data_len,
+            (Messaging::Legacy | Messaging::Ev1, _) if data_len == 0 => 0,
+            (Messaging::Legacy, CommunicationMode::Maced) => data_len + 4,
+            (Messaging::Legacy, CommunicationMode::Full) => padded(data_len + 2),
+            (Messaging::Ev1, CommunicationMode::Maced) => data_len + 8,
+            (Messaging::Ev1, CommunicationMode::Full) => padded(data_len + 4),
+            (Messaging::Ev2, CommunicationMode::Plain) => data_len,
+            (Messaging::Ev2, CommunicationMode::Full) if data_len > 0 => padded(data_len + 1) + 8,
+            (Messaging::Ev2, _) => data_len + 8,
+        }
+    }
+
+    // Reader side: `plain` enciphered as it is, for ChangeKey, whose
+    // checksums the caller lays out itself. EV2 has no checksum to lay out
+    // and treats it as any fully enciphered command.
+    pub fn wrap_enciphered(&mut self, command: u8, header: &[u8], plain: &[u8]) -> Vec<u8> {
+        let size = self.size();
+        let mut padded = plain.to_vec();
+        pad_zero(&mut padded, size);
+        match self.messaging {
+            Messaging::Legacy => [header, &cbc_send(&self.enc_key, &mut vec![0u8; size], &padded, CipherOp::Decipher)].concat(),
+            Messaging::Ev1 => [header, &cbc_send(&self.enc_key, &mut self.iv, &padded, CipherOp::Encipher)].concat(),
+            Messaging::Ev2 => self.wrap_command(command, header, plain, CommunicationMode::Full),
+        }
+    }
+
+    // Reader side: the data of a successful answer, checked and deciphered.
+    // EV1 cards only encipher answers that carry data; pass Maced for the
+    // others.
+    pub fn unwrap_response(&mut self, response: &[u8], mode: CommunicationMode) -> Result<Vec<u8>, MifareError> {
+        let size = self.size();
+        let status = OPERATION_OK;
+        match (self.messaging, mode) {
+            (Messaging::Legacy, CommunicationMode::Plain) => Ok(response.to_vec()),
+            (Messaging::Legacy, _) if response.is_empty() => Ok(Vec::new()),
+            (Messaging::Legacy, CommunicationMode::Maced) => {
+                let (data, mac) = Session::split_mac(response, 4)?;
+                Session::check_mac(&self.legacy_mac(data), mac)?;
+                Ok(data.to_vec())
+            },
+            (Messaging::Legacy, CommunicationMode::Full) => {
+                let plain = cbc_receive(&self.enc_key, &mut vec![0u8; size], response, CipherOp::Decipher);
+                let end = find_crc(&plain, size, 2, |covered| crc_a(covered).to_vec(), &[], &[])?;
+                Ok(plain[..end].to_vec())
+            },
+
+            (Messaging::Ev1, CommunicationMode::Full) => {
+                let plain = cbc_receive(&self.enc_key, &mut self.iv, response, CipherOp::Decipher);
+                let end = find_crc(&plain, size, 4, |covered| crc32(covered).to_vec(), &[], &[status])?;
+                Ok(plain[..end].to_vec())
+            },
+            (Messaging::Ev1, _) => {
+                let (data, mac) = Session::split_mac(response, 8)?;
+                let expected = self.ev1_cmac(&[data, &[status]].concat());
+                Session::check_mac(&expected, mac)?;
+                Ok(data.to_vec())
+            },
+
+            (Messaging::Ev2, CommunicationMode::Plain) => {
+                self.cmd_ctr = self.cmd_ctr.wrapping_add(1);
+                Ok(response.to_vec())
+            },
+            (Messaging::Ev2, _) => {
+                self.cmd_ctr = self.cmd_ctr.wrapping_add(1);
+                let (data, mac) = Session::split_mac(response, 8)?;
+                Session::check_mac(&self.ev2_mac(status, self.cmd_ctr, data), mac)?;
+                if mode == CommunicationMode::Maced || data.is_empty() {
+                    return Ok(data.to_vec());
+                }
+                let plain = cbc_receive(&self.enc_key, &mut self.ev2_iv([0x5A, 0xA5], self.cmd_ctr), data, CipherOp::Decipher);
+                unpad_iso(plain)
+            },
+        }
+    }
+
+    // Card side: header and data of a command, checked and deciphered, where
+    // `body` follows the command byte and starts with `header_len` bytes sent
+    // as they are
+    pub fn unwrap_command(&mut self, command: u8, header_len: usize, body: &[u8], mode: CommunicationMode) -> Result<Vec<u8>, MifareError> {
+        let size = self.size();
+        if body.len() < header_len {
+            return Err(MifareError::new("Command shorter than its header"));
+        }
+        let (header, rest) = body.split_at(header_len);
+        match (self.messaging, mode) {
+            (Messaging::Legacy, CommunicationMode::Plain) => Ok(body.to_vec()),
+            (Messaging::Legacy, _) if rest.is_empty() => Ok(body.to_vec()),
+            (Messaging::Legacy, CommunicationMode::Maced) => {
+                let (data, mac) = Session::split_mac(rest, 4)?;
+                Session::check_mac(&self.legacy_mac(data), mac)?;
+                Ok([header, data].concat())
+            },
+            (Messaging::Legacy, CommunicationMode::Full) => {
+                let plain = cbc_receive(&self.enc_key, &mut vec![0u8; size], rest, CipherOp::Encipher);
+                let end = find_crc(&plain, size, 2, |covered| crc_a(covered).to_vec(), &[], &[])?;
+                Ok([header, &plain[..end]].concat())
+            },
+
+            (Messaging::Ev1, CommunicationMode::Full) if !rest.is_empty() => {
+                let plain = cbc_receive(&self.enc_key, &mut self.iv, rest, CipherOp::Decipher);
+                let prefix = [&[command][..], header].concat();
+                let end = find_crc(&plain, size, 4, |covered| crc32(covered).to_vec(), &prefix, &[])?;
+                Ok([header, &plain[..end]].concat())
+            },
+            (Messaging::Ev1, CommunicationMode::Maced) if !rest.is_empty() => {
+                let (data, mac) = Session::split_mac(rest, 8)?;
+                let expected = self.ev1_cmac(&[&[command][..], header, data].concat());
+                Session::check_mac(&expected, mac)?;
+                Ok([header, data].concat())
+            },
+            (Messaging::Ev1, _) => {
+                self.ev1_cmac(&[&[command][..], body].concat());
+                Ok(body.to_vec())
+            },
+
+            (Messaging::Ev2, CommunicationMode::Plain) => Ok(body.to_vec()),
+            (Messaging::Ev2, _) => {
+                let (protected, mac) = Session::split_mac(body, 8)?;
+                Session::check_mac(&self.ev2_mac(command, self.cmd_ctr, protected), mac)?;
+                let data = &protected[header_len..];
+                if mode == CommunicationMode::Maced || data.is_empty() {
+                    return Ok(protected.to_vec());
+                }
+                let plain = cbc_receive(&self.enc_key, &mut self.ev2_iv([0xA5, 0x5A], self.cmd_ctr), data, CipherOp::Decipher);
+                Ok([header, &unpad_iso(plain)?].concat())
+            },
+        }
+    }
+
+    // Card side: header and deciphered data of a command sent with
+    // wrap_enciphered; legacy and EV1 data keeps its zero padding
+    pub fn unwrap_enciphered(&mut self, command: u8, header_len: usize, body: &[u8]) -> Result<Vec<u8>, MifareError> {
+        let size = self.size();
+        if body.len() < header_len || (self.messaging != Messaging::Ev2 && !(body.len() - header_len).is_multiple_of(size)) {
+            return Err(MifareError::new("Enciphered data is not a whole number of blocks"));
+        }
+        let (header, rest) = body.split_at(header_len);
+        match self.messaging {
+            Messaging::Legacy => Ok([header, &cbc_receive(&self.enc_key, &mut vec![0u8; size], rest, CipherOp::Encipher)].concat()),
+            Messaging::Ev1 => Ok([header, &cbc_receive(&self.enc_key, &mut self.iv, rest, CipherOp::Decipher)].concat()),
+            Messaging::Ev2 => self.unwrap_command(command, header_len, body, CommunicationMode::Full),
+        }
+    }
+
+    // Card side: a successful answer carrying `data`, protected as `mode` says
+    pub fn wrap_response(&mut self, data: &[u8], mode: CommunicationMode) -> Vec<u8> {
+        let size = self.size();
+        let status = OPERATION_OK;
+        match (self.messaging, mode) {
+            (Messaging::Legacy, CommunicationMode::Plain) => data.to_vec(),
+            (Messaging::Legacy, _) if data.is_empty() => Vec::new(),
+            (Messaging::Legacy, CommunicationMode::Maced) => [data, &self.legacy_mac(data)].concat(),
+            (Messaging::Legacy, CommunicationMode::Full) => {
+                let mut enc = [data, &crc_a(data)].concat();
+                pad_zero(&mut enc, size);
+                cbc_send(&self.enc_key, &mut vec![0u8; size], &enc, CipherOp::Encipher)
+            },
+
+            (Messaging::Ev1, CommunicationMode::Full) if !data.is_empty() => {
+                let mut enc = [data, &crc32(&[data, &[status]].concat())].concat();
+                pad_zero(&mut enc, size);
+                cbc_send(&self.enc_key, &mut self.iv, &enc, CipherOp::Encipher)
+            },
+            (Messaging::Ev1, _) => {
+                let mac = self.ev1_cmac(&[data, &[status]].concat());
+                [data, &mac].concat()
+            },
+
+            (Messaging::Ev2, CommunicationMode::Plain) => {
+                self.cmd_ctr = self.cmd_ctr.wrapping_add(1);
+                data.to_vec()
+            },
+            (Messaging::Ev2, _) => {
+                self.cmd_ctr = self.cmd_ctr.wrapping_add(1);
+                let body = if mode == CommunicationMode::Full && !data.is_empty() {
+                    let mut enc = data.to_vec();
+                    pad_iso(&mut enc, size);
+                    cbc_send(&self.enc_key, &mut self.ev2_iv([0x5A, 0xA5], self.cmd_ctr), &enc, CipherOp::Encipher)
+                } else {
+                    data.to_vec()
+                };
+                let mac = self.ev2_mac(status, self.cmd_ctr, &body);
+                [&body[..], &mac].concat()
+            },
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::error::Error;
+use std::ops::RangeInclusive;
+use crate::desfire::{
+    aid_bytes, le24, le32, AccessRights, CommunicationMode, Desfire, FileSettings, KeySettings, FREE_ACCESS,
+};
+use crate::error::MifareError;
+
+// DESFire application and file management, and the commands on file
+// contents. Writes to backup, value and record files only take effect on
+// CommitTransaction; AbortTransaction (or selecting another application)
+// drops them.
+
+pub const CREATE_APPLICATION: u8 = 0xCA;
+pub const DELETE_APPLICATION: u8 = 0xDA;
+pub const CREATE_STD_DATA_FILE: u8 = 0xCD;
+pub const CREATE_BACKUP_DATA_FILE: u8 = 0xCB;
+pub const CREATE_VALUE_FILE: u8 = 0xCC;
+pub const CREATE_LINEAR_RECORD_FILE: u8 = 0xC1;
+pub const CREATE_CYCLIC_RECORD_FILE: u8 = 0xC0;
+pub const DELETE_FILE: u8 = 0xDF;
+pub const READ_DATA: u8 = 0xBD;
+pub const WRITE_DATA: u8 = 0x3D;
+pub const GET_VALUE: u8 = 0x6C;
+pub const CREDIT: u8 = 0x0C;
+pub const DEBIT: u8 = 0xDC;
+pub const READ_RECORDS: u8 = 0xBB;
+pub const WRITE_RECORD: u8 = 0x3B;
+pub const COMMIT_TRANSACTION: u8 = 0xC7;
+pub const ABORT_TRANSACTION: u8 = 0xA7;
+
+// File numbers go up to 1F
+pub const MAX_FILE_NO: u8 = 0x1F;
+
+// File number, offset and length heading ReadData, WriteData and their
+// record counterparts
+pub const ACCESS_HEADER_LEN: usize = 7;
+
+// What is done to a file, deciding which access rights apply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOperation {
+    // ReadData, ReadRecords
+    Read,
+    // WriteData, WriteRecord
+    Write,
+    GetValue,
+    Credit,
+    Debit,
+}
+
+impl FileOperation {
+    // Keys that allow the operation, any of which will do
+    pub fn rights(&self, access: &AccessRights) -> Vec<u8> {
+        match self {
+            FileOperation::Read => vec![access.read, access.read_write],
+            FileOperation::Write => vec![access.write, access.read_write],
+            FileOperation::GetValue | FileOperation::Debit => vec![access.read, access.write, access.read_write],
+            FileOperation::Credit => vec![access.read_write],
+        }
+    }
+
+    // How the data travels: plain when free access allows the operation,
+    // otherwise as the file says
+    pub fn communication(&self, settings: &FileSettings) -> CommunicationMode {
+        if self.rights(&settings.access).contains(&FREE_ACCESS) {
+            CommunicationMode::Plain
+        } else {
+            settings.communication
+        }
+    }
+}
+
+// Answers without data are only MACed, even for enciphered files
+pub(crate) fn status_mode(mode: CommunicationMode) -> CommunicationMode {
+    match mode {
+        CommunicationMode::Full => CommunicationMode::Maced,
+        mode => mode,
+    }
+}
+
+// File number, 3-byte offset and 3-byte length or count
+fn access_header(file: u8, offset: u32, length: u32) -> Vec<u8> {
+    let mut header = vec![file];
+    header.extend_from_slice(&offset.to_le_bytes()[..3]);
+    header.extend_from_slice(&length.to_le_bytes()[..3]);
+    header
+}
+
+// Common head of the CreateXxxFile commands
+fn file_header(file: u8, communication: CommunicationMode, access: &AccessRights) -> Vec<u8> {
+    let mut data = vec![file, communication.to_byte()];
+    data.extend_from_slice(&access.encode());
+    data
+}
+
+fn len24(len: usize) -> Result<u32, MifareError> {
+    if len > 0xFFFFFF {
+        return Err(MifareError::new("Data too long for a DESFire file"));
+    }
+    Ok(len as u32)
+}
+
+impl<'a> Desfire<'a> {
+    // Create an application; needs the card level selected, and the card
+    // master key unless the card allows free creation
+    pub fn create_application(&self, aid: u32, settings: &KeySettings) -> Result<(), Box<dyn Error>> {
+        let mut data = aid_bytes(aid).to_vec();
+        data.extend_from_slice(&settings.encode());
+        self.command(CREATE_APPLICATION, &data)?;
+        Ok(())
+    }
+
+    // Delete an application with all its files; needs the card master key
+    pub fn delete_application(&self, aid: u32) -> Result<(), Box<dyn Error>> {
+        self.command(DELETE_APPLICATION, &aid_bytes(aid))?;
+        Ok(())
+    }
+
+    pub fn create_std_data_file(&self, file: u8, communication: CommunicationMode, access: &AccessRights, size: u32)
+        -> Result<(), Box<dyn Error>> {
+        let mut data = file_header(file, communication, access);
+        data.extend_from_slice(&size.to_le_bytes()[..3]);
+        self.command(CREATE_STD_DATA_FILE, &data)?;
+        Ok(())
+    }
+
+    pub fn create_backup_data_file(&self, file: u8, communication: CommunicationMode, access: &AccessRights, size: u32)
+        -> Result<(), Box<dyn Error>> {
+        let mut data = file_header(file, communication, access);
+        data.extend_from_slice(&size.to_le_bytes()[..3]);
+        self.command(CREATE_BACKUP_DATA_FILE, &data)?;
+        Ok(())
+    }
+
+    // Value file holding `value` within `limits`
+    pub fn create_value_file(&self, file: u8, communication: CommunicationMode, access: &AccessRights,
+                             limits: RangeInclusive<i32>, value: i32, limited_credit: bool) -> Result<(), Box<dyn Error>> {
+        let mut data = file_header(file, communication, access);
+        data.extend_from_slice(&limits.start().to_le_bytes());
+        data.extend_from_slice(&limits.end().to_le_bytes());
+        data.extend_from_slice(&value.to_le_bytes());
+        data.push(limited_credit as u8);
+        self.command(CREATE_VALUE_FILE, &data)?;
+        Ok(())
+    }
+
+    fn create_record_file(&self, command: u8, mut data: Vec<u8>, record_size: u32, max_records: u32) -> Result<(), Box<dyn Error>> {
+        data.extend_from_slice(&record_size.to_le_bytes()[..3]);
+        data.extend_from_slice(&max_records.to_le_bytes()[..3]);
+        self.command(command, &data)?;
+        Ok(())
+    }
+
+    // Record file that refuses new records once full
+    pub fn create_linear_record_file(&self, file: u8, communication: CommunicationMode, access: &AccessRights,
+                                     record_size: u32, max_records: u32) -> Result<(), Box<dyn Error>> {
+        self.create_record_file(CREATE_LINEAR_RECORD_FILE, file_header(file, communication, access), record_size, max_records)
+    }
+
+    // Record file that overwrites its oldest record once full; one record
+    // is kept spare, so it holds max_records - 1
+    pub fn create_cyclic_record_file(&self, file: u8, communication: CommunicationMode, access: &AccessRights,
+                                     record_size: u32, max_records: u32) -> Result<(), Box<dyn Error>> {
+        self.create_record_file(CREATE_CYCLIC_RECORD_FILE, file_header(file, communication, access), record_size, max_records)
+    }
+
+    pub fn delete_file(&self, file: u8) -> Result<(), Box<dyn Error>> {
+        self.command(DELETE_FILE, &[file])?;
+        Ok(())
+    }
+
+    // Communication mode of an operation on a file; only looked up when a
+    // session is open, as everything is plain otherwise
+    fn file_mode(&self, file: u8, operation: FileOperation) -> Result<CommunicationMode, Box<dyn Error>> {
+        if !self.is_authenticated() {
+            return Ok(CommunicationMode::Plain);
+        }
+        Ok(operation.communication(&self.get_file_settings(file)?))
+    }
+
+    // `length` bytes of a data file from `offset`, 0 for up to the end
+    pub fn read_data(&self, file: u8, offset: u32, length: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mode = self.file_mode(file, FileOperation::Read)?;
+        self.command_secure(READ_DATA, &access_header(file, offset, length), &[], mode, mode)
+    }
+
+    pub fn write_data(&self, file: u8, offset: u32, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mode = self.file_mode(file, FileOperation::Write)?;
+        let header = access_header(file, offset, len24(data.len())?);
+        self.command_secure(WRITE_DATA, &header, data, mode, status_mode(mode))?;
+        Ok(())
+    }
+
+    pub fn get_value(&self, file: u8) -> Result<i32, Box<dyn Error>> {
+        let mode = self.file_mode(file, FileOperation::GetValue)?;
+        let answer = self.command_secure(GET_VALUE, &[file], &[], mode, mode)?;
+        if answer.len() != 4 {
+            return Err(Box::new(MifareError::new("GetValue answer must be 4 bytes")));
+        }
+        Ok(le32(&answer) as i32)
+    }
+
+    fn change_value(&self, command: u8, operation: FileOperation, file: u8, amount: i32) -> Result<(), Box<dyn Error>> {
+        if amount < 0 {
+            return Err(Box::new(MifareError::new("Credit and debit amounts cannot be negative")));
+        }
+        let mode = self.file_mode(file, operation)?;
+        self.command_secure(command, &[file], &amount.to_le_bytes(), mode, status_mode(mode))?;
+        Ok(())
+    }
+
+    // Add to a value file, pending CommitTransaction
+    pub fn credit(&self, file: u8, amount: i32) -> Result<(), Box<dyn Error>> {
+        self.change_value(CREDIT, FileOperation::Credit, file, amount)
+    }
+
+    // Take from a value file, pending CommitTransaction
+    pub fn debit(&self, file: u8, amount: i32) -> Result<(), Box<dyn Error>> {
+        self.change_value(DEBIT, FileOperation::Debit, file, amount)
+    }
+
+    // `count` records (0 for all) from the `offset`-th newest back, oldest
+    // first, as one block of bytes
+    pub fn read_records(&self, file: u8, offset: u32, count: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mode = self.file_mode(file, FileOperation::Read)?;
+        self.command_secure(READ_RECORDS, &access_header(file, offset, count), &[], mode, mode)
+    }
+
+    // Write into the record of the current transaction, starting a new one
+    // if needed; pending CommitTransaction
+    pub fn write_record(&self, file: u8, offset: u32, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mode = self.file_mode(file, FileOperation::Write)?;
+        let header = access_header(file, offset, len24(data.len())?);
+        self.command_secure(WRITE_RECORD, &header, data, mode, status_mode(mode))?;
+        Ok(())
+    }
+
+    // Validate all pending writes of the selected application
+    pub fn commit_transaction(&self) -> Result<(), Box<dyn Error>> {
+        self.command(COMMIT_TRANSACTION, &[])?;
+        Ok(())
+    }
+
+    // Drop all pending writes of the selected application
+    pub fn abort_transaction(&self) -> Result<(), Box<dyn Error>> {
+        self.command(ABORT_TRANSACTION, &[])?;
+        Ok(())
+    }
+}
+
+// File number, offset and length of an access header
+pub fn decode_access_header(header: &[u8]) -> (u8, u32, u32) {
+    (header[0], le24(&header[1..4]), le24(&header[4..7]))
+}
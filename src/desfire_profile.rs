@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use crate::desfire::{
+    AccessRights, CipherType, CommunicationMode, Desfire, FileLayout, FileSettings, FileType, KeySettings, FREE_ACCESS, NO_ACCESS,
+    PICC_AID,
+};
+use crate::desfire_auth::{AuthMode, CHANGE_KEY_FROZEN, CHANGE_KEY_SAME};
+use crate::desfire_crypto::DesfireKey;
+use crate::error::MifareError;
+
+// Declarative layout of a DESFire card, kept in a text file with one item
+// per line and # comments:
+//
+//   picc-key aes 00112233445566778899AABBCCDDEEFF
+//   application 010203 aes keys=3 settings=0F
+//   key 1 11111111111111111111111111111111
+//   file 1 std-data size=32 comm=full access=1200 data=48656C6C6F
+//   file 2 value lower=0 upper=1000 value=100 limited-credit=no comm=mac access=1200
+//   file 3 cyclic-record record-size=16 records=5 comm=plain access=E200
+//
+// picc-key is the current card master key (all-zero DES if left out). Keys
+// and files belong to the application above them; keys not listed stay
+// zero. Ciphers are des (8 or 16-byte keys), 3k3des and aes. Access rights
+// are 4 hex digits: read, write, read/write and change key, E for free and
+// F for never. File types are std-data, backup-data, value, linear-record
+// and cyclic-record; data= gives the initial contents of data files.
+
+// A file and what it holds once created
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileProfile {
+    pub file_no: u8,
+    pub settings: FileSettings,
+    // Initial value of value files
+    pub value: i32,
+    // Initial contents of data files, written from offset 0
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApplicationProfile {
+    pub aid: u32,
+    pub key_settings: KeySettings,
+    // Keys to set, by number; the others stay zero
+    pub keys: BTreeMap<u8, DesfireKey>,
+    pub files: Vec<FileProfile>,
+}
+
+impl ApplicationProfile {
+    // Key `key_no` once provisioned
+    pub fn key(&self, key_no: u8) -> DesfireKey {
+        self.keys.get(&key_no).cloned().unwrap_or_else(|| DesfireKey::zero(self.key_settings.cipher))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub picc_key: DesfireKey,
+    pub applications: Vec<ApplicationProfile>,
+}
+
+// Hex bytes without separators, as keys are written in profiles
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, MifareError> {
+    let invalid = || MifareError::new(&format!("Invalid hex bytes: {}", text));
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(invalid());
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| invalid())).collect()
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, text: &str) -> Result<T, MifareError> {
+    text.parse().map_err(|_| MifareError::new(&format!("Invalid {}: {}", name, text)))
+}
+
+fn parse_cipher(text: &str) -> Result<CipherType, MifareError> {
+    match text {
+        "des" => Ok(CipherType::TripleDes),
+        "3k3des" => Ok(CipherType::TripleDes3K),
+        "aes" => Ok(CipherType::Aes),
+        _ => Err(MifareError::new(&format!("Unknown cipher {} (des, 3k3des or aes)", text))),
+    }
+}
+
+fn parse_communication(text: &str) -> Result<CommunicationMode, MifareError> {
+    match text {
+        "plain" => Ok(CommunicationMode::Plain),
+        "mac" => Ok(CommunicationMode::Maced),
+        "full" => Ok(CommunicationMode::Full),
+        _ => Err(MifareError::new(&format!("Unknown communication mode {} (plain, mac or full)", text))),
+    }
+}
+
+// Read, write, read/write and change key as 4 hex digits
+// Access rights as 4 hex digits: read, write, read/write and change keys
+pub fn parse_access(text: &str) -> Result<AccessRights, MifareError> {
+    let digits = text.chars().map(|c| c.to_digit(16).map(|d| d as u8)).collect::<Option<Vec<u8>>>();
+    match digits.as_deref() {
+        Some(&[read, write, read_write, change]) => Ok(AccessRights { read, write, read_write, change }),
+        _ => Err(MifareError::new(&format!("Access rights are 4 hex digits: {}", text))),
+    }
+}
+
+// The name=value options after the leading words of a line
+struct Options<'a> {
+    values: BTreeMap<&'a str, &'a str>,
+}
+
+impl<'a> Options<'a> {
+    fn parse(words: &[&'a str], allowed: &[&str]) -> Result<Options<'a>, MifareError> {
+        let mut values = BTreeMap::new();
+        for word in words {
+            let (name, value) = word.split_once('=')
+                .ok_or_else(|| MifareError::new(&format!("Expected name=value: {}", word)))?;
+            if !allowed.contains(&name) {
+                return Err(MifareError::new(&format!("Unknown option {} (expected {})", name, allowed.join(", "))));
+            }
+            values.insert(name, value);
+        }
+        Ok(Options { values })
+    }
+
+    fn get(&self, name: &str) -> Option<&'a str> {
+        self.values.get(name).copied()
+    }
+
+    fn require(&self, name: &str) -> Result<&'a str, MifareError> {
+        self.get(name).ok_or_else(|| MifareError::new(&format!("Missing {}=", name)))
+    }
+
+    fn number<T: std::str::FromStr>(&self, name: &str) -> Result<T, MifareError> {
+        parse_number(name, self.require(name)?)
+    }
+}
+
+fn parse_file(words: &[&str]) -> Result<FileProfile, MifareError> {
+    let [file_no, kind, rest @ ..] = words else {
+        return Err(MifareError::new("Expected: file <number> <type> options..."));
+    };
+    let file_no = parse_number("file number", file_no)?;
+    let (file_type, allowed): (FileType, &[&str]) = match *kind {
+        "std-data" => (FileType::StandardData, &["comm", "access", "size", "data"]),
+        "backup-data" => (FileType::BackupData, &["comm", "access", "size", "data"]),
+        "value" => (FileType::Value, &["comm", "access", "lower", "upper", "value", "limited-credit"]),
+        "linear-record" => (FileType::LinearRecord, &["comm", "access", "record-size", "records"]),
+        "cyclic-record" => (FileType::CyclicRecord, &["comm", "access", "record-size", "records"]),
+        _ => return Err(MifareError::new(&format!("Unknown file type {}", kind))),
+    };
+    let options = Options::parse(rest, allowed)?;
+
+    let mut value = 0;
+    let mut data = Vec::new();
+    let layout = match file_type {
+        FileType::Value => {
+            value = options.number("value")?;
+            let limited_credit_enabled = match options.get("limited-credit").unwrap_or("no") {
+                "yes" => true,
+                "no" => false,
+                other => return Err(MifareError::new(&format!("limited-credit is yes or no: {}", other))),
+            };
+            FileLayout::Value {
+                lower_limit: options.number("lower")?,
+                upper_limit: options.number("upper")?,
+                limited_credit_value: 0,
+                limited_credit_enabled,
+            }
+        },
+        FileType::LinearRecord | FileType::CyclicRecord => FileLayout::Record {
+            record_size: options.number("record-size")?,
+            max_records: options.number("records")?,
+            current_records: 0,
+        },
+        _ => {
+            let size = options.number("size")?;
+            data = options.get("data").map(parse_hex).transpose()?.unwrap_or_default();
+            if data.len() > size as usize {
+                return Err(MifareError::new(&format!("data= is {} bytes, more than the file size {}", data.len(), size)));
+            }
+            FileLayout::Data { size }
+        },
+    };
+    let settings = FileSettings {
+        file_type,
+        communication: parse_communication(options.require("comm")?)?,
+        access: parse_access(options.require("access")?)?,
+        layout,
+    };
+    Ok(FileProfile { file_no, settings, value, data })
+}
+
+impl Profile {
+    pub fn parse(text: &str) -> Result<Profile, MifareError> {
+        let mut profile = Profile { picc_key: DesfireKey::zero(CipherType::TripleDes), applications: Vec::new() };
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            profile.parse_line(&words).map_err(|e| MifareError::new(&format!("Line {}: {}", number + 1, e)))?;
+        }
+        Ok(profile)
+    }
+
+    pub fn load(path: &Path) -> Result<Profile, Box<dyn Error>> {
+        Ok(Profile::parse(&fs::read_to_string(path)?)?)
+    }
+
+    fn parse_line(&mut self, words: &[&str]) -> Result<(), MifareError> {
+        match words {
+            ["picc-key", cipher, key] => self.picc_key = DesfireKey::new(parse_cipher(cipher)?, &parse_hex(key)?)?,
+            ["application", aid, cipher, rest @ ..] => {
+                let options = Options::parse(rest, &["keys", "settings"])?;
+                let aid = u32::from_str_radix(aid, 16).ok().filter(|_| aid.len() == 6)
+                    .ok_or_else(|| MifareError::new(&format!("Application IDs are 6 hex digits: {}", aid)))?;
+                if aid == PICC_AID || self.applications.iter().any(|application| application.aid == aid) {
+                    return Err(MifareError::new(&format!("Application {:06X} cannot be listed", aid)));
+                }
+                let key_count = options.number("keys")?;
+                if !(1..=14).contains(&key_count) {
+                    return Err(MifareError::new("Applications have 1 to 14 keys"));
+                }
+                let settings = u8::from_str_radix(options.require("settings")?, 16)
+                    .map_err(|_| MifareError::new("settings= is one hex byte"))?;
+                let change_key = settings >> 4;
+                if change_key >= key_count && change_key != CHANGE_KEY_SAME && change_key != CHANGE_KEY_FROZEN {
+                    return Err(MifareError::new(&format!("settings= names change key {}, which the application lacks", change_key)));
+                }
+                let key_settings = KeySettings { settings, key_count, cipher: parse_cipher(cipher)? };
+                self.applications.push(ApplicationProfile { aid, key_settings, keys: BTreeMap::new(), files: Vec::new() });
+            },
+            ["key", key_no, key] => {
+                let application = self.applications.last_mut().ok_or_else(|| MifareError::new("Key before any application"))?;
+                let key_no: u8 = parse_number("key number", key_no)?;
+                if key_no >= application.key_settings.key_count {
+                    return Err(MifareError::new(&format!("Application has no key {}", key_no)));
+                }
+                application.keys.insert(key_no, DesfireKey::new(application.key_settings.cipher, &parse_hex(key)?)?);
+            },
+            ["file", rest @ ..] => {
+                let application = self.applications.last_mut().ok_or_else(|| MifareError::new("File before any application"))?;
+                let file = parse_file(rest)?;
+                let access = &file.settings.access;
+                let rights = [access.read, access.write, access.read_write, access.change];
+                if let Some(key_no) = rights.into_iter()
+                    .find(|&key_no| key_no >= application.key_settings.key_count && key_no != FREE_ACCESS && key_no != NO_ACCESS) {
+                    return Err(MifareError::new(&format!("access= names key {:X}, which the application lacks", key_no)));
+                }
+                if application.files.iter().any(|other| other.file_no == file.file_no) {
+                    return Err(MifareError::new(&format!("File {} listed twice", file.file_no)));
+                }
+                application.files.push(file);
+            },
+            _ => return Err(MifareError::new(&format!("Unknown line: {}", words.join(" ")))),
+        }
+        Ok(())
+    }
+}
+
+// Authentication that opens a session with a key of this type
+fn auth_mode(cipher: CipherType) -> AuthMode {
+    match cipher {
+        CipherType::Aes => AuthMode::Aes,
+        _ => AuthMode::Iso,
+    }
+}
+
+// Key that may write a file's contents, if any key is needed
+fn write_key(access: &AccessRights) -> Result<Option<u8>, MifareError> {
+    let rights = [access.write, access.read_write];
+    if rights.contains(&FREE_ACCESS) {
+        return Ok(None);
+    }
+    rights.into_iter().find(|&key| key != NO_ACCESS).map(Some)
+        .ok_or_else(|| MifareError::new("File contents can never be written"))
+}
+
+impl<'a> Desfire<'a> {
+    // Authenticate with one of the keys of the selected application unless
+    // the session already uses it
+    fn authenticate_as(&self, key_no: u8, keys: &[DesfireKey]) -> Result<(), Box<dyn Error>> {
+        if self.authenticated_key() != Some(key_no) {
+            let key = keys.get(key_no as usize)
+                .ok_or_else(|| MifareError::new(&format!("Application has no key {}", key_no)))?;
+            self.authenticate(auth_mode(key.cipher()), key_no, key)?;
+        }
+        Ok(())
+    }
+
+    fn create_file(&self, file: &FileProfile) -> Result<(), Box<dyn Error>> {
+        let FileProfile { file_no, settings, value, .. } = file;
+        match (settings.file_type, settings.layout) {
+            (FileType::StandardData, FileLayout::Data { size }) => {
+                self.create_std_data_file(*file_no, settings.communication, &settings.access, size)
+            },
+            (FileType::BackupData, FileLayout::Data { size }) => {
+                self.create_backup_data_file(*file_no, settings.communication, &settings.access, size)
+            },
+            (FileType::Value, FileLayout::Value { lower_limit, upper_limit, limited_credit_enabled, .. }) => {
+                self.create_value_file(*file_no, settings.communication, &settings.access, lower_limit..=upper_limit, *value,
+                                       limited_credit_enabled)
+            },
+            (FileType::LinearRecord, FileLayout::Record { record_size, max_records, .. }) => {
+                self.create_linear_record_file(*file_no, settings.communication, &settings.access, record_size, max_records)
+            },
+            (FileType::CyclicRecord, FileLayout::Record { record_size, max_records, .. }) => {
+                self.create_cyclic_record_file(*file_no, settings.communication, &settings.access, record_size, max_records)
+            },
+            _ => Err(Box::new(MifareError::new(&format!("File {} has settings that do not fit its type", file_no)))),
+        }
+    }
+
+    // Set the keys of a new application, whose keys are all zero: the
+    // others first, under whichever key may change them, and the master
+    // key last
+    fn provision_keys(&self, application: &ApplicationProfile, keys: &mut [DesfireKey], actions: &mut Vec<String>)
+        -> Result<(), Box<dyn Error>> {
+        let change_key = application.key_settings.settings >> 4;
+        let others = application.keys.iter().filter(|(&key_no, _)| key_no != 0);
+        for (&key_no, key) in others.chain(application.keys.get_key_value(&0)) {
+            let by = match (key_no, change_key) {
+                (0, _) => 0,
+                (_, CHANGE_KEY_FROZEN) => return Err(Box::new(MifareError::new("Application keys are frozen"))),
+                (_, CHANGE_KEY_SAME) => key_no,
+                (_, by) => by,
+            };
+            self.authenticate_as(by, keys)?;
+            let slot = keys.get(key_no as usize).cloned()
+                .ok_or_else(|| MifareError::new(&format!("Application has no key {}", key_no)))?;
+            let old_key = if by == key_no { None } else { Some(&slot) };
+            self.change_key(key_no, key, old_key, 0)?;
+            keys[key_no as usize] = key.clone();
+            actions.push(format!("Set key {} of application {:06X}", key_no, application.aid));
+        }
+        Ok(())
+    }
+
+    // Bring the card to the layout of `profile`: missing applications and
+    // files are created, new data files filled, and the keys of new
+    // applications set. What is already on the card is left as it is, and
+    // its keys must match the profile. Returns what was done.
+    pub fn provision(&self, profile: &Profile) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut actions = Vec::new();
+        self.select_application(PICC_AID)?;
+        self.authenticate(auth_mode(profile.picc_key.cipher()), 0, &profile.picc_key)?;
+        let existing = self.get_application_ids()?;
+        for application in profile.applications.iter().filter(|application| !existing.contains(&application.aid)) {
+            self.create_application(application.aid, &application.key_settings)?;
+            actions.push(format!("Created application {:06X}", application.aid));
+        }
+
+        for application in &profile.applications {
+            let new = !existing.contains(&application.aid);
+            let mut keys: Vec<DesfireKey> = (0..application.key_settings.key_count)
+                .map(|key_no| if new { DesfireKey::zero(application.key_settings.cipher) } else { application.key(key_no) })
+                .collect();
+            self.select_application(application.aid)?;
+            self.authenticate_as(0, &keys)?;
+            let files = self.get_file_ids()?;
+
+            for file in application.files.iter().filter(|file| !files.contains(&file.file_no)) {
+                self.authenticate_as(0, &keys)?;
+                self.create_file(file)?;
+                actions.push(format!("Created file {} in application {:06X}: {}", file.file_no, application.aid, file.settings));
+                if file.data.is_empty() {
+                    continue;
+                }
+                if let Some(key_no) = write_key(&file.settings.access)? {
+                    self.authenticate_as(key_no, &keys)?;
+                }
+                self.write_data(file.file_no, 0, &file.data)?;
+                if file.settings.file_type == FileType::BackupData {
+                    self.commit_transaction()?;
+                }
+                actions.push(format!("Wrote {} bytes to file {}", file.data.len(), file.file_no));
+            }
+
+            if new {
+                self.provision_keys(application, &mut keys, &mut actions)?;
+            }
+        }
+        self.select_application(PICC_AID)?;
+        Ok(actions)
+    }
+}
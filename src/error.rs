@@ -0,0 +1,41 @@
+use std::error::Error;
+use std::fmt;
+
+// Custom error type for MIFARE operations
+#[derive(Debug)]
+pub struct MifareError {
+    message: String,
+    status: Option<(u8, u8)>,
+}
+
+impl MifareError {
+    pub fn new(message: &str) -> Self {
+        MifareError {
+            message: message.to_string(),
+            status: None,
+        }
+    }
+
+    pub fn with_status(message: &str, status1: u8, status2: u8) -> Self {
+        MifareError {
+            message: message.to_string(),
+            status: Some((status1, status2)),
+        }
+    }
+
+    // Status bytes returned by the reader, if any
+    pub fn status(&self) -> Option<(u8, u8)> {
+        self.status
+    }
+}
+
+impl fmt::Display for MifareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some((s1, s2)) => write!(f, "{}: Status {:02X} {:02X}", self.message, s1, s2),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error for MifareError {}
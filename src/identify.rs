@@ -0,0 +1,345 @@
+use std::error::Error;
+use pcsc::Card;
+use crate::atr::{Atr, PCSC_RID};
+use crate::card_info::CardInfo;
+use crate::desfire::Desfire;
+use crate::originality::{check_originality, Originality};
+use crate::pn532::Pn532;
+
+// Enum for different Mifare card types
+#[derive(Debug, Clone, PartialEq)]
+pub enum MifareType {
+    MifareClassic1K,
+    MifareClassic4K,
+    MifareMini,
+    MifareUltralight,
+    Ntag,
+    MifareDesfire,
+    MifarePlus,
+    OtherMifare,
+    Unknown,
+}
+
+impl MifareType {
+    pub fn to_string(&self) -> &str {
+        match self {
+            MifareType::MifareClassic1K => "Mifare Classic 1K",
+            MifareType::MifareClassic4K => "Mifare Classic 4K",
+            MifareType::MifareMini => "Mifare Mini",
+            MifareType::MifareUltralight => "Mifare Ultralight",
+            MifareType::Ntag => "NTAG",
+            MifareType::MifareDesfire => "Mifare DESFire",
+            MifareType::MifarePlus => "Mifare Plus",
+            MifareType::OtherMifare => "Other Mifare Type",
+            MifareType::Unknown => "Unknown Card Type",
+        }
+    }
+}
+
+// What the card revealed about itself, following the identification
+// procedure of NXP AN10833 (MIFARE type identification procedure)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Evidence {
+    pub atqa: Option<[u8; 2]>,
+    pub sak: Option<u8>,
+    pub ats: Option<Vec<u8>>,
+    pub historical_bytes: Vec<u8>,
+    // Response to the Ultralight/NTAG GET_VERSION command (60)
+    pub ul_version: Option<Vec<u8>>,
+    // Whether the card answered the Ultralight C AUTHENTICATE command (1A 00),
+    // None when it was not tried
+    pub ul_c_auth: Option<bool>,
+    // ECC originality signature (READ_SIG), for cards that answered GET_VERSION
+    pub ul_signature: Option<Vec<u8>>,
+    // Concatenated frames of the DESFire GetVersion command (60, AF, AF)
+    pub desfire_version: Option<Vec<u8>>,
+}
+
+impl Evidence {
+    pub fn from_card_info(info: &CardInfo) -> Self {
+        Evidence {
+            atqa: info.atqa,
+            sak: info.sak,
+            ats: info.ats.clone(),
+            historical_bytes: info.historical_bytes.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+// Result of the identification
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identification {
+    pub card_type: MifareType,
+    pub product: String,
+    // Product generation, e.g. "EV1"
+    pub generation: Option<String>,
+    // User memory (Ultralight/NTAG) or total memory (Classic, Plus, DESFire) in bytes
+    pub memory_size: Option<usize>,
+    // MIFARE Plus security level, when it can be told
+    pub security_level: Option<String>,
+    // NXP originality signature check, for Ultralight EV1 and NTAG21x
+    pub originality: Option<Originality>,
+}
+
+impl Identification {
+    fn new(card_type: MifareType, product: &str, memory_size: Option<usize>) -> Self {
+        Identification {
+            card_type,
+            product: product.to_string(),
+            generation: None,
+            memory_size,
+            security_level: None,
+            originality: None,
+        }
+    }
+
+    fn generation(mut self, generation: &str) -> Self {
+        self.generation = Some(generation.to_string());
+        self
+    }
+
+    fn security_level(mut self, level: &str) -> Self {
+        self.security_level = Some(level.to_string());
+        self
+    }
+}
+
+// Ultralight / NTAG GET_VERSION: product type, subtype, major, minor, storage size
+struct VersionEntry {
+    version: [u8; 5],
+    card_type: MifareType,
+    product: &'static str,
+    generation: Option<&'static str>,
+    user_memory: usize,
+}
+
+const UL_VERSION_TABLE: &[VersionEntry] = &[
+    VersionEntry { version: [0x03, 0x01, 0x01, 0x00, 0x0B], card_type: MifareType::MifareUltralight, product: "MIFARE Ultralight EV1 (MF0UL11)", generation: Some("EV1"), user_memory: 48 },
+    VersionEntry { version: [0x03, 0x02, 0x01, 0x00, 0x0B], card_type: MifareType::MifareUltralight, product: "MIFARE Ultralight EV1 (MF0ULH11)", generation: Some("EV1"), user_memory: 48 },
+    VersionEntry { version: [0x03, 0x01, 0x01, 0x00, 0x0E], card_type: MifareType::MifareUltralight, product: "MIFARE Ultralight EV1 (MF0UL21)", generation: Some("EV1"), user_memory: 128 },
+    VersionEntry { version: [0x03, 0x02, 0x01, 0x00, 0x0E], card_type: MifareType::MifareUltralight, product: "MIFARE Ultralight EV1 (MF0ULH21)", generation: Some("EV1"), user_memory: 128 },
+    VersionEntry { version: [0x04, 0x01, 0x01, 0x00, 0x0B], card_type: MifareType::Ntag, product: "NTAG210", generation: None, user_memory: 48 },
+    VersionEntry { version: [0x04, 0x01, 0x02, 0x00, 0x0B], card_type: MifareType::Ntag, product: "NTAG210u", generation: None, user_memory: 48 },
+    VersionEntry { version: [0x04, 0x01, 0x01, 0x00, 0x0E], card_type: MifareType::Ntag, product: "NTAG212", generation: None, user_memory: 128 },
+    VersionEntry { version: [0x04, 0x02, 0x01, 0x00, 0x0F], card_type: MifareType::Ntag, product: "NTAG213", generation: None, user_memory: 144 },
+    VersionEntry { version: [0x04, 0x02, 0x01, 0x00, 0x11], card_type: MifareType::Ntag, product: "NTAG215", generation: None, user_memory: 504 },
+    VersionEntry { version: [0x04, 0x02, 0x01, 0x00, 0x13], card_type: MifareType::Ntag, product: "NTAG216", generation: None, user_memory: 888 },
+    VersionEntry { version: [0x04, 0x02, 0x03, 0x00, 0x0F], card_type: MifareType::Ntag, product: "NTAG213 TT", generation: None, user_memory: 144 },
+    VersionEntry { version: [0x04, 0x04, 0x01, 0x00, 0x0F], card_type: MifareType::Ntag, product: "NTAG213F", generation: None, user_memory: 144 },
+    VersionEntry { version: [0x04, 0x04, 0x01, 0x00, 0x13], card_type: MifareType::Ntag, product: "NTAG216F", generation: None, user_memory: 888 },
+    VersionEntry { version: [0x04, 0x05, 0x02, 0x01, 0x13], card_type: MifareType::Ntag, product: "NTAG I2C 1K", generation: None, user_memory: 888 },
+    VersionEntry { version: [0x04, 0x05, 0x02, 0x01, 0x15], card_type: MifareType::Ntag, product: "NTAG I2C 2K", generation: None, user_memory: 1904 },
+    VersionEntry { version: [0x04, 0x05, 0x02, 0x02, 0x13], card_type: MifareType::Ntag, product: "NTAG I2C plus 1K", generation: Some("plus"), user_memory: 888 },
+    VersionEntry { version: [0x04, 0x05, 0x02, 0x02, 0x15], card_type: MifareType::Ntag, product: "NTAG I2C plus 2K", generation: Some("plus"), user_memory: 1912 },
+];
+
+// Storage size byte of GET_VERSION: 2^n bytes, or between 2^n and 2^(n+1)
+// when the least significant bit is set; this returns the lower bound
+fn storage_size(code: u8) -> usize {
+    1usize << (code >> 1)
+}
+
+// Identify the card from everything it revealed, most specific evidence first
+pub fn identify(evidence: &Evidence) -> Identification {
+    if let Some(version) = &evidence.desfire_version {
+        if let Some(id) = identify_desfire_version(version) {
+            return id;
+        }
+    }
+
+    if let Some(version) = &evidence.ul_version {
+        if let Some(id) = identify_ul_version(version) {
+            return id;
+        }
+    }
+
+    let sak = match evidence.sak {
+        Some(sak) => sak,
+        None => return Identification::new(MifareType::Unknown, "Unknown card (no SAK)", None),
+    };
+    let atqa = evidence.atqa.map(u16::from_be_bytes).unwrap_or(0);
+    let plus_ats = is_plus_historical_bytes(&evidence.historical_bytes);
+
+    match sak {
+        0x00 => {
+            if evidence.ul_c_auth == Some(true) {
+                Identification::new(MifareType::MifareUltralight, "MIFARE Ultralight C (MF0ICU2)", Some(144))
+                    .generation("C")
+            } else if evidence.ul_c_auth == Some(false) && evidence.ul_version.is_none() && atqa == 0x0044 {
+                // No GET_VERSION and no 3DES authentication: first generation Ultralight
+                Identification::new(MifareType::MifareUltralight, "MIFARE Ultralight (MF0ICU1)", Some(48))
+            } else {
+                Identification::new(MifareType::MifareUltralight, "MIFARE Ultralight family / NFC Forum Type 2 tag", None)
+            }
+        },
+        0x08 if plus_ats => Identification::new(MifareType::MifarePlus, "MIFARE Plus 2K", Some(2048))
+            .security_level("SL1"),
+        0x08 => Identification::new(MifareType::MifareClassic1K, "MIFARE Classic 1K", Some(1024)),
+        0x09 => Identification::new(MifareType::MifareMini, "MIFARE Mini", Some(320)),
+        0x10 => Identification::new(MifareType::MifarePlus, "MIFARE Plus 2K", Some(2048))
+            .security_level("SL2"),
+        0x11 => Identification::new(MifareType::MifarePlus, "MIFARE Plus 4K", Some(4096))
+            .security_level("SL2"),
+        0x18 if plus_ats => Identification::new(MifareType::MifarePlus, "MIFARE Plus 4K", Some(4096))
+            .security_level("SL1"),
+        0x18 => Identification::new(MifareType::MifareClassic4K, "MIFARE Classic 4K", Some(4096)),
+        0x20 => {
+            if atqa == 0x0344 {
+                Identification::new(MifareType::MifareDesfire, "MIFARE DESFire", None)
+            } else if plus_ats {
+                // SL0 and SL3 answer identically to anti-collision; ATQA tells the size
+                let (product, size) = if atqa & 0x0F == 0x02 {
+                    ("MIFARE Plus 4K", 4096)
+                } else {
+                    ("MIFARE Plus 2K", 2048)
+                };
+                Identification::new(MifareType::MifarePlus, product, Some(size))
+                    .security_level("SL0/SL3")
+            } else {
+                Identification::new(MifareType::OtherMifare, "ISO/IEC 14443-4 smart card (SmartMX/JCOP)", None)
+            }
+        },
+        0x28 => Identification::new(MifareType::MifareClassic1K, "SmartMX with MIFARE Classic 1K emulation", Some(1024)),
+        0x38 => Identification::new(MifareType::MifareClassic4K, "SmartMX with MIFARE Classic 4K emulation", Some(4096)),
+        0x88 => Identification::new(MifareType::MifareClassic1K, "Infineon MIFARE Classic 1K", Some(1024)),
+        0x98 => Identification::new(MifareType::Unknown, "Gemplus MPCOS", None),
+        _ => Identification::new(MifareType::Unknown, &format!("Unknown card (SAK {:02X})", sak), None),
+    }
+}
+
+// MIFARE Plus (S, X, EV1) historical bytes start with C1 05
+fn is_plus_historical_bytes(historical_bytes: &[u8]) -> bool {
+    historical_bytes.starts_with(&[0xC1, 0x05])
+}
+
+// Card type announced by the ATR the reader built: the card name of a PC/SC
+// part 3 storage card (ISO 14443 A part 3), or the historical bytes an ISO
+// 14443-4 card sent in its ATS. Unknown when the ATR tells neither.
+pub fn identify_atr(atr: &[u8]) -> MifareType {
+    let atr = match Atr::parse(atr) {
+        Ok(atr) => atr,
+        Err(_) => return MifareType::Unknown,
+    };
+
+    if let Some(storage) = atr.pcsc_storage_card() {
+        if storage.rid != PCSC_RID || storage.standard != 0x03 {
+            return MifareType::Unknown;
+        }
+        return match storage.card_name {
+            0x0001 => MifareType::MifareClassic1K,
+            0x0002 => MifareType::MifareClassic4K,
+            0x0003 | 0x003A | 0x003D => MifareType::MifareUltralight,
+            0x0026 => MifareType::MifareMini,
+            0x0036..=0x0039 => MifareType::MifarePlus,
+            _ => MifareType::Unknown,
+        };
+    }
+
+    match atr.historical_bytes.as_slice() {
+        // DESFire ATS carries the single historical byte 80
+        [0x80] => MifareType::MifareDesfire,
+        h if is_plus_historical_bytes(h) => MifareType::MifarePlus,
+        _ => MifareType::Unknown,
+    }
+}
+
+// Decode an 8-byte Ultralight/NTAG GET_VERSION response
+pub fn identify_ul_version(version: &[u8]) -> Option<Identification> {
+    if version.len() < 8 || version[1] != 0x04 {
+        return None;
+    }
+
+    let key = &version[2..7];
+    if let Some(entry) = UL_VERSION_TABLE.iter().find(|e| e.version == key) {
+        let mut id = Identification::new(entry.card_type.clone(), entry.product, Some(entry.user_memory));
+        id.generation = entry.generation.map(|g| g.to_string());
+        return Some(id);
+    }
+
+    // Unlisted product: fall back to the product type and the storage size code
+    let size = Some(storage_size(version[6]));
+    match version[2] {
+        0x03 => Some(Identification::new(MifareType::MifareUltralight, "MIFARE Ultralight (unlisted version)", size)),
+        0x04 => Some(Identification::new(MifareType::Ntag, "NTAG (unlisted version)", size)),
+        _ => None,
+    }
+}
+
+// Decode the DESFire/Plus GetVersion response; only the hardware part is needed
+pub fn identify_desfire_version(version: &[u8]) -> Option<Identification> {
+    if version.len() < 7 || version[0] != 0x04 {
+        return None;
+    }
+
+    let hw_type = version[1];
+    let major = version[3];
+    let size = Some(storage_size(version[5]));
+
+    let id = match hw_type {
+        0x01 | 0x81 => {
+            let (product, generation) = match major {
+                0x00 => ("MIFARE DESFire (MF3ICD40)", "EV0"),
+                0x01 => ("MIFARE DESFire EV1", "EV1"),
+                0x10..=0x1F => ("MIFARE DESFire EV2", "EV2"),
+                0x30..=0x3F => ("MIFARE DESFire EV3", "EV3"),
+                _ => ("MIFARE DESFire", "unknown"),
+            };
+            let name = match size {
+                Some(bytes) if bytes >= 1024 => format!("{} {}K", product, bytes / 1024),
+                _ => product.to_string(),
+            };
+            Identification::new(MifareType::MifareDesfire, &name, size).generation(generation)
+        },
+        0x08 => Identification::new(MifareType::MifareDesfire, "MIFARE DESFire Light", size),
+        0x02 | 0x82 => {
+            let generation = match major {
+                0x11 => "EV1",
+                0x22 => "EV2",
+                _ => "S/X",
+            };
+            let name = match size {
+                Some(bytes) if bytes >= 1024 => format!("MIFARE Plus {} {}K", generation, bytes / 1024),
+                _ => format!("MIFARE Plus {}", generation),
+            };
+            // GetVersion is only answered in SL3 (or SL0)
+            Identification::new(MifareType::MifarePlus, &name, size)
+                .generation(generation)
+                .security_level("SL0/SL3")
+        },
+        _ => return None,
+    };
+
+    Some(id)
+}
+
+// Collect evidence from the card in the field and identify it
+pub fn probe(card: &Card) -> Result<(CardInfo, Identification), Box<dyn Error>> {
+    let info = CardInfo::query(card)?;
+    let mut evidence = Evidence::from_card_info(&info);
+    let pn532 = Pn532::new(card);
+
+    if info.sak == Some(0x00) {
+        evidence.ul_version = pn532.communicate_thru(&[0x60]).ok().filter(|v| v.len() >= 8);
+
+        if evidence.ul_version.is_none() {
+            // A NAK halts the card: wake it up before trying the next command
+            pn532.list_passive_targets(1)?;
+            let response = pn532.communicate_thru(&[0x1A, 0x00]).unwrap_or_default();
+            evidence.ul_c_auth = Some(response.first() == Some(&0xAF));
+        } else {
+            evidence.ul_signature = pn532.communicate_thru(&[0x3C, 0x00]).ok().filter(|s| s.len() == 32);
+        }
+
+        // Leave the card selected and idle for whoever uses it next
+        pn532.list_passive_targets(1)?;
+    } else if info.supports_iso14443_4() {
+        evidence.desfire_version = Desfire::new(card).get_version_bytes().ok();
+    }
+
+    let mut identification = identify(&evidence);
+    if evidence.ul_version.is_some() {
+        identification.originality = Some(check_originality(&info.uid, evidence.ul_signature.as_deref()));
+    }
+    Ok((info, identification))
+}
@@ -0,0 +1,107 @@
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use crate::error::MifareError;
+use crate::format_hex;
+
+// Keys tried on MIFARE Classic sectors, kept in a text file in the format of
+// Proxmark's mfc_default_keys.dic: one 12-digit hex key per line, # comments
+
+// Dictionary the card tools read from and mfkey adds recovered keys to
+pub const DICTIONARY_PATH: &str = "keys.dic";
+
+// Key from 12 hex digits
+pub fn parse_key(text: &str) -> Result<[u8; 6], MifareError> {
+    let invalid = || MifareError::new(&format!("Invalid key (12 hex digits expected): {}", text));
+    if text.len() != 12 || !text.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut key = [0u8; 6];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyDictionary {
+    keys: Vec<[u8; 6]>,
+}
+
+impl KeyDictionary {
+    pub fn new(keys: &[[u8; 6]]) -> KeyDictionary {
+        let mut dictionary = KeyDictionary::default();
+        for key in keys {
+            dictionary.add(*key);
+        }
+        dictionary
+    }
+
+    pub fn parse(text: &str) -> Result<KeyDictionary, MifareError> {
+        let mut dictionary = KeyDictionary::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let key = parse_key(line)
+                .map_err(|e| MifareError::new(&format!("Line {}: {}", number + 1, e)))?;
+            dictionary.add(key);
+        }
+        Ok(dictionary)
+    }
+
+    // Read a dictionary file; a missing file is an empty dictionary
+    pub fn load(path: &Path) -> Result<KeyDictionary, Box<dyn Error>> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(KeyDictionary::parse(&text)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(KeyDictionary::default()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    pub fn keys(&self) -> &[[u8; 6]] {
+        &self.keys
+    }
+
+    pub fn contains(&self, key: &[u8; 6]) -> bool {
+        self.keys.contains(key)
+    }
+
+    // Add a key at the end, returning false if it was already there
+    pub fn add(&mut self, key: [u8; 6]) -> bool {
+        if self.contains(&key) {
+            return false;
+        }
+        self.keys.push(key);
+        true
+    }
+
+    pub fn merge(&mut self, other: &KeyDictionary) {
+        for key in &other.keys {
+            self.add(*key);
+        }
+    }
+
+    // Append a key to a dictionary file unless it is there already, keeping
+    // the rest of the file as it is; returns whether it was added
+    pub fn add_to_file(path: &Path, key: [u8; 6]) -> Result<bool, Box<dyn Error>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(Box::new(e)),
+        };
+        if KeyDictionary::parse(&text)?.contains(&key) {
+            return Ok(false);
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if !text.is_empty() && !text.ends_with('\n') {
+            writeln!(file)?;
+        }
+        writeln!(file, "{}", format_hex(&key))?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,38 @@
+pub mod atr;
+pub mod atr_db;
+pub mod atr_pattern;
+pub mod capability;
+pub mod card_info;
+pub mod classic;
+pub mod classic_ndef;
+pub mod crc;
+pub mod crypto1;
+pub mod desfire;
+pub mod desfire_auth;
+pub mod desfire_crypto;
+pub mod desfire_files;
+pub mod desfire_profile;
+pub mod error;
+pub mod identify;
+pub mod keys;
+pub mod mad;
+pub mod magic;
+pub mod mfkey;
+pub mod ndef;
+pub mod nested;
+pub mod ntag;
+pub mod originality;
+pub mod pn532;
+pub mod simulator;
+pub mod transport;
+pub mod type2;
+pub mod ultralight;
+pub mod ultralight_c;
+
+// Helper function to format bytes as hex string
+pub fn format_hex(bytes: &[u8]) -> String {
+    bytes.iter()
+         .map(|b| format!("{:02X}", b))
+         .collect::<Vec<String>>()
+         .join("")
+}
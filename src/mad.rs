@@ -0,0 +1,333 @@
+use std::error::Error;
+use std::fmt;
+use crate::classic::{KeyType, MifareClassic};
+use crate::error::MifareError;
+
+// MIFARE Application Directory (NXP AN10787): which application each sector
+// of a MIFARE Classic card belongs to. MAD1 is in sector 0 blocks 1-2; on
+// cards with more than 16 sectors MAD2 adds sector 16 blocks 0-2.
+
+// Key A of the MAD sectors, public so any reader can find the applications
+pub const MAD_KEY_A: [u8; 6] = [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5];
+
+// AIDs, as 16-bit values: function cluster code in the high byte,
+// application code in the low byte. On the card they are stored low byte
+// first, so the NDEF AID E103 reads "03 E1".
+pub const AID_FREE: u16 = 0x0000;
+pub const AID_DEFECT: u16 = 0x0001;
+pub const AID_RESERVED: u16 = 0x0002;
+pub const AID_ADDITIONAL_DIRECTORY: u16 = 0x0003;
+pub const AID_CARD_HOLDER: u16 = 0x0004;
+pub const AID_NOT_APPLICABLE: u16 = 0x0005;
+pub const AID_NDEF: u16 = 0xE103;
+
+// AIDs with a fixed meaning
+const KNOWN_AIDS: [(u16, &str); 7] = [
+    (AID_FREE, "Free"),
+    (AID_DEFECT, "Defect"),
+    (AID_RESERVED, "Reserved"),
+    (AID_ADDITIONAL_DIRECTORY, "Additional directory info"),
+    (AID_CARD_HOLDER, "Card holder info"),
+    (AID_NOT_APPLICABLE, "Not applicable (beyond memory size)"),
+    (AID_NDEF, "NFC Forum NDEF"),
+];
+
+// Function clusters (high byte of the AID), as first and last code
+const FUNCTION_CLUSTERS: [(u8, u8, &str); 28] = [
+    (0x00, 0x00, "Card administration"),
+    (0x01, 0x07, "Miscellaneous applications"),
+    (0x08, 0x08, "Airlines"),
+    (0x09, 0x09, "Ferry traffic"),
+    (0x10, 0x10, "Railway services"),
+    (0x12, 0x12, "Transport"),
+    (0x18, 0x18, "City traffic"),
+    (0x19, 0x19, "Czech Railways"),
+    (0x20, 0x20, "Bus services"),
+    (0x21, 0x21, "Multi modal transit"),
+    (0x28, 0x28, "Taxi services"),
+    (0x30, 0x30, "Road toll"),
+    (0x31, 0x31, "Generic transport"),
+    (0x38, 0x38, "Company services"),
+    (0x40, 0x40, "City card services"),
+    (0x47, 0x48, "Access control & security"),
+    (0x49, 0x49, "VIGIK access control"),
+    (0x50, 0x50, "Ski ticketing"),
+    (0x51, 0x54, "Access control & security"),
+    (0x56, 0x58, "Academic services"),
+    (0x60, 0x60, "Food"),
+    (0x70, 0x70, "Hotel services"),
+    (0x71, 0x71, "Loyalty"),
+    (0x88, 0x88, "Electronic purse"),
+    (0xB8, 0xB8, "Banking"),
+    (0xC8, 0xC8, "Car parking"),
+    (0xE1, 0xE1, "NFC Forum"),
+    (0xF8, 0xFF, "Miscellaneous applications"),
+];
+
+// Description of an AID: its fixed meaning, else its function cluster
+pub fn aid_name(aid: u16) -> &'static str {
+    if let Some(&(_, name)) = KNOWN_AIDS.iter().find(|&&(known, _)| known == aid) {
+        return name;
+    }
+    let cluster = (aid >> 8) as u8;
+    FUNCTION_CLUSTERS.iter()
+        .find(|&&(first, last, _)| (first..=last).contains(&cluster))
+        .map(|&(_, _, name)| name)
+        .unwrap_or("Unknown")
+}
+
+// MAD version from the general purpose byte of the sector 0 trailer: the DA
+// bit says a MAD is present, the two low bits give its version
+pub fn mad_version(gpb: u8) -> Option<u8> {
+    match (gpb & 0x80 != 0, gpb & 0x03) {
+        (true, version @ (1 | 2)) => Some(version),
+        _ => None,
+    }
+}
+
+fn check_crc(name: &str, data: &[u8]) -> Result<(), MifareError> {
+    if crc8(&data[1..]) != data[0] {
+        return Err(MifareError::new(&format!("{} CRC mismatch: {:02X} instead of {:02X}", name, data[0], crc8(&data[1..]))));
+    }
+    Ok(())
+}
+
+// CRC-8 of the MAD (polynomial x^8 + x^4 + x^3 + x^2 + 1, preset C7)
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0xC7;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x1D } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+// Application directory of a card
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mad {
+    // Info byte: bits 5-0 point to the card publisher sector
+    pub info: u8,
+    // AID of every sector, indexed by sector number. The entries of the MAD
+    // sectors themselves (0 and 16) are unused.
+    pub aids: Vec<u16>,
+}
+
+impl Mad {
+    // Empty directory for a card with `sectors` sectors
+    pub fn new(sectors: u8) -> Mad {
+        Mad { info: 0x00, aids: vec![AID_FREE; sectors as usize] }
+    }
+
+    // Whether the directory needs MAD2
+    pub fn is_mad2(&self) -> bool {
+        self.aids.len() > 16
+    }
+
+    pub fn is_mad_sector(sector: u8) -> bool {
+        sector == 0 || sector == 16
+    }
+
+    // Decode from the 32 bytes of sector 0 blocks 1-2 and, for MAD2, the 48
+    // bytes of sector 16 blocks 0-2, checking the CRCs
+    pub fn decode(mad1: &[u8], mad2: Option<&[u8]>, sectors: u8) -> Result<Mad, MifareError> {
+        let mad = Mad::decode_unchecked(mad1, mad2, sectors)?;
+        check_crc("MAD1", mad1)?;
+        if let Some(mad2) = mad2.filter(|_| sectors > 16) {
+            check_crc("MAD2", mad2)?;
+        }
+        Ok(mad)
+    }
+
+    // Decode without checking the CRCs, to look at or repair a damaged MAD
+    pub fn decode_unchecked(mad1: &[u8], mad2: Option<&[u8]>, sectors: u8) -> Result<Mad, MifareError> {
+        if mad1.len() != 32 {
+            return Err(MifareError::new("MAD1 must be 32 bytes"));
+        }
+
+        let mut mad = Mad::new(sectors);
+        mad.info = mad1[1];
+        let stored = |data: &[u8], i: usize| u16::from_le_bytes([data[2 + 2 * i], data[3 + 2 * i]]);
+        for sector in 1..16.min(sectors as usize) {
+            mad.aids[sector] = stored(mad1, sector - 1);
+        }
+
+        if sectors > 16 {
+            let mad2 = mad2.ok_or_else(|| MifareError::new("Card has more than 16 sectors but no MAD2"))?;
+            if mad2.len() != 48 {
+                return Err(MifareError::new("MAD2 must be 48 bytes"));
+            }
+            for sector in 17..sectors as usize {
+                mad.aids[sector] = stored(mad2, sector - 17);
+            }
+        }
+
+        Ok(mad)
+    }
+
+    // Sector 0 blocks 1-2, with the CRC
+    pub fn encode_mad1(&self) -> [u8; 32] {
+        let mut data = [0u8; 32];
+        data[1] = self.info;
+        for sector in 1..16.min(self.aids.len()) {
+            data[2 * sector..2 * sector + 2].copy_from_slice(&self.aids[sector].to_le_bytes());
+        }
+        data[0] = crc8(&data[1..]);
+        data
+    }
+
+    // Sector 16 blocks 0-2, with the CRC, on cards that need MAD2
+    pub fn encode_mad2(&self) -> Option<[u8; 48]> {
+        if !self.is_mad2() {
+            return None;
+        }
+
+        let mut data = [0u8; 48];
+        data[1] = self.info;
+        for sector in 17..self.aids.len() {
+            let offset = 2 * (sector - 16);
+            data[offset..offset + 2].copy_from_slice(&self.aids[sector].to_le_bytes());
+        }
+        data[0] = crc8(&data[1..]);
+        Some(data)
+    }
+
+    pub fn aid(&self, sector: u8) -> Option<u16> {
+        if Mad::is_mad_sector(sector) {
+            return None;
+        }
+        self.aids.get(sector as usize).copied()
+    }
+
+    // Sectors of one application, in order
+    pub fn sectors_of(&self, aid: u16) -> Vec<u8> {
+        (0..self.aids.len() as u8).filter(|&sector| self.aid(sector) == Some(aid)).collect()
+    }
+
+    // Sector holding the card publisher information, if the info byte names one
+    pub fn card_publisher_sector(&self) -> Option<u8> {
+        match self.info & 0x3F {
+            0 => None,
+            sector => Some(sector),
+        }
+    }
+
+    pub fn set_card_publisher_sector(&mut self, sector: Option<u8>) -> Result<(), MifareError> {
+        let sector = sector.unwrap_or(0);
+        if sector != 0 && (Mad::is_mad_sector(sector) || sector as usize >= self.aids.len()) {
+            return Err(MifareError::new(&format!("Sector {} cannot hold the card publisher information", sector)));
+        }
+        self.info = (self.info & 0xC0) | sector;
+        Ok(())
+    }
+
+    // Assign the first `count` free sectors to `aid`, returning them
+    pub fn allocate(&mut self, aid: u16, count: usize) -> Result<Vec<u8>, MifareError> {
+        if aid == AID_FREE {
+            return Err(MifareError::new("Cannot allocate sectors to the free AID"));
+        }
+
+        let free = self.sectors_of(AID_FREE);
+        if free.len() < count {
+            return Err(MifareError::new(&format!("{} sectors requested, {} free", count, free.len())));
+        }
+        let sectors = free[..count].to_vec();
+        for &sector in &sectors {
+            self.aids[sector as usize] = aid;
+        }
+        Ok(sectors)
+    }
+
+    // Release every sector of `aid`, returning them
+    pub fn free(&mut self, aid: u16) -> Vec<u8> {
+        let sectors = self.sectors_of(aid);
+        for &sector in &sectors {
+            self.aids[sector as usize] = AID_FREE;
+        }
+        sectors
+    }
+}
+
+impl fmt::Display for Mad {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, info byte {:02X}", if self.is_mad2() { "MAD1 + MAD2" } else { "MAD1" }, self.info)?;
+        match self.card_publisher_sector() {
+            Some(sector) => writeln!(f, " (card publisher sector {})", sector)?,
+            None => writeln!(f, " (no card publisher sector)")?,
+        }
+
+        let sectors = (1..self.aids.len() as u8).filter(|&sector| !Mad::is_mad_sector(sector));
+        for (i, sector) in sectors.enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let aid = self.aids[sector as usize];
+            write!(f, "Sector {:02}: {:04X} {}", sector, aid, aid_name(aid))?;
+        }
+        Ok(())
+    }
+}
+
+// Reading and writing the MAD sectors
+impl MifareClassic<'_> {
+    // Read MAD1 and, for more than 16 sectors, MAD2, and decode them
+    fn read_mad_blocks(&self, sectors: u8, check_crcs: bool) -> Result<Mad, Box<dyn Error>> {
+        self.load_key(&MAD_KEY_A)?;
+        self.authenticate(0, KeyType::KeyA)?;
+        let mut mad1 = self.read_block(1)?;
+        mad1.extend(self.read_block(2)?);
+
+        let mad2 = if sectors > 16 {
+            self.authenticate(64, KeyType::KeyA)?;
+            let mut data = Vec::with_capacity(48);
+            for block in 64..67 {
+                data.extend(self.read_block(block)?);
+            }
+            Some(data)
+        } else {
+            None
+        };
+        if check_crcs {
+            Ok(Mad::decode(&mad1, mad2.as_deref(), sectors)?)
+        } else {
+            Ok(Mad::decode_unchecked(&mad1, mad2.as_deref(), sectors)?)
+        }
+    }
+
+    // Read the MAD with its public key A. `sectors` is 16 for a 1K card, 40
+    // for a 4K card.
+    pub fn read_mad(&self, sectors: u8) -> Result<Mad, Box<dyn Error>> {
+        self.read_mad_blocks(sectors, true)
+    }
+
+    // Read the MAD even if a CRC is wrong; writing it back repairs the CRC
+    pub fn read_mad_unchecked(&self, sectors: u8) -> Result<Mad, Box<dyn Error>> {
+        self.read_mad_blocks(sectors, false)
+    }
+
+    // MAD version from the sector 0 trailer, None when the card has no MAD
+    pub fn read_mad_version(&self) -> Result<Option<u8>, Box<dyn Error>> {
+        self.load_key(&MAD_KEY_A)?;
+        self.authenticate(0, KeyType::KeyA)?;
+        Ok(self.read_block(3)?.get(9).copied().and_then(mad_version))
+    }
+
+    // Write the MAD blocks with fresh CRCs, authenticating with `key` of the
+    // given type
+    pub fn write_mad(&self, mad: &Mad, key: &[u8; 6], key_type: KeyType) -> Result<(), Box<dyn Error>> {
+        let mad1 = mad.encode_mad1();
+        self.load_key(key)?;
+        self.authenticate(0, key_type)?;
+        self.write_block(1, &mad1[0..16])?;
+        self.write_block(2, &mad1[16..32])?;
+
+        if let Some(mad2) = mad.encode_mad2() {
+            self.authenticate(64, key_type)?;
+            for (i, block) in mad2.chunks(16).enumerate() {
+                self.write_block(64 + i as u8, block)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+use std::error::Error;
+use crate::classic::{KeyType, MifareClassic};
+use crate::crc::{crc_a, with_crc};
+use crate::error::MifareError;
+use crate::pn532::{Pn532, CIU_BIT_FRAMING, CIU_CRC_EN, CIU_RX_MODE, CIU_TX_MODE};
+use crate::transport::{RawFrame, RawTransceive};
+
+// "Magic" MIFARE Classic clones whose block 0 (UID, BCC, SAK, ATQA) can be
+// rewritten, unlike on genuine cards where it is locked at the factory
+
+// Generations of magic cards, by the way block 0 is unlocked
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MagicGeneration {
+    // Backdoor: 40 as a 7-bit frame, then 43, opens the card without
+    // authentication
+    Gen1a,
+    // "CUID": block 0 is written like any other block after authentication
+    Gen2,
+    // Proprietary 90 xx CC CC commands, recognised by the card's ATS
+    Gen3,
+    // "GTU" / Ultimate: CF-prefixed commands carrying a 4-byte password
+    Gen4,
+}
+
+impl MagicGeneration {
+    pub fn to_string(&self) -> &str {
+        match self {
+            MagicGeneration::Gen1a => "Gen1a (backdoor)",
+            MagicGeneration::Gen2 => "Gen2 (CUID, direct write)",
+            MagicGeneration::Gen3 => "Gen3 (APDU)",
+            MagicGeneration::Gen4 => "Gen4 (GTU)",
+        }
+    }
+}
+
+const HALT: [u8; 2] = [0x50, 0x00];
+const GEN1A_UNLOCK1: u8 = 0x40;
+const GEN1A_UNLOCK2: u8 = 0x43;
+const READ: u8 = 0x30;
+const WRITE: u8 = 0xA0;
+const ACK: u8 = 0x0A;
+
+// RATS with FSDI 256 bytes and CID 0, and the start of the ATS Gen3 cards
+// answer it with
+const RATS: [u8; 2] = [0xE0, 0x80];
+const GEN3_ATS: [u8; 9] = [0x09, 0x78, 0x00, 0x91, 0x02, 0xDA, 0xBC, 0x19, 0x10];
+const GEN3_WRITE_BLOCK0: [u8; 5] = [0x90, 0xF0, 0xCC, 0xCC, 0x10];
+const GEN3_OK: [u8; 2] = [0x90, 0x00];
+
+// Gen4 commands: CF, password, command code, parameters
+const GEN4_PREFIX: u8 = 0xCF;
+const GEN4_SET_ATQA_SAK: u8 = 0x35;
+const GEN4_GET_CONFIG: u8 = 0xC6;
+const GEN4_WRITE_BLOCK: u8 = 0xCD;
+const GEN4_READ_BLOCK: u8 = 0xCE;
+
+// Password of Gen4 cards as shipped
+pub const GEN4_DEFAULT_PASSWORD: [u8; 4] = [0x00; 4];
+
+// Block Check Character of a 4-byte UID
+pub fn bcc(uid: &[u8]) -> u8 {
+    uid.iter().fold(0, |acc, &byte| acc ^ byte)
+}
+
+// Manufacturer block of a card with a 4-byte UID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block0 {
+    pub uid: [u8; 4],
+    pub sak: u8,
+    // In the order the PN532 reports it (00 04 for Classic 1K); block 0
+    // stores it low byte first
+    pub atqa: [u8; 2],
+    pub manufacturer: [u8; 8],
+}
+
+impl Block0 {
+    // Decode block 0, refusing a wrong BCC: a card answering with it cannot
+    // be selected any more
+    pub fn decode(data: &[u8]) -> Result<Block0, MifareError> {
+        if data.len() != 16 {
+            return Err(MifareError::new("Block 0 must be 16 bytes"));
+        }
+        if bcc(&data[0..4]) != data[4] {
+            return Err(MifareError::new(&format!("Wrong BCC in block 0: {:02X} instead of {:02X}", data[4], bcc(&data[0..4]))));
+        }
+
+        let mut block = Block0 { uid: [0; 4], sak: data[5], atqa: [data[7], data[6]], manufacturer: [0; 8] };
+        block.uid.copy_from_slice(&data[0..4]);
+        block.manufacturer.copy_from_slice(&data[8..16]);
+        Ok(block)
+    }
+
+    pub fn encode(&self) -> [u8; 16] {
+        let mut data = [0u8; 16];
+        data[0..4].copy_from_slice(&self.uid);
+        data[4] = bcc(&self.uid);
+        data[5] = self.sak;
+        data[6] = self.atqa[1];
+        data[7] = self.atqa[0];
+        data[8..16].copy_from_slice(&self.manufacturer);
+        data
+    }
+}
+
+fn check_ack(response: &[u8], command: &str) -> Result<(), MifareError> {
+    match response {
+        [] => Ok(()),
+        [ack] if ack & 0x0F == ACK => Ok(()),
+        _ => Err(MifareError::new(&format!("{} not acknowledged", command))),
+    }
+}
+
+// Exchange a frame with the PN532's CRC handling off: append the CRC and
+// check and strip the one of responses longer than an ACK
+fn exchange_raw(pn532: &Pn532, frame: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let response = pn532.communicate_thru(&with_crc(frame))?;
+    if response.len() <= 2 {
+        return Ok(response);
+    }
+
+    let (payload, crc) = response.split_at(response.len() - 2);
+    if crc_a(payload) != crc {
+        return Err(Box::new(MifareError::new("Wrong CRC in card response")));
+    }
+    Ok(payload.to_vec())
+}
+
+// The same over a raw link, where the CRC is always ours; a 4-bit answer is
+// returned as it is
+fn exchange_link(link: &dyn RawTransceive, frame: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let answer = link.transceive_raw(&RawFrame::plain(&with_crc(frame)))?;
+    if answer.is_nibble() {
+        return Ok(answer.data);
+    }
+
+    let (payload, crc) = answer.data.split_at(answer.data.len().saturating_sub(2));
+    if crc_a(payload) != crc {
+        return Err(Box::new(MifareError::new("Wrong CRC in card response")));
+    }
+    Ok(payload.to_vec())
+}
+
+// Rewriting block 0 of magic cards. These work on the card the reader
+// activated, or on the card at the other end of a raw link; `key` is key A
+// of sector 0, which Gen2 cards need.
+impl MifareClassic<'_> {
+    // Activate the card again after a probe left it halted or in ISO 14443-4
+    fn reselect(&self) -> Result<(), Box<dyn Error>> {
+        self.reactivate()
+    }
+
+    // Send a frame outside the MIFARE commands, its CRC added and checked
+    fn transceive_frame(&self, frame: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self.raw_link() {
+            Some(link) => exchange_link(link, frame),
+            None => self.pn532()?.communicate_thru(frame),
+        }
+    }
+
+    // Send a frame once the Gen1a backdoor is open
+    fn backdoor_exchange(&self, frame: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self.raw_link() {
+            Some(link) => exchange_link(link, frame),
+            None => exchange_raw(&self.pn532()?, frame),
+        }
+    }
+
+    // Open a Gen1a card through its backdoor and run `operation` with the
+    // CRC handling off, restoring the PN532 registers afterwards
+    fn with_gen1a_backdoor<T>(&self, operation: impl FnOnce() -> Result<T, Box<dyn Error>>) -> Result<T, Box<dyn Error>> {
+        if let Some(link) = self.raw_link() {
+            // HALT gets no answer
+            let _ = link.transceive_raw(&RawFrame::plain(&with_crc(&HALT)));
+            check_ack(&link.transceive_raw(&RawFrame::short(GEN1A_UNLOCK1))?.data, "Gen1a unlock 40")?;
+            check_ack(&link.transceive_raw(&RawFrame::plain(&[GEN1A_UNLOCK2]))?.data, "Gen1a unlock 43")?;
+            return operation();
+        }
+
+        let pn532 = self.pn532()?;
+        let modes = pn532.read_registers(&[CIU_TX_MODE, CIU_RX_MODE])?;
+        pn532.write_registers(&[(CIU_TX_MODE, modes[0] & !CIU_CRC_EN), (CIU_RX_MODE, modes[1] & !CIU_CRC_EN)])?;
+
+        let result = (|| {
+            // HALT gets no answer
+            let _ = exchange_raw(&pn532, &HALT);
+
+            pn532.write_registers(&[(CIU_BIT_FRAMING, 0x07)])?;
+            let unlock1 = pn532.communicate_thru(&[GEN1A_UNLOCK1]);
+            pn532.write_registers(&[(CIU_BIT_FRAMING, 0x00)])?;
+            check_ack(&unlock1?, "Gen1a unlock 40")?;
+            check_ack(&pn532.communicate_thru(&[GEN1A_UNLOCK2])?, "Gen1a unlock 43")?;
+
+            operation()
+        })();
+
+        pn532.write_registers(&[(CIU_TX_MODE, modes[0]), (CIU_RX_MODE, modes[1]), (CIU_BIT_FRAMING, 0x00)])?;
+        result
+    }
+
+    fn gen4_command(&self, code: u8, params: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut frame = vec![GEN4_PREFIX];
+        frame.extend_from_slice(&GEN4_DEFAULT_PASSWORD);
+        frame.push(code);
+        frame.extend_from_slice(params);
+        self.transceive_frame(&frame)
+    }
+
+    fn authenticate_block0(&self, key: &[u8; 6]) -> Result<(), Box<dyn Error>> {
+        self.load_key(key)?;
+        self.authenticate(0, KeyType::KeyA)
+    }
+
+    fn is_gen2(&self, key: &[u8; 6]) -> Result<bool, Box<dyn Error>> {
+        // Writing block 0 back unchanged: genuine cards refuse, Gen2 cards
+        // accept without any change
+        self.authenticate_block0(key)?;
+        let block = self.read_block(0)?;
+        Ok(self.write_block(0, &block).is_ok())
+    }
+
+    // Which kind of magic card this is, None for a genuine card. Gen4 is
+    // only recognised with its default password.
+    pub fn detect_magic(&self, key: &[u8; 6]) -> Result<Option<MagicGeneration>, Box<dyn Error>> {
+        let gen1a = self.with_gen1a_backdoor(|| Ok(())).is_ok();
+        self.reselect()?;
+        if gen1a {
+            return Ok(Some(MagicGeneration::Gen1a));
+        }
+
+        let gen4 = self.gen4_command(GEN4_GET_CONFIG, &[]).is_ok_and(|config| config.len() >= 30);
+        self.reselect()?;
+        if gen4 {
+            return Ok(Some(MagicGeneration::Gen4));
+        }
+
+        let gen3 = self.transceive_frame(&RATS).is_ok_and(|ats| ats.starts_with(&GEN3_ATS));
+        self.reselect()?;
+        if gen3 {
+            return Ok(Some(MagicGeneration::Gen3));
+        }
+
+        let gen2 = self.is_gen2(key).unwrap_or(false);
+        self.reselect()?;
+        Ok(gen2.then_some(MagicGeneration::Gen2))
+    }
+
+    fn detect_magic_or_refuse(&self, key: &[u8; 6]) -> Result<MagicGeneration, Box<dyn Error>> {
+        self.detect_magic(key)?.ok_or_else(|| {
+            Box::new(MifareError::new("Not a magic card: block 0 of a genuine card cannot be changed")) as Box<dyn Error>
+        })
+    }
+
+    fn read_block0_as(&self, generation: MagicGeneration, key: &[u8; 6]) -> Result<Block0, Box<dyn Error>> {
+        let data = match generation {
+            MagicGeneration::Gen1a => self.with_gen1a_backdoor(|| self.backdoor_exchange(&[READ, 0x00]))?,
+            MagicGeneration::Gen4 => self.gen4_command(GEN4_READ_BLOCK, &[0x00])?,
+            MagicGeneration::Gen2 | MagicGeneration::Gen3 => {
+                self.authenticate_block0(key)?;
+                self.read_block(0)?
+            },
+        };
+        Ok(Block0::decode(&data)?)
+    }
+
+    fn write_block0_as(&self, generation: MagicGeneration, block: &Block0, key: &[u8; 6]) -> Result<(), Box<dyn Error>> {
+        let data = block.encode();
+        match generation {
+            MagicGeneration::Gen1a => self.with_gen1a_backdoor(|| {
+                check_ack(&self.backdoor_exchange(&[WRITE, 0x00])?, "Write block 0")?;
+                check_ack(&self.backdoor_exchange(&data)?, "Block 0 data")?;
+                Ok(())
+            }),
+            MagicGeneration::Gen2 => {
+                self.authenticate_block0(key)?;
+                self.write_block(0, &data)
+            },
+            MagicGeneration::Gen3 => {
+                let mut frame = GEN3_WRITE_BLOCK0.to_vec();
+                frame.extend_from_slice(&data);
+                if self.transceive_frame(&frame)? != GEN3_OK {
+                    return Err(Box::new(MifareError::new("Gen3 block 0 write refused")));
+                }
+                Ok(())
+            },
+            MagicGeneration::Gen4 => {
+                let mut params = vec![0x00];
+                params.extend_from_slice(&data);
+                check_ack(&self.gen4_command(GEN4_WRITE_BLOCK, &params)?, "Gen4 write block 0")?;
+                Ok(())
+            },
+        }
+    }
+
+    // Read block 0, through the backdoor where the card has one
+    pub fn read_magic_block0(&self, key: &[u8; 6]) -> Result<Block0, Box<dyn Error>> {
+        let generation = self.detect_magic_or_refuse(key)?;
+        self.read_block0_as(generation, key)
+    }
+
+    // Write a whole block 0, its BCC computed from the UID
+    pub fn write_magic_block0(&self, block: &Block0, key: &[u8; 6]) -> Result<MagicGeneration, Box<dyn Error>> {
+        let generation = self.detect_magic_or_refuse(key)?;
+        self.write_block0_as(generation, block, key)?;
+        Ok(generation)
+    }
+
+    // Change the UID, keeping the rest of block 0
+    pub fn set_uid(&self, uid: [u8; 4], key: &[u8; 6]) -> Result<MagicGeneration, Box<dyn Error>> {
+        let generation = self.detect_magic_or_refuse(key)?;
+        let block = Block0 { uid, ..self.read_block0_as(generation, key)? };
+        self.write_block0_as(generation, &block, key)?;
+        Ok(generation)
+    }
+
+    // Change SAK and ATQA. Gen4 cards answer with the values of their
+    // configuration, the others with those of block 0.
+    pub fn set_sak_atqa(&self, sak: u8, atqa: [u8; 2], key: &[u8; 6]) -> Result<MagicGeneration, Box<dyn Error>> {
+        let generation = self.detect_magic_or_refuse(key)?;
+        let block = Block0 { sak, atqa, ..self.read_block0_as(generation, key)? };
+        self.write_block0_as(generation, &block, key)?;
+        if generation == MagicGeneration::Gen4 {
+            check_ack(&self.gen4_command(GEN4_SET_ATQA_SAK, &[atqa[0], atqa[1], sak])?, "Gen4 set ATQA/SAK")?;
+        }
+        Ok(generation)
+    }
+}
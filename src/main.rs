@@ -1,6 +1,9 @@
 use std::time::Duration;
 use std::thread;
 use pcsc::{Context, Scope, ShareMode, Protocols, Error};
+use acr122u_test::card_info::CardInfo;
+use acr122u_test::format_hex;
+use acr122u_test::pn532::{Pn532, MAX_TARGETS};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("TokenFlow ACR122U Test");
@@ -40,7 +43,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Waiting for cards... (place card on reader and hold it steady)");
     println!("Press Ctrl+C to quit");
     
-    // Keep track of last detected UID to avoid repeats
+    // Keep track of last detected UIDs to avoid repeats
     let mut last_uid = String::new();
     
     // Main loop
@@ -53,43 +56,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 // Give the card a moment to stabilize
                 thread::sleep(Duration::from_millis(100));
                 
-                // APDU command to get UID
-                let get_uid = [0xFF, 0xCA, 0x00, 0x00, 0x00];
-                
-                // Prepare receive buffer
-                let mut recv_buffer = [0; 256];
-                
-                // Transmit command
-                match card.transmit(&get_uid, &mut recv_buffer) {
-                    Ok(response) => {
-                        if response.len() >= 2 {
-                            // Check for success (ends with 9000)
-                            if response[response.len()-2] == 0x90 && response[response.len()-1] == 0x00 {
-                                // Extract UID (excluding status bytes)
-                                let uid = &response[0..response.len()-2];
-                                
-                                // Format UID as hex
-                                let uid_str = uid.iter()
-                                    .map(|b| format!("{:02X}", b))
-                                    .collect::<Vec<String>>()
-                                    .join("");
-                                    
-                                // Only print if UID is different from last one
-                                if uid_str != last_uid {
-                                    println!("Card UID: {}", uid_str);
-                                    println!("Token ID: ACR122-{}", uid_str);
-                                    last_uid = uid_str;
+                // Run anti-collision so every card in the field is reported
+                match Pn532::new(&card).list_passive_targets(MAX_TARGETS) {
+                    Ok(targets) if !targets.is_empty() => {
+                        let uid_str = targets.iter()
+                            .map(|t| format_hex(&t.uid))
+                            .collect::<Vec<String>>()
+                            .join(",");
+
+                        // Only print if the set of UIDs is different from last one
+                        if uid_str != last_uid {
+                            if targets.len() > 1 {
+                                println!("{} cards in the field", targets.len());
+                            }
+                            let atr = card.status2_owned().map(|s| s.atr().to_vec()).unwrap_or_default();
+                            for target in &targets {
+                                println!("Target {}:", target.tg);
+                                let info = CardInfo::from_target(target, &atr);
+                                for line in info.to_string().lines() {
+                                    println!("  {}", line);
                                 }
-                            } else {
-                                println!("Error reading card. Status bytes: {:02X} {:02X}", 
-                                         response[response.len()-2], 
-                                         response[response.len()-1]);
+                                println!("  Token ID: ACR122-{}", format_hex(&info.uid));
                             }
-                        } else {
-                            println!("Invalid response length: {}", response.len());
+                            last_uid = uid_str;
                         }
                     },
-                    Err(e) => println!("Transmit error: {}", e),
+                    Ok(_) => println!("No target answered anti-collision"),
+                    Err(e) => println!("Anti-collision error: {}", e),
                 }
                 
                 // Disconnect from the card properly
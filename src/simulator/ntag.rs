@@ -0,0 +1,277 @@
+use std::cell::RefCell;
+use std::error::Error;
+use crate::error::MifareError;
+use crate::ntag::{attempt_limit, AccessConfig, Protection, PWD_AUTH};
+use crate::transport::Transceive;
+use crate::ultralight::{UltralightModel, ACK, COMPAT_WRITE, FAST_READ, GET_VERSION, LockBits, READ, READ_CNT, READ_SIG, WRITE};
+use super::{set_bits, NAK_AUTH_LIMIT, NAK_INVALID_ARGUMENT};
+
+struct State {
+    memory: Vec<u8>,
+    signature: [u8; 32],
+    // PWD_AUTH succeeded since the last activation
+    authenticated: bool,
+    // Consecutive failed PWD_AUTH, kept across activations like the real counter
+    failed_attempts: u32,
+    // A NAK sends the tag back to IDLE, where it ignores everything
+    halted: bool,
+    nfc_counter: u32,
+    // The NFC counter counts the first read after each activation
+    counted: bool,
+}
+
+// In-memory Ultralight EV1 / NTAG21x tag, answering the command set the way
+// the datasheets describe: lock bits, OTP, AUTH0/PROT, AUTHLIM lockout and
+// CFGLCK. PWD and PACK read as zeros, as on a real tag.
+pub struct NtagSimulator {
+    model: UltralightModel,
+    state: RefCell<State>,
+}
+
+impl NtagSimulator {
+    // A tag in its factory state: no lock bits set, password FFFFFFFF and
+    // protection disabled
+    pub fn new(model: UltralightModel, uid: &[u8; 7]) -> Result<NtagSimulator, MifareError> {
+        let config = model.config_page()
+            .ok_or_else(|| MifareError::new(&format!("{} is not simulated", model.to_string())))? as usize;
+
+        let mut memory = vec![0u8; model.page_count() * 4];
+        // UID with its two check bytes (cascade tag 88)
+        memory[0..3].copy_from_slice(&uid[0..3]);
+        memory[3] = 0x88 ^ uid[0] ^ uid[1] ^ uid[2];
+        memory[4..8].copy_from_slice(&uid[3..7]);
+        memory[8] = uid[3] ^ uid[4] ^ uid[5] ^ uid[6];
+        memory[9] = 0x48;
+
+        // Capability container NXP programs at the factory (datasheet values)
+        let cc_size = match model {
+            UltralightModel::Ntag213 => Some(0x12),
+            UltralightModel::Ntag215 => Some(0x3E),
+            UltralightModel::Ntag216 => Some(0x6D),
+            _ => None,
+        };
+        if let Some(size) = cc_size {
+            memory[12..16].copy_from_slice(&[0xE1, 0x10, size, 0x00]);
+        }
+
+        memory[config * 4 + 3] = 0xFF;
+        memory[(config + 2) * 4..(config + 3) * 4].copy_from_slice(&[0xFF; 4]);
+
+        Ok(NtagSimulator {
+            model,
+            state: RefCell::new(State {
+                memory,
+                signature: [0x00; 32],
+                authenticated: false,
+                failed_attempts: 0,
+                halted: false,
+                nfc_counter: 0,
+                counted: false,
+            }),
+        })
+    }
+
+    pub fn with_signature(self, signature: [u8; 32]) -> NtagSimulator {
+        self.state.borrow_mut().signature = signature;
+        self
+    }
+
+    // Raw memory, including PWD and PACK
+    pub fn memory(&self) -> Vec<u8> {
+        self.state.borrow().memory.clone()
+    }
+
+    pub fn failed_attempts(&self) -> u32 {
+        self.state.borrow().failed_attempts
+    }
+
+    fn version(&self) -> [u8; 8] {
+        let (product, subtype, size) = match self.model {
+            UltralightModel::UltralightEv1_11 => (0x03, 0x01, 0x0B),
+            UltralightModel::UltralightEv1_21 => (0x03, 0x01, 0x0E),
+            UltralightModel::Ntag210 => (0x04, 0x01, 0x0B),
+            UltralightModel::Ntag212 => (0x04, 0x01, 0x0E),
+            UltralightModel::Ntag213 => (0x04, 0x02, 0x0F),
+            UltralightModel::Ntag215 => (0x04, 0x02, 0x11),
+            _ => (0x04, 0x02, 0x13),
+        };
+        [0x00, 0x04, product, subtype, 0x01, 0x00, size, 0x03]
+    }
+
+    fn config_page(&self) -> usize {
+        self.model.config_page().unwrap_or_default() as usize
+    }
+
+    fn access(&self, state: &State) -> AccessConfig {
+        let cfg = self.config_page() * 4;
+        let page = |offset: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&state.memory[offset..offset + 4]);
+            bytes
+        };
+        AccessConfig::decode(page(cfg), page(cfg + 4))
+    }
+
+    fn locks(&self, state: &State) -> LockBits {
+        let dynamic_lock = self.model.dynamic_lock_page().map(|page| {
+            let offset = page as usize * 4;
+            [state.memory[offset], state.memory[offset + 1], state.memory[offset + 2]]
+        });
+        LockBits {
+            static_lock: [state.memory[10], state.memory[11]],
+            dynamic_lock,
+            otp: [state.memory[12], state.memory[13], state.memory[14], state.memory[15]],
+        }
+    }
+
+    fn read_protected(&self, state: &State, page: usize) -> bool {
+        let access = self.access(state);
+        !state.authenticated && access.protection == Protection::ReadWrite && page >= access.auth0 as usize
+    }
+
+    // Page contents as the card returns them: PWD and PACK never leave the tag
+    fn page_data(&self, state: &State, page: usize) -> [u8; 4] {
+        let config = self.config_page();
+        if page == config + 2 || page == config + 3 || self.read_protected(state, page) {
+            return [0x00; 4];
+        }
+
+        let mut data = [0u8; 4];
+        data.copy_from_slice(&state.memory[page * 4..page * 4 + 4]);
+        data
+    }
+
+    fn count_read(&self, state: &mut State) {
+        if !state.counted && self.access(state).nfc_counter {
+            state.nfc_counter = (state.nfc_counter + 1).min(0xFF_FFFF);
+            state.counted = true;
+        }
+    }
+
+    fn read(&self, state: &mut State, page: usize) -> Vec<u8> {
+        let pages = self.model.page_count();
+        if page >= pages || self.read_protected(state, page) {
+            return vec![NAK_INVALID_ARGUMENT];
+        }
+
+        self.count_read(state);
+        // Roll over at the end of memory
+        (0..4).flat_map(|i| self.page_data(state, (page + i) % pages)).collect()
+    }
+
+    fn fast_read(&self, state: &mut State, start: usize, end: usize) -> Vec<u8> {
+        if start > end || end >= self.model.page_count() || (start..=end).any(|page| self.read_protected(state, page)) {
+            return vec![NAK_INVALID_ARGUMENT];
+        }
+
+        self.count_read(state);
+        (start..=end).flat_map(|page| self.page_data(state, page)).collect()
+    }
+
+    fn write(&self, state: &mut State, page: usize, data: &[u8]) -> Vec<u8> {
+        let config = self.config_page();
+        let access = self.access(state);
+        let locks = self.locks(state);
+        let offset = page * 4;
+
+        if page < 2 || page >= self.model.page_count() {
+            return vec![NAK_INVALID_ARGUMENT];
+        }
+        if !state.authenticated && page >= access.auth0 as usize {
+            return vec![NAK_INVALID_ARGUMENT];
+        }
+
+        match page {
+            // Lock and OTP bits can only be set
+            2 => {
+                state.memory[offset + 2] |= data[2];
+                state.memory[offset + 3] |= data[3];
+            },
+            3 if locks.otp_locked() => return vec![NAK_INVALID_ARGUMENT],
+            3 => set_bits(&mut state.memory[offset..offset + 4], data),
+            _ if Some(page as u8) == self.model.dynamic_lock_page() => {
+                set_bits(&mut state.memory[offset..offset + 3], &data[0..3]);
+            },
+            _ if page == config || page == config + 1 => {
+                if access.cfglck {
+                    return vec![NAK_INVALID_ARGUMENT];
+                }
+                state.memory[offset..offset + 4].copy_from_slice(data);
+            },
+            _ if locks.is_page_locked(self.model, page as u8) => return vec![NAK_INVALID_ARGUMENT],
+            _ => state.memory[offset..offset + 4].copy_from_slice(data),
+        }
+
+        vec![ACK]
+    }
+
+    fn pwd_auth(&self, state: &mut State, password: &[u8]) -> Vec<u8> {
+        let access = self.access(state);
+        let limit = attempt_limit(self.model, access.authlim);
+        if limit.is_some_and(|limit| state.failed_attempts >= limit) {
+            return vec![NAK_AUTH_LIMIT];
+        }
+
+        let pwd = (self.config_page() + 2) * 4;
+        if state.memory[pwd..pwd + 4] != *password {
+            state.failed_attempts += 1;
+            return vec![NAK_AUTH_LIMIT];
+        }
+
+        state.failed_attempts = 0;
+        state.authenticated = true;
+        state.memory[pwd + 4..pwd + 6].to_vec()
+    }
+
+    fn nak(&self, state: &mut State, response: Vec<u8>) -> Vec<u8> {
+        if response.len() == 1 && response[0] != ACK {
+            state.halted = true;
+        }
+        response
+    }
+}
+
+impl Transceive for NtagSimulator {
+    fn transceive(&self, frame: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut state = self.state.borrow_mut();
+        if state.halted {
+            return Err(Box::new(MifareError::new("No response from the tag (halted)")));
+        }
+
+        let state = &mut *state;
+        let response = match frame {
+            [GET_VERSION] => self.version().to_vec(),
+            [READ, page] => self.read(state, *page as usize),
+            [FAST_READ, start, end] => self.fast_read(state, *start as usize, *end as usize),
+            [WRITE, page, data @ ..] if data.len() == 4 => self.write(state, *page as usize, data),
+            [COMPAT_WRITE, page, data @ ..] if data.len() == 16 => self.write(state, *page as usize, &data[0..4]),
+            // Ultralight EV1 has three counters, which the simulator keeps at zero
+            [READ_CNT, counter] if self.model.counters().len() == 3 => {
+                if *counter <= 2 { vec![0x00; 3] } else { vec![NAK_INVALID_ARGUMENT] }
+            },
+            [READ_CNT, counter] => {
+                let access = self.access(state);
+                let counter_allowed = *counter == 2 && access.nfc_counter && (!access.nfc_counter_protected || state.authenticated);
+                if counter_allowed {
+                    state.nfc_counter.to_le_bytes()[0..3].to_vec()
+                } else {
+                    vec![NAK_INVALID_ARGUMENT]
+                }
+            },
+            [READ_SIG, 0x00] => state.signature.to_vec(),
+            [PWD_AUTH, password @ ..] if password.len() == 4 => self.pwd_auth(state, password),
+            _ => vec![NAK_INVALID_ARGUMENT],
+        };
+
+        Ok(self.nak(state, response))
+    }
+
+    // A new activation drops the authentication
+    fn reactivate(&self) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.borrow_mut();
+        state.halted = false;
+        state.authenticated = false;
+        state.counted = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+use std::cell::RefCell;
+use std::error::Error;
+use crate::error::MifareError;
+use crate::ntag::Protection;
+use crate::transport::Transceive;
+use crate::ultralight::{UltralightModel, ACK, COMPAT_WRITE, LockBits, READ, WRITE};
+use crate::ultralight_c::{self, key_pages, AuthConfig, AUTH0_PAGE, AUTH1_PAGE, AUTHENTICATE, AUTHENTICATE_PART2, DEFAULT_KEY, KEY_PAGE};
+use super::{set_bits, NAK_INVALID_ARGUMENT};
+
+struct UltralightCState {
+    memory: Vec<u8>,
+    authenticated: bool,
+    // RndB and ek(RndB) between the two AUTHENTICATE steps
+    challenge: Option<([u8; 8], [u8; 8])>,
+    halted: bool,
+}
+
+// In-memory Ultralight C: lock bits, OTP, AUTH0/AUTH1 and the 3DES mutual
+// authentication. The key pages are write-only, as on a real card.
+pub struct UltralightCSimulator {
+    rnd_b: [u8; 8],
+    state: RefCell<UltralightCState>,
+}
+
+impl UltralightCSimulator {
+    // A card in its factory state: default key, authentication disabled
+    pub fn new(uid: &[u8; 7]) -> UltralightCSimulator {
+        let model = UltralightModel::UltralightC;
+        let mut memory = vec![0u8; model.page_count() * 4];
+        memory[0..3].copy_from_slice(&uid[0..3]);
+        memory[3] = 0x88 ^ uid[0] ^ uid[1] ^ uid[2];
+        memory[4..8].copy_from_slice(&uid[3..7]);
+        memory[8] = uid[3] ^ uid[4] ^ uid[5] ^ uid[6];
+
+        memory[AUTH0_PAGE as usize * 4] = 0x30;
+        for (i, page) in key_pages(&DEFAULT_KEY).iter().enumerate() {
+            let offset = (KEY_PAGE as usize + i) * 4;
+            memory[offset..offset + 4].copy_from_slice(page);
+        }
+
+        UltralightCSimulator {
+            rnd_b: [0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+            state: RefCell::new(UltralightCState { memory, authenticated: false, challenge: None, halted: false }),
+        }
+    }
+
+    // RndB the card uses, normally random
+    pub fn with_rnd_b(self, rnd_b: [u8; 8]) -> UltralightCSimulator {
+        UltralightCSimulator { rnd_b, ..self }
+    }
+
+    // Raw memory, including the key pages
+    pub fn memory(&self) -> Vec<u8> {
+        self.state.borrow().memory.clone()
+    }
+
+    // Key as the card uses it, rebuilt from pages 2C-2F
+    fn key(&self, state: &UltralightCState) -> [u8; 16] {
+        let stored = &state.memory[KEY_PAGE as usize * 4..KEY_PAGE as usize * 4 + 16];
+        let mut key = [0u8; 16];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = stored[(i / 8) * 8 + 7 - i % 8];
+        }
+        key
+    }
+
+    fn access(&self, state: &UltralightCState) -> AuthConfig {
+        AuthConfig::decode(state.memory[AUTH0_PAGE as usize * 4], state.memory[AUTH1_PAGE as usize * 4])
+    }
+
+    fn read(&self, state: &UltralightCState, page: usize) -> Vec<u8> {
+        let access = self.access(state);
+        let read_protected = !state.authenticated && access.protection == Protection::ReadWrite && page >= access.auth0 as usize;
+        // The key pages cannot be read at all
+        if page >= KEY_PAGE as usize || read_protected {
+            return vec![NAK_INVALID_ARGUMENT];
+        }
+
+        // Roll over at the end of the readable memory
+        (0..4).flat_map(|i| {
+            let offset = (page + i) % KEY_PAGE as usize * 4;
+            state.memory[offset..offset + 4].to_vec()
+        }).collect()
+    }
+
+    fn write(&self, state: &mut UltralightCState, page: usize, data: &[u8]) -> Vec<u8> {
+        let model = UltralightModel::UltralightC;
+        let access = self.access(state);
+        let offset = page * 4;
+
+        if page < 2 || page >= model.page_count() {
+            return vec![NAK_INVALID_ARGUMENT];
+        }
+        if !state.authenticated && page >= access.auth0 as usize {
+            return vec![NAK_INVALID_ARGUMENT];
+        }
+
+        let locks = LockBits {
+            static_lock: [state.memory[10], state.memory[11]],
+            dynamic_lock: Some([state.memory[0xA0], state.memory[0xA1], 0x00]),
+            otp: [state.memory[12], state.memory[13], state.memory[14], state.memory[15]],
+        };
+        match page {
+            2 => {
+                state.memory[offset + 2] |= data[2];
+                state.memory[offset + 3] |= data[3];
+            },
+            3 if locks.otp_locked() => return vec![NAK_INVALID_ARGUMENT],
+            3 => set_bits(&mut state.memory[offset..offset + 4], data),
+            0x28 => set_bits(&mut state.memory[offset..offset + 2], &data[0..2]),
+            _ if locks.is_page_locked(model, page as u8) => return vec![NAK_INVALID_ARGUMENT],
+            _ => state.memory[offset..offset + 4].copy_from_slice(data),
+        }
+
+        vec![ACK]
+    }
+
+    fn authenticate(&self, state: &mut UltralightCState) -> Vec<u8> {
+        let key = self.key(state);
+        let mut ek_rnd_b = [0u8; 8];
+        ek_rnd_b.copy_from_slice(&ultralight_c::encrypt(&key, &[0x00; 8], &self.rnd_b));
+        state.authenticated = false;
+        state.challenge = Some((self.rnd_b, ek_rnd_b));
+
+        let mut response = vec![AUTHENTICATE_PART2];
+        response.extend_from_slice(&ek_rnd_b);
+        response
+    }
+
+    fn authenticate_part2(&self, state: &mut UltralightCState, token: &[u8]) -> Vec<u8> {
+        let (rnd_b, ek_rnd_b) = match state.challenge.take() {
+            Some(challenge) => challenge,
+            None => return vec![NAK_INVALID_ARGUMENT],
+        };
+
+        let key = self.key(state);
+        let plain = ultralight_c::decrypt(&key, &ek_rnd_b, token);
+        if plain[8..16] != ultralight_c::rotate(&rnd_b) {
+            return vec![NAK_INVALID_ARGUMENT];
+        }
+
+        let mut rnd_a = [0u8; 8];
+        rnd_a.copy_from_slice(&plain[0..8]);
+        let mut iv = [0u8; 8];
+        iv.copy_from_slice(&token[8..16]);
+        state.authenticated = true;
+
+        let mut response = vec![0x00];
+        response.extend_from_slice(&ultralight_c::encrypt(&key, &iv, &ultralight_c::rotate(&rnd_a)));
+        response
+    }
+}
+
+impl Transceive for UltralightCSimulator {
+    fn transceive(&self, frame: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut state = self.state.borrow_mut();
+        if state.halted {
+            return Err(Box::new(MifareError::new("No response from the tag (halted)")));
+        }
+
+        let state = &mut *state;
+        let response = match frame {
+            [READ, page] => self.read(state, *page as usize),
+            [WRITE, page, data @ ..] if data.len() == 4 => self.write(state, *page as usize, data),
+            [COMPAT_WRITE, page, data @ ..] if data.len() == 16 => self.write(state, *page as usize, &data[0..4]),
+            [AUTHENTICATE, 0x00] => self.authenticate(state),
+            [AUTHENTICATE_PART2, token @ ..] if token.len() == 16 => self.authenticate_part2(state, token),
+            _ => vec![NAK_INVALID_ARGUMENT],
+        };
+
+        if response.len() == 1 && response[0] != ACK {
+            state.halted = true;
+        }
+        Ok(response)
+    }
+
+    fn reactivate(&self) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.borrow_mut();
+        state.halted = false;
+        state.authenticated = false;
+        state.challenge = None;
+        Ok(())
+    }
+}
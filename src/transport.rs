@@ -0,0 +1,239 @@
+use std::cell::Cell;
+use std::error::Error;
+use pcsc::Card;
+use crate::crypto1::plain_parity;
+use crate::pn532::{Pn532, CIU_BIT_FRAMING, CIU_CONTROL, CIU_CRC_EN, CIU_MANUAL_RCV, CIU_MF_CRYPTO1_ON, CIU_PARITY_DISABLE, CIU_RX_MODE, CIU_STATUS2, CIU_TX_MODE};
+
+// Exchange of ISO/IEC 14443-3 frames with a card, CRC handled by the transport,
+// so card handlers run the same against the reader and a simulator. A MIFARE
+// (compatibility) write is sent as one frame: A0, address, 16 data bytes.
+// A 4-bit ACK may come back as an empty response or as the single byte 0A.
+pub trait Transceive {
+    fn transceive(&self, frame: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    // Bring the card back to ACTIVE after a NAK or a failed authentication
+    // left it halted
+    fn reactivate(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+// ISO 7816-4 APDUs for ISO/IEC 14443-4 cards, which the reader carries
+// over ISO-DEP itself; the answer ends with the two status bytes
+pub trait ApduTransceive {
+    fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>;
+}
+
+impl ApduTransceive for Card {
+    fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut recv_buffer = [0; pcsc::MAX_BUFFER_SIZE];
+        Ok(Card::transmit(self, apdu, &mut recv_buffer)?.to_vec())
+    }
+}
+
+impl<T: ApduTransceive + ?Sized> ApduTransceive for &T {
+    fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        (**self).transmit(apdu)
+    }
+}
+
+// Frame at the bit level, for Crypto1 traffic where the parity bits are
+// encrypted too: the bytes as sent (CRC included), one parity bit per byte,
+// and the number of bits, 4 for an ACK or NAK and 7 for a short frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFrame {
+    pub data: Vec<u8>,
+    pub parity: Vec<u8>,
+    pub bits: usize,
+}
+
+impl RawFrame {
+    pub fn new(data: Vec<u8>, parity: Vec<u8>) -> RawFrame {
+        let bits = data.len() * 8;
+        RawFrame { data, parity, bits }
+    }
+
+    // Unencrypted frame with the usual parity bits
+    pub fn plain(data: &[u8]) -> RawFrame {
+        RawFrame::new(data.to_vec(), plain_parity(data))
+    }
+
+    // 4-bit ACK or NAK, which has no parity bit
+    pub fn nibble(value: u8) -> RawFrame {
+        RawFrame { data: vec![value & 0x0F], parity: Vec::new(), bits: 4 }
+    }
+
+    // 7-bit short frame (REQA, WUPA or a magic card's backdoor command),
+    // which has no parity bit either
+    pub fn short(value: u8) -> RawFrame {
+        RawFrame { data: vec![value & 0x7F], parity: Vec::new(), bits: 7 }
+    }
+
+    pub fn is_nibble(&self) -> bool {
+        self.bits == 4
+    }
+}
+
+// Exchange of raw frames, for commands the reader cannot encrypt itself
+pub trait RawTransceive {
+    fn transceive_raw(&self, frame: &RawFrame) -> Result<RawFrame, Box<dyn Error>>;
+
+    // Bring the card back to ACTIVE after it halted
+    fn reactivate(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+impl<T: RawTransceive + ?Sized> RawTransceive for &T {
+    fn transceive_raw(&self, frame: &RawFrame) -> Result<RawFrame, Box<dyn Error>> {
+        (**self).transceive_raw(frame)
+    }
+
+    fn reactivate(&self) -> Result<(), Box<dyn Error>> {
+        (**self).reactivate()
+    }
+}
+
+// Bit stream of a raw frame as the PN532 sends it with its parity handling
+// off: each byte least significant bit first followed by its parity bit.
+// Returns the bytes and the number of valid bits in the last one (0 for 8).
+pub fn pack_bits(frame: &RawFrame) -> (Vec<u8>, u8) {
+    if frame.bits < 8 {
+        return (vec![frame.data[0] & ((1 << frame.bits) - 1)], frame.bits as u8);
+    }
+
+    let mut bits = Vec::with_capacity(frame.data.len() * 9);
+    for (&byte, &parity) in frame.data.iter().zip(&frame.parity) {
+        bits.extend((0..8).map(|i| (byte >> i) & 1));
+        bits.push(parity & 1);
+    }
+    let packed = bits.chunks(8)
+        .map(|chunk| chunk.iter().enumerate().fold(0, |byte, (i, bit)| byte | bit << i))
+        .collect();
+    (packed, (bits.len() % 8) as u8)
+}
+
+// Inverse of `pack_bits`, for a received bit stream
+pub fn unpack_bits(data: &[u8], last_bits: u8) -> RawFrame {
+    let total = match (data.len(), last_bits) {
+        (0, _) => 0,
+        (len, 0) => len * 8,
+        (len, last) => (len - 1) * 8 + last as usize,
+    };
+    if total < 9 {
+        return RawFrame { data: data.to_vec(), parity: Vec::new(), bits: total };
+    }
+
+    let bit = |n: usize| (data[n / 8] >> (n % 8)) & 1;
+    let bytes = total / 9;
+    let frame_data = (0..bytes).map(|i| (0..8).fold(0, |byte, j| byte | bit(9 * i + j) << j)).collect();
+    let parity = (0..bytes).map(|i| bit(9 * i + 8)).collect();
+    RawFrame::new(frame_data, parity)
+}
+
+// Raw frames through the PN532 with CRC and parity handled by the caller,
+// for Crypto1 done in software. The registers it changes are restored when
+// it is dropped.
+pub struct RawReaderLink<'a> {
+    pn532: Pn532<'a>,
+    // CIU_TxMode, CIU_RxMode and CIU_ManualRCV as they were
+    saved: [u8; 3],
+}
+
+impl<'a> RawReaderLink<'a> {
+    pub fn new(card: &'a Card) -> Result<RawReaderLink<'a>, Box<dyn Error>> {
+        let pn532 = Pn532::new(card);
+        let values = pn532.read_registers(&[CIU_TX_MODE, CIU_RX_MODE, CIU_MANUAL_RCV])?;
+        let link = RawReaderLink { pn532, saved: [values[0], values[1], values[2]] };
+        link.enter_raw_mode()?;
+        Ok(link)
+    }
+
+    fn enter_raw_mode(&self) -> Result<(), Box<dyn Error>> {
+        // The reader's own Crypto1 must not touch the frames either
+        let status2 = self.pn532.read_registers(&[CIU_STATUS2])?[0];
+        self.pn532.write_registers(&[
+            (CIU_TX_MODE, self.saved[0] & !CIU_CRC_EN),
+            (CIU_RX_MODE, self.saved[1] & !CIU_CRC_EN),
+            (CIU_MANUAL_RCV, self.saved[2] | CIU_PARITY_DISABLE),
+            (CIU_STATUS2, status2 & !CIU_MF_CRYPTO1_ON),
+        ])
+    }
+
+    fn restore(&self) -> Result<(), Box<dyn Error>> {
+        self.pn532.write_registers(&[
+            (CIU_TX_MODE, self.saved[0]),
+            (CIU_RX_MODE, self.saved[1]),
+            (CIU_MANUAL_RCV, self.saved[2]),
+            (CIU_BIT_FRAMING, 0x00),
+        ])
+    }
+}
+
+impl RawTransceive for RawReaderLink<'_> {
+    fn transceive_raw(&self, frame: &RawFrame) -> Result<RawFrame, Box<dyn Error>> {
+        let (data, last_bits) = pack_bits(frame);
+        self.pn532.write_registers(&[(CIU_BIT_FRAMING, last_bits)])?;
+        let response = self.pn532.communicate_thru(&data)?;
+        let rx_last_bits = self.pn532.read_registers(&[CIU_CONTROL])?[0] & 0x07;
+        Ok(unpack_bits(&response, rx_last_bits))
+    }
+
+    fn reactivate(&self) -> Result<(), Box<dyn Error>> {
+        // Anti-collision needs the usual CRC and parity handling
+        self.restore()?;
+        self.pn532.list_passive_targets(1)?;
+        self.enter_raw_mode()
+    }
+}
+
+impl Drop for RawReaderLink<'_> {
+    fn drop(&mut self) {
+        let _ = self.restore();
+    }
+}
+
+// Frames sent through the ACR122U's PN532
+pub struct ReaderLink<'a> {
+    pn532: Pn532<'a>,
+    // PN532 target number; the card the reader activated itself is target 1
+    tg: u8,
+    // Whether InCommunicateThru currently talks to `tg`
+    selected: Cell<bool>,
+}
+
+impl<'a> ReaderLink<'a> {
+    // Talk to the card the reader activated
+    pub fn new(card: &'a Card) -> Self {
+        ReaderLink { pn532: Pn532::new(card), tg: 1, selected: Cell::new(true) }
+    }
+
+    // Talk to one of the targets found by InListPassiveTarget
+    pub fn with_target(card: &'a Card, tg: u8) -> Self {
+        ReaderLink { pn532: Pn532::new(card), tg, selected: Cell::new(false) }
+    }
+}
+
+impl Transceive for ReaderLink<'_> {
+    fn transceive(&self, frame: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        match frame.first() {
+            // The PN532 knows the MIFARE read and write commands, including the
+            // two steps of a compatibility write and the 4-bit ACKs
+            Some(0x30) | Some(0xA0) | Some(0xA2) => self.pn532.data_exchange(self.tg, frame),
+            _ => {
+                if !self.selected.get() {
+                    self.pn532.select(self.tg)?;
+                    self.selected.set(true);
+                }
+                self.pn532.communicate_thru(frame)
+            },
+        }
+    }
+
+    fn reactivate(&self) -> Result<(), Box<dyn Error>> {
+        // Listing again wakes the card up and makes it target 1
+        self.pn532.list_passive_targets(1)?;
+        self.selected.set(self.tg == 1);
+        Ok(())
+    }
+}
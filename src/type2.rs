@@ -0,0 +1,479 @@
+use std::error::Error;
+use std::ops::Range;
+use crate::error::MifareError;
+use crate::ndef::NdefMessage;
+use crate::ultralight::{dynamic_lock_bit, MifareUltralight, UltralightModel, PAGE_SIZE};
+
+// NFC Forum Type 2 Tag: a Capability Container in page 3, followed from page
+// 4 on by a data area of TLV blocks
+
+// CC byte 0
+pub const NDEF_MAGIC: u8 = 0xE1;
+// Mapping version 1.0
+pub const MAPPING_VERSION: u8 = 0x10;
+// Access conditions in CC byte 3
+pub const ACCESS_GRANTED: u8 = 0x0;
+pub const ACCESS_NONE: u8 = 0xF;
+
+// TLV block types
+pub const TLV_NULL: u8 = 0x00;
+pub const TLV_LOCK_CONTROL: u8 = 0x01;
+pub const TLV_MEMORY_CONTROL: u8 = 0x02;
+pub const TLV_NDEF: u8 = 0x03;
+pub const TLV_PROPRIETARY: u8 = 0xFD;
+pub const TLV_TERMINATOR: u8 = 0xFE;
+
+// Byte address of the data area
+const DATA_START: usize = 4 * PAGE_SIZE;
+
+// Capability Container, page 3 (an OTP page: bits can only be set)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapabilityContainer {
+    pub version: u8,
+    // Data area size in bytes (8 times CC byte 2)
+    pub data_size: usize,
+    pub read_access: u8,
+    pub write_access: u8,
+}
+
+impl CapabilityContainer {
+    pub fn decode(cc: &[u8; 4]) -> Result<CapabilityContainer, MifareError> {
+        if cc[0] != NDEF_MAGIC {
+            return Err(MifareError::new("Tag is not NDEF formatted (no CC magic number)"));
+        }
+        if cc[1] >> 4 != MAPPING_VERSION >> 4 {
+            return Err(MifareError::new(&format!("Unsupported Type 2 Tag mapping version {}.{}", cc[1] >> 4, cc[1] & 0x0F)));
+        }
+
+        Ok(CapabilityContainer {
+            version: cc[1],
+            data_size: cc[2] as usize * 8,
+            read_access: cc[3] >> 4,
+            write_access: cc[3] & 0x0F,
+        })
+    }
+
+    pub fn encode(&self) -> [u8; 4] {
+        [NDEF_MAGIC, self.version, (self.data_size / 8) as u8, (self.read_access << 4) | (self.write_access & 0x0F)]
+    }
+
+    // CC of a freshly formatted tag, with CC byte 2 as the datasheets give
+    // it. NTAG215 and NTAG216 announce a little less than their user memory.
+    pub fn for_model(model: UltralightModel) -> CapabilityContainer {
+        let size = match model {
+            UltralightModel::Ultralight | UltralightModel::UltralightEv1_11 | UltralightModel::Ntag210 => 0x06,
+            UltralightModel::UltralightEv1_21 | UltralightModel::Ntag212 => 0x10,
+            UltralightModel::UltralightC | UltralightModel::Ntag213 => 0x12,
+            UltralightModel::Ntag215 => 0x3E,
+            UltralightModel::Ntag216 => 0x6D,
+        };
+        CapabilityContainer {
+            version: MAPPING_VERSION,
+            data_size: size * 8,
+            read_access: ACCESS_GRANTED,
+            write_access: ACCESS_GRANTED,
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.write_access != ACCESS_GRANTED
+    }
+}
+
+// Area announced by a Lock Control or Memory Control TLV, which NDEF data
+// must skip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlArea {
+    // Byte address from the start of the tag memory
+    pub position: usize,
+    // Size in bytes
+    pub size: usize,
+    // Bytes protected by one lock bit (Lock Control only)
+    pub bytes_per_lock_bit: usize,
+}
+
+impl ControlArea {
+    // Lock Control: size in bits; Memory Control: size in bytes
+    fn decode(value: &[u8], size_in_bits: bool) -> Result<ControlArea, MifareError> {
+        if value.len() != 3 {
+            return Err(MifareError::new("Control TLV must be 3 bytes long"));
+        }
+
+        // Page address (in units of 2^n bytes) and byte offset, then the
+        // exponents of bytes per lock bit and bytes per page
+        let bytes_per_page = 1usize << (value[2] & 0x0F);
+        let position = (value[0] >> 4) as usize * bytes_per_page + (value[0] & 0x0F) as usize;
+        let size = match (value[1] as usize, size_in_bits) {
+            (0, true) => 256 / 8,
+            (bits, true) => bits.div_ceil(8),
+            (bytes, false) => bytes,
+        };
+
+        Ok(ControlArea { position, size, bytes_per_lock_bit: 1 << (value[2] >> 4) })
+    }
+
+    fn contains(&self, address: usize) -> bool {
+        (self.position..self.position + self.size).contains(&address)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tlv {
+    Null,
+    LockControl(ControlArea),
+    MemoryControl(ControlArea),
+    // Raw NDEF message
+    Ndef(Vec<u8>),
+    Proprietary(Vec<u8>),
+    Terminator,
+}
+
+impl Tlv {
+    pub fn encode(&self) -> Vec<u8> {
+        let (tag, value) = match self {
+            Tlv::Null => return vec![TLV_NULL],
+            Tlv::Terminator => return vec![TLV_TERMINATOR],
+            Tlv::LockControl(area) => (TLV_LOCK_CONTROL, control_value(area, area.size * 8)),
+            Tlv::MemoryControl(area) => (TLV_MEMORY_CONTROL, control_value(area, area.size)),
+            Tlv::Ndef(value) => (TLV_NDEF, value.clone()),
+            Tlv::Proprietary(value) => (TLV_PROPRIETARY, value.clone()),
+        };
+
+        let mut out = vec![tag];
+        out.extend_from_slice(&encode_length(value.len()));
+        out.extend_from_slice(&value);
+        out
+    }
+}
+
+// Control TLV value, with the smallest "page" size that fits the position
+// in two nibbles
+fn control_value(area: &ControlArea, size: usize) -> Vec<u8> {
+    let page_exponent = (2..8).find(|n| area.position >> n < 16 && area.position % (1 << n) < 16).unwrap_or(8);
+    let page = area.position >> page_exponent;
+    let offset = area.position % (1 << page_exponent);
+    let lock_exponent = area.bytes_per_lock_bit.trailing_zeros() as u8;
+    vec![((page as u8) << 4) | offset as u8, size as u8, (lock_exponent << 4) | page_exponent as u8]
+}
+
+// One byte up to FE, else FF and two bytes
+fn encode_length(len: usize) -> Vec<u8> {
+    if len < 0xFF {
+        vec![len as u8]
+    } else {
+        vec![0xFF, (len >> 8) as u8, len as u8]
+    }
+}
+
+// TLV blocks of a data area with their offsets, up to and including the
+// Terminator or the end of the area
+pub fn walk_tlvs(area: &[u8]) -> Result<Vec<(usize, Tlv)>, MifareError> {
+    let mut tlvs = Vec::new();
+    let mut offset = 0;
+
+    while offset < area.len() {
+        let start = offset;
+        let tag = area[offset];
+        offset += 1;
+
+        match tag {
+            TLV_NULL => {
+                tlvs.push((start, Tlv::Null));
+                continue;
+            },
+            TLV_TERMINATOR => {
+                tlvs.push((start, Tlv::Terminator));
+                break;
+            },
+            _ => {},
+        }
+
+        let range = value_range(area, start)?;
+        let value = &area[range.clone()];
+        offset = range.end;
+
+        let tlv = match tag {
+            TLV_LOCK_CONTROL => Tlv::LockControl(ControlArea::decode(value, true)?),
+            TLV_MEMORY_CONTROL => Tlv::MemoryControl(ControlArea::decode(value, false)?),
+            TLV_NDEF => Tlv::Ndef(value.to_vec()),
+            TLV_PROPRIETARY => Tlv::Proprietary(value.to_vec()),
+            // Unknown blocks are skipped, as the mapping asks
+            _ => continue,
+        };
+        tlvs.push((start, tlv));
+    }
+
+    Ok(tlvs)
+}
+
+// Value of the block whose tag is at `start`: one length byte up to FE, else
+// FF and two bytes
+fn value_range(area: &[u8], start: usize) -> Result<Range<usize>, MifareError> {
+    let truncated = || MifareError::new("Truncated TLV block");
+    let mut offset = start + 1;
+    let mut len = *area.get(offset).ok_or_else(truncated)? as usize;
+    offset += 1;
+    if len == 0xFF {
+        let bytes = area.get(offset..offset + 2).ok_or_else(truncated)?;
+        len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+        offset += 2;
+    }
+    area.get(offset..offset + len).ok_or_else(truncated)?;
+    Ok(offset..offset + len)
+}
+
+// End of the last block before the Terminator, trailing Null blocks excluded
+fn blocks_end(area: &[u8]) -> Result<usize, MifareError> {
+    let mut offset = 0;
+    let mut end = 0;
+    while offset < area.len() {
+        match area[offset] {
+            TLV_NULL => offset += 1,
+            TLV_TERMINATOR => break,
+            _ => {
+                offset = value_range(area, offset)?.end;
+                end = offset;
+            },
+        }
+    }
+    Ok(end)
+}
+
+pub fn parse_tlvs(area: &[u8]) -> Result<Vec<Tlv>, MifareError> {
+    Ok(walk_tlvs(area)?.into_iter().map(|(_, tlv)| tlv).collect())
+}
+
+// Lock and reserved areas, from the control TLVs at the start of the raw
+// data area (they come before any NDEF TLV)
+fn control_areas(raw: &[u8]) -> Vec<ControlArea> {
+    let mut areas = Vec::new();
+    let mut offset = 0;
+    while offset + 4 < raw.len() {
+        match raw[offset] {
+            TLV_NULL => offset += 1,
+            tag @ (TLV_LOCK_CONTROL | TLV_MEMORY_CONTROL) => {
+                if let Ok(area) = ControlArea::decode(&raw[offset + 2..offset + 5], tag == TLV_LOCK_CONTROL) {
+                    areas.push(area);
+                }
+                offset += 2 + raw[offset + 1] as usize;
+            },
+            _ => break,
+        }
+    }
+    areas
+}
+
+// NDEF message, as a Type 2 Tag holds it
+pub struct DataArea {
+    pub cc: CapabilityContainer,
+    // Data area as read, from byte 16
+    raw: Vec<u8>,
+    // Byte addresses TLVs are stored at: the data area minus control areas
+    addresses: Vec<usize>,
+}
+
+impl DataArea {
+    pub fn new(cc: CapabilityContainer, raw: Vec<u8>) -> DataArea {
+        let areas = control_areas(&raw);
+        let addresses = (DATA_START..DATA_START + raw.len())
+            .filter(|&address| !areas.iter().any(|area| area.contains(address)))
+            .collect();
+        DataArea { cc, raw, addresses }
+    }
+
+    // The TLV byte stream
+    pub fn tlv_bytes(&self) -> Vec<u8> {
+        self.addresses.iter().map(|&address| self.raw[address - DATA_START]).collect()
+    }
+
+    // Room for TLVs, control areas excluded
+    pub fn capacity(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn tlvs(&self) -> Result<Vec<Tlv>, MifareError> {
+        parse_tlvs(&self.tlv_bytes())
+    }
+
+    // The first NDEF message
+    pub fn ndef_message(&self) -> Result<NdefMessage, MifareError> {
+        let ndef = self.tlvs()?.into_iter()
+            .find_map(|tlv| match tlv {
+                Tlv::Ndef(value) => Some(value),
+                _ => None,
+            })
+            .ok_or_else(|| MifareError::new("No NDEF TLV on the tag"))?;
+
+        if ndef.is_empty() {
+            return Ok(NdefMessage::empty());
+        }
+        NdefMessage::parse(&ndef)
+    }
+
+    // TLV stream with `message` replacing the first NDEF TLV (or inserted
+    // after the control TLVs), the blocks after it kept, and the same stream
+    // with the NDEF length still zero, to be written first so a torn write
+    // leaves an empty message
+    pub fn with_message(&self, message: &NdefMessage) -> Result<(Vec<u8>, Vec<u8>), MifareError> {
+        let stream = self.tlv_bytes();
+        let tlvs = walk_tlvs(&stream)?;
+        let (start, rest) = match tlvs.iter().find(|(_, tlv)| matches!(tlv, Tlv::Ndef(_))) {
+            Some(&(offset, _)) => (offset, value_range(&stream, offset)?.end),
+            None => {
+                let offset = tlvs.iter()
+                    .find(|(_, tlv)| matches!(tlv, Tlv::Proprietary(_) | Tlv::Terminator))
+                    .map(|&(offset, _)| offset)
+                    .unwrap_or(stream.len());
+                (offset, offset)
+            },
+        };
+        let tail = &stream[rest..blocks_end(&stream)?.max(rest)];
+
+        let bytes = if message.is_empty() { Vec::new() } else { message.to_bytes()? };
+        let tlv = Tlv::Ndef(bytes).encode();
+        let mut blocks = stream[..start].to_vec();
+        blocks.extend_from_slice(&tlv);
+        blocks.extend_from_slice(tail);
+        if blocks.len() > stream.len() {
+            return Err(MifareError::new(&format!(
+                "NDEF message needs {} bytes, the tag has room for {}",
+                tlv.len(), stream.len().saturating_sub(start + tail.len())
+            )));
+        }
+        if blocks.len() < stream.len() {
+            blocks.push(TLV_TERMINATOR);
+        }
+
+        // Bytes past the Terminator stay as they are
+        let mut updated = stream.clone();
+        updated[..blocks.len()].copy_from_slice(&blocks);
+
+        // The length field is one byte, or FF and two bytes
+        let mut empty = updated.clone();
+        let length = if tlv[1] == 0xFF { start + 2..start + 4 } else { start + 1..start + 2 };
+        for byte in &mut empty[length] {
+            *byte = 0x00;
+        }
+        Ok((empty, updated))
+    }
+
+    // Raw data area after replacing the TLV stream
+    fn raw_with(&self, stream: &[u8]) -> Vec<u8> {
+        let mut raw = self.raw.clone();
+        for (&address, &byte) in self.addresses.iter().zip(stream) {
+            raw[address - DATA_START] = byte;
+        }
+        raw
+    }
+}
+
+// Type 2 Tag NDEF mapping on Ultralight and NTAG21x
+impl MifareUltralight<'_> {
+    pub fn read_capability_container(&self) -> Result<CapabilityContainer, Box<dyn Error>> {
+        Ok(CapabilityContainer::decode(&self.read_page(3)?)?)
+    }
+
+    // Pages `start` up to `start + count`, four at a time
+    fn read_pages(&self, start: usize, count: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut data = Vec::with_capacity(count * PAGE_SIZE);
+        let mut page = start;
+        while page < start + count {
+            let block = self.read(page as u8)?;
+            let pages = (start + count - page).min(4);
+            data.extend_from_slice(&block[..pages * PAGE_SIZE]);
+            page += pages;
+        }
+        Ok(data)
+    }
+
+    pub fn read_data_area(&self) -> Result<DataArea, Box<dyn Error>> {
+        let cc = self.read_capability_container()?;
+        let raw = self.read_pages(4, cc.data_size.div_ceil(PAGE_SIZE))?;
+        Ok(DataArea::new(cc, raw[..cc.data_size].to_vec()))
+    }
+
+    pub fn read_ndef(&self) -> Result<NdefMessage, Box<dyn Error>> {
+        if self.read_capability_container()?.read_access != ACCESS_GRANTED {
+            return Err(Box::new(MifareError::new("CC denies read access")));
+        }
+        Ok(self.read_data_area()?.ndef_message()?)
+    }
+
+    // Write the pages of `target` that differ from `current`
+    fn write_changed_pages(&self, current: &[u8], target: &[u8]) -> Result<(), Box<dyn Error>> {
+        for (i, (old, new)) in current.chunks(PAGE_SIZE).zip(target.chunks(PAGE_SIZE)).enumerate() {
+            if old != new {
+                self.write_page((4 + i) as u8, new)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Replace the NDEF message. The NDEF length is written last, so a tag
+    // pulled away part way holds an empty message rather than a corrupt one.
+    pub fn write_ndef(&self, message: &NdefMessage) -> Result<(), Box<dyn Error>> {
+        let area = self.read_data_area()?;
+        if area.cc.is_read_only() {
+            return Err(Box::new(MifareError::new("Tag is read-only (CC write access)")));
+        }
+
+        let (empty, updated) = area.with_message(message)?;
+        let empty = area.raw_with(&empty);
+        self.write_changed_pages(&area.raw, &empty)?;
+        self.write_changed_pages(&empty, &area.raw_with(&updated))
+    }
+
+    // Format a blank tag: CC and an empty NDEF message. The CC is OTP, so a
+    // tag with another CC cannot be formatted.
+    pub fn format_ndef(&self, model: UltralightModel) -> Result<(), Box<dyn Error>> {
+        let cc = CapabilityContainer::for_model(model);
+        let current = self.read_page(3)?;
+        if current == [0x00; 4] {
+            self.write_page(3, &cc.encode())?;
+        } else {
+            let existing = CapabilityContainer::decode(&current)?;
+            if existing.is_read_only() {
+                return Err(Box::new(MifareError::new("Tag is read-only (CC write access)")));
+            }
+        }
+
+        let mut tlvs = Vec::new();
+        // The Ultralight C dynamic lock bytes follow the data area
+        if model == UltralightModel::UltralightC {
+            if let Some(page) = model.dynamic_lock_page() {
+                let area = ControlArea { position: page as usize * PAGE_SIZE, size: 2, bytes_per_lock_bit: 16 };
+                tlvs.extend(Tlv::LockControl(area).encode());
+            }
+        }
+        tlvs.extend(Tlv::Ndef(Vec::new()).encode());
+        tlvs.push(TLV_TERMINATOR);
+
+        let pages = tlvs.len().div_ceil(PAGE_SIZE);
+        tlvs.resize(pages * PAGE_SIZE, 0x00);
+        for (i, page) in tlvs.chunks(PAGE_SIZE).enumerate() {
+            self.write_page((4 + i) as u8, page)?;
+        }
+        Ok(())
+    }
+
+    // Make the tag permanently read-only: CC write access first, then the
+    // dynamic lock bits of the pages from 16 on, then the static lock bits
+    // (which also lock the CC). None of this can be undone.
+    pub fn make_read_only(&self, model: UltralightModel) -> Result<(), Box<dyn Error>> {
+        let mut cc = self.read_capability_container()?;
+        cc.write_access = ACCESS_NONE;
+        self.write_page(3, &cc.encode())?;
+
+        if let Some(lock_page) = model.dynamic_lock_page() {
+            let mut bits = [0u8; 4];
+            for page in 16..model.user_end() {
+                if let Some((byte, mask)) = dynamic_lock_bit(model, page) {
+                    bits[byte] |= mask;
+                }
+            }
+            self.write_page(lock_page, &bits)?;
+        }
+
+        self.write_page(2, &[0x00, 0x00, 0xFF, 0xFF])
+    }
+}
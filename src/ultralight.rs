@@ -0,0 +1,421 @@
+use std::error::Error;
+use pcsc::Card;
+use crate::error::MifareError;
+use crate::pn532::Target;
+use crate::transport::{ReaderLink, Transceive};
+
+// Type 2 tag command set shared by MIFARE Ultralight and NTAG21x
+pub const GET_VERSION: u8 = 0x60;
+pub const READ: u8 = 0x30;
+pub const FAST_READ: u8 = 0x3A;
+pub const WRITE: u8 = 0xA2;
+pub const COMPAT_WRITE: u8 = 0xA0;
+pub const READ_CNT: u8 = 0x39;
+pub const READ_SIG: u8 = 0x3C;
+pub const ACK: u8 = 0x0A;
+
+pub const PAGE_SIZE: usize = 4;
+
+// Pages per FAST_READ, keeping responses well below the reader's frame size
+const FAST_READ_PAGES: usize = 15;
+
+// Ultralight and NTAG21x products, which differ in memory layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UltralightModel {
+    // MF0ICU1
+    Ultralight,
+    // MF0ICU2
+    UltralightC,
+    // MF0UL11 / MF0ULH11
+    UltralightEv1_11,
+    // MF0UL21 / MF0ULH21
+    UltralightEv1_21,
+    Ntag210,
+    Ntag212,
+    Ntag213,
+    Ntag215,
+    Ntag216,
+}
+
+impl UltralightModel {
+    // Model from a GET_VERSION response (vendor, type, subtype, major, minor,
+    // storage size, protocol)
+    pub fn from_version(version: &[u8]) -> Option<UltralightModel> {
+        if version.len() < 8 || version[1] != 0x04 {
+            return None;
+        }
+
+        match (version[2], version[6]) {
+            (0x03, 0x0B) => Some(UltralightModel::UltralightEv1_11),
+            (0x03, 0x0E) => Some(UltralightModel::UltralightEv1_21),
+            (0x04, 0x0B) => Some(UltralightModel::Ntag210),
+            (0x04, 0x0E) => Some(UltralightModel::Ntag212),
+            (0x04, 0x0F) => Some(UltralightModel::Ntag213),
+            (0x04, 0x11) => Some(UltralightModel::Ntag215),
+            (0x04, 0x13) => Some(UltralightModel::Ntag216),
+            _ => None,
+        }
+    }
+
+    pub fn to_string(&self) -> &str {
+        match self {
+            UltralightModel::Ultralight => "MIFARE Ultralight",
+            UltralightModel::UltralightC => "MIFARE Ultralight C",
+            UltralightModel::UltralightEv1_11 => "MIFARE Ultralight EV1 (MF0UL11)",
+            UltralightModel::UltralightEv1_21 => "MIFARE Ultralight EV1 (MF0UL21)",
+            UltralightModel::Ntag210 => "NTAG210",
+            UltralightModel::Ntag212 => "NTAG212",
+            UltralightModel::Ntag213 => "NTAG213",
+            UltralightModel::Ntag215 => "NTAG215",
+            UltralightModel::Ntag216 => "NTAG216",
+        }
+    }
+
+    // Total number of pages, including UID, lock and configuration pages
+    pub fn page_count(&self) -> usize {
+        match self {
+            UltralightModel::Ultralight => 16,
+            UltralightModel::UltralightC => 48,
+            UltralightModel::UltralightEv1_11 | UltralightModel::Ntag210 => 20,
+            UltralightModel::UltralightEv1_21 | UltralightModel::Ntag212 => 41,
+            UltralightModel::Ntag213 => 45,
+            UltralightModel::Ntag215 => 135,
+            UltralightModel::Ntag216 => 231,
+        }
+    }
+
+    // First page after the user memory
+    pub fn user_end(&self) -> u8 {
+        match self {
+            UltralightModel::Ultralight | UltralightModel::UltralightEv1_11 | UltralightModel::Ntag210 => 0x10,
+            UltralightModel::UltralightC | UltralightModel::Ntag213 => 0x28,
+            UltralightModel::UltralightEv1_21 | UltralightModel::Ntag212 => 0x24,
+            UltralightModel::Ntag215 => 0x82,
+            UltralightModel::Ntag216 => 0xE2,
+        }
+    }
+
+    // User memory, from page 4 up to `user_end`
+    pub fn user_memory(&self) -> usize {
+        (self.user_end() as usize - 4) * PAGE_SIZE
+    }
+
+    // Page holding the dynamic lock bytes, on products with more than 16 pages
+    // of data
+    pub fn dynamic_lock_page(&self) -> Option<u8> {
+        match self {
+            UltralightModel::Ultralight | UltralightModel::UltralightEv1_11 | UltralightModel::Ntag210 => None,
+            _ => Some(self.user_end()),
+        }
+    }
+
+    // Pages covered by one dynamic lock bit: two on MF0UL21 and NTAG212/213,
+    // sixteen on NTAG215/216
+    fn dynamic_lock_granularity(&self) -> usize {
+        match self {
+            UltralightModel::UltralightC => 4,
+            UltralightModel::Ntag215 | UltralightModel::Ntag216 => 16,
+            _ => 2,
+        }
+    }
+
+    // CFG0 (MIRROR, AUTH0), followed by CFG1 (ACCESS), PWD and PACK
+    pub fn config_page(&self) -> Option<u8> {
+        match self {
+            UltralightModel::Ultralight | UltralightModel::UltralightC => None,
+            UltralightModel::UltralightEv1_11 | UltralightModel::Ntag210 => Some(0x10),
+            _ => self.dynamic_lock_page().map(|page| page + 1),
+        }
+    }
+
+    // EV1 and NTAG21x add FAST_READ, READ_CNT and READ_SIG
+    pub fn has_ev1_commands(&self) -> bool {
+        !matches!(self, UltralightModel::Ultralight | UltralightModel::UltralightC)
+    }
+
+    // Counters READ_CNT accepts: three on Ultralight EV1, the NFC counter
+    // (number 2) on NTAG21x
+    pub fn counters(&self) -> &'static [u8] {
+        match self {
+            UltralightModel::UltralightEv1_11 | UltralightModel::UltralightEv1_21 => &[0, 1, 2],
+            UltralightModel::Ntag213 | UltralightModel::Ntag215 | UltralightModel::Ntag216 => &[2],
+            _ => &[],
+        }
+    }
+}
+
+// Byte and bit of the dynamic lock bytes that write protects a user page
+// from page 16 on
+pub fn dynamic_lock_bit(model: UltralightModel, page: u8) -> Option<(usize, u8)> {
+    if page < 16 || page >= model.user_end() {
+        return None;
+    }
+    model.dynamic_lock_page()?;
+
+    let group = (page as usize - 16) / model.dynamic_lock_granularity();
+    if model == UltralightModel::UltralightC {
+        // Bits 1-3 and 5-7 of the first byte; bits 0 and 4 are block-locks
+        let bit = if group < 3 { group + 1 } else { group + 2 };
+        return Some((0, 1 << bit));
+    }
+    Some((group / 8, 1 << (group % 8)))
+}
+
+// Lock and OTP bytes, read from page 2 and 3 and the dynamic lock page
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockBits {
+    // Bytes 2 and 3 of page 2
+    pub static_lock: [u8; 2],
+    pub dynamic_lock: Option<[u8; 3]>,
+    // Page 3; bits can only be set, never cleared
+    pub otp: [u8; 4],
+}
+
+impl LockBits {
+    // Whether page 3 can no longer be written
+    pub fn otp_locked(&self) -> bool {
+        self.static_lock[0] & 0x08 != 0
+    }
+
+    // Whether a page is write protected by a lock bit. Lock and configuration
+    // pages are not covered.
+    pub fn is_page_locked(&self, model: UltralightModel, page: u8) -> bool {
+        match page {
+            3 => self.otp_locked(),
+            // L4..L7 in the first static lock byte, L8..L15 in the second
+            4..=7 => self.static_lock[0] & (1 << page) != 0,
+            8..=15 => self.static_lock[1] & (1 << (page - 8)) != 0,
+            _ => match (self.dynamic_lock, dynamic_lock_bit(model, page)) {
+                (Some(dynamic), Some((byte, mask))) => dynamic[byte] & mask != 0,
+                _ => false,
+            },
+        }
+    }
+
+    pub fn locked_pages(&self, model: UltralightModel) -> Vec<u8> {
+        (3..model.user_end()).filter(|&page| self.is_page_locked(model, page)).collect()
+    }
+}
+
+// Structure to represent a MIFARE Ultralight or NTAG21x card
+pub struct MifareUltralight<'a> {
+    link: Box<dyn Transceive + 'a>,
+}
+
+impl<'a> MifareUltralight<'a> {
+    // Create a handler for the card the reader activated
+    pub fn new(card: &'a Card) -> Self {
+        MifareUltralight { link: Box::new(ReaderLink::new(card)) }
+    }
+
+    // Create a handler for one of the targets found by InListPassiveTarget
+    pub fn with_target(card: &'a Card, target: &Target) -> Self {
+        MifareUltralight { link: Box::new(ReaderLink::with_target(card, target.tg)) }
+    }
+
+    // Create a handler on any transport, e.g. a simulated tag
+    pub fn with_transceiver<T: Transceive + 'a>(link: T) -> Self {
+        MifareUltralight { link: Box::new(link) }
+    }
+
+    pub(crate) fn transceive(&self, frame: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.link.transceive(frame)
+    }
+
+    // Wake the card up after a NAK halted it
+    pub fn reactivate(&self) -> Result<(), Box<dyn Error>> {
+        self.link.reactivate()
+    }
+
+    // Commands answered with a 4-bit ACK or NAK
+    pub(crate) fn expect_ack(&self, frame: &[u8], command: &str) -> Result<(), Box<dyn Error>> {
+        let response = self.transceive(frame)?;
+        match response.as_slice() {
+            [] | [ACK] => Ok(()),
+            [nak] => Err(Box::new(MifareError::new(&format!("{} refused: NAK {:X}", command, nak)))),
+            _ => Err(Box::new(MifareError::new(&format!("Unexpected {} response", command)))),
+        }
+    }
+
+    // Read UID of the card from pages 0 and 1 (the BCC bytes are skipped)
+    pub fn read_uid(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let data = self.read(0)?;
+        let mut uid = data[0..3].to_vec();
+        uid.extend_from_slice(&data[4..8]);
+        Ok(uid)
+    }
+
+    // GET_VERSION, on Ultralight EV1 and NTAG21x
+    pub fn get_version(&self) -> Result<[u8; 8], Box<dyn Error>> {
+        let response = self.transceive(&[GET_VERSION])?;
+        response.get(0..8)
+            .and_then(|v| v.try_into().ok())
+            .ok_or_else(|| Box::new(MifareError::new("Invalid GET_VERSION response")) as Box<dyn Error>)
+    }
+
+    // Find out the product: GET_VERSION first, then the Ultralight C
+    // authentication command, falling back to the plain Ultralight
+    pub fn detect_model(&self) -> Result<UltralightModel, Box<dyn Error>> {
+        if let Ok(version) = self.get_version() {
+            if let Some(model) = UltralightModel::from_version(&version) {
+                return Ok(model);
+            }
+        }
+
+        // Older products NAK unknown commands and halt
+        self.reactivate()?;
+        let response = self.transceive(&[0x1A, 0x00]).unwrap_or_default();
+        self.reactivate()?;
+
+        if response.first() == Some(&0xAF) {
+            Ok(UltralightModel::UltralightC)
+        } else {
+            Ok(UltralightModel::Ultralight)
+        }
+    }
+
+    // READ: 16 bytes starting at `page`, rolling over at the end of memory
+    pub fn read(&self, page: u8) -> Result<[u8; 16], Box<dyn Error>> {
+        let response = self.transceive(&[READ, page])?;
+        if response.len() == 1 {
+            return Err(Box::new(MifareError::new(&format!("READ of page {} refused: NAK {:X}", page, response[0]))));
+        }
+
+        response.get(0..16)
+            .and_then(|d| d.try_into().ok())
+            .ok_or_else(|| Box::new(MifareError::new("Invalid READ response length")) as Box<dyn Error>)
+    }
+
+    pub fn read_page(&self, page: u8) -> Result<[u8; 4], Box<dyn Error>> {
+        let data = self.read(page)?;
+        Ok([data[0], data[1], data[2], data[3]])
+    }
+
+    // FAST_READ: pages `start` to `end` inclusive in one command
+    pub fn fast_read(&self, start: u8, end: u8) -> Result<Vec<u8>, Box<dyn Error>> {
+        if end < start {
+            return Err(Box::new(MifareError::new("FAST_READ end page before start page")));
+        }
+
+        let response = self.transceive(&[FAST_READ, start, end])?;
+        let expected = (end - start) as usize * PAGE_SIZE + PAGE_SIZE;
+        if response.len() != expected {
+            return Err(Box::new(MifareError::new(&format!(
+                "FAST_READ returned {} bytes instead of {}", response.len(), expected
+            ))));
+        }
+        Ok(response)
+    }
+
+    // WRITE: one 4-byte page
+    pub fn write_page(&self, page: u8, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        if data.len() != PAGE_SIZE {
+            return Err(Box::new(MifareError::new("Page data must be exactly 4 bytes")));
+        }
+
+        let mut frame = vec![WRITE, page];
+        frame.extend_from_slice(data);
+        self.expect_ack(&frame, &format!("WRITE of page {}", page))
+    }
+
+    // COMPATIBILITY WRITE: the MIFARE Classic write frame, of which only the
+    // first 4 bytes are stored
+    pub fn compat_write(&self, page: u8, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        if data.len() != PAGE_SIZE {
+            return Err(Box::new(MifareError::new("Page data must be exactly 4 bytes")));
+        }
+
+        let mut frame = vec![COMPAT_WRITE, page];
+        frame.extend_from_slice(data);
+        frame.resize(2 + 16, 0x00);
+        self.expect_ack(&frame, &format!("COMPATIBILITY WRITE of page {}", page))
+    }
+
+    // Set bits of the OTP page; bits already set stay set
+    pub fn write_otp(&self, bits: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.write_page(3, bits)
+    }
+
+    // READ_CNT: 24-bit one-way counter
+    pub fn read_counter(&self, counter: u8) -> Result<u32, Box<dyn Error>> {
+        let response = self.transceive(&[READ_CNT, counter])?;
+        match response.as_slice() {
+            [b0, b1, b2] => Ok(u32::from_le_bytes([*b0, *b1, *b2, 0])),
+            [nak] => Err(Box::new(MifareError::new(&format!("READ_CNT {} refused: NAK {:X}", counter, nak)))),
+            _ => Err(Box::new(MifareError::new("Invalid READ_CNT response length"))),
+        }
+    }
+
+    // READ_SIG: NXP originality signature over the UID
+    pub fn read_signature(&self) -> Result<[u8; 32], Box<dyn Error>> {
+        let response = self.transceive(&[READ_SIG, 0x00])?;
+        response.get(0..32)
+            .filter(|_| response.len() == 32)
+            .and_then(|s| s.try_into().ok())
+            .ok_or_else(|| Box::new(MifareError::new("Invalid READ_SIG response")) as Box<dyn Error>)
+    }
+
+    pub fn read_lock_bits(&self, model: UltralightModel) -> Result<LockBits, Box<dyn Error>> {
+        let header = self.read(2)?;
+        let dynamic_lock = match model.dynamic_lock_page() {
+            Some(page) => {
+                let data = self.read_page(page)?;
+                Some([data[0], data[1], data[2]])
+            },
+            None => None,
+        };
+
+        Ok(LockBits {
+            static_lock: [header[2], header[3]],
+            dynamic_lock,
+            otp: [header[4], header[5], header[6], header[7]],
+        })
+    }
+
+    // Read every page of the card. Reading stops at the first page the card
+    // refuses (e.g. behind password protection), returning what was read;
+    // the reason is only an error when not even page 0 is readable.
+    pub fn dump(&self, model: UltralightModel) -> Result<Vec<[u8; 4]>, Box<dyn Error>> {
+        let count = model.page_count();
+        let mut pages: Vec<[u8; 4]> = Vec::with_capacity(count);
+
+        while pages.len() < count {
+            let start = pages.len();
+            let chunk = if model.has_ev1_commands() {
+                let end = (start + FAST_READ_PAGES).min(count) - 1;
+                self.fast_read(start as u8, end as u8)
+            } else {
+                self.read(start as u8).map(|data| data.to_vec())
+            };
+
+            match chunk {
+                Ok(data) => {
+                    for page in data.chunks(PAGE_SIZE).take(count - start) {
+                        pages.push([page[0], page[1], page[2], page[3]]);
+                    }
+                },
+                Err(e) => {
+                    // The NAK halted the card. A FAST_READ fails as a whole, so
+                    // find the first refused page one READ at a time.
+                    self.reactivate()?;
+                    while model.has_ev1_commands() && pages.len() < count {
+                        match self.read_page(pages.len() as u8) {
+                            Ok(page) => pages.push(page),
+                            Err(_) => {
+                                self.reactivate()?;
+                                break;
+                            },
+                        }
+                    }
+
+                    if pages.is_empty() {
+                        return Err(e);
+                    }
+                    break;
+                },
+            }
+        }
+
+        Ok(pages)
+    }
+}
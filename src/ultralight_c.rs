@@ -0,0 +1,232 @@
+use std::error::Error;
+use des::cipher::generic_array::GenericArray;
+use des::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use des::TdesEde2;
+use crate::error::MifareError;
+use crate::ntag::Protection;
+use crate::ultralight::{MifareUltralight, UltralightModel};
+
+// Two-pass mutual authentication: 1A 00 is answered with AF ek(RndB), and
+// AF ek(RndA || RndB') with 00 ek(RndA')
+pub const AUTHENTICATE: u8 = 0x1A;
+pub const AUTHENTICATE_PART2: u8 = 0xAF;
+
+// First page that needs authentication (byte 0), 30h and up for none
+pub const AUTH0_PAGE: u8 = 0x2A;
+// Bit 0 of byte 0: set to restrict writes only, clear to restrict reads too
+pub const AUTH1_PAGE: u8 = 0x2B;
+// Four write-only pages holding the 16-byte 2-key 3DES key
+pub const KEY_PAGE: u8 = 0x2C;
+
+// Factory key: pages 2C-2F read "BREAKMEIFYOUCAN!", so each DES key is
+// "BREAKMEI" and "FYOUCAN!" reversed
+pub const DEFAULT_KEY: [u8; 16] = [
+    0x49, 0x45, 0x4D, 0x4B, 0x41, 0x45, 0x52, 0x42, 0x21, 0x4E, 0x41, 0x43, 0x55, 0x4F, 0x59, 0x46,
+];
+
+const AUTH0_DISABLED: u8 = 0x30;
+
+// 2-key 3DES in CBC mode, as the card uses it; `data` is a multiple of 8 bytes
+pub(crate) fn encrypt(key: &[u8; 16], iv: &[u8; 8], data: &[u8]) -> Vec<u8> {
+    let cipher = TdesEde2::new(GenericArray::from_slice(key));
+    let mut chain = *iv;
+    let mut output = Vec::with_capacity(data.len());
+    for block in data.chunks(8) {
+        let mut buffer = GenericArray::clone_from_slice(block);
+        for (byte, previous) in buffer.iter_mut().zip(chain.iter()) {
+            *byte ^= previous;
+        }
+        cipher.encrypt_block(&mut buffer);
+        chain.copy_from_slice(&buffer);
+        output.extend_from_slice(&buffer);
+    }
+    output
+}
+
+pub(crate) fn decrypt(key: &[u8; 16], iv: &[u8; 8], data: &[u8]) -> Vec<u8> {
+    let cipher = TdesEde2::new(GenericArray::from_slice(key));
+    let mut chain = *iv;
+    let mut output = Vec::with_capacity(data.len());
+    for block in data.chunks(8) {
+        let mut buffer = GenericArray::clone_from_slice(block);
+        cipher.decrypt_block(&mut buffer);
+        for (byte, previous) in buffer.iter_mut().zip(chain.iter()) {
+            *byte ^= previous;
+        }
+        chain.copy_from_slice(block);
+        output.extend_from_slice(&buffer);
+    }
+    output
+}
+
+// Random number rotated left by one byte, as both sides return it
+pub(crate) fn rotate(rnd: &[u8; 8]) -> [u8; 8] {
+    let mut rotated = *rnd;
+    rotated.rotate_left(1);
+    rotated
+}
+
+fn block(data: &[u8]) -> Result<[u8; 8], MifareError> {
+    data.try_into().map_err(|_| MifareError::new("Invalid AUTHENTICATE response length"))
+}
+
+// Key as written to pages 2C-2F: each 8-byte DES key is stored with its
+// bytes reversed
+pub fn key_pages(key: &[u8; 16]) -> [[u8; 4]; 4] {
+    let mut pages = [[0u8; 4]; 4];
+    for (i, page) in pages.iter_mut().enumerate() {
+        // Pages 2C, 2D hold K1 bytes 7..0, pages 2E, 2F K2 bytes 15..8
+        let last = (i / 2) * 8 + 7 - (i % 2) * 4;
+        for (j, byte) in page.iter_mut().enumerate() {
+            *byte = key[last - j];
+        }
+    }
+    pages
+}
+
+// Reader side of the mutual authentication, kept apart from the card I/O so
+// each step can be checked against known vectors
+pub struct Handshake {
+    key: [u8; 16],
+    rnd_a: [u8; 8],
+    // Last ciphertext block, the IV of the next step
+    iv: [u8; 8],
+}
+
+impl Handshake {
+    pub fn new(key: &[u8; 16], rnd_a: &[u8; 8]) -> Handshake {
+        Handshake { key: *key, rnd_a: *rnd_a, iv: [0x00; 8] }
+    }
+
+    // Decrypt ek(RndB) from the card's first answer and build ek(RndA || RndB')
+    pub fn respond(&mut self, ek_rnd_b: &[u8]) -> Result<[u8; 16], MifareError> {
+        let ek_rnd_b = block(ek_rnd_b)?;
+        let rnd_b = block(&decrypt(&self.key, &[0x00; 8], &ek_rnd_b))?;
+
+        let mut plain = self.rnd_a.to_vec();
+        plain.extend_from_slice(&rotate(&rnd_b));
+        let token = encrypt(&self.key, &ek_rnd_b, &plain);
+
+        self.iv = block(&token[8..16])?;
+        let mut response = [0u8; 16];
+        response.copy_from_slice(&token);
+        Ok(response)
+    }
+
+    // Check that the card's ek(RndA') proves it knows the key too
+    pub fn verify(&self, ek_rnd_a: &[u8]) -> Result<(), MifareError> {
+        let rnd_a = decrypt(&self.key, &self.iv, &block(ek_rnd_a)?);
+        if rnd_a != rotate(&self.rnd_a) {
+            return Err(MifareError::new("Card did not prove knowledge of the key (RndA' mismatch)"));
+        }
+        Ok(())
+    }
+}
+
+// AUTH0 and AUTH1 of an Ultralight C
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthConfig {
+    // First page that needs authentication
+    pub auth0: u8,
+    pub protection: Protection,
+}
+
+impl AuthConfig {
+    pub fn decode(auth0: u8, auth1: u8) -> AuthConfig {
+        AuthConfig {
+            auth0,
+            protection: if auth1 & 0x01 != 0 { Protection::Write } else { Protection::ReadWrite },
+        }
+    }
+
+    pub fn auth1(&self) -> u8 {
+        match self.protection {
+            Protection::Write => 0x01,
+            Protection::ReadWrite => 0x00,
+        }
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.auth0 < AUTH0_DISABLED
+    }
+}
+
+fn require_ultralight_c(model: UltralightModel) -> Result<(), Box<dyn Error>> {
+    if model != UltralightModel::UltralightC {
+        return Err(Box::new(MifareError::new(&format!("{} has no 3DES authentication", model.to_string()))));
+    }
+    Ok(())
+}
+
+// Ultralight C 3DES authentication and its configuration
+impl MifareUltralight<'_> {
+    // Mutual authentication with the 16-byte key. A failure halts the card;
+    // it is reactivated before returning.
+    pub fn authenticate_3des(&self, key: &[u8; 16]) -> Result<(), Box<dyn Error>> {
+        let mut rnd_a = [0u8; 8];
+        getrandom::getrandom(&mut rnd_a)?;
+
+        let result = self.run_handshake(Handshake::new(key, &rnd_a));
+        if result.is_err() {
+            let _ = self.reactivate();
+        }
+        result
+    }
+
+    fn run_handshake(&self, mut handshake: Handshake) -> Result<(), Box<dyn Error>> {
+        let response = self.transceive(&[AUTHENTICATE, 0x00])?;
+        let ek_rnd_b = match response.split_first() {
+            Some((&AUTHENTICATE_PART2, ek_rnd_b)) => ek_rnd_b,
+            _ => return Err(Box::new(MifareError::new("AUTHENTICATE refused"))),
+        };
+
+        let mut frame = vec![AUTHENTICATE_PART2];
+        frame.extend_from_slice(&handshake.respond(ek_rnd_b)?);
+        let response = self.transceive(&frame)?;
+        match response.split_first() {
+            Some((&0x00, ek_rnd_a)) => Ok(handshake.verify(ek_rnd_a)?),
+            _ => Err(Box::new(MifareError::new("Authentication failed: wrong key"))),
+        }
+    }
+
+    pub fn read_auth_config(&self, model: UltralightModel) -> Result<AuthConfig, Box<dyn Error>> {
+        require_ultralight_c(model)?;
+        let data = self.read(AUTH0_PAGE)?;
+        Ok(AuthConfig::decode(data[0], data[4]))
+    }
+
+    // AUTH1 first, so AUTH0 only takes effect with the intended protection
+    pub fn write_auth_config(&self, model: UltralightModel, config: &AuthConfig) -> Result<(), Box<dyn Error>> {
+        require_ultralight_c(model)?;
+        self.write_page(AUTH1_PAGE, &[config.auth1(), 0x00, 0x00, 0x00])?;
+        self.write_page(AUTH0_PAGE, &[config.auth0, 0x00, 0x00, 0x00])
+    }
+
+    // Write a new key to pages 2C-2F; it cannot be read back
+    pub fn change_key(&self, model: UltralightModel, key: &[u8; 16]) -> Result<(), Box<dyn Error>> {
+        require_ultralight_c(model)?;
+        for (i, page) in key_pages(key).iter().enumerate() {
+            self.write_page(KEY_PAGE + i as u8, page)?;
+        }
+        Ok(())
+    }
+
+    // Protect the pages from `auth0` on with `key`. The key is written first,
+    // so a failure part way never leaves the card protected by an unknown key.
+    pub fn enable_3des_protection(&self, model: UltralightModel, key: &[u8; 16],
+                                  auth0: u8, protection: Protection) -> Result<(), Box<dyn Error>> {
+        if !(0x03..AUTH0_DISABLED).contains(&auth0) {
+            return Err(Box::new(MifareError::new("AUTH0 must be between 03h and 2Fh")));
+        }
+
+        self.change_key(model, key)?;
+        self.write_auth_config(model, &AuthConfig { auth0, protection })
+    }
+
+    // AUTH0 = 30h switches the protection off; needs a prior authentication
+    // when the configuration pages are protected
+    pub fn disable_3des_protection(&self, model: UltralightModel) -> Result<(), Box<dyn Error>> {
+        require_ultralight_c(model)?;
+        self.write_page(AUTH0_PAGE, &[AUTH0_DISABLED, 0x00, 0x00, 0x00])
+    }
+}
@@ -0,0 +1,94 @@
+use acr122u_test::atr::{card_name, Atr, InterfaceBytes, PCSC_RID};
+use acr122u_test::identify::{identify_atr, MifareType};
+
+// ATRs the ACR122U builds for the cards it sees
+const CLASSIC_1K: &[u8] = &[
+    0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x6A,
+];
+const CLASSIC_4K: &[u8] = &[
+    0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x03, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x69,
+];
+const ULTRALIGHT: &[u8] = &[
+    0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x03, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x68,
+];
+const MINI: &[u8] = &[
+    0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x03, 0x00, 0x26, 0x00, 0x00, 0x00, 0x00, 0x4D,
+];
+const DESFIRE: &[u8] = &[0x3B, 0x81, 0x80, 0x01, 0x80, 0x80];
+const PLUS: &[u8] = &[0x3B, 0x87, 0x80, 0x01, 0xC1, 0x05, 0x2F, 0x2F, 0x01, 0xBC, 0xD6, 0xA9];
+
+#[test]
+fn storage_card_atr_is_decoded() {
+    let atr = Atr::parse(CLASSIC_1K).unwrap();
+    assert!(atr.is_direct_convention());
+    assert_eq!(atr.t0, 0x8F);
+    assert_eq!(atr.interface, [
+        InterfaceBytes { ta: None, tb: None, tc: None, td: Some(0x80) },
+        InterfaceBytes { ta: None, tb: None, tc: None, td: Some(0x01) },
+    ]);
+    assert_eq!(atr.protocols(), [0, 1]);
+    assert_eq!(atr.historical_bytes, &CLASSIC_1K[4..19]);
+    assert_eq!(atr.tck, Some(0x6A));
+    assert_eq!(atr.tck_valid(), Some(true));
+
+    let storage = atr.pcsc_storage_card().unwrap();
+    assert_eq!(storage.rid, PCSC_RID);
+    assert_eq!(storage.standard, 0x03);
+    assert_eq!(storage.card_name, 0x0001);
+    assert_eq!(card_name(storage.card_name), Some("MIFARE Classic 1K"));
+    assert_eq!(Atr::parse(MINI).unwrap().pcsc_storage_card().unwrap().card_name, 0x0026);
+}
+
+#[test]
+fn iso14443_4_atr_carries_ats_historical_bytes() {
+    let atr = Atr::parse(DESFIRE).unwrap();
+    assert_eq!(atr.historical_bytes, [0x80]);
+    assert_eq!(atr.tck_valid(), Some(true));
+    assert_eq!(atr.pcsc_storage_card(), None);
+
+    let atr = Atr::parse(PLUS).unwrap();
+    assert_eq!(atr.historical_bytes, [0xC1, 0x05, 0x2F, 0x2F, 0x01, 0xBC, 0xD6]);
+    assert_eq!(atr.pcsc_storage_card(), None);
+}
+
+#[test]
+fn tck_is_checked() {
+    let mut bytes = CLASSIC_1K.to_vec();
+    bytes[19] ^= 0x01;
+    assert_eq!(Atr::parse(&bytes).unwrap().tck_valid(), Some(false));
+
+    // T=0 only: no TCK
+    let atr = Atr::parse(&[0x3B, 0x02, 0x14, 0x50]).unwrap();
+    assert_eq!(atr.tck, None);
+    assert_eq!(atr.tck_valid(), None);
+}
+
+#[test]
+fn malformed_atrs_are_rejected() {
+    assert!(Atr::parse(&[0x3B]).is_err());
+    assert!(Atr::parse(&[0x3A, 0x00]).is_err());
+    assert!(Atr::parse(&CLASSIC_1K[..19]).is_err());
+    assert!(Atr::parse(&CLASSIC_1K[..10]).is_err());
+    assert!(Atr::parse(&[CLASSIC_1K, &[0x00]].concat()).is_err());
+    assert!(Atr::parse(&[0x3B, 0x80]).is_err());
+}
+
+#[test]
+fn card_type_from_atr() {
+    assert_eq!(identify_atr(CLASSIC_1K), MifareType::MifareClassic1K);
+    assert_eq!(identify_atr(CLASSIC_4K), MifareType::MifareClassic4K);
+    assert_eq!(identify_atr(ULTRALIGHT), MifareType::MifareUltralight);
+    assert_eq!(identify_atr(MINI), MifareType::MifareMini);
+    assert_eq!(identify_atr(DESFIRE), MifareType::MifareDesfire);
+    assert_eq!(identify_atr(PLUS), MifareType::MifarePlus);
+
+    // Only the card name counts: a FeliCa storage card whose ATR has
+    // "00 01" elsewhere, or "00 01" in the historical bytes of another card
+    let mut felica = CLASSIC_1K.to_vec();
+    felica[12] = 0x11;
+    felica[14] = 0x3B;
+    felica[16] = 0x01;
+    assert_eq!(identify_atr(&felica), MifareType::Unknown);
+    assert_eq!(identify_atr(&[0x3B, 0x02, 0x00, 0x01]), MifareType::Unknown);
+    assert_eq!(identify_atr(&[]), MifareType::Unknown);
+}
@@ -0,0 +1,156 @@
+use std::fs;
+use std::path::PathBuf;
+use acr122u_test::atr_db::{AtrConflict, AtrDatabase, AtrSource};
+
+// ATR the ACR122U reports for a MIFARE Classic 1K card
+const CLASSIC_1K_ATR: [u8; 20] = [
+    0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x6A,
+];
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("acr122u_test-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn bundled_lookup_ranks_exact_entry_first() {
+    let db = AtrDatabase::bundled();
+    let matches = db.lookup(&CLASSIC_1K_ATR);
+    assert!(matches.len() >= 2);
+    assert_eq!(matches[0].pattern.source(), "3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 01 00 00 00 00 6A");
+    assert!(matches[0].descriptions[0].contains("MIFARE Classic 1K"));
+    assert!(matches.iter().any(|e| e.pattern.source() == "3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 .. .. 00 00 00 00 .."));
+    assert!(matches.windows(2).all(|w| w[0].pattern.specificity_cmp(&w[1].pattern).is_le()));
+    assert!(db.lookup(&[0x3B, 0x00]).is_empty());
+}
+
+#[test]
+fn cache_round_trip() {
+    let db = AtrDatabase::parse("3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 01 00 00 00 00 6A\n\tClassic 1K\n\n\
+                                 3B 8[0-9A-F] 80 01 .*\n\tAny contactless card\n\tSecond line\n", AtrSource::Override);
+    let conflicts = vec![AtrConflict {
+        pattern: "3B 00".to_string(),
+        kept: AtrSource::Override,
+        kept_descriptions: vec!["Mine".to_string()],
+        dropped: AtrSource::Bundled,
+        dropped_descriptions: vec!["Theirs".to_string()],
+    }];
+    let path = temp_path("round-trip.cache");
+    db.save_cache(&path, &conflicts, 42).unwrap();
+
+    let (loaded, loaded_conflicts) = AtrDatabase::load_cache(&path, 42).unwrap();
+    assert_eq!(loaded.entries(), db.entries());
+    assert_eq!(loaded_conflicts, conflicts);
+    assert_eq!(loaded.lookup(&CLASSIC_1K_ATR).len(), 2);
+
+    // A cache built from other sources is refused
+    assert!(AtrDatabase::load_cache(&path, 43).is_err());
+
+    // So is a truncated one: the trie is read back, not rebuilt
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..bytes.len() - 8]).unwrap();
+    assert!(AtrDatabase::load_cache(&path, 42).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn load_cached_rebuilds_when_sources_change() {
+    let cache = temp_path("rebuild.cache");
+    let overrides = temp_path("rebuild.local.txt");
+    fs::write(&overrides, "3B 00\n\tFirst\n").unwrap();
+
+    let (db, _) = AtrDatabase::load_cached(&cache, None, Some(&overrides)).unwrap();
+    assert_eq!(db.lookup(&[0x3B, 0x00])[0].descriptions, ["First"]);
+    assert!(cache.exists());
+    let (db, _) = AtrDatabase::load_cached(&cache, None, Some(&overrides)).unwrap();
+    assert_eq!(db.len(), AtrDatabase::bundled().len() + 1);
+
+    fs::write(&overrides, "3B 00\n\tSecond, longer\n").unwrap();
+    let (db, _) = AtrDatabase::load_cached(&cache, None, Some(&overrides)).unwrap();
+    assert_eq!(db.lookup(&[0x3B, 0x00])[0].descriptions, ["Second, longer"]);
+
+    fs::remove_file(&cache).unwrap();
+    fs::remove_file(&overrides).unwrap();
+}
+
+const UPSTREAM: &str = "\
+3B 00
+\tUpstream card
+
+3B 01
+\tSame everywhere
+";
+
+const LOCAL: &str = "\
+# Corrected and new entries
+3b 00
+\tCorrected card
+
+3B 01
+\tSame everywhere
+
+3B 02 ..
+\tPrivate badge
+\thttps://example.com/badge
+";
+
+#[test]
+fn merge_reports_conflicts() {
+    let mut db = AtrDatabase::parse(UPSTREAM, AtrSource::Downloaded);
+    let conflicts = db.merge(AtrDatabase::parse(LOCAL, AtrSource::Override));
+    assert_eq!(conflicts, [AtrConflict {
+        pattern: "3b 00".to_string(),
+        kept: AtrSource::Override,
+        kept_descriptions: vec!["Corrected card".to_string()],
+        dropped: AtrSource::Downloaded,
+        dropped_descriptions: vec!["Upstream card".to_string()],
+    }]);
+    assert_eq!(db.len(), 3);
+    assert_eq!(db.lookup(&[0x3B, 0x00])[0].descriptions, ["Corrected card"]);
+    assert_eq!(db.lookup(&[0x3B, 0x01])[0].source, AtrSource::Downloaded);
+
+    // A lower-precedence source loses against what is already there
+    let mut db = AtrDatabase::parse(LOCAL, AtrSource::Override);
+    let conflicts = db.merge(AtrDatabase::parse(UPSTREAM, AtrSource::Bundled));
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!((conflicts[0].kept, conflicts[0].dropped), (AtrSource::Override, AtrSource::Bundled));
+    assert_eq!(db.lookup(&[0x3B, 0x00])[0].descriptions, ["Corrected card"]);
+}
+
+#[test]
+fn export_lists_only_new_patterns() {
+    let upstream = AtrDatabase::parse(UPSTREAM, AtrSource::Downloaded);
+    let local = AtrDatabase::parse(LOCAL, AtrSource::Override);
+    let mut out = Vec::new();
+    assert_eq!(local.export_additions(&upstream, &mut out).unwrap(), 1);
+    assert_eq!(String::from_utf8(out).unwrap(), "3B 02 ..\n\tPrivate badge\n\thttps://example.com/badge\n\n");
+
+    // The export reads back as the same entry
+    let exported = {
+        let mut out = Vec::new();
+        local.export_additions(&upstream, &mut out).unwrap();
+        AtrDatabase::parse(&String::from_utf8(out).unwrap(), AtrSource::Override)
+    };
+    assert_eq!(exported.entries(), &local.entries()[2..]);
+}
+
+#[test]
+fn merge_replaces_every_duplicate() {
+    // The bundled list may carry the same pattern twice
+    let mut db = AtrDatabase::parse("3B 00\n\tFirst copy\n\n3B 00\n\tSecond copy\n\n3B 01\n\tOther\n", AtrSource::Bundled);
+    assert_eq!(db.lookup(&[0x3B, 0x00]).len(), 2);
+
+    let conflicts = db.merge(AtrDatabase::parse("3B 00\n\tCorrected card\n", AtrSource::Override));
+    let dropped: Vec<&[String]> = conflicts.iter().map(|c| &c.dropped_descriptions[..]).collect();
+    assert_eq!(dropped, [["First copy"], ["Second copy"]]);
+    assert!(conflicts.iter().all(|c| c.kept_descriptions == ["Corrected card"]));
+
+    let matches = db.lookup(&[0x3B, 0x00]);
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].descriptions, ["Corrected card"]);
+    assert_eq!(db.len(), 2);
+
+    // Duplicates of the higher source both stay against a lower one
+    let mut db = AtrDatabase::parse("3B 00\n\tMine\n\n3B 00\n\tAlso mine\n", AtrSource::Override);
+    assert_eq!(db.merge(AtrDatabase::parse("3B 00\n\tTheirs\n", AtrSource::Downloaded)).len(), 1);
+    assert_eq!(db.lookup(&[0x3B, 0x00]).len(), 2);
+}
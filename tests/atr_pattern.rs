@@ -0,0 +1,94 @@
+mod common;
+
+use acr122u_test::atr_pattern::{atr_to_string, AtrPattern};
+use common::hex;
+
+// Patterns as they appear in smartcard_list.txt
+const CLASSIC_1K: &str = "3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 01 00 00 00 00 6A";
+const PCSC_PART3: &str = "3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 .. .. 00 00 00 00 ..";
+const KAZAKH_ID: &str = "3B 39 19 00 53 4F 4D 41 44 53 33 30 3[1,3]";
+const GEMXPLORE: &str = "3B 3B .. 00 80 6. A[FE] 03 0[CD] .. .. 83 .. 90 00";
+const UNI_CARD: &str = "3B EF 00 FF 81 31 42 45 .* 38";
+
+#[test]
+fn wildcard_bytes() {
+    let pattern = AtrPattern::compile(PCSC_PART3).unwrap();
+    assert!(pattern.matches(&hex(CLASSIC_1K)));
+    assert!(pattern.matches(&hex("3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 02 00 00 00 00 69")));
+    assert!(pattern.matches(&hex("3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 3A 00 00 00 00 51")));
+    // Different RID, a byte short or a byte too many
+    assert!(!pattern.matches(&hex("3B 8F 80 01 80 4F 0C A0 00 00 03 07 03 00 01 00 00 00 00 6A")));
+    assert!(!pattern.matches(&hex("3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 01 00 00 00 00")));
+    assert!(!pattern.matches(&hex("3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 01 00 00 00 00 6A 00")));
+
+    assert!(!pattern.is_exact());
+    assert_eq!(pattern.wildcard_count(), 6);
+    assert_eq!(pattern.fixed_count(), 34);
+}
+
+#[test]
+fn bracket_classes() {
+    let pattern = AtrPattern::compile(KAZAKH_ID).unwrap();
+    assert!(pattern.matches(&hex("3B 39 19 00 53 4F 4D 41 44 53 33 30 31")));
+    assert!(pattern.matches(&hex("3B 39 19 00 53 4F 4D 41 44 53 33 30 33")));
+    assert!(!pattern.matches(&hex("3B 39 19 00 53 4F 4D 41 44 53 33 30 32")));
+    assert!(!pattern.matches(&hex("3B 39 19 00 53 4F 4D 41 44 53 33 30 41")));
+
+    let pattern = AtrPattern::compile(GEMXPLORE).unwrap();
+    assert!(pattern.matches(&hex("3B 3B 94 00 80 65 AF 03 0D 01 74 83 0F 90 00")));
+    assert!(pattern.matches(&hex("3B 3B 11 00 80 69 AE 03 0C 01 6F 83 00 90 00")));
+    assert!(!pattern.matches(&hex("3B 3B 94 00 80 65 AD 03 0D 01 74 83 0F 90 00")));
+    assert!(!pattern.matches(&hex("3B 3B 94 00 80 65 AF 03 0E 01 74 83 0F 90 00")));
+
+    let pattern = AtrPattern::compile("3B [1-5]F [^0]0").unwrap();
+    assert!(pattern.matches(&[0x3B, 0x3F, 0x10]));
+    assert!(!pattern.matches(&[0x3B, 0x6F, 0x10]));
+    assert!(!pattern.matches(&[0x3B, 0x3F, 0x00]));
+}
+
+#[test]
+fn repetitions() {
+    let pattern = AtrPattern::compile(UNI_CARD).unwrap();
+    assert!(pattern.matches(&hex("3B EF 00 FF 81 31 42 45 01 38")));
+    assert!(pattern.matches(&hex("3B EF 00 FF 81 31 42 45 65 63 08 24 13 03 38")));
+    assert!(!pattern.matches(&hex("3B EF 00 FF 81 31 42 45 01 39")));
+    assert!(!pattern.matches(&hex("3B EF 00 FF 81 31 42 46 01 38")));
+    // Repetitions have no fixed length and cannot be indexed by byte
+    assert_eq!(pattern.byte_masks(), None);
+}
+
+#[test]
+fn byte_masks_widen_classes_to_nibbles() {
+    let pattern = AtrPattern::compile(KAZAKH_ID).unwrap();
+    let masks = pattern.byte_masks().unwrap();
+    assert_eq!(masks.len(), 13);
+    assert_eq!(masks[0], (0x3B, 0xFF));
+    assert_eq!(masks[12], (0x30, 0xF0));
+    assert_eq!(AtrPattern::compile(PCSC_PART3).unwrap().byte_masks().unwrap()[13], (0x00, 0x00));
+}
+
+#[test]
+fn ranking_puts_exact_patterns_first() {
+    let mut patterns: Vec<AtrPattern> = [UNI_CARD, PCSC_PART3, "3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 0. 00 00 00 00 ..", CLASSIC_1K]
+        .iter()
+        .map(|source| AtrPattern::compile(source).unwrap())
+        .collect();
+    patterns.sort_by(|a, b| a.specificity_cmp(b));
+    let order: Vec<&str> = patterns.iter().map(|p| p.source()).collect();
+    assert_eq!(order, [CLASSIC_1K, "3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 0. 00 00 00 00 ..", PCSC_PART3, UNI_CARD]);
+    assert!(patterns[0].is_exact());
+}
+
+#[test]
+fn malformed_patterns_are_rejected() {
+    assert!(AtrPattern::compile("3B [12").is_err());
+    assert!(AtrPattern::compile("* 3B").is_err());
+    assert!(AtrPattern::compile("3B G0").is_err());
+    assert!(AtrPattern::compile("3B [5-1]").is_err());
+}
+
+#[test]
+fn atr_text_form() {
+    assert_eq!(atr_to_string(&hex(CLASSIC_1K)), CLASSIC_1K);
+    assert!(AtrPattern::compile(&atr_to_string(&hex(CLASSIC_1K))).unwrap().is_exact());
+}
@@ -0,0 +1,114 @@
+use acr122u_test::capability::{Capabilities, CryptoSuite, Handler, Operation};
+use acr122u_test::identify::{Identification, MifareType};
+
+struct Case {
+    card_type: MifareType,
+    product: &'static str,
+    generation: Option<&'static str>,
+    security_level: Option<&'static str>,
+    handlers: &'static [Handler],
+    crypto: &'static [CryptoSuite],
+}
+
+const CASES: &[Case] = &[
+    Case { card_type: MifareType::MifareClassic1K, product: "MIFARE Classic 1K", generation: None, security_level: None,
+           handlers: &[Handler::Classic], crypto: &[CryptoSuite::Crypto1] },
+    Case { card_type: MifareType::MifareClassic4K, product: "MIFARE Classic 4K", generation: None, security_level: None,
+           handlers: &[Handler::Classic], crypto: &[CryptoSuite::Crypto1] },
+    Case { card_type: MifareType::MifareMini, product: "MIFARE Mini", generation: None, security_level: None,
+           handlers: &[Handler::Classic], crypto: &[CryptoSuite::Crypto1] },
+    Case { card_type: MifareType::MifareUltralight, product: "MIFARE Ultralight (MF0ICU1)", generation: None, security_level: None,
+           handlers: &[Handler::Ultralight], crypto: &[CryptoSuite::None] },
+    Case { card_type: MifareType::MifareUltralight, product: "MIFARE Ultralight C (MF0ICU2)", generation: Some("C"), security_level: None,
+           handlers: &[Handler::Ultralight], crypto: &[CryptoSuite::TripleDes] },
+    Case { card_type: MifareType::MifareUltralight, product: "MIFARE Ultralight EV1 (MF0UL21)", generation: Some("EV1"), security_level: None,
+           handlers: &[Handler::Ultralight], crypto: &[CryptoSuite::Password32] },
+    Case { card_type: MifareType::Ntag, product: "NTAG215", generation: None, security_level: None,
+           handlers: &[Handler::Ultralight], crypto: &[CryptoSuite::Password32] },
+    Case { card_type: MifareType::MifareDesfire, product: "MIFARE DESFire", generation: Some("EV0"), security_level: None,
+           handlers: &[Handler::Desfire], crypto: &[CryptoSuite::Des, CryptoSuite::TripleDes] },
+    Case { card_type: MifareType::MifareDesfire, product: "MIFARE DESFire EV1 4K", generation: Some("EV1"), security_level: None,
+           handlers: &[Handler::Desfire], crypto: &[CryptoSuite::Des, CryptoSuite::TripleDes, CryptoSuite::Aes128] },
+    Case { card_type: MifareType::MifareDesfire, product: "MIFARE DESFire EV3 2K", generation: Some("EV3"), security_level: None,
+           handlers: &[Handler::Desfire], crypto: &[CryptoSuite::Des, CryptoSuite::TripleDes, CryptoSuite::Aes128] },
+    Case { card_type: MifareType::MifareDesfire, product: "MIFARE DESFire Light", generation: None, security_level: None,
+           handlers: &[Handler::Desfire], crypto: &[CryptoSuite::Aes128] },
+    // Only SL1 is Classic compatible
+    Case { card_type: MifareType::MifarePlus, product: "MIFARE Plus 2K", generation: None, security_level: Some("SL1"),
+           handlers: &[Handler::Classic], crypto: &[CryptoSuite::Crypto1] },
+    Case { card_type: MifareType::MifarePlus, product: "MIFARE Plus 4K", generation: None, security_level: Some("SL2"),
+           handlers: &[], crypto: &[CryptoSuite::Crypto1, CryptoSuite::Aes128] },
+    Case { card_type: MifareType::MifarePlus, product: "MIFARE Plus EV1 2K", generation: Some("EV1"), security_level: Some("SL0/SL3"),
+           handlers: &[], crypto: &[CryptoSuite::Aes128] },
+    Case { card_type: MifareType::MifarePlus, product: "MIFARE Plus", generation: None, security_level: None,
+           handlers: &[], crypto: &[CryptoSuite::Crypto1, CryptoSuite::Aes128] },
+    Case { card_type: MifareType::Unknown, product: "Unknown card (SAK 53)", generation: None, security_level: None,
+           handlers: &[], crypto: &[] },
+];
+
+fn identification(case: &Case) -> Identification {
+    Identification {
+        card_type: case.card_type.clone(),
+        product: case.product.to_string(),
+        generation: case.generation.map(str::to_string),
+        memory_size: None,
+        security_level: case.security_level.map(str::to_string),
+        originality: None,
+    }
+}
+
+#[test]
+fn handlers_per_identification() {
+    for case in CASES {
+        let caps = Capabilities::for_identification(&identification(case));
+        assert_eq!(caps.card_type, case.card_type, "{}", case.product);
+        assert_eq!(caps.handlers, case.handlers, "{}", case.product);
+        assert_eq!(caps.crypto, case.crypto, "{}", case.product);
+    }
+}
+
+#[test]
+fn ultralight_generations_change_operations() {
+    let case = &CASES[4];
+    let caps = Capabilities::for_identification(&identification(case));
+    assert!(caps.supports(Operation::ThreeDesAuth));
+    assert!(!caps.supports(Operation::PasswordAuth));
+
+    let case = &CASES[5];
+    let caps = Capabilities::for_identification(&identification(case));
+    assert!(caps.supports(Operation::PasswordAuth));
+    assert!(caps.supports(Operation::OriginalitySignature));
+    assert!(caps.unavailable_operations().is_empty());
+}
+
+#[test]
+fn reported_memory_size_wins() {
+    let mut id = identification(&CASES[6]);
+    assert_eq!(Capabilities::for_identification(&id).memory_size, None);
+    id.memory_size = Some(504);
+    assert_eq!(Capabilities::for_identification(&id).memory_size, Some(504));
+    // The family default stays when the card reported nothing
+    assert_eq!(Capabilities::for_identification(&identification(&CASES[0])).memory_size, Some(1024));
+}
+
+#[test]
+fn operations_without_a_handler_are_unavailable() {
+    // SL3 Plus: block operations need AES, which no handler speaks
+    let caps = Capabilities::for_identification(&identification(&CASES[13]));
+    assert!(caps.available_operations().is_empty());
+    assert_eq!(caps.unavailable_operations(), caps.operations);
+
+    // Everything a Classic 1K supports, NDEF included, has a handler
+    let caps = Capabilities::for_identification(&identification(&CASES[0]));
+    assert!(caps.unavailable_operations().is_empty());
+}
+
+#[test]
+fn desfire_offers_secure_messaging() {
+    for case in &CASES[7..11] {
+        let caps = Capabilities::for_identification(&identification(case));
+        assert_eq!(caps.available_operations(), [Operation::ReadUid, Operation::Applications, Operation::SecureMessaging], "{}", case.product);
+        // Type 4 Tag NDEF is on the card, but not mapped yet
+        assert_eq!(caps.unavailable_operations(), [Operation::Ndef], "{}", case.product);
+    }
+}
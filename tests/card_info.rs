@@ -0,0 +1,78 @@
+mod common;
+
+use acr122u_test::card_info::{ats_historical_bytes, CardInfo};
+use acr122u_test::pn532::Target;
+use common::hex;
+
+// ATR the ACR122U builds for a MIFARE Classic 1K and a DESFire
+const CLASSIC_1K_ATR: &str = "3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 00 01 00 00 00 00 6A";
+const DESFIRE_ATR: &str = "3B 81 80 01 80 80";
+
+#[test]
+fn ats_interface_bytes_are_skipped() {
+    let cases = [
+        // DESFire EV1: TA, TB and TC present, FSCI 5
+        ("06 75 77 81 02 80", "80"),
+        // Same with FSCI 8: the low nibble of T0 does not move the historical bytes
+        ("06 78 77 81 02 80", "80"),
+        // MIFARE Plus
+        ("0C 75 77 80 02 C1 05 2F 2F 01 BC D6", "C1 05 2F 2F 01 BC D6"),
+        // TA only, TB only, TC only
+        ("04 18 77 AB", "AB"),
+        ("04 28 81 AB", "AB"),
+        ("04 48 02 AB", "AB"),
+        // TA and TC, without TB
+        ("06 58 77 02 AB CD", "AB CD"),
+        // No interface bytes
+        ("03 08 AB", "AB"),
+    ];
+    for (ats, historical) in cases {
+        assert_eq!(ats_historical_bytes(&hex(ats)), hex(historical), "ATS {}", ats);
+    }
+}
+
+#[test]
+fn ats_length_comes_from_tl() {
+    // Bytes after TL (e.g. a CRC left by the reader) are not historical bytes
+    assert_eq!(ats_historical_bytes(&hex("03 08 AB 12 34")), [0xAB]);
+    // A TL longer than the data stops at what was received
+    assert_eq!(ats_historical_bytes(&hex("06 05 AB CD")), [0xAB, 0xCD]);
+    assert!(ats_historical_bytes(&hex("06 75 77 81")).is_empty());
+    // No historical bytes, a TL-only ATS and nothing at all
+    assert!(ats_historical_bytes(&hex("05 75 77 81 02")).is_empty());
+    assert!(ats_historical_bytes(&hex("01")).is_empty());
+    assert!(ats_historical_bytes(&[]).is_empty());
+}
+
+#[test]
+fn historical_bytes_prefer_the_ats() {
+    let target = Target {
+        tg: 1,
+        atqa: [0x03, 0x44],
+        sak: 0x20,
+        uid: hex("04 12 34 56 78 9A BC"),
+        ats: Some(hex("06 75 77 81 02 80")),
+    };
+    let info = CardInfo::from_target(&target, &hex(DESFIRE_ATR));
+    assert_eq!(info.uid, target.uid);
+    assert_eq!(info.atqa, Some([0x03, 0x44]));
+    assert_eq!(info.sak, Some(0x20));
+    assert_eq!(info.ats, target.ats);
+    assert_eq!(info.historical_bytes, [0x80]);
+    assert!(info.supports_iso14443_4());
+}
+
+#[test]
+fn historical_bytes_fall_back_to_the_atr() {
+    let target = Target { tg: 1, atqa: [0x00, 0x04], sak: 0x08, uid: hex("DE AD BE EF"), ats: None };
+    let info = CardInfo::from_target(&target, &hex(CLASSIC_1K_ATR));
+    assert_eq!(info.ats, None);
+    assert_eq!(info.historical_bytes, hex("80 4F 0C A0 00 00 03 06 03 00 01 00 00 00 00"));
+    assert!(!info.supports_iso14443_4());
+    assert_eq!(info.to_string(), "UID: DEADBEEF\nATQA: 0004\nSAK: 08\n\
+                                  Historical bytes: 804F0CA00000030603000100000000\n\
+                                  ATR: 3B8F8001804F0CA000000306030001000000006A");
+
+    // Without an ATR either, there are none
+    assert!(CardInfo::from_target(&target, &[]).historical_bytes.is_empty());
+}
@@ -0,0 +1,123 @@
+use acr122u_test::classic::{sector_first_block, sector_trailer, KeyType, MifareClassic};
+use acr122u_test::classic_ndef::{data_blocks, ndef_tlv_stream, parse_ndef_stream, NFC_KEY_A};
+use acr122u_test::mad::AID_NDEF;
+use acr122u_test::ndef::{NdefMessage, NdefRecord};
+use acr122u_test::simulator::ClassicSimulator;
+
+#[test]
+fn sector_layout() {
+    assert_eq!(sector_first_block(1), 4);
+    assert_eq!(sector_trailer(15), 63);
+    assert_eq!(sector_first_block(32), 128);
+    assert_eq!(sector_trailer(32), 143);
+    assert_eq!(sector_trailer(39), 255);
+}
+
+#[test]
+fn data_blocks_skip_trailers() {
+    assert_eq!(data_blocks(&[1, 2]), vec![4, 5, 6, 8, 9, 10]);
+    assert_eq!(data_blocks(&[39]).len(), 15);
+}
+
+#[test]
+fn tlv_stream_spans_blocks() {
+    let message = NdefMessage::new(vec![NdefRecord::uri("https://www.example.com/some/longer/path")]);
+    let stream = ndef_tlv_stream(&message, 3 * 16).unwrap();
+    assert_eq!(stream.len() % 16, 0);
+    assert_eq!(stream[0], 0x03);
+    assert_eq!(parse_ndef_stream(&stream).unwrap(), message);
+
+    // An empty message fits in one block
+    let empty = ndef_tlv_stream(&NdefMessage::empty(), 16).unwrap();
+    assert_eq!(&empty[0..3], &[0x03, 0x00, 0xFE]);
+    assert!(parse_ndef_stream(&empty).unwrap().is_empty());
+
+    assert!(ndef_tlv_stream(&message, 16).is_err());
+    assert!(parse_ndef_stream(&[0x00; 16]).is_err());
+}
+
+const UID: [u8; 4] = [0xCD, 0x76, 0x92, 0x74];
+const FACTORY_KEY: [u8; 6] = [0xFF; 6];
+const KEY_B: [u8; 6] = [0x4D, 0x3A, 0x99, 0xC3, 0x51, 0xDD];
+
+fn long_message() -> NdefMessage {
+    // Longer than the three data blocks of one sector
+    NdefMessage::new(vec![
+        NdefRecord::uri("https://www.example.com/a/path/long/enough/to/need/a/second/sector"),
+        NdefRecord::text("en", "and a text record after it"),
+    ])
+}
+
+#[test]
+fn format_write_read_round_trip() {
+    let card = ClassicSimulator::new(&UID);
+    let classic = MifareClassic::with_raw_transceiver(&card, &UID);
+    classic.format_ndef(16, &FACTORY_KEY).unwrap();
+
+    // MAD sector: public key A A0..A5, MAD access bits, GPB of MAD1, key B
+    let mut trailer = vec![0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0x78, 0x77, 0x88, 0xC1];
+    trailer.extend_from_slice(&FACTORY_KEY);
+    assert_eq!(card.block(3).to_vec(), trailer);
+    // NDEF sectors: key A D3F7D3F7D3F7, read with key A, write with key B
+    for sector in 1..16 {
+        let mut trailer = NFC_KEY_A.to_vec();
+        trailer.extend_from_slice(&[0x7F, 0x07, 0x88, 0x40]);
+        trailer.extend_from_slice(&FACTORY_KEY);
+        assert_eq!(card.block(sector_trailer(sector)).to_vec(), trailer, "sector {}", sector);
+    }
+
+    let mad = classic.read_mad(16).unwrap();
+    assert_eq!(mad.sectors_of(AID_NDEF), (1..16).collect::<Vec<u8>>());
+    assert!(classic.read_ndef(16).unwrap().is_empty());
+
+    classic.write_ndef(16, &long_message(), &FACTORY_KEY).unwrap();
+    assert_eq!(classic.read_ndef(16).unwrap(), long_message());
+    assert_ne!(card.block(8), [0x00; 16]);
+}
+
+#[test]
+fn writes_need_key_b() {
+    let card = ClassicSimulator::new(&UID);
+    let classic = MifareClassic::with_raw_transceiver(&card, &UID);
+    classic.format_ndef(16, &KEY_B).unwrap_err();
+
+    // The key passed to format_ndef is the current key A; change it first
+    let card = (0..16).fold(ClassicSimulator::new(&UID), |card, sector| card.with_key(sector, KeyType::KeyA, KEY_B));
+    let classic = MifareClassic::with_raw_transceiver(&card, &UID);
+    classic.format_ndef(16, &KEY_B).unwrap();
+    assert_eq!(card.block(7)[10..16], KEY_B);
+
+    // Neither the public key A nor the old factory key write
+    assert!(classic.write_ndef(16, &long_message(), &NFC_KEY_A).is_err());
+    assert!(classic.write_ndef(16, &long_message(), &FACTORY_KEY).is_err());
+    assert!(classic.read_ndef(16).unwrap().is_empty());
+
+    // Key B authenticates again for each sector the message reaches
+    classic.write_ndef(16, &long_message(), &KEY_B).unwrap();
+    assert_eq!(classic.read_ndef(16).unwrap(), long_message());
+}
+
+#[test]
+fn short_uid_is_refused() {
+    let card = ClassicSimulator::new(&UID);
+    let classic = MifareClassic::with_raw_transceiver(&card, &UID[..3]);
+    classic.load_key(&FACTORY_KEY).unwrap();
+    let error = classic.authenticate(4, KeyType::KeyA).unwrap_err();
+    assert_eq!(error.to_string(), "Target UID too short for authentication");
+}
+
+#[test]
+fn mad_is_written_after_the_ndef_sectors() {
+    // Sector 9 refuses the key: formatting stops there
+    let card = ClassicSimulator::new(&UID).with_key(9, KeyType::KeyA, KEY_B);
+    let classic = MifareClassic::with_raw_transceiver(&card, &UID);
+    assert!(classic.format_ndef(16, &FACTORY_KEY).is_err());
+
+    assert_eq!(card.block(sector_trailer(8))[0..6], NFC_KEY_A);
+    assert_eq!(card.block(sector_trailer(10))[0..6], FACTORY_KEY);
+    // No MAD points at the half formatted card, and sector 0 is untouched
+    assert_eq!(card.block(1), [0x00; 16]);
+    assert_eq!(card.block(2), [0x00; 16]);
+    assert_eq!(card.block(3)[0..10], [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x07, 0x80, 0x69]);
+    assert!(classic.read_ndef(16).is_err());
+}
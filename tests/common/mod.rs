@@ -0,0 +1,15 @@
+// Helpers shared by the integration tests; each test crate uses only some
+#![allow(dead_code)]
+
+use acr122u_test::error::MifareError;
+
+// Hex bytes, written either as space separated pairs or run together
+pub fn hex(text: &str) -> Vec<u8> {
+    let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    digits.chunks(2).map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).unwrap()).collect()
+}
+
+// SW1 SW2 carried by a card error, if any
+pub fn status(error: Box<dyn std::error::Error>) -> Option<(u8, u8)> {
+    error.downcast_ref::<MifareError>().and_then(MifareError::status)
+}
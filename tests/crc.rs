@@ -0,0 +1,14 @@
+use acr122u_test::crc::{crc_a, with_crc};
+
+#[test]
+fn crc_a_vectors() {
+    assert_eq!(crc_a(&[0x50, 0x00]), [0x57, 0xCD]);
+    assert_eq!(crc_a(&[0x30, 0x00]), [0x02, 0xA8]);
+    assert_eq!(crc_a(&[0xE0, 0x80]), [0x31, 0x73]);
+}
+
+#[test]
+fn crc_is_appended_low_byte_first() {
+    assert_eq!(with_crc(&[0x50, 0x00]), [0x50, 0x00, 0x57, 0xCD]);
+    assert_eq!(with_crc(&[]), [0x63, 0x63]);
+}
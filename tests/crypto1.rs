@@ -0,0 +1,143 @@
+use acr122u_test::classic::KeyType;
+use acr122u_test::crc::{crc_a, with_crc};
+use acr122u_test::crypto1::{is_prng_nonce, plain_parity, prng_successor, CardAuth, Crypto1, ReaderAuth};
+use acr122u_test::simulator::ClassicSimulator;
+use acr122u_test::transport::{RawFrame, RawTransceive};
+
+// Authentication captured with key FFFFFFFFFFFF (the mfkey64 example trace)
+const UID: u32 = 0x9C599B32;
+const NT: u32 = 0x82A4166C;
+const NR_ENC: u32 = 0xA1E458CE;
+const AR_ENC: u32 = 0x6EEA41E0;
+const AT_ENC: u32 = 0x5CADF439;
+
+const KEY: [u8; 6] = [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5];
+
+#[test]
+fn key_loads_into_lfsr() {
+    let cipher = Crypto1::from_key(&KEY);
+    assert_eq!(cipher.lfsr(), 0xA0A1A2A3A4A5);
+    assert_eq!(cipher.key(), KEY);
+    assert_eq!(Crypto1::new(0xA0A1A2A3A4A5), cipher);
+}
+
+#[test]
+fn captured_trace_decrypts() {
+    let mut cipher = Crypto1::from_key(&[0xFF; 6]);
+    cipher.word(UID ^ NT, false);
+    let nr = NR_ENC ^ cipher.word(NR_ENC, true);
+    assert_eq!(nr, 0xEFEA1CDA);
+    assert_eq!(AR_ENC ^ cipher.word(0, false), prng_successor(NT, 64));
+    assert_eq!(AT_ENC ^ cipher.word(0, false), prng_successor(NT, 96));
+}
+
+#[test]
+fn rollback_undoes_clocking() {
+    let start = Crypto1::from_key(&KEY);
+    let mut cipher = start;
+    let ks = cipher.word(UID ^ NT, false);
+    assert_ne!(cipher, start);
+    assert_eq!(cipher.rollback_word(UID ^ NT, false), ks);
+    assert_eq!(cipher.lfsr(), start.lfsr());
+
+    cipher.word(NR_ENC, true);
+    cipher.rollback_word(NR_ENC, true);
+    assert_eq!(cipher.lfsr(), start.lfsr());
+}
+
+#[test]
+fn prng() {
+    assert!(is_prng_nonce(NT));
+    assert!(is_prng_nonce(0x01200145));
+    assert!(!is_prng_nonce(0x01200146));
+    assert_eq!(prng_successor(prng_successor(NT, 10), 22), prng_successor(NT, 32));
+}
+
+#[test]
+fn three_pass_authentication() {
+    let nt = prng_successor(0x01200145, 160);
+    let card = CardAuth::new(&KEY, UID, nt);
+    let mut reader = ReaderAuth::new(&KEY, UID, nt);
+
+    let (nr_ar, parity) = reader.respond(0x12345678);
+    let (mut card_cipher, at, at_parity) = card.respond(&nr_ar, &parity).unwrap();
+    let mut reader_cipher = reader.verify(&at, &at_parity).unwrap();
+
+    // Both sides now share the keystream, parity bits included
+    let (data, parity) = reader_cipher.encrypt(&[0x30, 0x04, 0x26, 0xEE]);
+    assert_eq!(card_cipher.decrypt(&data, &parity).unwrap(), [0x30, 0x04, 0x26, 0xEE]);
+
+    // A parity bit out of place is noticed
+    let (data, mut parity) = card_cipher.encrypt(&[0x00; 4]);
+    parity[2] ^= 1;
+    assert!(reader_cipher.decrypt(&data, &parity).is_err());
+}
+
+#[test]
+fn wrong_key_is_rejected() {
+    let nt = prng_successor(0x01200145, 160);
+    let mut reader = ReaderAuth::new(&[0xFF; 6], UID, nt);
+    let (nr_ar, parity) = reader.respond(0x12345678);
+    assert!(CardAuth::new(&KEY, UID, nt).respond(&nr_ar, &parity).is_err());
+}
+
+#[test]
+fn nested_nonce_is_recovered() {
+    let nt = prng_successor(0x01200145, 300);
+    let (card, encrypted_nt, _) = CardAuth::nested(&KEY, UID, nt);
+    let mut reader = ReaderAuth::nested(&KEY, UID, u32::from_be_bytes(encrypted_nt));
+    assert_eq!(reader.nt(), nt);
+
+    let (nr_ar, parity) = reader.respond(0xCAFEBABE);
+    let (_, at, at_parity) = card.respond(&nr_ar, &parity).unwrap();
+    assert!(reader.verify(&at, &at_parity).is_ok());
+}
+
+fn encrypted(cipher: &mut Crypto1, data: &[u8]) -> RawFrame {
+    let (data, parity) = cipher.encrypt(&with_crc(data));
+    RawFrame::new(data, parity)
+}
+
+fn authenticate(card: &ClassicSimulator, block: u8, key: &[u8; 6]) -> Result<Crypto1, Box<dyn std::error::Error>> {
+    let nt = card.transceive_raw(&RawFrame::plain(&with_crc(&[0x60, block])))?;
+    assert_eq!(nt.parity, plain_parity(&nt.data));
+    let mut reader = ReaderAuth::new(key, card.uid(), u32::from_be_bytes(nt.data[..].try_into()?));
+    let (nr_ar, parity) = reader.respond(0x01020304);
+    let at = card.transceive_raw(&RawFrame::new(nr_ar.to_vec(), parity.to_vec()))?;
+    Ok(reader.verify(&at.data, &at.parity)?)
+}
+
+#[test]
+fn simulator_read_write_and_nested() {
+    let card = ClassicSimulator::new(&[0xCD, 0x76, 0x92, 0x74]).with_key(1, KeyType::KeyA, KEY);
+
+    let mut cipher = authenticate(&card, 0, &[0xFF; 6]).unwrap();
+    let block = card.transceive_raw(&encrypted(&mut cipher, &[0x30, 0x00])).unwrap();
+    let plain = cipher.decrypt(&block.data, &block.parity).unwrap();
+    assert_eq!(&plain[0..5], &[0xCD, 0x76, 0x92, 0x74, 0x5D]);
+    assert_eq!(&plain[16..], &crc_a(&plain[0..16]));
+
+    // Nested authentication to sector 1 inside the encrypted session
+    let encrypted_nt = card.transceive_raw(&encrypted(&mut cipher, &[0x60, 0x04])).unwrap();
+    let mut reader = ReaderAuth::nested(&KEY, card.uid(), u32::from_be_bytes(encrypted_nt.data[..].try_into().unwrap()));
+    assert!(is_prng_nonce(reader.nt()));
+    let (nr_ar, parity) = reader.respond(0x0BADCAFE);
+    let at = card.transceive_raw(&RawFrame::new(nr_ar.to_vec(), parity.to_vec())).unwrap();
+    let mut cipher = reader.verify(&at.data, &at.parity).unwrap();
+
+    let ack = card.transceive_raw(&encrypted(&mut cipher, &[0xA0, 0x05])).unwrap();
+    assert_eq!(cipher.nibble(ack.data[0]), 0x0A);
+    let ack = card.transceive_raw(&encrypted(&mut cipher, &[0x42; 16])).unwrap();
+    assert_eq!(cipher.nibble(ack.data[0]), 0x0A);
+    assert_eq!(card.block(5), [0x42; 16]);
+}
+
+#[test]
+fn simulator_rejects_wrong_key() {
+    let card = ClassicSimulator::new(&[0xCD, 0x76, 0x92, 0x74]).with_key(1, KeyType::KeyA, KEY);
+    assert!(authenticate(&card, 4, &[0xFF; 6]).is_err());
+    // Halted until reactivated
+    assert!(authenticate(&card, 0, &[0xFF; 6]).is_err());
+    card.reactivate().unwrap();
+    assert!(authenticate(&card, 4, &KEY).is_ok());
+}
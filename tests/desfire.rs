@@ -0,0 +1,114 @@
+mod common;
+
+use acr122u_test::desfire::{
+    status_name, wrap, AccessRights, CipherType, CommunicationMode, Desfire, FileLayout, FileSettings, FileType,
+    KeySettings, APPLICATION_NOT_FOUND, FILE_NOT_FOUND, FREE_ACCESS, PICC_AID,
+};
+use acr122u_test::identify::{identify_desfire_version, MifareType};
+use acr122u_test::simulator::DesfireSimulator;
+use common::status;
+
+const UID: [u8; 7] = [0x04, 0x52, 0x1C, 0x82, 0x3A, 0x61, 0x80];
+
+const AES_APP: KeySettings = KeySettings { settings: 0x0B, key_count: 3, cipher: CipherType::Aes };
+
+fn data_file(size: u32) -> FileSettings {
+    FileSettings {
+        file_type: FileType::StandardData,
+        communication: CommunicationMode::Plain,
+        access: AccessRights { read: FREE_ACCESS, write: 1, read_write: 1, change: 0 },
+        layout: FileLayout::Data { size },
+    }
+}
+
+#[test]
+fn commands_are_wrapped() {
+    assert_eq!(wrap(0x60, &[]), [0x90, 0x60, 0x00, 0x00, 0x00]);
+    assert_eq!(wrap(0x5A, &[0x01, 0x02, 0x03]), [0x90, 0x5A, 0x00, 0x00, 0x03, 0x01, 0x02, 0x03, 0x00]);
+    assert_eq!(status_name(0x9D), "PERMISSION_DENIED");
+}
+
+#[test]
+fn version_follows_additional_frames() {
+    let desfire = Desfire::with_transceiver(DesfireSimulator::new(&UID));
+    let bytes = desfire.get_version_bytes().unwrap();
+    assert_eq!(bytes.len(), 28);
+
+    let version = desfire.get_version().unwrap();
+    assert_eq!(version.uid, UID);
+    assert_eq!(version.hardware.major, 0x12);
+    assert_eq!(version.hardware.storage_bytes(), 8192);
+    assert!(version.hardware.storage_is_exact());
+    assert!(version.to_string().contains("UID: 04521C823A6180"));
+
+    let identification = identify_desfire_version(&bytes).unwrap();
+    assert_eq!(identification.card_type, MifareType::MifareDesfire);
+    assert_eq!(identification.generation.as_deref(), Some("EV2"));
+}
+
+#[test]
+fn applications_and_files() {
+    let value_file = FileSettings {
+        file_type: FileType::Value,
+        communication: CommunicationMode::Full,
+        access: AccessRights { read: 1, write: 2, read_write: 2, change: 0 },
+        layout: FileLayout::Value { lower_limit: -100, upper_limit: 10000, limited_credit_value: 0, limited_credit_enabled: true },
+    };
+    let record_file = FileSettings {
+        file_type: FileType::CyclicRecord,
+        communication: CommunicationMode::Maced,
+        access: AccessRights { read: 1, write: 1, read_write: 1, change: 0 },
+        layout: FileLayout::Record { record_size: 16, max_records: 10, current_records: 3 },
+    };
+    let card = DesfireSimulator::new(&UID)
+        .with_application(0x123456, AES_APP)
+        .with_file(0x123456, 0x01, data_file(32))
+        .with_file(0x123456, 0x02, value_file)
+        .with_file(0x123456, 0x05, record_file);
+    let desfire = Desfire::with_transceiver(&card);
+
+    assert_eq!(desfire.get_application_ids().unwrap(), vec![0x123456]);
+    assert_eq!(desfire.get_key_settings().unwrap().cipher, CipherType::TripleDes);
+    assert_eq!(desfire.free_memory().unwrap(), 7936);
+
+    desfire.select_application(0x123456).unwrap();
+    assert_eq!(desfire.get_key_settings().unwrap(), AES_APP);
+    assert_eq!(desfire.get_file_ids().unwrap(), vec![0x01, 0x02, 0x05]);
+    assert_eq!(desfire.get_file_settings(0x01).unwrap(), data_file(32));
+    assert_eq!(desfire.get_file_settings(0x02).unwrap(), value_file);
+    assert_eq!(desfire.get_file_settings(0x05).unwrap(), record_file);
+    assert_eq!(desfire.get_file_settings(0x01).unwrap().to_string(),
+               "Standard data file, plain, read free, write key 1, read/write key 1, change key 0, 32 bytes");
+
+    let error = desfire.get_file_settings(0x03).unwrap_err();
+    assert_eq!(status(error), Some((0x91, FILE_NOT_FOUND)));
+
+    desfire.select_application(PICC_AID).unwrap();
+    assert!(desfire.get_file_ids().unwrap().is_empty());
+}
+
+#[test]
+fn long_answers_are_chained() {
+    let aids: Vec<u32> = (1..=30).map(|i| 0xF00000 | i).collect();
+    let card = aids.iter().fold(DesfireSimulator::new(&UID), |card, &aid| card.with_application(aid, AES_APP));
+    // 90 bytes of IDs do not fit one frame
+    assert_eq!(Desfire::with_transceiver(card).get_application_ids().unwrap(), aids);
+}
+
+#[test]
+fn unknown_application_is_reported() {
+    let desfire = Desfire::with_transceiver(DesfireSimulator::new(&UID));
+    let error = desfire.select_application(0xABCDEF).unwrap_err();
+    assert!(error.to_string().contains("APPLICATION_NOT_FOUND"));
+    assert_eq!(status(error), Some((0x91, APPLICATION_NOT_FOUND)));
+}
+
+#[test]
+fn settings_round_trip() {
+    let access = AccessRights { read: 0x3, write: 0xE, read_write: 0xF, change: 0x0 };
+    assert_eq!(access.encode(), [0xF0, 0x3E]);
+    assert_eq!(AccessRights::decode(&access.encode()), access);
+    assert_eq!(KeySettings::decode(&[0x0F, 0x81]).unwrap(), KeySettings { settings: 0x0F, key_count: 1, cipher: CipherType::Aes });
+    assert!(KeySettings::decode(&[0x0F, 0xC1]).is_err());
+    assert!(FileSettings::decode(&[0x02, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00]).is_err());
+}
@@ -0,0 +1,269 @@
+mod common;
+
+use acr122u_test::desfire::{CipherType, CommunicationMode, Desfire, KeySettings, AUTHENTICATION_ERROR, PICC_AID};
+use acr122u_test::desfire_auth::{change_key_cryptogram, AuthMode, CardHandshake, Handshake};
+use acr122u_test::desfire_crypto::{cmac, cmac_subkeys, crc32, DesfireKey, Messaging, Session};
+use acr122u_test::simulator::DesfireSimulator;
+use common::{hex, status};
+
+const UID: [u8; 7] = [0x04, 0x52, 0x1C, 0x82, 0x3A, 0x61, 0x80];
+
+const APP: u32 = 0x010203;
+
+// Reader and card sessions opened by the same authentication
+fn sessions(mode: AuthMode, key: &DesfireKey) -> (Session, Session) {
+    let rnd_a = &hex("13C5DB8A5930439FC3DEF9A4C675360F")[..mode.random_size(key)];
+    let rnd_b = &hex("B9E2FC789B64BF237CCCAA20EC7E6E48")[..mode.random_size(key)];
+    let mut reader = Handshake::new(mode, 0, key, rnd_a).unwrap();
+    let mut card = CardHandshake::new(mode, 0, key, rnd_b, [0x01, 0x02, 0x03, 0x04], None).unwrap();
+    let token = reader.respond(&card.challenge()).unwrap();
+    let (answer, card_session) = card.answer(&token).unwrap();
+    (reader.verify(&answer).unwrap(), card_session)
+}
+
+fn keys() -> Vec<(AuthMode, DesfireKey)> {
+    let tdes: [u8; 16] = hex("00112233445566778899AABBCCDDEEFF").try_into().unwrap();
+    vec![
+        (AuthMode::Legacy, DesfireKey::des(&[0x11; 8])),
+        (AuthMode::Legacy, DesfireKey::tdes(&tdes)),
+        (AuthMode::Iso, DesfireKey::des(&[0x22; 8])),
+        (AuthMode::Iso, DesfireKey::tdes(&tdes)),
+        (AuthMode::Iso, DesfireKey::tdes3k(&[0x33; 24])),
+        (AuthMode::Aes, DesfireKey::aes(&tdes)),
+        (AuthMode::Ev2First, DesfireKey::aes(&tdes)),
+    ]
+}
+
+#[test]
+fn cmac_matches_rfc_4493() {
+    let key = DesfireKey::aes(&hex("2B7E151628AED2A6ABF7158809CF4F3C").try_into().unwrap());
+    let (k1, k2) = cmac_subkeys(&key);
+    assert_eq!(k1, hex("FBEED618357133667C85E08F7236A8DE"));
+    assert_eq!(k2, hex("F7DDAC306AE266CCF90BC11EE46D513B"));
+
+    let message = hex(concat!(
+        "6BC1BEE22E409F96E93D7E117393172A", "AE2D8A571E03AC9C9EB76FAC45AF8E51",
+        "30C81C46A35CE411E5FBC1191A0A52EF", "F69F2445DF4F9B17AD2B417BE66C3710",
+    ));
+    assert_eq!(cmac(&key, &mut [0u8; 16], &[]), hex("BB1D6929E95937287FA37D129B756746"));
+    assert_eq!(cmac(&key, &mut [0u8; 16], &message[..16]), hex("070A16B46B4D4144F79BDD9DD04A287C"));
+    assert_eq!(cmac(&key, &mut [0u8; 16], &message[..40]), hex("DFA66747DE9AE63030CA32611497C827"));
+    assert_eq!(cmac(&key, &mut [0u8; 16], &message), hex("51F0BEBF7E3B9D92FC49741779363CFE"));
+}
+
+#[test]
+fn crc32_skips_the_final_inversion() {
+    // Complement of the IEEE check value CBF43926
+    assert_eq!(crc32(b"123456789"), 0x340BC6D9u32.to_le_bytes());
+}
+
+// AuthenticateEV2First with the all-zero AES key, from NXP AN12196
+#[test]
+fn ev2_first_matches_nxp_vectors() {
+    let key = DesfireKey::zero(CipherType::Aes);
+    let rnd_a = hex("13C5DB8A5930439FC3DEF9A4C675360F");
+    let rnd_b = hex("B9E2FC789B64BF237CCCAA20EC7E6E48");
+
+    let mut card = CardHandshake::new(AuthMode::Ev2First, 0, &key, &rnd_b, [0x9D, 0x00, 0xC4, 0xDF], None).unwrap();
+    let challenge = card.challenge();
+    assert_eq!(challenge, hex("A04C124213C186F22399D33AC2A30215"));
+
+    let mut reader = Handshake::new(AuthMode::Ev2First, 0, &key, &rnd_a).unwrap();
+    let token = reader.respond(&challenge).unwrap();
+    assert_eq!(token, hex("35C3E05A752E0144BAC0DE51C1F22C56B34408A23D8AEA266CAB947EA8E0118D"));
+
+    let (answer, _) = card.answer(&token).unwrap();
+    assert_eq!(answer, hex("3FA64DB5446D1F34CD6EA311167F5E4985B89690C04A05F17FA7AB2F08120663"));
+
+    let session = reader.verify(&answer).unwrap();
+    assert_eq!(session.ti(), [0x9D, 0x00, 0xC4, 0xDF]);
+    assert_eq!(session.cmd_ctr(), 0);
+    assert_eq!(session.enc_key().bytes(), &hex("1309C877509E5A215007FF0ED19CA564")[..]);
+    assert_eq!(session.mac_key().bytes(), &hex("4C6626F5E72EA694202139295C7A7FC7")[..]);
+}
+
+// The vectors below pin every byte the reader sends and checks, so that a
+// mistake shared by Session and the simulator cannot pass unnoticed. They
+// were computed with a separate implementation of the NXP algorithms (Python
+// and the cryptography package), starting from the RndA/RndB and, for EV2,
+// the session keys and TI of the AN12196 AuthenticateEV2First example.
+
+const WRITE_HEADER: [u8; 7] = [0x01, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00];
+const WRITE_DATA: [u8; 5] = [0x00, 0x01, 0x02, 0x03, 0x04];
+
+#[test]
+fn legacy_authentication_trace() {
+    let key = DesfireKey::tdes(&hex("00112233445566778899AABBCCDDEEFF").try_into().unwrap());
+    let mut reader = Handshake::new(AuthMode::Legacy, 0, &key, &hex("13C5DB8A5930439F")).unwrap();
+    assert_eq!(reader.respond(&hex("FBDAB822B5492057")).unwrap(), hex("9EF7F8EB899764DE0266D4F57C514A2A"));
+    let mut session = reader.verify(&hex("23B9A8793E64A64D")).unwrap();
+    assert_eq!(session.enc_key().bytes(), &hex("13C5DB8AB9E2FC785930439F9B64BF23")[..]);
+
+    // CRC16 and zero padding, deciphered from a zero IV
+    let sent = session.wrap_command(0x3D, &WRITE_HEADER, &WRITE_DATA, CommunicationMode::Full);
+    assert_eq!(sent, [&WRITE_HEADER[..], &hex("4F5303FA472299CB")].concat());
+    let sent = session.wrap_command(0x3D, &WRITE_HEADER, &WRITE_DATA, CommunicationMode::Maced);
+    assert_eq!(sent, [&WRITE_HEADER[..], &WRITE_DATA, &hex("0CF0A469")].concat());
+}
+
+#[test]
+fn iso_3k3des_authentication_trace() {
+    let key = DesfireKey::tdes3k(&(0x10..0x28).collect::<Vec<u8>>().try_into().unwrap());
+    let mut reader = Handshake::new(AuthMode::Iso, 0, &key, &hex("13C5DB8A5930439FC3DEF9A4C675360F")).unwrap();
+    let token = reader.respond(&hex("0D6B7BF36E2D65750F99071E424D2C35")).unwrap();
+    assert_eq!(token, hex("ECD85D7740D700E6F2635504EF47E1D6F6DF5DE7C471A6D99E5DC17CD80D714C"));
+    let session = reader.verify(&hex("DF6116E49CE179F8C006B184320E51A9")).unwrap();
+    assert_eq!(session.messaging(), Messaging::Ev1);
+    assert_eq!(session.enc_key().bytes(), &hex("13C5DB8AB9E2FC78439FC3DEBF237CCCC675360FEC7E6E48")[..]);
+}
+
+#[test]
+fn aes_trace_chains_the_cmac_iv() {
+    let key = DesfireKey::aes(&hex("00112233445566778899AABBCCDDEEFF").try_into().unwrap());
+    let mut reader = Handshake::new(AuthMode::Aes, 0, &key, &hex("13C5DB8A5930439FC3DEF9A4C675360F")).unwrap();
+    let token = reader.respond(&hex("B9235F38D49834D2AB9A02B961FFF282")).unwrap();
+    assert_eq!(token, hex("8ADC6FD949A5A794F5FC2810837AF3E9BA335CCBE81AD704BC23BF39486F4915"));
+    let mut session = reader.verify(&hex("C8305EFA725FBFEE850E543B0CE13F69")).unwrap();
+    assert_eq!(session.enc_key().bytes(), &hex("13C5DB8AB9E2FC78C675360FEC7E6E48")[..]);
+
+    // GetKeySettings: the plain command still feeds the CMAC IV that checks
+    // the answer
+    assert_eq!(session.wrap_command(0x45, &[], &[], CommunicationMode::Plain), Vec::<u8>::new());
+    assert_eq!(session.unwrap_response(&hex("0F01733ABE7919B3FFA1"), CommunicationMode::Maced).unwrap(), [0x0F, 0x01]);
+
+    let sent = session.wrap_command(0x3D, &WRITE_HEADER, &WRITE_DATA, CommunicationMode::Maced);
+    assert_eq!(sent, [&WRITE_HEADER[..], &WRITE_DATA, &hex("D8EDE18128ACBC55")].concat());
+
+    // CRC32 over command, header and data, zero padded, enciphered from the
+    // carried IV; the status-only answer is MACed from the last cipher block
+    let sent = session.wrap_command(0x3D, &WRITE_HEADER, &WRITE_DATA, CommunicationMode::Full);
+    assert_eq!(sent, [&WRITE_HEADER[..], &hex("6CC4BCC2EC0FBF3E1424F1887FED7F27")].concat());
+    assert_eq!(session.unwrap_response(&hex("84BA1C4C06CF616D"), CommunicationMode::Maced).unwrap(), Vec::<u8>::new());
+    assert_eq!(session.unwrap_response(&hex("E1BE2D7EB5A8B427E86301EE4B967989"), CommunicationMode::Full).unwrap(), WRITE_DATA);
+}
+
+fn an12196_session(cmd_ctr: u16) -> Session {
+    let enc_key = DesfireKey::aes(&hex("1309C877509E5A215007FF0ED19CA564").try_into().unwrap());
+    let mac_key = DesfireKey::aes(&hex("4C6626F5E72EA694202139295C7A7FC7").try_into().unwrap());
+    Session::ev2(0, enc_key, mac_key, [0x9D, 0x00, 0xC4, 0xDF], cmd_ctr)
+}
+
+#[test]
+fn ev2_maced_get_file_settings() {
+    let mut session = an12196_session(0);
+    // MACt over F5 || CmdCtr || TI || FileNo
+    assert_eq!(session.wrap_command(0xF5, &[0x02], &[], CommunicationMode::Maced), hex("02046FD9C80D11D175"));
+    let settings = session.unwrap_response(&hex("0040EEEE20000021C5DE9B4455AD22"), CommunicationMode::Maced).unwrap();
+    assert_eq!(settings, hex("0040EEEE200000"));
+    assert_eq!(session.cmd_ctr(), 1);
+
+    // The same answer under the previous counter is refused
+    let mut session = an12196_session(1);
+    assert!(session.unwrap_response(&hex("0040EEEE20000021C5DE9B4455AD22"), CommunicationMode::Maced).is_err());
+}
+
+#[test]
+fn ev2_enciphered_change_key() {
+    // Key 0, the session's own key: NewKey || KeyVer, ISO/IEC 9797-1 padded
+    let new_key = DesfireKey::aes(&hex("5004BF991F408672B1EF00F08F9E8647").try_into().unwrap());
+    let plain = change_key_cryptogram(Messaging::Ev2, 0x00, &new_key, None, 0x01).unwrap();
+    let sent = an12196_session(1).wrap_enciphered(0xC4, &[0x00], &plain);
+    assert_eq!(sent, hex("0017A89C847FF9877D28B354FA62C7D43C3076D4D0CC640F8A6EC8CED23C38A4F5F9554B3CE0BD5752"));
+
+    // Another key: (NewKey ^ OldKey) || KeyVer || CRC32(NewKey)
+    let new_key = DesfireKey::aes(&hex("F3847D627727ED3BC9C4CC050489B966").try_into().unwrap());
+    let plain = change_key_cryptogram(Messaging::Ev2, 0x02, &new_key, Some(&DesfireKey::zero(CipherType::Aes)), 0x01).unwrap();
+    let sent = an12196_session(2).wrap_enciphered(0xC4, &[0x02], &plain);
+    assert_eq!(sent, hex("02ED615AFC2D0A97D8F5BF76FF7BA73F4C782E14A4E4016D05CC5A0CCBE70B26C8345D4C5D4D0A8CB2"));
+}
+
+#[test]
+fn ev2_enciphered_answer() {
+    let mut session = an12196_session(2);
+    let answer = hex("760D052DADADFD0A49D9B7449AE4FE69F020B3408F8593AA");
+    assert_eq!(session.unwrap_response(&answer, CommunicationMode::Full).unwrap(), WRITE_DATA);
+}
+
+#[test]
+fn every_mode_protects_commands() {
+    for (mode, key) in keys() {
+        let (mut reader, mut card) = sessions(mode, &key);
+        for comm in [CommunicationMode::Plain, CommunicationMode::Maced, CommunicationMode::Full] {
+            let data: Vec<u8> = (0..37).collect();
+            let sent = reader.wrap_command(0x3D, &[0x01, 0x00, 0x00, 0x00], &data, comm);
+            let received = card.unwrap_command(0x3D, 4, &sent, comm).unwrap();
+            assert_eq!(received, [&[0x01, 0x00, 0x00, 0x00][..], &data].concat(), "{} {}", mode.to_string(), comm.to_string());
+
+            let answer = card.wrap_response(&data[..5], comm);
+            assert_eq!(reader.unwrap_response(&answer, comm).unwrap(), &data[..5], "{} {}", mode.to_string(), comm.to_string());
+        }
+    }
+}
+
+#[test]
+fn tampering_is_detected() {
+    for (mode, key) in keys() {
+        let (mut reader, mut card) = sessions(mode, &key);
+        let mut sent = reader.wrap_command(0x3D, &[0x01], &[0xAA; 20], CommunicationMode::Maced);
+        sent[3] ^= 0x01;
+        assert!(card.unwrap_command(0x3D, 1, &sent, CommunicationMode::Maced).is_err(), "{}", mode.to_string());
+    }
+}
+
+#[test]
+fn every_authentication_opens_a_session_on_the_card() {
+    for (mode, key) in keys() {
+        let settings = KeySettings { settings: 0x0F, key_count: 2, cipher: key.cipher() };
+        let card = DesfireSimulator::new(&UID).with_application(APP, settings).with_key(APP, 1, key.clone());
+        let desfire = Desfire::with_transceiver(card);
+        desfire.select_application(APP).unwrap();
+        desfire.authenticate(mode, 1, &key).unwrap();
+        assert_eq!(desfire.authenticated_key(), Some(1));
+
+        // Chained, MACed and enciphered answers all pass under the session
+        assert_eq!(desfire.get_version().unwrap().uid, UID, "{}", mode.to_string());
+        assert_eq!(desfire.get_key_settings().unwrap(), settings);
+        assert_eq!(desfire.get_card_uid().unwrap(), UID, "{}", mode.to_string());
+        assert_eq!(desfire.get_file_ids().unwrap(), Vec::<u8>::new());
+        assert!(desfire.is_authenticated());
+    }
+}
+
+#[test]
+fn ev2_non_first_keeps_the_transaction() {
+    let key = DesfireKey::aes(&[0x5A; 16]);
+    let settings = KeySettings { settings: 0x0F, key_count: 2, cipher: CipherType::Aes };
+    let card = DesfireSimulator::new(&UID).with_application(APP, settings).with_key(APP, 1, key.clone());
+    let desfire = Desfire::with_transceiver(card);
+    desfire.select_application(APP).unwrap();
+
+    assert!(desfire.authenticate(AuthMode::Ev2NonFirst, 0, &DesfireKey::zero(CipherType::Aes)).is_err());
+    desfire.authenticate(AuthMode::Ev2First, 0, &DesfireKey::zero(CipherType::Aes)).unwrap();
+    desfire.get_key_settings().unwrap();
+    desfire.authenticate(AuthMode::Ev2NonFirst, 1, &key).unwrap();
+    assert_eq!(desfire.authenticated_key(), Some(1));
+    assert_eq!(desfire.get_card_uid().unwrap(), UID);
+}
+
+#[test]
+fn wrong_key_is_refused() {
+    let desfire = Desfire::with_transceiver(DesfireSimulator::new(&UID));
+    let error = desfire.authenticate(AuthMode::Legacy, 0, &DesfireKey::des(&[0x02; 8])).unwrap_err();
+    assert_eq!(status(error), Some((0x91, AUTHENTICATION_ERROR)));
+    assert!(!desfire.is_authenticated());
+
+    // An AES key cannot open a DES authentication, and the reader says so
+    assert!(desfire.authenticate(AuthMode::Legacy, 0, &DesfireKey::zero(CipherType::Aes)).is_err());
+}
+
+#[test]
+fn session_ends_with_selection() {
+    let desfire = Desfire::with_transceiver(DesfireSimulator::new(&UID));
+    assert_eq!(status(desfire.get_card_uid().unwrap_err()), None);
+
+    desfire.authenticate(AuthMode::Iso, 0, &DesfireKey::zero(CipherType::TripleDes)).unwrap();
+    assert_eq!(desfire.get_card_uid().unwrap(), UID);
+    desfire.select_application(PICC_AID).unwrap();
+    assert!(!desfire.is_authenticated());
+    assert!(desfire.get_card_uid().is_err());
+}
@@ -0,0 +1,319 @@
+mod common;
+
+use acr122u_test::desfire::{
+    AccessRights, CipherType, CommunicationMode, Desfire, FileLayout, KeySettings, AUTHENTICATION_ERROR, BOUNDARY_ERROR,
+    DUPLICATE_ERROR, FREE_ACCESS, NO_ACCESS, PERMISSION_DENIED, PICC_AID,
+};
+use acr122u_test::desfire_auth::AuthMode;
+use acr122u_test::desfire_crypto::DesfireKey;
+use acr122u_test::desfire_profile::{parse_access, parse_hex, Profile};
+use acr122u_test::simulator::DesfireSimulator;
+use common::status;
+
+const UID: [u8; 7] = [0x04, 0x52, 0x1C, 0x82, 0x3A, 0x61, 0x80];
+
+const APP: u32 = 0x010203;
+
+// Key 0 changes the others; the application is locked down otherwise
+const LOCKED: u8 = 0x09;
+
+fn access(read: u8, write: u8, read_write: u8) -> AccessRights {
+    AccessRights { read, write, read_write, change: 0 }
+}
+
+// Card with one empty application of three keys, selected
+fn card(cipher: CipherType, settings: u8) -> Desfire<'static> {
+    let desfire = Desfire::with_transceiver(DesfireSimulator::new(&UID));
+    desfire.authenticate(AuthMode::Iso, 0, &DesfireKey::zero(CipherType::TripleDes)).unwrap();
+    desfire.create_application(APP, &KeySettings { settings, key_count: 3, cipher }).unwrap();
+    desfire.select_application(APP).unwrap();
+    desfire
+}
+
+fn every_mode() -> Vec<(AuthMode, CipherType)> {
+    vec![
+        (AuthMode::Legacy, CipherType::TripleDes),
+        (AuthMode::Iso, CipherType::TripleDes3K),
+        (AuthMode::Aes, CipherType::Aes),
+        (AuthMode::Ev2First, CipherType::Aes),
+    ]
+}
+
+#[test]
+fn applications_are_created_and_deleted() {
+    let desfire = Desfire::with_transceiver(DesfireSimulator::new(&UID));
+    let settings = KeySettings { settings: 0x0F, key_count: 2, cipher: CipherType::Aes };
+    // The factory card lets anyone create applications, but not delete them
+    desfire.create_application(APP, &settings).unwrap();
+    assert_eq!(status(desfire.create_application(APP, &settings).unwrap_err()), Some((0x91, DUPLICATE_ERROR)));
+    assert_eq!(status(desfire.delete_application(APP).unwrap_err()), Some((0x91, AUTHENTICATION_ERROR)));
+
+    desfire.select_application(APP).unwrap();
+    assert_eq!(desfire.get_key_settings().unwrap(), settings);
+    desfire.select_application(PICC_AID).unwrap();
+    desfire.authenticate(AuthMode::Legacy, 0, &DesfireKey::zero(CipherType::TripleDes)).unwrap();
+    desfire.delete_application(APP).unwrap();
+    assert!(desfire.get_application_ids().unwrap().is_empty());
+}
+
+#[test]
+fn data_files_in_every_mode() {
+    for (mode, cipher) in every_mode() {
+        for communication in [CommunicationMode::Plain, CommunicationMode::Maced, CommunicationMode::Full] {
+            let desfire = card(cipher, LOCKED);
+            desfire.authenticate(mode, 0, &DesfireKey::zero(cipher)).unwrap();
+            desfire.create_std_data_file(1, communication, &access(1, 2, NO_ACCESS), 200).unwrap();
+            let memory = desfire.free_memory().unwrap();
+            desfire.create_backup_data_file(2, communication, &access(1, 2, NO_ACCESS), 32).unwrap();
+            assert_eq!(memory - desfire.free_memory().unwrap(), 32);
+
+            // Key 0 may neither read nor write; long data spans several frames
+            let label = format!("{} {}", mode.to_string(), communication.to_string());
+            assert_eq!(status(desfire.read_data(1, 0, 0).unwrap_err()), Some((0x91, AUTHENTICATION_ERROR)), "{}", label);
+            let data: Vec<u8> = (0..150).collect();
+            desfire.authenticate(mode, 2, &DesfireKey::zero(cipher)).unwrap();
+            desfire.write_data(1, 10, &data).unwrap();
+            desfire.write_data(2, 0, &[0x5A; 4]).unwrap();
+
+            desfire.authenticate(mode, 1, &DesfireKey::zero(cipher)).unwrap();
+            assert_eq!(desfire.read_data(1, 10, 150).unwrap(), data, "{}", label);
+            assert_eq!(desfire.read_data(1, 0, 0).unwrap().len(), 200, "{}", label);
+            assert_eq!(status(desfire.read_data(1, 190, 20).unwrap_err()), Some((0x91, BOUNDARY_ERROR)), "{}", label);
+
+            // Backup files change with the transaction only
+            desfire.authenticate(mode, 1, &DesfireKey::zero(cipher)).unwrap();
+            assert_eq!(desfire.read_data(2, 0, 4).unwrap(), [0x00; 4], "{}", label);
+            desfire.commit_transaction().unwrap();
+            assert_eq!(desfire.read_data(2, 0, 4).unwrap(), [0x5A; 4], "{}", label);
+        }
+    }
+}
+
+#[test]
+fn backup_files_follow_the_transaction() {
+    let desfire = card(CipherType::Aes, 0x0F);
+    desfire.create_backup_data_file(1, CommunicationMode::Plain, &access(FREE_ACCESS, FREE_ACCESS, 0), 8).unwrap();
+    desfire.write_data(1, 0, &[0x11; 8]).unwrap();
+    assert_eq!(desfire.read_data(1, 0, 0).unwrap(), [0x00; 8]);
+    desfire.commit_transaction().unwrap();
+    assert_eq!(desfire.read_data(1, 0, 0).unwrap(), [0x11; 8]);
+
+    desfire.write_data(1, 0, &[0x22; 4]).unwrap();
+    desfire.abort_transaction().unwrap();
+    desfire.commit_transaction().unwrap();
+    assert_eq!(desfire.read_data(1, 0, 0).unwrap(), [0x11; 8]);
+
+    // Selecting another application drops the transaction as well
+    desfire.write_data(1, 0, &[0x33; 4]).unwrap();
+    desfire.select_application(APP).unwrap();
+    desfire.commit_transaction().unwrap();
+    assert_eq!(desfire.read_data(1, 0, 0).unwrap(), [0x11; 8]);
+}
+
+#[test]
+fn values_stay_within_limits() {
+    for (mode, cipher) in every_mode() {
+        let desfire = card(cipher, LOCKED);
+        desfire.authenticate(mode, 0, &DesfireKey::zero(cipher)).unwrap();
+        desfire.create_value_file(4, CommunicationMode::Full, &access(1, 2, 2), -10..=100, 50, false).unwrap();
+        assert_eq!(desfire.get_file_settings(4).unwrap().layout,
+                   FileLayout::Value { lower_limit: -10, upper_limit: 100, limited_credit_value: 0, limited_credit_enabled: false });
+
+        // Key 1 only reads and debits; crediting needs the read/write key
+        desfire.authenticate(mode, 1, &DesfireKey::zero(cipher)).unwrap();
+        assert_eq!(desfire.get_value(4).unwrap(), 50, "{}", mode.to_string());
+        desfire.debit(4, 55).unwrap();
+        assert_eq!(desfire.get_value(4).unwrap(), 50);
+        desfire.commit_transaction().unwrap();
+        assert_eq!(desfire.get_value(4).unwrap(), -5);
+        assert_eq!(status(desfire.debit(4, 6).unwrap_err()), Some((0x91, BOUNDARY_ERROR)));
+        desfire.authenticate(mode, 1, &DesfireKey::zero(cipher)).unwrap();
+        assert_eq!(status(desfire.credit(4, 1).unwrap_err()), Some((0x91, AUTHENTICATION_ERROR)));
+
+        desfire.authenticate(mode, 2, &DesfireKey::zero(cipher)).unwrap();
+        desfire.credit(4, 105).unwrap();
+        assert_eq!(status(desfire.credit(4, 1).unwrap_err()), Some((0x91, BOUNDARY_ERROR)));
+        // The refused credit ends the session but not the transaction
+        desfire.authenticate(mode, 2, &DesfireKey::zero(cipher)).unwrap();
+        desfire.commit_transaction().unwrap();
+        assert_eq!(desfire.get_value(4).unwrap(), 100, "{}", mode.to_string());
+        assert!(desfire.credit(4, -1).is_err());
+    }
+}
+
+#[test]
+fn record_files_keep_their_records() {
+    let desfire = card(CipherType::Aes, 0x0F);
+    let free = access(FREE_ACCESS, FREE_ACCESS, 0);
+    desfire.create_linear_record_file(1, CommunicationMode::Plain, &free, 4, 2).unwrap();
+    desfire.create_cyclic_record_file(2, CommunicationMode::Plain, &free, 4, 3).unwrap();
+
+    // One record per transaction, written in parts
+    desfire.write_record(1, 0, &[0x01, 0x01]).unwrap();
+    desfire.write_record(1, 2, &[0x02, 0x02]).unwrap();
+    desfire.commit_transaction().unwrap();
+    desfire.write_record(1, 0, &[0x03; 4]).unwrap();
+    desfire.commit_transaction().unwrap();
+    assert_eq!(desfire.read_records(1, 0, 0).unwrap(), [0x01, 0x01, 0x02, 0x02, 0x03, 0x03, 0x03, 0x03]);
+    assert_eq!(desfire.read_records(1, 0, 1).unwrap(), [0x03; 4]);
+    assert_eq!(desfire.read_records(1, 1, 1).unwrap(), [0x01, 0x01, 0x02, 0x02]);
+    assert_eq!(status(desfire.write_record(1, 0, &[0x04]).unwrap_err()), Some((0x91, BOUNDARY_ERROR)));
+    assert_eq!(status(desfire.write_record(1, 2, &[0x04; 3]).unwrap_err()), Some((0x91, BOUNDARY_ERROR)));
+
+    // The cyclic file holds two records and drops the oldest
+    for record in 1..=3 {
+        desfire.write_record(2, 0, &[record; 4]).unwrap();
+        desfire.commit_transaction().unwrap();
+    }
+    assert_eq!(desfire.read_records(2, 0, 0).unwrap(), [[0x02; 4], [0x03; 4]].concat());
+    assert_eq!(desfire.get_file_settings(2).unwrap().layout, FileLayout::Record { record_size: 4, max_records: 3, current_records: 2 });
+    assert_eq!(status(desfire.read_records(2, 2, 1).unwrap_err()), Some((0x91, BOUNDARY_ERROR)));
+}
+
+#[test]
+fn access_rights_are_enforced() {
+    let desfire = card(CipherType::Aes, LOCKED);
+    // Files need the master key here
+    assert_eq!(status(desfire.delete_file(1).unwrap_err()), Some((0x91, AUTHENTICATION_ERROR)));
+    desfire.authenticate(AuthMode::Aes, 0, &DesfireKey::zero(CipherType::Aes)).unwrap();
+    desfire.create_std_data_file(1, CommunicationMode::Plain, &access(FREE_ACCESS, NO_ACCESS, NO_ACCESS), 16).unwrap();
+    assert_eq!(status(desfire.create_std_data_file(1, CommunicationMode::Plain, &access(0, 0, 0), 16).unwrap_err()),
+               Some((0x91, DUPLICATE_ERROR)));
+
+    desfire.authenticate(AuthMode::Aes, 0, &DesfireKey::zero(CipherType::Aes)).unwrap();
+    assert_eq!(desfire.read_data(1, 0, 4).unwrap(), [0x00; 4]);
+    assert_eq!(status(desfire.write_data(1, 0, &[0x01]).unwrap_err()), Some((0x91, PERMISSION_DENIED)));
+
+    desfire.authenticate(AuthMode::Aes, 0, &DesfireKey::zero(CipherType::Aes)).unwrap();
+    desfire.delete_file(1).unwrap();
+    assert!(desfire.get_file_ids().unwrap().is_empty());
+}
+
+#[test]
+fn keys_change_in_every_mode() {
+    for (mode, cipher) in every_mode() {
+        let desfire = card(cipher, 0x0F);
+        let zero = DesfireKey::zero(cipher);
+        let new_key = DesfireKey::new(cipher, &vec![0x24; zero.bytes().len()]).unwrap();
+        let master = DesfireKey::new(cipher, &vec![0x42; zero.bytes().len()]).unwrap();
+
+        // Another key needs its current value; the session goes on
+        desfire.authenticate(mode, 0, &zero).unwrap();
+        desfire.change_key(1, &new_key, Some(&zero), 1).unwrap();
+        assert!(desfire.is_authenticated(), "{}", mode.to_string());
+        // The session's own key ends it
+        desfire.change_key(0, &master, None, 1).unwrap();
+        assert!(!desfire.is_authenticated());
+
+        assert!(desfire.authenticate(mode, 1, &zero).is_err());
+        desfire.authenticate(mode, 1, &new_key).unwrap();
+        desfire.authenticate(mode, 0, &master).unwrap();
+        desfire.change_key_settings(0x0B).unwrap();
+        assert_eq!(desfire.get_key_settings().unwrap().settings, 0x0B, "{}", mode.to_string());
+    }
+}
+
+#[test]
+fn card_master_key_changes_type() {
+    let desfire = Desfire::with_transceiver(DesfireSimulator::new(&UID));
+    let key = DesfireKey::aes(&[0x77; 16]);
+    desfire.authenticate(AuthMode::Iso, 0, &DesfireKey::zero(CipherType::TripleDes)).unwrap();
+    desfire.change_key(0, &key, None, 0).unwrap();
+    desfire.authenticate(AuthMode::Aes, 0, &key).unwrap();
+    assert_eq!(desfire.get_key_settings().unwrap().cipher, CipherType::Aes);
+
+    // Frozen settings stay as they are
+    desfire.change_key_settings(0x07).unwrap();
+    desfire.authenticate(AuthMode::Aes, 0, &key).unwrap();
+    assert_eq!(status(desfire.change_key_settings(0x0F).unwrap_err()), Some((0x91, PERMISSION_DENIED)));
+}
+
+const PROFILE: &str = "
+# Transit card
+picc-key des 0000000000000000
+application 010203 aes keys=3 settings=09  # key 0 changes the others
+key 0 000102030405060708090A0B0C0D0E0F
+key 2 22222222222222222222222222222222
+file 1 std-data size=80 comm=full access=1200 data=48656C6C6F
+file 2 backup-data size=16 comm=mac access=E100 data=0102
+file 3 value lower=0 upper=500 value=20 limited-credit=yes comm=mac access=1222
+file 4 cyclic-record record-size=16 records=5 comm=plain access=E200
+application 0A0B0C des keys=1 settings=0F
+";
+
+#[test]
+fn profiles_are_parsed() {
+    let profile = Profile::parse(PROFILE).unwrap();
+    assert_eq!(profile.applications.len(), 2);
+    let application = &profile.applications[0];
+    assert_eq!(application.key_settings, KeySettings { settings: 0x09, key_count: 3, cipher: CipherType::Aes });
+    assert_eq!(application.key(1), DesfireKey::zero(CipherType::Aes));
+    assert_eq!(application.key(2), DesfireKey::aes(&[0x22; 16]));
+    assert_eq!(application.files.len(), 4);
+    assert_eq!(application.files[0].data, b"Hello");
+    assert_eq!(application.files[2].value, 20);
+
+    let error = Profile::parse("application 010203 aes keys=3 settings=0F\nfile 1 std-data size=8 comm=full\n").unwrap_err();
+    assert_eq!(error.to_string(), "Line 2: Missing access=");
+    assert!(Profile::parse("key 0 00").is_err());
+    assert!(Profile::parse("application 010203 aes keys=20 settings=0F").is_err());
+    assert!(Profile::parse("application 010203 aes keys=2 settings=0F\nfile 1 value comm=plain access=0000 lower=0 upper=1 value=0 size=3").is_err());
+}
+
+#[test]
+fn profiles_name_only_keys_the_application_has() {
+    let error = Profile::parse("application 010203 aes keys=2 settings=5F\nkey 1 11111111111111111111111111111111").unwrap_err();
+    assert_eq!(error.to_string(), "Line 1: settings= names change key 5, which the application lacks");
+    assert!(Profile::parse("application 010203 aes keys=2 settings=1F").is_ok());
+    assert!(Profile::parse("application 010203 aes keys=2 settings=EF").is_ok());
+    assert!(Profile::parse("application 010203 aes keys=2 settings=FF").is_ok());
+
+    let error = Profile::parse("application 010203 aes keys=2 settings=0F\nfile 1 std-data size=8 comm=plain access=5200").unwrap_err();
+    assert_eq!(error.to_string(), "Line 2: access= names key 5, which the application lacks");
+    assert!(Profile::parse("application 010203 aes keys=2 settings=0F\nfile 1 std-data size=8 comm=plain access=2100").is_err());
+    assert!(Profile::parse("application 010203 aes keys=2 settings=0F\nfile 1 std-data size=8 comm=plain access=EF10").is_ok());
+}
+
+#[test]
+fn cards_are_provisioned_from_a_profile() {
+    let profile = Profile::parse(PROFILE).unwrap();
+    let desfire = Desfire::with_transceiver(DesfireSimulator::new(&UID));
+    let actions = desfire.provision(&profile).unwrap();
+    assert!(actions.contains(&"Created application 010203".to_string()));
+    assert!(actions.contains(&"Set key 0 of application 010203".to_string()));
+    assert_eq!(desfire.get_application_ids().unwrap(), [0x010203, 0x0A0B0C]);
+
+    let master = profile.applications[0].key(0);
+    desfire.select_application(0x010203).unwrap();
+    desfire.authenticate(AuthMode::Aes, 0, &master).unwrap();
+    assert_eq!(desfire.get_file_ids().unwrap(), [1, 2, 3, 4]);
+    desfire.authenticate(AuthMode::Aes, 1, &DesfireKey::zero(CipherType::Aes)).unwrap();
+    assert_eq!(desfire.read_data(1, 0, 5).unwrap(), b"Hello");
+    assert_eq!(desfire.read_data(2, 0, 2).unwrap(), [0x01, 0x02]);
+    assert_eq!(desfire.get_value(3).unwrap(), 20);
+    desfire.authenticate(AuthMode::Aes, 2, &DesfireKey::aes(&[0x22; 16])).unwrap();
+
+    // Provisioning again finds everything in place
+    assert!(desfire.provision(&profile).unwrap().is_empty());
+    assert_eq!(desfire.selected_application(), PICC_AID);
+}
+
+#[test]
+fn hex_input_is_checked() {
+    assert_eq!(parse_hex("00A1ff").unwrap(), [0x00, 0xA1, 0xFF]);
+    assert!(parse_hex("").unwrap().is_empty());
+    // Odd length, non-hex and non-ASCII input are errors, not panics
+    assert!(parse_hex("0A1").is_err());
+    assert!(parse_hex("0G").is_err());
+    assert!(parse_hex("aéb").is_err());
+    assert!(parse_hex("éa").is_err());
+}
+
+#[test]
+fn access_rights_are_four_digits() {
+    assert_eq!(parse_access("12E0").unwrap(), AccessRights { read: 1, write: 2, read_write: 0xE, change: 0 });
+    assert_eq!(parse_access("ffff").unwrap(), AccessRights { read: 0xF, write: 0xF, read_write: 0xF, change: 0xF });
+    assert!(parse_access("12E").is_err());
+    assert!(parse_access("12E01").is_err());
+    assert!(parse_access("12G0").is_err());
+}
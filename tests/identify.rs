@@ -0,0 +1,119 @@
+use acr122u_test::card_info::ats_historical_bytes;
+use acr122u_test::identify::{identify, Evidence, MifareType};
+
+// ATS of a DESFire EV1 (TL, T0, TA, TB, TC, one historical byte)
+const DESFIRE_ATS: &[u8] = &[0x06, 0x75, 0x77, 0x81, 0x02, 0x80];
+// ATS of a MIFARE Plus, whose historical bytes start with C1 05
+const PLUS_ATS: &[u8] = &[0x0C, 0x75, 0x77, 0x80, 0x02, 0xC1, 0x05, 0x2F, 0x2F, 0x01, 0xBC, 0xD6];
+
+struct Case {
+    atqa: Option<u16>,
+    sak: Option<u8>,
+    ats: Option<&'static [u8]>,
+    // Ultralight/NTAG GET_VERSION, or DESFire/Plus GetVersion when the card
+    // has an ATS
+    version: Option<&'static [u8]>,
+    ul_c_auth: Option<bool>,
+    card_type: MifareType,
+    product: &'static str,
+    memory_size: Option<usize>,
+    generation: Option<&'static str>,
+    security_level: Option<&'static str>,
+}
+
+const CASES: &[Case] = &[
+    Case { atqa: Some(0x0044), sak: Some(0x00), ats: None, version: Some(&[0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x0F, 0x03]), ul_c_auth: None,
+           card_type: MifareType::Ntag, product: "NTAG213", memory_size: Some(144),
+           generation: None, security_level: None },
+    Case { atqa: Some(0x0044), sak: Some(0x00), ats: None, version: Some(&[0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x11, 0x03]), ul_c_auth: None,
+           card_type: MifareType::Ntag, product: "NTAG215", memory_size: Some(504),
+           generation: None, security_level: None },
+    Case { atqa: Some(0x0044), sak: Some(0x00), ats: None, version: Some(&[0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x13, 0x03]), ul_c_auth: None,
+           card_type: MifareType::Ntag, product: "NTAG216", memory_size: Some(888),
+           generation: None, security_level: None },
+    Case { atqa: Some(0x0044), sak: Some(0x00), ats: None, version: Some(&[0x00, 0x04, 0x03, 0x01, 0x01, 0x00, 0x0B, 0x03]), ul_c_auth: None,
+           card_type: MifareType::MifareUltralight, product: "MIFARE Ultralight EV1 (MF0UL11)", memory_size: Some(48),
+           generation: Some("EV1"), security_level: None },
+    Case { atqa: Some(0x0044), sak: Some(0x00), ats: None, version: Some(&[0x00, 0x04, 0x03, 0x01, 0x01, 0x00, 0x0E, 0x03]), ul_c_auth: None,
+           card_type: MifareType::MifareUltralight, product: "MIFARE Ultralight EV1 (MF0UL21)", memory_size: Some(128),
+           generation: Some("EV1"), security_level: None },
+    Case { atqa: Some(0x0044), sak: Some(0x00), ats: None, version: None, ul_c_auth: Some(true),
+           card_type: MifareType::MifareUltralight, product: "MIFARE Ultralight C (MF0ICU2)", memory_size: Some(144),
+           generation: Some("C"), security_level: None },
+    Case { atqa: Some(0x0044), sak: Some(0x00), ats: None, version: None, ul_c_auth: Some(false),
+           card_type: MifareType::MifareUltralight, product: "MIFARE Ultralight (MF0ICU1)", memory_size: Some(48),
+           generation: None, security_level: None },
+    Case { atqa: Some(0x0344), sak: Some(0x20), ats: Some(DESFIRE_ATS), version: Some(&[0x04, 0x01, 0x01, 0x01, 0x00, 0x18, 0x05]), ul_c_auth: None,
+           card_type: MifareType::MifareDesfire, product: "MIFARE DESFire EV1 4K", memory_size: Some(4096),
+           generation: Some("EV1"), security_level: None },
+    Case { atqa: Some(0x0344), sak: Some(0x20), ats: Some(DESFIRE_ATS), version: Some(&[0x04, 0x01, 0x01, 0x12, 0x00, 0x1A, 0x05]), ul_c_auth: None,
+           card_type: MifareType::MifareDesfire, product: "MIFARE DESFire EV2 8K", memory_size: Some(8192),
+           generation: Some("EV2"), security_level: None },
+    Case { atqa: Some(0x0344), sak: Some(0x20), ats: Some(DESFIRE_ATS), version: Some(&[0x04, 0x01, 0x01, 0x33, 0x00, 0x16, 0x05]), ul_c_auth: None,
+           card_type: MifareType::MifareDesfire, product: "MIFARE DESFire EV3 2K", memory_size: Some(2048),
+           generation: Some("EV3"), security_level: None },
+    // Without GetVersion, ATQA 0344 and SAK 20 still tell a DESFire
+    Case { atqa: Some(0x0344), sak: Some(0x20), ats: Some(DESFIRE_ATS), version: None, ul_c_auth: None,
+           card_type: MifareType::MifareDesfire, product: "MIFARE DESFire", memory_size: None,
+           generation: None, security_level: None },
+    Case { atqa: Some(0x0004), sak: Some(0x08), ats: Some(PLUS_ATS), version: None, ul_c_auth: None,
+           card_type: MifareType::MifarePlus, product: "MIFARE Plus 2K", memory_size: Some(2048),
+           generation: None, security_level: Some("SL1") },
+    Case { atqa: Some(0x0002), sak: Some(0x18), ats: Some(PLUS_ATS), version: None, ul_c_auth: None,
+           card_type: MifareType::MifarePlus, product: "MIFARE Plus 4K", memory_size: Some(4096),
+           generation: None, security_level: Some("SL1") },
+    Case { atqa: Some(0x0004), sak: Some(0x10), ats: None, version: None, ul_c_auth: None,
+           card_type: MifareType::MifarePlus, product: "MIFARE Plus 2K", memory_size: Some(2048),
+           generation: None, security_level: Some("SL2") },
+    Case { atqa: Some(0x0002), sak: Some(0x11), ats: None, version: None, ul_c_auth: None,
+           card_type: MifareType::MifarePlus, product: "MIFARE Plus 4K", memory_size: Some(4096),
+           generation: None, security_level: Some("SL2") },
+    Case { atqa: Some(0x0042), sak: Some(0x20), ats: Some(PLUS_ATS), version: None, ul_c_auth: None,
+           card_type: MifareType::MifarePlus, product: "MIFARE Plus 4K", memory_size: Some(4096),
+           generation: None, security_level: Some("SL0/SL3") },
+    Case { atqa: Some(0x0044), sak: Some(0x20), ats: Some(PLUS_ATS), version: Some(&[0x04, 0x02, 0x01, 0x11, 0x00, 0x16, 0x05]), ul_c_auth: None,
+           card_type: MifareType::MifarePlus, product: "MIFARE Plus EV1 2K", memory_size: Some(2048),
+           generation: Some("EV1"), security_level: Some("SL0/SL3") },
+    Case { atqa: Some(0x0004), sak: Some(0x08), ats: None, version: None, ul_c_auth: None,
+           card_type: MifareType::MifareClassic1K, product: "MIFARE Classic 1K", memory_size: Some(1024),
+           generation: None, security_level: None },
+    Case { atqa: Some(0x0002), sak: Some(0x18), ats: None, version: None, ul_c_auth: None,
+           card_type: MifareType::MifareClassic4K, product: "MIFARE Classic 4K", memory_size: Some(4096),
+           generation: None, security_level: None },
+    Case { atqa: Some(0x0004), sak: Some(0x09), ats: None, version: None, ul_c_auth: None,
+           card_type: MifareType::MifareMini, product: "MIFARE Mini", memory_size: Some(320),
+           generation: None, security_level: None },
+    Case { atqa: Some(0x0004), sak: Some(0x53), ats: None, version: None, ul_c_auth: None,
+           card_type: MifareType::Unknown, product: "Unknown card (SAK 53)", memory_size: None,
+           generation: None, security_level: None },
+    Case { atqa: None, sak: None, ats: None, version: None, ul_c_auth: None,
+           card_type: MifareType::Unknown, product: "Unknown card (no SAK)", memory_size: None,
+           generation: None, security_level: None },
+];
+
+fn evidence(case: &Case) -> Evidence {
+    let version = case.version.map(|v| v.to_vec());
+    Evidence {
+        atqa: case.atqa.map(u16::to_be_bytes),
+        sak: case.sak,
+        ats: case.ats.map(|ats| ats.to_vec()),
+        historical_bytes: case.ats.map(ats_historical_bytes).unwrap_or_default(),
+        ul_version: version.clone().filter(|_| case.ats.is_none()),
+        ul_c_auth: case.ul_c_auth,
+        ul_signature: None,
+        desfire_version: version.filter(|_| case.ats.is_some()),
+    }
+}
+
+#[test]
+fn identification_table() {
+    for case in CASES {
+        let id = identify(&evidence(case));
+        let context = format!("ATQA {:04X?} SAK {:02X?} version {:02X?}", case.atqa, case.sak, case.version);
+        assert_eq!(id.card_type, case.card_type, "{}", context);
+        assert_eq!(id.product, case.product, "{}", context);
+        assert_eq!(id.memory_size, case.memory_size, "{}", context);
+        assert_eq!(id.generation.as_deref(), case.generation, "{}", context);
+        assert_eq!(id.security_level.as_deref(), case.security_level, "{}", context);
+    }
+}
@@ -0,0 +1,117 @@
+use acr122u_test::mad::{aid_name, crc8, mad_version, Mad, AID_FREE, AID_NDEF};
+
+// Sector 0 blocks 1-2 of a 1K card formatted for NDEF
+const NDEF_MAD1: [u8; 32] = [
+    0x14, 0x01, 0x03, 0xE1, 0x03, 0xE1, 0x03, 0xE1, 0x03, 0xE1, 0x03, 0xE1, 0x03, 0xE1, 0x03, 0xE1,
+    0x03, 0xE1, 0x03, 0xE1, 0x03, 0xE1, 0x03, 0xE1, 0x03, 0xE1, 0x03, 0xE1, 0x03, 0xE1, 0x03, 0xE1,
+];
+
+#[test]
+fn crc_of_known_mad() {
+    assert_eq!(crc8(&NDEF_MAD1[1..]), 0x14);
+}
+
+#[test]
+fn decode_ndef_mad() {
+    let mad = Mad::decode(&NDEF_MAD1, None, 16).unwrap();
+    assert_eq!(mad.info, 0x01);
+    assert_eq!(mad.aid(0), None);
+    assert_eq!(mad.aid(1), Some(AID_NDEF));
+    assert_eq!(mad.sectors_of(AID_NDEF), (1..16).collect::<Vec<u8>>());
+    assert_eq!(mad.encode_mad1(), NDEF_MAD1);
+    assert_eq!(mad.encode_mad2(), None);
+}
+
+#[test]
+fn crc_mismatch_is_rejected() {
+    let mut mad1 = NDEF_MAD1;
+    mad1[5] = 0x00;
+    assert!(Mad::decode(&mad1, None, 16).is_err());
+}
+
+#[test]
+fn mad2_round_trip() {
+    let mut mad = Mad::new(40);
+    mad.aids[1] = AID_NDEF;
+    mad.aids[17] = AID_NDEF;
+    mad.aids[39] = 0x1234;
+
+    let mad1 = mad.encode_mad1();
+    let mad2 = mad.encode_mad2().unwrap();
+    assert_eq!(&mad2[2..4], &[0x03, 0xE1]);
+    assert_eq!(&mad2[46..48], &[0x34, 0x12]);
+
+    let decoded = Mad::decode(&mad1, Some(&mad2), 40).unwrap();
+    assert_eq!(decoded, mad);
+    assert_eq!(decoded.sectors_of(AID_NDEF), vec![1, 17]);
+    assert_eq!(decoded.aid(16), None);
+    assert_eq!(decoded.aid(2), Some(AID_FREE));
+
+    assert!(Mad::decode(&mad1, None, 40).is_err());
+}
+
+#[test]
+fn known_aids() {
+    assert_eq!(aid_name(AID_NDEF), "NFC Forum NDEF");
+    assert_eq!(aid_name(AID_FREE), "Free");
+    assert_eq!(aid_name(0x0001), "Defect");
+    assert_eq!(aid_name(0x1801), "City traffic");
+    assert_eq!(aid_name(0x5203), "Access control & security");
+    assert_eq!(aid_name(0xE200), "Unknown");
+}
+
+#[test]
+fn version_from_gpb() {
+    assert_eq!(mad_version(0xC1), Some(1));
+    assert_eq!(mad_version(0xC2), Some(2));
+    assert_eq!(mad_version(0x69), None);
+    assert_eq!(mad_version(0x80), None);
+}
+
+#[test]
+fn card_publisher_sector() {
+    let mut mad = Mad::decode(&NDEF_MAD1, None, 16).unwrap();
+    assert_eq!(mad.card_publisher_sector(), Some(1));
+
+    mad.set_card_publisher_sector(Some(15)).unwrap();
+    assert_eq!(mad.info, 0x0F);
+    mad.set_card_publisher_sector(None).unwrap();
+    assert_eq!(mad.card_publisher_sector(), None);
+
+    assert!(mad.set_card_publisher_sector(Some(16)).is_err());
+    assert!(Mad::new(40).set_card_publisher_sector(Some(16)).is_err());
+    assert!(Mad::new(40).set_card_publisher_sector(Some(39)).is_ok());
+}
+
+#[test]
+fn allocate_and_free() {
+    let mut mad = Mad::new(40);
+    assert_eq!(mad.allocate(AID_NDEF, 2).unwrap(), vec![1, 2]);
+    assert_eq!(mad.allocate(0x1801, 15).unwrap(), (3..16).chain(17..19).collect::<Vec<u8>>());
+    assert!(mad.allocate(0x5203, 22).is_err());
+    assert!(mad.allocate(AID_FREE, 1).is_err());
+
+    assert_eq!(mad.free(AID_NDEF), vec![1, 2]);
+    assert_eq!(mad.allocate(0x5203, 3).unwrap(), vec![1, 2, 19]);
+    assert_eq!(mad.sectors_of(AID_FREE).len(), 20);
+}
+
+#[test]
+fn damaged_mad_is_repaired() {
+    let mut mad1 = NDEF_MAD1;
+    mad1[0] = 0x00;
+    assert!(Mad::decode(&mad1, None, 16).is_err());
+
+    let mad = Mad::decode_unchecked(&mad1, None, 16).unwrap();
+    assert_eq!(mad.encode_mad1(), NDEF_MAD1);
+}
+
+#[test]
+fn display() {
+    let mut mad = Mad::new(16);
+    mad.allocate(AID_NDEF, 1).unwrap();
+    let text = mad.to_string();
+    assert!(text.starts_with("MAD1, info byte 00 (no card publisher sector)\n"));
+    assert!(text.contains("Sector 01: E103 NFC Forum NDEF"));
+    assert!(text.ends_with("Sector 15: 0000 Free"));
+}
@@ -0,0 +1,95 @@
+use acr122u_test::classic::{KeyType, MifareClassic};
+use acr122u_test::magic::{bcc, Block0, MagicGeneration};
+use acr122u_test::simulator::ClassicSimulator;
+
+// Block 0 of a MIFARE Classic 1K with UID CD 76 92 74
+const BLOCK0: [u8; 16] = [
+    0xCD, 0x76, 0x92, 0x74, 0x5D, 0x08, 0x04, 0x00, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+];
+
+#[test]
+fn block0_round_trip() {
+    assert_eq!(bcc(&BLOCK0[0..4]), 0x5D);
+
+    let block = Block0::decode(&BLOCK0).unwrap();
+    assert_eq!(block.uid, [0xCD, 0x76, 0x92, 0x74]);
+    assert_eq!(block.sak, 0x08);
+    assert_eq!(block.atqa, [0x00, 0x04]);
+    assert_eq!(block.encode(), BLOCK0);
+}
+
+#[test]
+fn new_uid_gets_its_bcc() {
+    let block = Block0 { uid: [0x01, 0x02, 0x03, 0x04], ..Block0::decode(&BLOCK0).unwrap() };
+    let data = block.encode();
+    assert_eq!(data[4], 0x04);
+    assert_eq!(&data[5..], &BLOCK0[5..]);
+}
+
+#[test]
+fn wrong_bcc_is_refused() {
+    let mut data = BLOCK0;
+    data[4] ^= 0xFF;
+    assert!(Block0::decode(&data).is_err());
+    assert!(Block0::decode(&BLOCK0[..15]).is_err());
+}
+
+const UID: [u8; 4] = [0xCD, 0x76, 0x92, 0x74];
+const KEY: [u8; 6] = [0xFF; 6];
+const WRONG_KEY: [u8; 6] = [0x4D, 0x3A, 0x99, 0xC3, 0x51, 0xDD];
+
+fn magic_card(generation: MagicGeneration) -> ClassicSimulator {
+    ClassicSimulator::new(&UID).with_magic(generation).unwrap()
+}
+
+#[test]
+fn genuine_card_is_refused() {
+    let card = ClassicSimulator::new(&UID);
+    let classic = MifareClassic::with_raw_transceiver(&card, &UID);
+    assert_eq!(classic.detect_magic(&KEY).unwrap(), None);
+
+    let before = card.block(0);
+    assert!(classic.set_uid([0x01, 0x02, 0x03, 0x04], &KEY).is_err());
+    assert!(classic.read_magic_block0(&KEY).is_err());
+    assert_eq!(card.block(0), before);
+
+    // The card is still usable afterwards
+    classic.load_key(&KEY).unwrap();
+    classic.authenticate(4, KeyType::KeyA).unwrap();
+    assert_eq!(classic.read_block(4).unwrap(), [0x00; 16]);
+}
+
+#[test]
+fn gen1a_rewrites_block0_through_the_backdoor() {
+    let card = magic_card(MagicGeneration::Gen1a);
+    let classic = MifareClassic::with_raw_transceiver(&card, &UID);
+    // The backdoor needs no key
+    assert_eq!(classic.detect_magic(&WRONG_KEY).unwrap(), Some(MagicGeneration::Gen1a));
+    assert_eq!(classic.read_magic_block0(&WRONG_KEY).unwrap().uid, UID);
+
+    assert_eq!(classic.set_uid([0x01, 0x02, 0x03, 0x04], &WRONG_KEY).unwrap(), MagicGeneration::Gen1a);
+    assert_eq!(card.block(0)[0..8], [0x01, 0x02, 0x03, 0x04, 0x04, 0x08, 0x04, 0x00]);
+}
+
+#[test]
+fn gen2_rewrites_block0_after_authentication() {
+    let card = magic_card(MagicGeneration::Gen2);
+    let classic = MifareClassic::with_raw_transceiver(&card, &UID);
+    assert_eq!(classic.detect_magic(&KEY).unwrap(), Some(MagicGeneration::Gen2));
+
+    // SAK 18 and ATQA 00 02 of a Classic 4K, ATQA stored low byte first
+    assert_eq!(classic.set_sak_atqa(0x18, [0x00, 0x02], &KEY).unwrap(), MagicGeneration::Gen2);
+    let block = Block0::decode(&card.block(0)).unwrap();
+    assert_eq!((block.uid, block.sak, block.atqa), (UID, 0x18, [0x00, 0x02]));
+    assert_eq!(card.block(0)[5..8], [0x18, 0x02, 0x00]);
+
+    // Gen2 cards need key A of sector 0
+    assert!(classic.set_uid([0x01, 0x02, 0x03, 0x04], &WRONG_KEY).is_err());
+    assert_eq!(card.block(0)[0..4], UID);
+}
+
+#[test]
+fn only_gen1a_and_gen2_are_simulated() {
+    assert!(ClassicSimulator::new(&UID).with_magic(MagicGeneration::Gen3).is_err());
+    assert!(ClassicSimulator::new(&UID).with_magic(MagicGeneration::Gen4).is_err());
+}
@@ -0,0 +1,69 @@
+use acr122u_test::crypto1::{prng_successor, CardAuth, ReaderAuth};
+use acr122u_test::keys::{parse_key, KeyDictionary};
+use acr122u_test::mfkey::{mfkey32, mfkey64, recover_key, AuthTrace};
+
+const UID: u32 = 0xCD769274;
+const KEY: [u8; 6] = [0x4D, 0x3A, 0x99, 0xC3, 0x51, 0xDD];
+
+// Authentication with `key` as a sniffer would capture it
+fn capture(key: &[u8; 6], nt: u32, nr: u32) -> AuthTrace {
+    let mut reader = ReaderAuth::new(key, UID, nt);
+    let (nr_ar, parity) = reader.respond(nr);
+    let (_, at, _) = CardAuth::new(key, UID, nt).respond(&nr_ar, &parity).unwrap();
+    AuthTrace {
+        uid: UID,
+        nt,
+        nr_enc: u32::from_be_bytes(nr_ar[0..4].try_into().unwrap()),
+        ar_enc: u32::from_be_bytes(nr_ar[4..8].try_into().unwrap()),
+        at_enc: Some(u32::from_be_bytes(at)),
+    }
+}
+
+#[test]
+fn mfkey64_known_trace() {
+    let traces = AuthTrace::parse_line("9c599b32 82a4166c a1e458ce 6eea41e0 5cadf439").unwrap();
+    assert_eq!(mfkey64(&traces[0]), Some([0xFF; 6]));
+}
+
+#[test]
+fn mfkey32_reader_only() {
+    let first = AuthTrace { at_enc: None, ..capture(&KEY, prng_successor(0x01200145, 160), 0x11223344) };
+    let second = AuthTrace { at_enc: None, ..capture(&KEY, prng_successor(0x01200145, 320), 0x55667788) };
+    assert_eq!(mfkey64(&first), None);
+    assert_eq!(mfkey32(&first, &second), Some(KEY));
+    assert_eq!(recover_key(&[first, second]), Some(KEY));
+}
+
+#[test]
+fn different_keys_give_nothing() {
+    let first = capture(&KEY, prng_successor(0x01200145, 160), 0x11223344);
+    let second = capture(&[0xFF; 6], prng_successor(0x01200145, 320), 0x55667788);
+    assert_eq!(mfkey32(&first, &second), None);
+}
+
+#[test]
+fn trace_lines() {
+    let traces = AuthTrace::parse_line("12345678 1AD8DF2B 1D316024 620EF048 30D6CB07 C52077E2 837AC61A").unwrap();
+    assert_eq!(traces.len(), 2);
+    assert_eq!(traces[1].uid, 0x12345678);
+    assert_eq!(traces[1].nt, 0x30D6CB07);
+    assert_eq!(traces[1].at_enc, None);
+
+    assert!(AuthTrace::parse_line("12345678 1AD8DF2B 1D316024").is_err());
+    assert!(AuthTrace::parse_line("12345678 1AD8DF2B 1D316024 XYZ").is_err());
+}
+
+#[test]
+fn key_dictionary() {
+    let text = "# Factory\nFFFFFFFFFFFF\n\na0a1a2a3a4a5  # MAD\nFFFFFFFFFFFF\n";
+    let mut dictionary = KeyDictionary::parse(text).unwrap();
+    assert_eq!(dictionary.keys(), &[[0xFF; 6], [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5]]);
+
+    assert!(dictionary.add(KEY));
+    assert!(!dictionary.add(KEY));
+    assert_eq!(dictionary.keys().len(), 3);
+
+    assert_eq!(parse_key("4D3A99C351DD").unwrap(), KEY);
+    assert!(parse_key("4D3A99C351").is_err());
+    assert!(KeyDictionary::parse("FFFFFFFFFFFF\nnot a key\n").is_err());
+}
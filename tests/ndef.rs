@@ -0,0 +1,153 @@
+use acr122u_test::ndef::{Action, NdefMessage, NdefRecord, SmartPoster, Text, TextEncoding, Tnf};
+
+fn single(record: NdefRecord) -> Vec<u8> {
+    NdefMessage::new(vec![record]).to_bytes().unwrap()
+}
+
+#[test]
+fn empty_message() {
+    assert_eq!(NdefMessage::empty().to_bytes().unwrap(), [0xD0, 0x00, 0x00]);
+    let message = NdefMessage::parse(&[0xD0, 0x00, 0x00]).unwrap();
+    assert!(message.is_empty());
+}
+
+#[test]
+fn uri_record_uses_longest_prefix() {
+    let bytes = single(NdefRecord::uri("https://www.example.com"));
+    assert_eq!(bytes, [
+        0xD1, 0x01, 0x0C, b'U', 0x02, b'e', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'c', b'o', b'm',
+    ]);
+
+    assert_eq!(NdefRecord::uri("urn:epc:id:sgtin:1").payload[0], 0x1E);
+    assert_eq!(NdefRecord::uri("tel:+3212345").payload[0], 0x05);
+    assert_eq!(NdefRecord::uri("geo:50.8,4.3").payload[0], 0x00);
+
+    for uri in ["https://www.example.com", "urn:epc:id:sgtin:1", "mailto:a@b.c", "geo:50.8,4.3"] {
+        assert_eq!(NdefRecord::uri(uri).as_uri().unwrap(), uri);
+    }
+}
+
+#[test]
+fn text_record_utf8() {
+    let bytes = single(NdefRecord::text("en", "Hello"));
+    assert_eq!(bytes, [0xD1, 0x01, 0x08, b'T', 0x02, b'e', b'n', b'H', b'e', b'l', b'l', b'o']);
+
+    let message = NdefMessage::parse(&bytes).unwrap();
+    assert_eq!(message.records[0].as_text().unwrap(), Text::new("en", "Hello"));
+}
+
+#[test]
+fn text_record_utf16() {
+    let text = Text { language: "fr".to_string(), text: "Été".to_string(), encoding: TextEncoding::Utf16 };
+    let record = NdefRecord::text_record(&text);
+    assert_eq!(record.payload, [0x82, b'f', b'r', 0x00, 0xC9, 0x00, b't', 0x00, 0xE9]);
+    assert_eq!(record.as_text().unwrap(), text);
+
+    // Little-endian with a byte order mark
+    let record = NdefRecord::new(Tnf::WellKnown, b"T", &[0x82, b'd', b'e', 0xFF, 0xFE, b'O', 0x00, b'K', 0x00]);
+    assert_eq!(record.as_text().unwrap().text, "OK");
+}
+
+#[test]
+fn long_record_and_id() {
+    let payload = vec![0x5A; 300];
+    let record = NdefRecord::mime("application/octet-stream", &payload).with_id(b"blob");
+    let bytes = single(record.clone());
+
+    // No SR: 4-byte payload length; IL: ID length after it
+    assert_eq!(bytes[0], 0xC0 | 0x08 | 0x02);
+    assert_eq!(&bytes[2..6], &[0x00, 0x00, 0x01, 0x2C]);
+    assert_eq!(bytes[6], 4);
+
+    let message = NdefMessage::parse(&bytes).unwrap();
+    assert_eq!(message.records, vec![record]);
+    assert_eq!(message.records[0].id, b"blob");
+}
+
+#[test]
+fn chunked_record_is_joined() {
+    let bytes = [
+        0xB2, 0x0A, 0x03, b't', b'e', b'x', b't', b'/', b'p', b'l', b'a', b'i', b'n', b'a', b'b', b'c',
+        0x36, 0x00, 0x02, b'd', b'e',
+        0x56, 0x00, 0x01, b'f',
+    ];
+    let message = NdefMessage::parse(&bytes).unwrap();
+    assert_eq!(message.records.len(), 1);
+    let (mime_type, data) = message.records[0].as_mime().unwrap();
+    assert_eq!(mime_type, "text/plain");
+    assert_eq!(data, b"abcdef");
+
+    assert_eq!(message.to_chunked_bytes(3).unwrap(), [
+        0xB2, 0x0A, 0x03, b't', b'e', b'x', b't', b'/', b'p', b'l', b'a', b'i', b'n', b'a', b'b', b'c',
+        0x56, 0x00, 0x03, b'd', b'e', b'f',
+    ]);
+}
+
+#[test]
+fn chunked_messages_round_trip() {
+    let message = NdefMessage::new(vec![
+        NdefRecord::text("en", "A somewhat longer text that will not fit in one chunk"),
+        NdefRecord::uri("https://example.com/"),
+        NdefRecord::mime("image/png", &[0x89; 40]).with_id(b"1"),
+    ]);
+    for chunk_size in [1, 7, 16, 1000] {
+        let bytes = message.to_chunked_bytes(chunk_size).unwrap();
+        assert_eq!(NdefMessage::parse(&bytes).unwrap(), message);
+    }
+}
+
+#[test]
+fn smart_poster() {
+    let poster = SmartPoster::new("https://www.example.com/menu")
+        .title("en", "Menu")
+        .title("nl", "Menukaart")
+        .action(Action::Do);
+    let record = NdefRecord::smart_poster(&poster);
+    assert_eq!(record.record_type, b"Sp");
+
+    let message = NdefMessage::parse(&single(record)).unwrap();
+    assert_eq!(message.records[0].as_smart_poster().unwrap(), poster);
+    assert_eq!(message.records[0].describe(), "Smart Poster \"Menu\": https://www.example.com/menu");
+}
+
+#[test]
+fn external_and_android_application_records() {
+    let record = NdefRecord::external("Example.com", "Badge", &[1, 2, 3]);
+    let (name, data) = record.as_external().unwrap();
+    assert_eq!(name, "example.com:badge");
+    assert_eq!(data, [1, 2, 3]);
+    assert_eq!(record.as_android_application(), None);
+
+    let message = NdefMessage::new(vec![NdefRecord::uri("https://example.com"), NdefRecord::android_application("com.example.app")]);
+    let parsed = NdefMessage::parse(&message.to_bytes().unwrap()).unwrap();
+    assert_eq!(parsed.records[1].as_android_application().unwrap(), "com.example.app");
+    assert_eq!(parsed.records[1].tnf, Tnf::External);
+}
+
+#[test]
+fn absolute_uri_record() {
+    let record = NdefRecord::absolute_uri("http://example.com/schema");
+    assert_eq!(record.tnf.to_byte(), 3);
+    assert_eq!(record.as_uri().unwrap(), "http://example.com/schema");
+}
+
+#[test]
+fn malformed_messages_are_rejected() {
+    // Truncated payload
+    assert!(NdefMessage::parse(&[0xD1, 0x01, 0x05, b'T', 0x02]).is_err());
+    // First record without MB
+    assert!(NdefMessage::parse(&[0x51, 0x01, 0x01, b'U', 0x00]).is_err());
+    // Second record with MB
+    assert!(NdefMessage::parse(&[0x91, 0x01, 0x01, b'U', 0x00, 0xD1, 0x01, 0x01, b'U', 0x00]).is_err());
+    // No ME
+    assert!(NdefMessage::parse(&[0x91, 0x01, 0x01, b'U', 0x00]).is_err());
+    // Unchanged record outside a chunked record
+    assert!(NdefMessage::parse(&[0xD6, 0x00, 0x00]).is_err());
+    // ME in the middle of a chunked record
+    assert!(NdefMessage::parse(&[0xF2, 0x01, 0x01, b'a', 0x00]).is_err());
+    // Empty record with a type
+    assert!(NdefMessage::parse(&[0xD0, 0x01, 0x00, b'x']).is_err());
+    // Bytes after ME
+    assert!(NdefMessage::parse(&[0xD0, 0x00, 0x00, 0x00]).is_err());
+    assert!(NdefMessage::new(Vec::new()).to_bytes().is_err());
+}
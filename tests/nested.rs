@@ -0,0 +1,71 @@
+use acr122u_test::classic::KeyType;
+use acr122u_test::crypto1::{prng_successor, CardAuth};
+use acr122u_test::nested::{candidate_keys, nonce_distance, parity_matches, NestedAttack};
+use acr122u_test::simulator::ClassicSimulator;
+use acr122u_test::transport::{pack_bits, unpack_bits, RawFrame};
+
+const UID: [u8; 4] = [0xCD, 0x76, 0x92, 0x74];
+const KEY: [u8; 6] = [0x4D, 0x3A, 0x99, 0xC3, 0x51, 0xDD];
+
+#[test]
+fn distance_between_nonces() {
+    let nt = prng_successor(0x01200145, 1000);
+    assert_eq!(nonce_distance(nt, nt), Some(0));
+    assert_eq!(nonce_distance(nt, prng_successor(nt, 321)), Some(321));
+    // Not a PRNG nonce at all
+    assert_eq!(nonce_distance(nt, 0x01200146), None);
+}
+
+#[test]
+fn parity_and_keystream_give_the_key() {
+    let uid = u32::from_be_bytes(UID);
+    let nt = prng_successor(0x01200145, 777);
+    let (_, encrypted_nt, parity) = CardAuth::nested(&KEY, uid, nt);
+    let encrypted_nt = u32::from_be_bytes(encrypted_nt);
+
+    assert!(parity_matches(nt, encrypted_nt, &parity));
+    // Three parity bits let about one in eight wrong nonces through
+    let wrong = (770..785).filter(|&n| n != 777)
+        .filter(|&n| parity_matches(prng_successor(0x01200145, n), encrypted_nt, &parity))
+        .count();
+    assert_eq!(wrong, 1);
+
+    assert!(candidate_keys(uid, nt, encrypted_nt).contains(&KEY));
+}
+
+#[test]
+fn raw_frames_pack_with_parity() {
+    let frame = RawFrame::new(vec![0x93, 0x20, 0xFF], vec![1, 0, 1]);
+    let (bits, last_bits) = pack_bits(&frame);
+    assert_eq!(bits.len(), 4);
+    assert_eq!(last_bits, 3);
+    assert_eq!(unpack_bits(&bits, last_bits), frame);
+
+    let ack = RawFrame::nibble(0x0A);
+    let (bits, last_bits) = pack_bits(&ack);
+    assert_eq!((bits, last_bits), (vec![0x0A], 4));
+    assert!(unpack_bits(&[0x0A], 4).is_nibble());
+
+    // Short frames go out as 7 bits without parity
+    assert_eq!(pack_bits(&RawFrame::short(0x40)), (vec![0x40], 7));
+}
+
+#[test]
+fn nested_attack_recovers_key() {
+    let card = ClassicSimulator::new(&UID)
+        .with_key(5, KeyType::KeyA, KEY)
+        .with_nonce_distance(200);
+    let attack = NestedAttack::new(&card, card.uid(), 0, KeyType::KeyA, [0xFF; 6]).with_tolerance(3);
+
+    assert_eq!(attack.measure_distances(3).unwrap(), vec![200]);
+    assert_eq!(attack.recover_key(20, KeyType::KeyA).unwrap(), KEY);
+    assert!(attack.verify_key(20, KeyType::KeyA, &KEY));
+    assert!(!attack.verify_key(20, KeyType::KeyA, &[0xFF; 6]));
+}
+
+#[test]
+fn nested_attack_needs_the_known_key() {
+    let card = ClassicSimulator::new(&UID).with_key(0, KeyType::KeyA, KEY);
+    let attack = NestedAttack::new(&card, card.uid(), 0, KeyType::KeyA, [0xFF; 6]);
+    assert!(attack.recover_key(4, KeyType::KeyA).is_err());
+}
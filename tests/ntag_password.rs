@@ -0,0 +1,176 @@
+use acr122u_test::ntag::{Protection, DEFAULT_PASSWORD};
+use acr122u_test::simulator::NtagSimulator;
+use acr122u_test::ultralight::{MifareUltralight, UltralightModel};
+
+const UID: [u8; 7] = [0x04, 0x51, 0x2C, 0x8A, 0x3B, 0x6E, 0x80];
+const PASSWORD: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+const PACK: [u8; 2] = [0xAB, 0xCD];
+
+fn tag(model: UltralightModel) -> MifareUltralight<'static> {
+    MifareUltralight::with_transceiver(NtagSimulator::new(model, &UID).unwrap())
+}
+
+#[test]
+fn factory_tag_is_unprotected() {
+    let ntag = tag(UltralightModel::Ntag215);
+    assert_eq!(ntag.detect_model().unwrap(), UltralightModel::Ntag215);
+
+    let config = ntag.read_access_config(UltralightModel::Ntag215).unwrap();
+    assert!(!config.is_protected(UltralightModel::Ntag215));
+    assert_eq!(config.authlim, 0);
+    assert!(!config.cfglck);
+
+    ntag.write_page(0x10, &[1, 2, 3, 4]).unwrap();
+    assert_eq!(ntag.read_page(0x10).unwrap(), [1, 2, 3, 4]);
+}
+
+#[test]
+fn read_write_protection_needs_pwd_auth() {
+    let model = UltralightModel::Ntag215;
+    let ntag = tag(model);
+    ntag.write_page(0x10, &[1, 2, 3, 4]).unwrap();
+    ntag.enable_password(model, &PASSWORD, &PACK, 0x10, Protection::ReadWrite, 0).unwrap();
+
+    // Pages before AUTH0 stay open
+    ntag.write_page(0x0F, &[5, 6, 7, 8]).unwrap();
+    assert!(ntag.read_page(0x10).is_err());
+    ntag.reactivate().unwrap();
+    assert!(ntag.write_page(0x10, &[0; 4]).is_err());
+    ntag.reactivate().unwrap();
+
+    ntag.authenticate_password(&PASSWORD, &PACK).unwrap();
+    assert_eq!(ntag.read_page(0x10).unwrap(), [1, 2, 3, 4]);
+    ntag.write_page(0x10, &[9, 9, 9, 9]).unwrap();
+
+    let config = ntag.read_access_config(model).unwrap();
+    assert_eq!(config.auth0, 0x10);
+    assert_eq!(config.protection, Protection::ReadWrite);
+
+    // A new activation needs a new PWD_AUTH
+    ntag.reactivate().unwrap();
+    assert!(ntag.read_page(0x10).is_err());
+}
+
+#[test]
+fn write_protection_leaves_reads_open() {
+    let model = UltralightModel::Ntag213;
+    let ntag = tag(model);
+    ntag.enable_password(model, &PASSWORD, &PACK, 0x04, Protection::Write, 0).unwrap();
+
+    assert!(ntag.read_page(0x04).is_ok());
+    assert!(ntag.write_page(0x04, &[1, 1, 1, 1]).is_err());
+    ntag.reactivate().unwrap();
+
+    ntag.pwd_auth(&PASSWORD).unwrap();
+    ntag.write_page(0x04, &[1, 1, 1, 1]).unwrap();
+}
+
+#[test]
+fn password_and_pack_read_as_zero() {
+    let model = UltralightModel::Ntag216;
+    let ntag = tag(model);
+    ntag.set_password(model, &PASSWORD, &PACK).unwrap();
+
+    let pwd_page = model.config_page().unwrap() + 2;
+    assert_eq!(ntag.read_page(pwd_page).unwrap(), [0; 4]);
+    assert_eq!(ntag.pwd_auth(&PASSWORD).unwrap(), PACK);
+}
+
+#[test]
+fn wrong_pack_is_reported() {
+    let model = UltralightModel::Ntag213;
+    let ntag = tag(model);
+    ntag.set_password(model, &PASSWORD, &PACK).unwrap();
+    assert!(ntag.authenticate_password(&PASSWORD, &[0x00, 0x00]).is_err());
+}
+
+#[test]
+fn authlim_locks_out_after_failed_attempts() {
+    let model = UltralightModel::Ntag215;
+    let ntag = tag(model);
+    // AUTHLIM 1 allows 2^1 failed attempts on NTAG21x
+    ntag.enable_password(model, &PASSWORD, &PACK, 0x04, Protection::ReadWrite, 1).unwrap();
+    assert!(ntag.read_access_config(model).is_err());
+    ntag.reactivate().unwrap();
+
+    assert!(ntag.pwd_auth(&DEFAULT_PASSWORD).is_err());
+    assert!(ntag.pwd_auth(&DEFAULT_PASSWORD).is_err());
+
+    // Even the right password is refused now, for good
+    assert!(ntag.pwd_auth(&PASSWORD).is_err());
+    ntag.reactivate().unwrap();
+    assert!(ntag.pwd_auth(&PASSWORD).is_err());
+}
+
+#[test]
+fn successful_auth_resets_attempt_counter() {
+    let model = UltralightModel::Ntag213;
+    let ntag = tag(model);
+    ntag.enable_password(model, &PASSWORD, &PACK, 0x04, Protection::Write, 1).unwrap();
+
+    assert!(ntag.pwd_auth(&DEFAULT_PASSWORD).is_err());
+    ntag.pwd_auth(&PASSWORD).unwrap();
+    ntag.reactivate().unwrap();
+    assert!(ntag.pwd_auth(&DEFAULT_PASSWORD).is_err());
+    ntag.pwd_auth(&PASSWORD).unwrap();
+}
+
+#[test]
+fn ultralight_ev1_authlim_counts_attempts() {
+    let model = UltralightModel::UltralightEv1_11;
+    let ntag = tag(model);
+    // On Ultralight EV1, AUTHLIM is the number of attempts itself
+    ntag.enable_password(model, &PASSWORD, &PACK, 0xFF, Protection::Write, 3).unwrap();
+    assert_eq!(ntag.read_access_config(model).unwrap().attempt_limit(model), Some(3));
+
+    for _ in 0..3 {
+        assert!(ntag.pwd_auth(&DEFAULT_PASSWORD).is_err());
+    }
+    assert!(ntag.pwd_auth(&PASSWORD).is_err());
+}
+
+#[test]
+fn cfglck_freezes_configuration_but_not_password() {
+    let model = UltralightModel::Ntag215;
+    let ntag = tag(model);
+    ntag.lock_config(model).unwrap();
+    assert!(ntag.read_access_config(model).unwrap().cfglck);
+
+    assert!(ntag.enable_password(model, &PASSWORD, &PACK, 0x04, Protection::ReadWrite, 0).is_err());
+    ntag.reactivate().unwrap();
+    assert!(ntag.disable_password(model).is_err());
+    ntag.reactivate().unwrap();
+
+    ntag.set_password(model, &PASSWORD, &PACK).unwrap();
+    assert_eq!(ntag.pwd_auth(&PASSWORD).unwrap(), PACK);
+}
+
+#[test]
+fn disable_password_after_auth() {
+    let model = UltralightModel::Ntag213;
+    let ntag = tag(model);
+    ntag.enable_password(model, &PASSWORD, &PACK, 0x04, Protection::ReadWrite, 0).unwrap();
+    ntag.reactivate().unwrap();
+
+    assert!(ntag.disable_password(model).is_err());
+    ntag.reactivate().unwrap();
+    ntag.pwd_auth(&PASSWORD).unwrap();
+    ntag.disable_password(model).unwrap();
+
+    ntag.reactivate().unwrap();
+    assert!(!ntag.read_access_config(model).unwrap().is_protected(model));
+    assert!(ntag.read_page(0x10).is_ok());
+}
+
+#[test]
+fn dump_stops_at_read_protected_pages() {
+    let model = UltralightModel::Ntag213;
+    let ntag = tag(model);
+    ntag.enable_password(model, &PASSWORD, &PACK, 0x20, Protection::ReadWrite, 0).unwrap();
+    ntag.reactivate().unwrap();
+
+    assert_eq!(ntag.dump(model).unwrap().len(), 0x20);
+
+    ntag.pwd_auth(&PASSWORD).unwrap();
+    assert_eq!(ntag.dump(model).unwrap().len(), model.page_count());
+}
@@ -0,0 +1,98 @@
+use acr122u_test::originality::{check_originality, verify_signature, Originality, NXP_NTAG21X_PUBLIC_KEY};
+use acr122u_test::simulator::NtagSimulator;
+use acr122u_test::ultralight::{MifareUltralight, UltralightModel};
+
+// Signature over the UID made with a test key pair (d = 2B7E1516...09CF4F3C mod n),
+// computed independently of this crate
+const UID: [u8; 7] = [0x04, 0x51, 0x2C, 0x8A, 0x3B, 0x6E, 0x80];
+const TEST_PUBLIC_KEY: [u8; 33] = [
+    0x04,
+    0xDC, 0xA0, 0xB4, 0xC6, 0x36, 0x96, 0x62, 0x6B, 0x82, 0xD8, 0x66, 0xEE, 0x47, 0x49, 0x7B, 0xC7,
+    0x66, 0xF3, 0x5E, 0x6C, 0x9E, 0xAD, 0x08, 0xA3, 0x3D, 0xD9, 0xB9, 0x31, 0xCA, 0xAF, 0xB6, 0x13,
+];
+const SIGNATURE: [u8; 32] = [
+    0x04, 0xC0, 0x7D, 0x66, 0x27, 0x84, 0xD5, 0x3B, 0xFD, 0x6D, 0x7D, 0x6B, 0xE2, 0x34, 0x7E, 0x26,
+    0x4D, 0xF1, 0x6A, 0xD8, 0xA1, 0x18, 0x84, 0xD7, 0x48, 0xF2, 0xB7, 0xD8, 0x6D, 0x16, 0x50, 0x3C,
+];
+
+#[test]
+fn valid_signature_verifies() {
+    assert!(verify_signature(&UID, &SIGNATURE, &TEST_PUBLIC_KEY));
+}
+
+#[test]
+fn signature_is_bound_to_uid_and_key() {
+    let mut uid = UID;
+    uid[6] ^= 0x01;
+    assert!(!verify_signature(&uid, &SIGNATURE, &TEST_PUBLIC_KEY));
+    assert!(!verify_signature(&UID, &SIGNATURE, &NXP_NTAG21X_PUBLIC_KEY));
+
+    let mut signature = SIGNATURE;
+    signature[31] ^= 0x80;
+    assert!(!verify_signature(&UID, &signature, &TEST_PUBLIC_KEY));
+}
+
+#[test]
+fn malformed_input_is_rejected() {
+    assert!(!verify_signature(&UID, &SIGNATURE[..31], &TEST_PUBLIC_KEY));
+    assert!(!verify_signature(&UID, &[0x00; 32], &TEST_PUBLIC_KEY));
+
+    // A point off the curve
+    let mut key = TEST_PUBLIC_KEY;
+    key[32] ^= 0x01;
+    assert!(!verify_signature(&UID, &SIGNATURE, &key));
+}
+
+#[test]
+fn originality_of_simulated_tags() {
+    let model = UltralightModel::Ntag213;
+    let blank = MifareUltralight::with_transceiver(NtagSimulator::new(model, &UID).unwrap());
+    let signature = blank.read_signature().unwrap();
+    assert_eq!(check_originality(&UID, Some(&signature)), Originality::Clone);
+
+    let signed = NtagSimulator::new(model, &UID).unwrap().with_signature(SIGNATURE);
+    let tag = MifareUltralight::with_transceiver(signed);
+    let signature = tag.read_signature().unwrap();
+    assert_eq!(check_originality(&tag.read_uid().unwrap(), Some(&signature)), Originality::Clone);
+    assert!(verify_signature(&tag.read_uid().unwrap(), &signature, &TEST_PUBLIC_KEY));
+
+    assert_eq!(check_originality(&UID, None), Originality::Unknown);
+}
+
+// UIDs and READ_SIG answers of two genuine NXP NTAG21x tags
+const NXP_SIGNED: [([u8; 7], [u8; 32]); 2] = [
+    ([0x04, 0xE1, 0x0C, 0xDA, 0x99, 0x3C, 0x80], [
+        0x8B, 0x76, 0x05, 0x2E, 0xE4, 0x2F, 0x55, 0x67, 0xBE, 0xB5, 0x32, 0x38, 0xB3, 0xE3, 0xF9, 0x95,
+        0x07, 0x07, 0xC0, 0xDC, 0xC9, 0x56, 0xB5, 0xC5, 0xEF, 0xCF, 0xDB, 0x70, 0x9B, 0x2D, 0x82, 0xB3,
+    ]),
+    ([0x04, 0xDB, 0x0B, 0xDA, 0x99, 0x3C, 0x80], [
+        0x60, 0x48, 0xEF, 0xD9, 0x41, 0x7C, 0xD1, 0x0F, 0x6B, 0x7F, 0x18, 0x18, 0xD4, 0x71, 0xA7, 0xFE,
+        0x5B, 0x46, 0x86, 0x8D, 0x2E, 0xAB, 0xDC, 0x63, 0x07, 0xA1, 0xE0, 0xAA, 0xE1, 0x39, 0xD8, 0xD0,
+    ]),
+];
+
+#[test]
+fn nxp_signed_tags_are_genuine() {
+    for (uid, signature) in NXP_SIGNED {
+        assert!(verify_signature(&uid, &signature, &NXP_NTAG21X_PUBLIC_KEY));
+        assert_eq!(check_originality(&uid, Some(&signature)), Originality::Genuine);
+
+        let tag = MifareUltralight::with_transceiver(NtagSimulator::new(UltralightModel::Ntag215, &uid).unwrap().with_signature(signature));
+        assert_eq!(check_originality(&tag.read_uid().unwrap(), Some(&tag.read_signature().unwrap())), Originality::Genuine);
+    }
+}
+
+#[test]
+fn altered_nxp_signatures_fail() {
+    let (uid, signature) = NXP_SIGNED[0];
+    let mut cloned_uid = uid;
+    cloned_uid[3] ^= 0x01;
+    assert_eq!(check_originality(&cloned_uid, Some(&signature)), Originality::Clone);
+
+    let mut altered = signature;
+    altered[0] ^= 0x01;
+    assert_eq!(check_originality(&uid, Some(&altered)), Originality::Clone);
+
+    // A genuine signature copied onto another tag
+    assert_eq!(check_originality(&NXP_SIGNED[1].0, Some(&signature)), Originality::Clone);
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use acr122u_test::pn532::{parse_type_a_targets, Target};
+use common::hex;
+
+// ATS of a DESFire EV1 (TL, T0, TA, TB, TC, one historical byte)
+const DESFIRE_ATS: [u8; 6] = [0x06, 0x75, 0x77, 0x81, 0x02, 0x80];
+
+#[test]
+fn single_target_with_4_byte_uid() {
+    let targets = parse_type_a_targets(&hex("01 01 00 04 08 04 DE AD BE EF")).unwrap();
+    assert_eq!(targets, [Target { tg: 1, atqa: [0x00, 0x04], sak: 0x08, uid: vec![0xDE, 0xAD, 0xBE, 0xEF], ats: None }]);
+    assert_eq!(targets[0].atqa_value(), 0x0004);
+    assert!(!targets[0].supports_iso14443_4());
+}
+
+#[test]
+fn uid_lengths() {
+    let targets = parse_type_a_targets(&hex("01 01 00 44 00 07 04 51 2C 8A 3B 6E 80")).unwrap();
+    assert_eq!(targets[0].uid, hex("04 51 2C 8A 3B 6E 80"));
+    assert_eq!(targets[0].ats, None);
+
+    let targets = parse_type_a_targets(&hex("01 01 00 44 00 0A 04 51 2C 8A 3B 6E 80 11 22 33")).unwrap();
+    assert_eq!(targets[0].uid, hex("04 51 2C 8A 3B 6E 80 11 22 33"));
+}
+
+#[test]
+fn ats_follows_iso14443_4_targets() {
+    let mut payload = hex("01 01 03 44 20 07 04 12 34 56 78 9A BC");
+    payload.extend_from_slice(&DESFIRE_ATS);
+    let targets = parse_type_a_targets(&payload).unwrap();
+    assert_eq!(targets.len(), 1);
+    assert_eq!(targets[0].atqa_value(), 0x0344);
+    assert!(targets[0].supports_iso14443_4());
+    assert_eq!(targets[0].ats.as_deref(), Some(&DESFIRE_ATS[..]));
+
+    // SAK 20 without an ATS in the response
+    let targets = parse_type_a_targets(&hex("01 01 03 44 20 07 04 12 34 56 78 9A BC")).unwrap();
+    assert_eq!(targets[0].ats, None);
+}
+
+#[test]
+fn two_targets() {
+    // A DESFire with its ATS, then a Classic 1K
+    let mut payload = hex("02 01 03 44 20 07 04 12 34 56 78 9A BC");
+    payload.extend_from_slice(&DESFIRE_ATS);
+    payload.extend_from_slice(&hex("02 00 04 08 04 DE AD BE EF"));
+    let targets = parse_type_a_targets(&payload).unwrap();
+    assert_eq!(targets.len(), 2);
+    assert_eq!((targets[0].tg, targets[0].sak), (1, 0x20));
+    assert_eq!(targets[0].ats.as_deref(), Some(&DESFIRE_ATS[..]));
+    assert_eq!(targets[1], Target { tg: 2, atqa: [0x00, 0x04], sak: 0x08, uid: vec![0xDE, 0xAD, 0xBE, 0xEF], ats: None });
+
+    // The other way round: the Classic's bytes are not taken as an ATS
+    let mut payload = hex("02 01 00 04 08 04 DE AD BE EF 02 03 44 20 07 04 12 34 56 78 9A BC");
+    payload.extend_from_slice(&DESFIRE_ATS);
+    let targets = parse_type_a_targets(&payload).unwrap();
+    assert_eq!(targets[0].ats, None);
+    assert_eq!(targets[1].uid, hex("04 12 34 56 78 9A BC"));
+    assert_eq!(targets[1].ats.as_deref(), Some(&DESFIRE_ATS[..]));
+}
+
+#[test]
+fn no_target() {
+    assert_eq!(parse_type_a_targets(&[0x00]).unwrap(), []);
+}
+
+#[test]
+fn truncated_payloads() {
+    assert!(parse_type_a_targets(&[]).is_err());
+    // Header cut short, UID cut short, ATS cut short and an empty ATS
+    assert!(parse_type_a_targets(&hex("01 01 00 04 08")).is_err());
+    assert!(parse_type_a_targets(&hex("01 01 00 04 08 04 DE AD BE")).is_err());
+    assert!(parse_type_a_targets(&hex("01 01 03 44 20 04 DE AD BE EF 06 75 77")).is_err());
+    assert!(parse_type_a_targets(&hex("01 01 03 44 20 04 DE AD BE EF 00")).is_err());
+    // The count announces a second target that is missing
+    assert!(parse_type_a_targets(&hex("02 01 00 04 08 04 DE AD BE EF")).is_err());
+}
@@ -0,0 +1,213 @@
+use acr122u_test::ndef::{NdefMessage, NdefRecord};
+use acr122u_test::simulator::{NtagSimulator, UltralightCSimulator};
+use acr122u_test::type2::{parse_tlvs, CapabilityContainer, ControlArea, DataArea, Tlv};
+use acr122u_test::ultralight::{MifareUltralight, UltralightModel};
+
+const UID: [u8; 7] = [0x04, 0x51, 0x2C, 0x8A, 0x3B, 0x6E, 0x80];
+
+fn ntag(model: UltralightModel) -> MifareUltralight<'static> {
+    MifareUltralight::with_transceiver(NtagSimulator::new(model, &UID).unwrap())
+}
+
+fn url(uri: &str) -> NdefMessage {
+    NdefMessage::new(vec![NdefRecord::uri(uri)])
+}
+
+#[test]
+fn capability_container() {
+    let cc = CapabilityContainer::decode(&[0xE1, 0x10, 0x12, 0x00]).unwrap();
+    assert_eq!(cc.data_size, 144);
+    assert!(!cc.is_read_only());
+    assert_eq!(cc, CapabilityContainer::for_model(UltralightModel::Ntag213));
+    assert_eq!(cc.encode(), [0xE1, 0x10, 0x12, 0x00]);
+
+    assert!(CapabilityContainer::decode(&[0x00, 0x10, 0x12, 0x00]).is_err());
+    assert!(CapabilityContainer::decode(&[0xE1, 0x20, 0x12, 0x00]).is_err());
+    assert!(CapabilityContainer::decode(&[0xE1, 0x10, 0x12, 0x0F]).unwrap().is_read_only());
+
+    // Factory CCs of the NTAG213/215/216 datasheets
+    let factory = [
+        (UltralightModel::Ntag213, [0xE1, 0x10, 0x12, 0x00]),
+        (UltralightModel::Ntag215, [0xE1, 0x10, 0x3E, 0x00]),
+        (UltralightModel::Ntag216, [0xE1, 0x10, 0x6D, 0x00]),
+    ];
+    for (model, cc) in factory {
+        assert_eq!(CapabilityContainer::for_model(model).encode(), cc, "{:?}", model);
+        // Formatting a factory tag keeps its CC as it is
+        let tag = ntag(model);
+        assert_eq!(tag.read_page(3).unwrap(), cc, "{:?}", model);
+        tag.format_ndef(model).unwrap();
+        assert_eq!(tag.read_page(3).unwrap(), cc, "{:?}", model);
+    }
+}
+
+#[test]
+fn tlv_blocks() {
+    let tlvs = parse_tlvs(&[0x00, 0x01, 0x03, 0xA0, 0x10, 0x44, 0x03, 0x00, 0xFE, 0x55]).unwrap();
+    let lock = ControlArea { position: 0xA0, size: 2, bytes_per_lock_bit: 16 };
+    assert_eq!(tlvs, vec![Tlv::Null, Tlv::LockControl(lock), Tlv::Ndef(Vec::new()), Tlv::Terminator]);
+    assert_eq!(Tlv::LockControl(lock).encode(), [0x01, 0x03, 0xA0, 0x10, 0x44]);
+
+    // Three-byte length from 255 bytes on
+    let long = Tlv::Ndef(vec![0xAA; 300]).encode();
+    assert_eq!(&long[0..4], &[0x03, 0xFF, 0x01, 0x2C]);
+    assert_eq!(parse_tlvs(&long).unwrap(), vec![Tlv::Ndef(vec![0xAA; 300])]);
+
+    assert!(parse_tlvs(&[0x03, 0x05, 0x00]).is_err());
+}
+
+#[test]
+fn reserved_memory_is_skipped() {
+    let cc = CapabilityContainer::decode(&[0xE1, 0x10, 0x06, 0x00]).unwrap();
+    let message = url("https://example.com/");
+    let ndef = Tlv::Ndef(message.to_bytes().unwrap()).encode();
+
+    // Memory Control TLV reserving the 4 bytes at address 0x20 (page 8),
+    // which the NDEF TLV then jumps over
+    let mut raw = vec![0x02, 0x03, 0x80, 0x04, 0x02];
+    raw.extend_from_slice(&ndef[..11]);
+    raw.extend_from_slice(&[0xEE; 4]);
+    raw.extend_from_slice(&ndef[11..]);
+    raw.push(0xFE);
+    raw.resize(48, 0x00);
+
+    let area = DataArea::new(cc, raw);
+    assert_eq!(area.capacity(), 44);
+    assert_eq!(area.ndef_message().unwrap(), message);
+}
+
+#[test]
+fn format_write_and_read_ntag() {
+    let model = UltralightModel::Ntag213;
+    let tag = ntag(model);
+    assert!(tag.read_ndef().is_err());
+
+    tag.format_ndef(model).unwrap();
+    assert!(tag.read_ndef().unwrap().is_empty());
+    assert_eq!(tag.read_page(4).unwrap(), [0x03, 0x00, 0xFE, 0x00]);
+
+    let message = url("https://www.example.com/a/page/with/a/rather/long/path");
+    tag.write_ndef(&message).unwrap();
+    assert_eq!(tag.read_ndef().unwrap(), message);
+
+    // A shorter message replaces it
+    let message = NdefMessage::new(vec![NdefRecord::text("en", "hi")]);
+    tag.write_ndef(&message).unwrap();
+    assert_eq!(tag.read_ndef().unwrap(), message);
+}
+
+#[test]
+fn long_message_spans_pages() {
+    let model = UltralightModel::Ntag215;
+    let tag = ntag(model);
+    tag.format_ndef(model).unwrap();
+
+    let vcard = "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Jo Example\r\nNOTE:".to_string() + &"x".repeat(300) + "\r\nEND:VCARD\r\n";
+    let message = NdefMessage::new(vec![NdefRecord::mime("text/vcard", vcard.as_bytes())]);
+    tag.write_ndef(&message).unwrap();
+    assert_eq!(tag.read_ndef().unwrap(), message);
+    assert_eq!(&tag.read_page(4).unwrap()[0..2], &[0x03, 0xFF]);
+}
+
+#[test]
+fn message_must_fit() {
+    let model = UltralightModel::Ntag213;
+    let tag = ntag(model);
+    tag.format_ndef(model).unwrap();
+
+    let message = NdefMessage::new(vec![NdefRecord::mime("application/octet-stream", &[0x00; 200])]);
+    assert!(tag.write_ndef(&message).is_err());
+    assert!(tag.read_ndef().unwrap().is_empty());
+}
+
+#[test]
+fn length_is_written_last() {
+    let cc = CapabilityContainer::for_model(UltralightModel::Ntag213);
+    let mut raw = vec![0x03, 0x00, 0xFE];
+    raw.resize(cc.data_size, 0x00);
+    let area = DataArea::new(cc, raw);
+
+    let message = url("https://example.com/");
+    let (empty, updated) = area.with_message(&message).unwrap();
+    assert_eq!(empty[1], 0x00);
+    assert_eq!(&empty[2..], &updated[2..]);
+    assert!(DataArea::new(cc, empty).ndef_message().unwrap().is_empty());
+    assert_eq!(DataArea::new(cc, updated).ndef_message().unwrap(), message);
+}
+
+#[test]
+fn other_tlvs_are_kept() {
+    let model = UltralightModel::Ntag213;
+    let tag = ntag(model);
+    tag.format_ndef(model).unwrap();
+
+    // A Proprietary TLV after the NDEF TLV moves along as the message grows
+    // and shrinks
+    let proprietary = Tlv::Proprietary(vec![0xCA, 0xFE, 0x00]);
+    let mut raw = Tlv::Ndef(Vec::new()).encode();
+    raw.extend(proprietary.encode());
+    raw.push(0xFE);
+    raw.resize(8, 0x00);
+    tag.write_page(4, &raw[0..4]).unwrap();
+    tag.write_page(5, &raw[4..8]).unwrap();
+
+    for message in [url("https://www.example.com/a/long/path"), url("https://a.io/")] {
+        tag.write_ndef(&message).unwrap();
+        assert_eq!(tag.read_ndef().unwrap(), message);
+        let tlvs = tag.read_data_area().unwrap().tlvs().unwrap();
+        assert_eq!(tlvs[1..], [proprietary.clone(), Tlv::Terminator]);
+    }
+
+    // Without an NDEF TLV the message goes in front of it
+    let cc = CapabilityContainer::for_model(model);
+    let mut raw = proprietary.encode();
+    raw.push(0xFE);
+    raw.resize(cc.data_size, 0x00);
+    let area = DataArea::new(cc, raw);
+    let message = url("https://example.com/");
+    let (_, updated) = area.with_message(&message).unwrap();
+    let tlvs = parse_tlvs(&updated).unwrap();
+    assert_eq!(tlvs, [Tlv::Ndef(message.to_bytes().unwrap()), proprietary.clone(), Tlv::Terminator]);
+
+    // The blocks kept count against the room left
+    let mut raw = Tlv::Ndef(Vec::new()).encode();
+    raw.extend(Tlv::Proprietary(vec![0x00; 100]).encode());
+    raw.resize(cc.data_size, 0x00);
+    let area = DataArea::new(cc, raw);
+    let message = NdefMessage::new(vec![NdefRecord::mime("application/octet-stream", &[0x00; 40])]);
+    assert!(area.with_message(&message).is_err());
+}
+
+#[test]
+fn ultralight_c_format_keeps_lock_control() {
+    let model = UltralightModel::UltralightC;
+    let tag = MifareUltralight::with_transceiver(UltralightCSimulator::new(&UID));
+    tag.format_ndef(model).unwrap();
+
+    assert_eq!(tag.read_page(3).unwrap(), [0xE1, 0x10, 0x12, 0x00]);
+    let tlvs = tag.read_data_area().unwrap().tlvs().unwrap();
+    assert!(matches!(tlvs[0], Tlv::LockControl(ControlArea { position: 0xA0, size: 2, .. })));
+
+    let message = url("https://example.com/");
+    tag.write_ndef(&message).unwrap();
+    assert_eq!(tag.read_ndef().unwrap(), message);
+    assert_eq!(tag.read_page(4).unwrap(), [0x01, 0x03, 0xA0, 0x10]);
+}
+
+#[test]
+fn read_only_tag() {
+    let model = UltralightModel::Ntag213;
+    let tag = ntag(model);
+    tag.format_ndef(model).unwrap();
+    let message = url("https://example.com/");
+    tag.write_ndef(&message).unwrap();
+
+    tag.make_read_only(model).unwrap();
+    assert!(tag.read_capability_container().unwrap().is_read_only());
+    assert_eq!(tag.read_ndef().unwrap(), message);
+    assert!(tag.write_ndef(&url("https://example.org/")).is_err());
+
+    let locks = tag.read_lock_bits(model).unwrap();
+    assert_eq!(locks.locked_pages(model), (3..model.user_end()).collect::<Vec<u8>>());
+    assert!(tag.write_page(0x20, &[0; 4]).is_err());
+}
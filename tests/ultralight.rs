@@ -0,0 +1,168 @@
+use acr122u_test::simulator::NtagSimulator;
+use acr122u_test::ultralight::{dynamic_lock_bit, LockBits, MifareUltralight, UltralightModel};
+
+const UID: [u8; 7] = [0x04, 0x51, 0x2C, 0x8A, 0x3B, 0x6E, 0x80];
+
+// Models the NTAG simulator covers
+const SIMULATED: [UltralightModel; 5] = [
+    UltralightModel::UltralightEv1_11,
+    UltralightModel::UltralightEv1_21,
+    UltralightModel::Ntag213,
+    UltralightModel::Ntag215,
+    UltralightModel::Ntag216,
+];
+
+struct Layout {
+    model: UltralightModel,
+    // GET_VERSION response
+    version: [u8; 8],
+    page_count: usize,
+    user_end: u8,
+    user_memory: usize,
+    dynamic_lock_page: Option<u8>,
+    config_page: Option<u8>,
+}
+
+// Memory organisation from the MF0ULX1 and NTAG213/215/216 datasheets
+const LAYOUTS: [Layout; 5] = [
+    Layout { model: UltralightModel::UltralightEv1_11, version: [0x00, 0x04, 0x03, 0x01, 0x01, 0x00, 0x0B, 0x03],
+             page_count: 20, user_end: 0x10, user_memory: 48, dynamic_lock_page: None, config_page: Some(0x10) },
+    Layout { model: UltralightModel::UltralightEv1_21, version: [0x00, 0x04, 0x03, 0x01, 0x01, 0x00, 0x0E, 0x03],
+             page_count: 41, user_end: 0x24, user_memory: 128, dynamic_lock_page: Some(0x24), config_page: Some(0x25) },
+    Layout { model: UltralightModel::Ntag213, version: [0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x0F, 0x03],
+             page_count: 45, user_end: 0x28, user_memory: 144, dynamic_lock_page: Some(0x28), config_page: Some(0x29) },
+    Layout { model: UltralightModel::Ntag215, version: [0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x11, 0x03],
+             page_count: 135, user_end: 0x82, user_memory: 504, dynamic_lock_page: Some(0x82), config_page: Some(0x83) },
+    Layout { model: UltralightModel::Ntag216, version: [0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x13, 0x03],
+             page_count: 231, user_end: 0xE2, user_memory: 888, dynamic_lock_page: Some(0xE2), config_page: Some(0xE3) },
+];
+
+#[test]
+fn memory_layouts() {
+    for layout in &LAYOUTS {
+        let model = layout.model;
+        assert_eq!(UltralightModel::from_version(&layout.version), Some(model));
+        assert_eq!(model.page_count(), layout.page_count, "{:?}", model);
+        assert_eq!(model.user_end(), layout.user_end, "{:?}", model);
+        assert_eq!(model.user_memory(), layout.user_memory, "{:?}", model);
+        assert_eq!(model.dynamic_lock_page(), layout.dynamic_lock_page, "{:?}", model);
+        assert_eq!(model.config_page(), layout.config_page, "{:?}", model);
+        // CFG0, CFG1, PWD and PACK are the last pages
+        assert_eq!(layout.config_page.unwrap() as usize + 4, layout.page_count, "{:?}", model);
+    }
+}
+
+#[test]
+fn unknown_versions_are_rejected() {
+    // Not NXP, a MIFARE Plus, an unknown storage size and a short response
+    assert_eq!(UltralightModel::from_version(&[0x00, 0x05, 0x04, 0x02, 0x01, 0x00, 0x0F, 0x03]), None);
+    assert_eq!(UltralightModel::from_version(&[0x00, 0x04, 0x02, 0x01, 0x00, 0x00, 0x0F, 0x03]), None);
+    assert_eq!(UltralightModel::from_version(&[0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x15, 0x03]), None);
+    assert_eq!(UltralightModel::from_version(&[0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x0F]), None);
+}
+
+#[test]
+fn dynamic_lock_bits() {
+    // MF0UL21: one bit per two pages, pages 16-35 in byte 0 and bits 0-1 of
+    // byte 1
+    let model = UltralightModel::UltralightEv1_21;
+    assert_eq!(dynamic_lock_bit(model, 15), None);
+    assert_eq!(dynamic_lock_bit(model, 16), Some((0, 0x01)));
+    assert_eq!(dynamic_lock_bit(model, 17), Some((0, 0x01)));
+    assert_eq!(dynamic_lock_bit(model, 18), Some((0, 0x02)));
+    assert_eq!(dynamic_lock_bit(model, 31), Some((0, 0x80)));
+    assert_eq!(dynamic_lock_bit(model, 32), Some((1, 0x01)));
+    assert_eq!(dynamic_lock_bit(model, 35), Some((1, 0x02)));
+    assert_eq!(dynamic_lock_bit(model, 36), None);
+
+    // NTAG213: one bit per two pages, pages 16-39
+    let model = UltralightModel::Ntag213;
+    assert_eq!(dynamic_lock_bit(model, 16), Some((0, 0x01)));
+    assert_eq!(dynamic_lock_bit(model, 18), Some((0, 0x02)));
+    assert_eq!(dynamic_lock_bit(model, 39), Some((1, 0x08)));
+    assert_eq!(dynamic_lock_bit(model, 40), None);
+
+    // NTAG215 and NTAG216: one bit per sixteen pages
+    let model = UltralightModel::Ntag215;
+    assert_eq!(dynamic_lock_bit(model, 31), Some((0, 0x01)));
+    assert_eq!(dynamic_lock_bit(model, 32), Some((0, 0x02)));
+    assert_eq!(dynamic_lock_bit(model, 129), Some((0, 0x80)));
+    assert_eq!(dynamic_lock_bit(model, 130), None);
+    let model = UltralightModel::Ntag216;
+    assert_eq!(dynamic_lock_bit(model, 144), Some((1, 0x01)));
+    assert_eq!(dynamic_lock_bit(model, 225), Some((1, 0x20)));
+    assert_eq!(dynamic_lock_bit(model, 226), None);
+
+    // Ultralight C: four pages per bit, skipping the block-lock bits 0 and 4
+    let model = UltralightModel::UltralightC;
+    assert_eq!(dynamic_lock_bit(model, 16), Some((0, 0x02)));
+    assert_eq!(dynamic_lock_bit(model, 27), Some((0, 0x08)));
+    assert_eq!(dynamic_lock_bit(model, 28), Some((0, 0x20)));
+    assert_eq!(dynamic_lock_bit(model, 39), Some((0, 0x80)));
+
+    // Products without dynamic lock bytes
+    assert_eq!(dynamic_lock_bit(UltralightModel::UltralightEv1_11, 16), None);
+    assert_eq!(dynamic_lock_bit(UltralightModel::Ultralight, 16), None);
+}
+
+#[test]
+fn lock_bits_map_to_pages() {
+    let locks = LockBits {
+        // OTP, pages 4 and 7, pages 8 and 15
+        static_lock: [0x98, 0x81],
+        // Pages 16-17 and 34-35
+        dynamic_lock: Some([0x01, 0x02, 0x00]),
+        otp: [0x00; 4],
+    };
+    let model = UltralightModel::UltralightEv1_21;
+    assert!(locks.otp_locked());
+    assert!(locks.is_page_locked(model, 4));
+    assert!(!locks.is_page_locked(model, 5));
+    assert_eq!(locks.locked_pages(model), [3, 4, 7, 8, 15, 16, 17, 34, 35]);
+
+    // Without dynamic lock bytes only the static bits count
+    let model = UltralightModel::UltralightEv1_11;
+    let locks = LockBits { dynamic_lock: None, ..locks };
+    assert_eq!(locks.locked_pages(model), [3, 4, 7, 8, 15]);
+    assert!(!LockBits { static_lock: [0x00; 2], dynamic_lock: None, otp: [0xFF; 4] }.otp_locked());
+}
+
+#[test]
+fn lock_bits_read_back_from_the_tag() {
+    for model in SIMULATED {
+        let tag = MifareUltralight::with_transceiver(NtagSimulator::new(model, &UID).unwrap());
+        assert!(tag.read_lock_bits(model).unwrap().locked_pages(model).is_empty(), "{:?}", model);
+
+        tag.write_page(2, &[0x00, 0x00, 0x10, 0x00]).unwrap();
+        if let Some(page) = model.dynamic_lock_page() {
+            tag.write_page(page, &[0x01, 0x00, 0x00, 0x00]).unwrap();
+        }
+
+        let locks = tag.read_lock_bits(model).unwrap();
+        assert_eq!(locks.static_lock, [0x10, 0x00], "{:?}", model);
+        assert_eq!(locks.dynamic_lock, model.dynamic_lock_page().map(|_| [0x01, 0x00, 0x00]), "{:?}", model);
+        assert!(locks.is_page_locked(model, 4));
+        assert!(tag.write_page(4, &[0x00; 4]).is_err());
+        if model.dynamic_lock_page().is_some() {
+            assert!(locks.is_page_locked(model, 16), "{:?}", model);
+        }
+    }
+}
+
+#[test]
+fn dump_reads_every_page() {
+    for model in SIMULATED {
+        let tag = MifareUltralight::with_transceiver(NtagSimulator::new(model, &UID).unwrap());
+        let last_user_page = model.user_end() - 1;
+        tag.write_page(last_user_page, &[0xDE, 0xAD, 0xBE, 0xEF]).unwrap();
+
+        let pages = tag.dump(model).unwrap();
+        assert_eq!(pages.len(), model.page_count(), "{:?}", model);
+        assert_eq!(pages[0][0..3], UID[0..3]);
+        assert_eq!(pages[0][3], 0x88 ^ UID[0] ^ UID[1] ^ UID[2]);
+        assert_eq!(pages[1], [UID[3], UID[4], UID[5], UID[6]]);
+        assert_eq!(pages[last_user_page as usize], [0xDE, 0xAD, 0xBE, 0xEF], "{:?}", model);
+        // PWD reads as zeros
+        assert_eq!(pages[model.config_page().unwrap() as usize + 2], [0x00; 4], "{:?}", model);
+    }
+}
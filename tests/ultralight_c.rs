@@ -0,0 +1,130 @@
+mod common;
+
+use acr122u_test::ntag::Protection;
+use acr122u_test::simulator::UltralightCSimulator;
+use acr122u_test::ultralight::{MifareUltralight, UltralightModel};
+use acr122u_test::ultralight_c::{key_pages, AuthConfig, Handshake, DEFAULT_KEY};
+use common::hex;
+
+const UID: [u8; 7] = [0x04, 0x51, 0x2C, 0x8A, 0x3B, 0x6E, 0x80];
+const MODEL: UltralightModel = UltralightModel::UltralightC;
+const KEY: [u8; 16] = [
+    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF,
+];
+
+fn tag() -> MifareUltralight<'static> {
+    MifareUltralight::with_transceiver(UltralightCSimulator::new(&UID))
+}
+
+// Handshake vectors: key, RndA, ek(RndB), ek(RndA || RndB'), ek(RndA'). The
+// first is the authentication example of the MF0ICU2 datasheet (RndB =
+// 51E764602678DF2B), the second was computed with an independent 3DES
+// implementation
+#[test]
+fn handshake_matches_known_vectors() {
+    let vectors = [
+        (DEFAULT_KEY, "A8AF3B256C75ED40", "577293FD2F34CA51", "0A638559FC7737F9F15D7862EBBE967A", "3B884FA07C137CE1"),
+        ([0x00; 16], "A1B2C3D4E5F60718", "57AFED789CEFF248", "20E9DAF7D1EEC7F86761994C356F4282", "B10AB1BCD69902A0"),
+    ];
+
+    for (key, rnd_a, ek_rnd_b, token, ek_rnd_a) in vectors {
+        let mut handshake = Handshake::new(&key, &hex(rnd_a).try_into().unwrap());
+        assert_eq!(handshake.respond(&hex(ek_rnd_b)).unwrap().to_vec(), hex(token));
+        handshake.verify(&hex(ek_rnd_a)).unwrap();
+    }
+}
+
+#[test]
+fn handshake_rejects_wrong_card_answer() {
+    let mut handshake = Handshake::new(&DEFAULT_KEY, &hex("A8AF3B256C75ED40").try_into().unwrap());
+    handshake.respond(&hex("577293FD2F34CA51")).unwrap();
+    assert!(handshake.verify(&hex("3B884FA07C137CE0")).is_err());
+    assert!(handshake.verify(&hex("3B884FA0")).is_err());
+}
+
+#[test]
+fn default_key_layout() {
+    // "BREAKMEIFYOUCAN!" as it reads in pages 2C-2F of a factory card
+    assert_eq!(key_pages(&DEFAULT_KEY), [
+        *b"BREA", *b"KMEI", *b"FYOU", *b"CAN!",
+    ]);
+}
+
+#[test]
+fn factory_card_authenticates_with_default_key() {
+    let card = tag();
+    assert_eq!(card.detect_model().unwrap(), MODEL);
+    assert!(!card.read_auth_config(MODEL).unwrap().is_protected());
+
+    card.authenticate_3des(&DEFAULT_KEY).unwrap();
+    assert!(card.authenticate_3des(&KEY).is_err());
+    // The failure was recovered from
+    card.authenticate_3des(&DEFAULT_KEY).unwrap();
+}
+
+#[test]
+fn key_pages_are_write_only() {
+    let card = tag();
+    assert!(card.read_page(0x2C).is_err());
+    card.reactivate().unwrap();
+
+    card.change_key(MODEL, &KEY).unwrap();
+    card.authenticate_3des(&KEY).unwrap();
+    assert!(card.authenticate_3des(&DEFAULT_KEY).is_err());
+}
+
+#[test]
+fn read_write_protection_needs_authentication() {
+    let card = tag();
+    card.write_page(0x10, &[1, 2, 3, 4]).unwrap();
+    card.enable_3des_protection(MODEL, &KEY, 0x10, Protection::ReadWrite).unwrap();
+
+    assert!(card.read_page(0x10).is_err());
+    card.reactivate().unwrap();
+    assert!(card.write_page(0x10, &[0; 4]).is_err());
+    card.reactivate().unwrap();
+    card.write_page(0x0F, &[5, 6, 7, 8]).unwrap();
+
+    card.authenticate_3des(&KEY).unwrap();
+    assert_eq!(card.read_page(0x10).unwrap(), [1, 2, 3, 4]);
+    assert_eq!(card.read_auth_config(MODEL).unwrap(), AuthConfig { auth0: 0x10, protection: Protection::ReadWrite });
+
+    // A new activation needs a new authentication
+    card.reactivate().unwrap();
+    assert!(card.read_page(0x10).is_err());
+}
+
+#[test]
+fn write_protection_leaves_reads_open() {
+    let card = tag();
+    card.enable_3des_protection(MODEL, &KEY, 0x04, Protection::Write).unwrap();
+
+    assert!(card.read_page(0x04).is_ok());
+    assert!(card.write_page(0x04, &[1, 1, 1, 1]).is_err());
+    card.reactivate().unwrap();
+
+    card.authenticate_3des(&KEY).unwrap();
+    card.write_page(0x04, &[1, 1, 1, 1]).unwrap();
+}
+
+#[test]
+fn disable_protection_after_authentication() {
+    let card = tag();
+    card.enable_3des_protection(MODEL, &KEY, 0x04, Protection::ReadWrite).unwrap();
+    card.reactivate().unwrap();
+
+    assert!(card.disable_3des_protection(MODEL).is_err());
+    card.reactivate().unwrap();
+    card.authenticate_3des(&KEY).unwrap();
+    card.disable_3des_protection(MODEL).unwrap();
+
+    card.reactivate().unwrap();
+    assert!(card.read_page(0x10).is_ok());
+}
+
+#[test]
+fn protection_is_ultralight_c_only() {
+    let card = tag();
+    assert!(card.change_key(UltralightModel::Ntag213, &KEY).is_err());
+    assert!(card.enable_3des_protection(MODEL, &KEY, 0x30, Protection::Write).is_err());
+}